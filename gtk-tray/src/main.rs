@@ -7,7 +7,7 @@ use std::sync::Mutex;
 use std::thread;
 use stray::message::menu::{MenuType, TrayMenu};
 use stray::message::tray::{IconPixmap, StatusNotifierItem};
-use stray::message::{NotifierItemCommand, NotifierItemMessage};
+use stray::message::{ItemAddress, MenuPath, NotifierItemCommand, NotifierItemMessage};
 use stray::StatusNotifierWatcher;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
@@ -21,14 +21,15 @@ pub struct StatusNotifierWrapper {
     menu: stray::message::menu::MenuItem,
 }
 
-static STATE: Lazy<Mutex<HashMap<String, NotifierItem>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static STATE: Lazy<Mutex<HashMap<ItemAddress, NotifierItem>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 impl StatusNotifierWrapper {
     fn into_menu_item(
         self,
         sender: mpsc::Sender<NotifierItemCommand>,
-        notifier_address: String,
-        menu_path: String,
+        notifier_address: ItemAddress,
+        menu_path: MenuPath,
     ) -> MenuItem {
         let item: Box<dyn AsRef<MenuItem>> = match self.menu.menu_type {
             MenuType::Separator => Box::new(SeparatorMenuItem::new()),
@@ -81,30 +82,17 @@ impl NotifierItem {
     }
 
     fn get_icon_from_pixmaps(&self, pixmaps: &[IconPixmap]) -> Option<Image> {
-        let pixmap = pixmaps
-            .iter()
-            .find(|pm| pm.height > 20 && pm.height < 32)
-            .expect("No icon of suitable size found");
+        let pixmap = IconPixmap::select_best(pixmaps, 24).filter(|pixmap| pixmap.is_valid())?;
 
-        let pixbuf = gtk::gdk_pixbuf::Pixbuf::new(
+        let pixbuf = gtk::gdk_pixbuf::Pixbuf::from_bytes(
+            &glib::Bytes::from_owned(pixmap.to_rgba()),
             gtk::gdk_pixbuf::Colorspace::Rgb,
             true,
             8,
             pixmap.width,
             pixmap.height,
-        )
-        .expect("Failed to allocate pixbuf");
-
-        for y in 0..pixmap.height {
-            for x in 0..pixmap.width {
-                let index = (y * pixmap.width + x) * 4;
-                let a = pixmap.pixels[index as usize];
-                let r = pixmap.pixels[(index + 1) as usize];
-                let g = pixmap.pixels[(index + 2) as usize];
-                let b = pixmap.pixels[(index + 3) as usize];
-                pixbuf.put_pixel(x as u32, y as u32, r, g, b, a);
-            }
-        }
+            pixmap.width * 4,
+        );
 
         Some(Image::from_pixbuf(Some(&pixbuf)))
     }
@@ -169,12 +157,32 @@ fn spawn_local_handler(
                     address: id,
                     item,
                     menu,
+                    ..
                 } => {
-                    state.insert(id, NotifierItem { item: *item, menu });
+                    state.insert(
+                        id,
+                        NotifierItem {
+                            item: item.as_ref().clone(),
+                            menu: menu.cached().cloned(),
+                        },
+                    );
                 }
-                NotifierItemMessage::Remove { address } => {
+                NotifierItemMessage::Remove { address, .. }
+                | NotifierItemMessage::Rejected { address, .. } => {
                     state.remove(&address);
                 }
+                NotifierItemMessage::Reset { .. } => {
+                    state.clear();
+                }
+                NotifierItemMessage::WatcherLost { .. } => {
+                    eprintln!("Another tray took the StatusNotifierWatcher name over");
+                }
+                NotifierItemMessage::CommandFailed { command, error, .. } => {
+                    eprintln!("{command} failed: {error}");
+                }
+                NotifierItemMessage::Error { address, error, .. } => {
+                    eprintln!("{address} failed: {error}");
+                }
             }
 
             for child in v_box.children() {
@@ -199,10 +207,8 @@ fn spawn_local_handler(
                                 menu: submenu.to_owned(),
                             })
                             .map(|item| {
-                                let menu_path =
-                                    notifier_item.item.menu.as_ref().unwrap().to_string();
-                                let address = address.to_string();
-                                item.into_menu_item(cmd_tx.clone(), address, menu_path)
+                                let menu_path = notifier_item.item.menu.clone().unwrap();
+                                item.into_menu_item(cmd_tx.clone(), address.clone(), menu_path)
                             })
                             .for_each(|item| menu.append(&item));
 
@@ -230,6 +236,7 @@ fn start_communication_thread(
 
         runtime.block_on(async {
             let tray = StatusNotifierWatcher::new(cmd_rx).await.unwrap();
+            tokio::spawn(tray.run(stray::CancellationToken::new()));
             let mut host = tray.create_notifier_host("MyHost").await.unwrap();
 
             while let Ok(message) = host.recv().await {
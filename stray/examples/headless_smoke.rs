@@ -0,0 +1,12 @@
+// Validate stray against the local dbus daemon and zbus version without any tray application,
+// run with `cargo run --example headless_smoke --features test-util`.
+#[tokio::main]
+async fn main() {
+    match stray::test_util::smoke_test().await {
+        Ok(()) => println!("stray smoke test passed"),
+        Err(err) => {
+            eprintln!("stray smoke test failed: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,11 +1,10 @@
 use stray::StatusNotifierWatcher;
 use tokio::join;
-use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> stray::error::Result<()> {
-    let (_cmd_tx, cmd_rx) = mpsc::channel(10);
-    let tray = StatusNotifierWatcher::new(cmd_rx).await?;
+    let tray = StatusNotifierWatcher::new(None).await?;
+    tokio::spawn(tray.run(stray::CancellationToken::new()));
 
     let mut host_one = tray.create_notifier_host("host_one").await.unwrap();
     let mut host_two = tray.create_notifier_host("host_two").await.unwrap();
@@ -0,0 +1,487 @@
+// Drive `DbusNotifierWatcher` on a private bus the way existing hosts and items use the
+// StatusNotifierWatcher interface:
+// - libappindicator registers its items by object path,
+// - KStatusNotifierItem registers the well-known name it owns,
+// - some items register their unique name,
+// - plasmashell and waybar register a host, then read `RegisteredStatusNotifierItems` and follow
+//   the registration signals,
+// - hosts are registered again when the bus restarts.
+// Tests are skipped when `dbus-daemon` is not installed.
+
+use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
+use crate::dbus::notifier_watcher_service::DbusNotifierWatcher;
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::registry::{Registry, RegistryEvent};
+use crate::{Bus, CancellationToken, StatusNotifierWatcher};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{Stream, StreamExt};
+use zbus::fdo::PropertiesProxy;
+use zbus::names::InterfaceName;
+use zbus::{CacheProperties, Connection, ConnectionBuilder};
+
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// A dbus-daemon private to a test, killed along with it
+struct PrivateBus {
+    daemon: Child,
+    address: String,
+    // The socket of a bus started with `PrivateBus::start_at`
+    socket: Option<PathBuf>,
+}
+
+impl PrivateBus {
+    fn start() -> Option<Self> {
+        let (daemon, address) = spawn_daemon(None)?;
+        Some(PrivateBus {
+            daemon,
+            address,
+            socket: None,
+        })
+    }
+
+    // A bus listening on a fixed socket, so it can be restarted at the same address
+    fn start_at(socket: &Path) -> Option<Self> {
+        let _ = std::fs::remove_file(socket);
+        let (daemon, _) = spawn_daemon(Some(socket))?;
+        Some(PrivateBus {
+            daemon,
+            address: format!("unix:path={}", socket.display()),
+            socket: Some(socket.to_path_buf()),
+        })
+    }
+
+    // Kill the daemon and start a new one on the same socket, as when the session bus restarts
+    fn restart(&mut self) {
+        let socket = self
+            .socket
+            .clone()
+            .expect("only buses started at a socket restart");
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = std::fs::remove_file(&socket);
+        let (daemon, _) = spawn_daemon(Some(&socket)).expect("failed to restart dbus-daemon");
+        self.daemon = daemon;
+    }
+
+    async fn connect(&self) -> Connection {
+        ConnectionBuilder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+// Start a dbus-daemon, on `socket` if any, and return it along with its address
+fn spawn_daemon(socket: Option<&Path>) -> Option<(Child, String)> {
+    let mut command = Command::new("dbus-daemon");
+    command.args(["--session", "--nofork", "--print-address"]);
+    if let Some(socket) = socket {
+        command.arg(format!("--address=unix:path={}", socket.display()));
+    }
+
+    let mut daemon = match command.stdout(Stdio::piped()).stderr(Stdio::null()).spawn() {
+        Ok(daemon) => daemon,
+        Err(err) => {
+            eprintln!("Skipping conformance test, failed to start dbus-daemon: {err}");
+            return None;
+        }
+    };
+
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take()?)
+        .read_line(&mut address)
+        .ok()?;
+
+    Some((daemon, address.trim().to_string()))
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+// The watcher served on the private bus, along with the events it sent to the registry
+struct Watcher {
+    _connection: Connection,
+    registry: mpsc::Receiver<RegistryEvent>,
+}
+
+impl Watcher {
+    async fn serve(bus: &PrivateBus) -> Self {
+        let (sender, _) = broadcast::channel(8);
+        let (events, _) = broadcast::channel(8);
+        let (_registry, handle, registry) =
+            Registry::new(Arc::new(WatcherConfig::default()), sender);
+        let watcher = DbusNotifierWatcher::new(handle, events, None, 0);
+
+        let connection = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .name(WATCHER_NAME)
+            .unwrap()
+            .serve_at(WATCHER_PATH, watcher)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        Watcher {
+            _connection: connection,
+            registry,
+        }
+    }
+
+    // The next item removed from the registry
+    async fn removed(&mut self) -> String {
+        loop {
+            let event = tokio::time::timeout(TIMEOUT, self.registry.recv())
+                .await
+                .expect("timed out waiting for a registry event")
+                .expect("registry channel closed");
+
+            if let RegistryEvent::ItemRemoved { address } = event {
+                return address.to_string();
+            }
+        }
+    }
+}
+
+async fn watcher_proxy(connection: &Connection) -> StatusNotifierWatcherProxy<'static> {
+    StatusNotifierWatcherProxy::builder(connection)
+        .destination(WATCHER_NAME)
+        .unwrap()
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+        .unwrap()
+}
+
+async fn next<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+    tokio::time::timeout(TIMEOUT, stream.next())
+        .await
+        .expect("timed out waiting for a signal")
+        .expect("signal stream closed")
+}
+
+fn unique_name(connection: &Connection) -> String {
+    connection.unique_name().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn should_register_items_by_object_path() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let _watcher = Watcher::serve(&bus).await;
+    let host = watcher_proxy(&bus.connect().await).await;
+    let mut registered = host
+        .receive_status_notifier_item_registered()
+        .await
+        .unwrap();
+
+    // libappindicator registers the object path of the item, the watcher prepends the sender
+    let item = bus.connect().await;
+    watcher_proxy(&item)
+        .await
+        .register_status_notifier_item("/org/ayatana/NotificationItem/nm_applet")
+        .await
+        .unwrap();
+
+    let expected = format!(
+        "{}/org/ayatana/NotificationItem/nm_applet",
+        unique_name(&item)
+    );
+    let signal = next(&mut registered).await;
+    assert_eq!(signal.args().unwrap().service(), &expected);
+    assert_eq!(
+        host.registered_status_notifier_items().await.unwrap(),
+        vec![expected]
+    );
+}
+
+#[tokio::test]
+async fn should_register_items_by_unique_name() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let _watcher = Watcher::serve(&bus).await;
+    let host = watcher_proxy(&bus.connect().await).await;
+    let mut registered = host
+        .receive_status_notifier_item_registered()
+        .await
+        .unwrap();
+
+    let item = bus.connect().await;
+    watcher_proxy(&item)
+        .await
+        .register_status_notifier_item(&unique_name(&item))
+        .await
+        .unwrap();
+
+    let expected = format!("{}/StatusNotifierItem", unique_name(&item));
+    let signal = next(&mut registered).await;
+    assert_eq!(signal.args().unwrap().service(), &expected);
+    assert_eq!(
+        host.registered_status_notifier_items().await.unwrap(),
+        vec![expected]
+    );
+}
+
+#[tokio::test]
+async fn should_register_items_by_well_known_name() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let mut watcher = Watcher::serve(&bus).await;
+    let host = watcher_proxy(&bus.connect().await).await;
+    let mut registered = host
+        .receive_status_notifier_item_registered()
+        .await
+        .unwrap();
+    let mut unregistered = host
+        .receive_status_notifier_item_unregistered()
+        .await
+        .unwrap();
+
+    // KStatusNotifierItem owns a name per item and registers it
+    let name = "org.kde.StatusNotifierItem-4242-1";
+    let item = bus.connect().await;
+    item.request_name(name).await.unwrap();
+    let item_watcher = watcher_proxy(&item).await;
+    item_watcher
+        .register_status_notifier_item(name)
+        .await
+        .unwrap();
+
+    // The name is resolved to its owner so hosts build their proxies against a unique name
+    let expected = format!("{}/StatusNotifierItem", unique_name(&item));
+    let signal = next(&mut registered).await;
+    assert_eq!(signal.args().unwrap().service(), &expected);
+    assert_eq!(
+        host.registered_status_notifier_items().await.unwrap(),
+        vec![expected.clone()]
+    );
+
+    // The item is unregistered with the name it was registered with
+    item_watcher
+        .unregister_status_notifier_item(name)
+        .await
+        .unwrap();
+
+    let signal = next(&mut unregistered).await;
+    assert_eq!(signal.args().unwrap().service(), &expected);
+    assert_eq!(watcher.removed().await, expected);
+    assert!(host
+        .registered_status_notifier_items()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn should_unregister_items_independently() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let mut watcher = Watcher::serve(&bus).await;
+    let host = watcher_proxy(&bus.connect().await).await;
+    let mut unregistered = host
+        .receive_status_notifier_item_unregistered()
+        .await
+        .unwrap();
+
+    // Applications such as Electron ones export several items from a single connection
+    let item = bus.connect().await;
+    let item_watcher = watcher_proxy(&item).await;
+    for path in [
+        "/org/ayatana/NotificationItem/one",
+        "/org/ayatana/NotificationItem/two",
+    ] {
+        item_watcher
+            .register_status_notifier_item(path)
+            .await
+            .unwrap();
+    }
+
+    let owner = unique_name(&item);
+    let one = format!("{owner}/org/ayatana/NotificationItem/one");
+    let two = format!("{owner}/org/ayatana/NotificationItem/two");
+    let mut items = host.registered_status_notifier_items().await.unwrap();
+    items.sort();
+    assert_eq!(items, vec![one.clone(), two.clone()]);
+
+    // Unregistering a path only removes that item
+    item_watcher
+        .unregister_status_notifier_item("/org/ayatana/NotificationItem/one")
+        .await
+        .unwrap();
+
+    let signal = next(&mut unregistered).await;
+    assert_eq!(signal.args().unwrap().service(), &one);
+    assert_eq!(watcher.removed().await, one);
+    assert_eq!(
+        host.registered_status_notifier_items().await.unwrap(),
+        vec![two.clone()]
+    );
+
+    // Unregistering the owner, as done when it leaves the bus, removes the remaining items
+    item_watcher
+        .unregister_status_notifier_item(&owner)
+        .await
+        .unwrap();
+
+    let signal = next(&mut unregistered).await;
+    assert_eq!(signal.args().unwrap().service(), &two);
+    assert_eq!(watcher.removed().await, two);
+    assert!(host
+        .registered_status_notifier_items()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn should_notify_registered_items_changes() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let _watcher = Watcher::serve(&bus).await;
+    let host = bus.connect().await;
+    let properties = PropertiesProxy::builder(&host)
+        .destination(WATCHER_NAME)
+        .unwrap()
+        .path(WATCHER_PATH)
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let mut changed = properties.receive_properties_changed().await.unwrap();
+
+    let item = bus.connect().await;
+    watcher_proxy(&item)
+        .await
+        .register_status_notifier_item("/StatusNotifierItem")
+        .await
+        .unwrap();
+
+    // Hosts relying on the property cache of their proxy, like waybar, are notified of the change
+    let signal = next(&mut changed).await;
+    let args = signal.args().unwrap();
+    assert_eq!(
+        args.interface_name(),
+        &InterfaceName::from_static_str_unchecked(WATCHER_NAME)
+    );
+    assert!(args
+        .changed_properties()
+        .contains_key("RegisteredStatusNotifierItems"));
+}
+
+#[tokio::test]
+async fn should_track_registered_hosts() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let _watcher = Watcher::serve(&bus).await;
+    let host = watcher_proxy(&bus.connect().await).await;
+    let mut host_registered = host
+        .receive_status_notifier_host_registered()
+        .await
+        .unwrap();
+
+    assert!(!host.is_status_notifier_host_registered().await.unwrap());
+    assert_eq!(host.protocol_version().await.unwrap(), 0);
+
+    // plasmashell and waybar register a host name built from their pid
+    host.register_status_notifier_host("org.kde.StatusNotifierHost-1234")
+        .await
+        .unwrap();
+
+    next(&mut host_registered).await;
+    assert!(host.is_status_notifier_host_registered().await.unwrap());
+}
+
+#[tokio::test]
+async fn should_reject_invalid_services() {
+    let Some(bus) = PrivateBus::start() else {
+        return;
+    };
+    let _watcher = Watcher::serve(&bus).await;
+    let client = watcher_proxy(&bus.connect().await).await;
+
+    for service in ["", "not a bus name", "/invalid//path"] {
+        assert!(
+            client.register_status_notifier_item(service).await.is_err(),
+            "item {service:?} should be rejected"
+        );
+    }
+
+    assert!(client.register_status_notifier_host("").await.is_err());
+    assert!(client
+        .registered_status_notifier_items()
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!client.is_status_notifier_host_registered().await.unwrap());
+}
+
+#[tokio::test]
+async fn should_register_hosts_again_when_the_bus_restarts() {
+    let socket = std::env::temp_dir().join(format!("stray-conformance-{}", std::process::id()));
+    let Some(mut bus) = PrivateBus::start_at(&socket) else {
+        return;
+    };
+    let watcher = StatusNotifierWatcher::builder()
+        .bus(Bus::Address(bus.address.clone()))
+        .build(None)
+        .await
+        .unwrap();
+    let token = CancellationToken::new();
+    let run = tokio::spawn(watcher.run(token.clone()));
+    let host = watcher.create_notifier_host("restart").await.unwrap();
+    let name = format!(
+        "org.freedesktop.StatusNotifierHost-{}-restart",
+        std::process::id()
+    );
+
+    bus.restart();
+
+    // The watcher reconnects with a backoff, poll the new watcher until the host is back
+    let client = watcher_proxy(&bus.connect().await).await;
+    tokio::time::timeout(TIMEOUT * 2, async {
+        while !client
+            .is_status_notifier_host_registered()
+            .await
+            .unwrap_or(false)
+        {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("the host was not registered again");
+
+    // The host owns its name on the new bus, through the connection it hands out
+    let connection = host.connection();
+    let dbus = zbus::fdo::DBusProxy::new(&connection).await.unwrap();
+    assert_eq!(
+        dbus.get_name_owner(name.as_str().try_into().unwrap())
+            .await
+            .unwrap()
+            .as_str(),
+        unique_name(&connection)
+    );
+
+    token.cancel();
+    run.await.unwrap().unwrap();
+}
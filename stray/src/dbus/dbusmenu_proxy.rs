@@ -31,11 +31,10 @@ pub struct SubMenuLayout {
     pub submenus: Vec<OwnedValue>,
 }
 
-#[allow(dead_code)]
-type GroupProperties = Vec<(i32, HashMap<String, zbus::zvariant::OwnedValue>)>;
+pub type GroupProperties = Vec<(i32, HashMap<String, zbus::zvariant::OwnedValue>)>;
 
 #[dbus_proxy(interface = "com.canonical.dbusmenu", assume_defaults = true)]
-trait DBusMenu {
+pub trait DBusMenu {
     fn about_to_show(&self, id: i32) -> zbus::Result<bool>;
 
     fn event(
@@ -74,9 +73,15 @@ trait DBusMenu {
     #[dbus_proxy(signal)]
     fn layout_updated(&self, revision: u32, parent: i32) -> zbus::Result<()>;
 
+    #[dbus_proxy(property)]
+    fn icon_theme_path(&self) -> zbus::Result<Vec<String>>;
+
     #[dbus_proxy(property)]
     fn status(&self) -> zbus::Result<String>;
 
+    #[dbus_proxy(property)]
+    fn text_direction(&self) -> zbus::Result<String>;
+
     #[dbus_proxy(property)]
     fn version(&self) -> zbus::Result<u32>;
 }
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::Result;
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use crate::message::tray::StatusNotifierItem;
+use crate::message::{ItemAddress, NotifierItemCommand, ScrollOrientation};
+
+// Menus are not forwarded, the spec value for items without a dbusmenu
+const NO_DBUSMENU: &str = "/NO_DBUSMENU";
+
+// A copy of an item watched by stray, exported on another bus. Properties are served from the
+// last known state of the item and method calls are sent back to the item as commands.
+pub(crate) struct ForwardedItem {
+    address: ItemAddress,
+    item: Arc<StatusNotifierItem>,
+    commands: mpsc::Sender<NotifierItemCommand>,
+}
+
+impl ForwardedItem {
+    pub(crate) fn new(
+        address: ItemAddress,
+        item: Arc<StatusNotifierItem>,
+        commands: mpsc::Sender<NotifierItemCommand>,
+    ) -> Self {
+        ForwardedItem {
+            address,
+            item,
+            commands,
+        }
+    }
+
+    // Replace the forwarded state and emit the signals of the properties which changed
+    pub(crate) async fn update(
+        &mut self,
+        item: Arc<StatusNotifierItem>,
+        ctxt: &SignalContext<'_>,
+    ) -> Result<()> {
+        let previous = std::mem::replace(&mut self.item, item);
+
+        if previous.title != self.item.title {
+            Self::new_title(ctxt).await?;
+        }
+
+        let same_pixmaps = match (&previous.icon_pixmap, &self.item.icon_pixmap) {
+            (Some(previous), Some(current)) => {
+                previous.len() == current.len()
+                    && previous
+                        .iter()
+                        .zip(current)
+                        .all(|(previous, current)| Arc::ptr_eq(&previous.pixels, &current.pixels))
+            }
+            (previous, current) => previous.is_none() && current.is_none(),
+        };
+        if previous.icon_name != self.item.icon_name || !same_pixmaps {
+            Self::new_icon(ctxt).await?;
+        }
+
+        if previous.attention_icon_name != self.item.attention_icon_name {
+            Self::new_attention_icon(ctxt).await?;
+        }
+
+        if previous.status != self.item.status {
+            Self::new_status(ctxt, self.item.status.as_str()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, command: NotifierItemCommand) -> fdo::Result<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+}
+
+#[dbus_interface(name = "org.kde.StatusNotifierItem")]
+impl ForwardedItem {
+    async fn activate(&self, x: i32, y: i32) -> fdo::Result<()> {
+        self.send(NotifierItemCommand::Activate {
+            notifier_address: self.address.clone(),
+            x,
+            y,
+            activation_token: None,
+        })
+        .await
+    }
+
+    async fn secondary_activate(&self, _x: i32, _y: i32) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "SecondaryActivate is not forwarded".to_string(),
+        ))
+    }
+
+    async fn context_menu(&self, _x: i32, _y: i32) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "ContextMenu is not forwarded".to_string(),
+        ))
+    }
+
+    async fn scroll(&self, delta: i32, orientation: &str) -> fdo::Result<()> {
+        let orientation = match orientation.to_lowercase().as_str() {
+            "horizontal" => ScrollOrientation::Horizontal,
+            "vertical" => ScrollOrientation::Vertical,
+            other => {
+                return Err(fdo::Error::InvalidArgs(format!(
+                    "Unknown scroll orientation {other}"
+                )))
+            }
+        };
+
+        self.send(NotifierItemCommand::Scroll {
+            notifier_address: self.address.clone(),
+            delta,
+            orientation,
+        })
+        .await
+    }
+
+    #[dbus_interface(signal)]
+    async fn new_title(ctxt: &SignalContext<'_>) -> Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn new_icon(ctxt: &SignalContext<'_>) -> Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn new_attention_icon(ctxt: &SignalContext<'_>) -> Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn new_status(ctxt: &SignalContext<'_>, status: &str) -> Result<()>;
+
+    #[dbus_interface(property)]
+    fn category(&self) -> String {
+        self.item.category.as_str().to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn id(&self) -> String {
+        self.item.id.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn title(&self) -> String {
+        self.item.title.clone().unwrap_or_default()
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> String {
+        self.item.status.as_str().to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn window_id(&self) -> i32 {
+        self.item.window_id.unwrap_or_default()
+    }
+
+    #[dbus_interface(property)]
+    fn icon_theme_path(&self) -> String {
+        self.item
+            .icon_theme_path
+            .as_deref()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn icon_name(&self) -> String {
+        self.item
+            .icon_name
+            .as_deref()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn icon_pixmap(&self) -> Vec<(i32, i32, Vec<u8>)> {
+        self.item
+            .icon_pixmap
+            .iter()
+            .flatten()
+            .map(|pixmap| (pixmap.width, pixmap.height, pixmap.pixels.to_vec()))
+            .collect()
+    }
+
+    #[dbus_interface(property)]
+    fn attention_icon_name(&self) -> String {
+        self.item
+            .attention_icon_name
+            .as_deref()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        ObjectPath::from_static_str_unchecked(NO_DBUSMENU).into()
+    }
+}
@@ -1,4 +1,9 @@
+#[cfg(test)]
+mod conformance_tests;
 pub(super) mod dbusmenu_proxy;
+pub(super) mod forwarded_item_service;
 pub(super) mod notifier_item_proxy;
 pub(super) mod notifier_watcher_proxy;
 pub(super) mod notifier_watcher_service;
+#[cfg(feature = "icon-loader")]
+pub(super) mod settings_portal_proxy;
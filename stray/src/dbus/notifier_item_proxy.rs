@@ -12,17 +12,19 @@
 
 use zbus::dbus_proxy;
 
-#[allow(dead_code)]
-type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>);
+pub type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>);
 
 #[dbus_proxy(interface = "org.kde.StatusNotifierItem", assume_defaults = true)]
-trait StatusNotifierItem {
+pub trait StatusNotifierItem {
     /// Activate method
     fn activate(&self, x: i32, y: i32) -> zbus::Result<()>;
 
     /// ContextMenu method
     fn context_menu(&self, x: i32, y: i32) -> zbus::Result<()>;
 
+    /// ProvideXdgActivationToken method, a KDE extension not implemented by every item
+    fn provide_xdg_activation_token(&self, token: &str) -> zbus::Result<()>;
+
     /// Scroll method
     fn scroll(&self, delta: i32, orientation: &str) -> zbus::Result<()>;
 
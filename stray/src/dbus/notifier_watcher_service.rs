@@ -1,53 +1,202 @@
-use std::collections::HashSet;
-use tokio::sync::broadcast;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use zbus::dbus_interface;
+use tokio::sync::broadcast;
+use zbus::fdo::DBusProxy;
+use zbus::names::{BusName, UniqueName, WellKnownName};
+use zbus::zvariant::ObjectPath;
 use zbus::Result;
-use zbus::{MessageHeader, SignalContext};
+use zbus::{dbus_interface, fdo};
+use zbus::{Connection, MessageHeader, SignalContext};
 
-use crate::NotifierItemMessage;
+use crate::message::{ItemAddress, WatcherEvent};
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use crate::notifier_watcher::persisted_items::PersistedItems;
+use crate::notifier_watcher::registry::RegistryHandle;
 
 pub struct DbusNotifierWatcher {
     pub status_notifier_hosts: HashSet<String>,
-    pub registered_status_notifier_items: HashSet<String>,
+    // The object paths of the registered items, keyed by the unique name of their owner
+    pub registered_status_notifier_items: HashMap<String, BTreeSet<String>>,
     pub protocol_version: i32,
     pub is_status_notifier_host_registered: bool,
-    pub sender: broadcast::Sender<NotifierItemMessage>,
+    // The well-known names items were registered with, and the owner and path they resolved to
+    well_known_names: HashMap<String, (String, String)>,
+    registry: RegistryHandle,
+    events: broadcast::Sender<WatcherEvent>,
+    persisted: Option<PersistedItems>,
+}
+
+const DEFAULT_ITEM_PATH: &str = "/StatusNotifierItem";
+
+fn invalid_service(service: &str, err: impl std::fmt::Display) -> fdo::Error {
+    fdo::Error::InvalidArgs(format!("Invalid service '{service}': {err}"))
 }
 
 impl DbusNotifierWatcher {
-    pub(crate) fn new(sender: broadcast::Sender<NotifierItemMessage>) -> Self {
+    pub(crate) fn new(
+        registry: RegistryHandle,
+        events: broadcast::Sender<WatcherEvent>,
+        persisted: Option<PersistedItems>,
+        protocol_version: i32,
+    ) -> Self {
         DbusNotifierWatcher {
-            registered_status_notifier_items: HashSet::new(),
-            protocol_version: 0,
+            registered_status_notifier_items: HashMap::new(),
+            protocol_version,
             is_status_notifier_host_registered: false,
             status_notifier_hosts: HashSet::new(),
-            sender,
+            well_known_names: HashMap::new(),
+            registry,
+            events,
+            persisted,
         }
     }
 }
 
 impl DbusNotifierWatcher {
-    pub async fn remove_notifier(&mut self, notifier_address: &str) -> Result<()> {
-        let to_remove = self
-            .registered_status_notifier_items
-            .iter()
-            .find(|item| item.contains(notifier_address))
-            .cloned();
-
-        if let Some(notifier) = to_remove {
-            let removed = self.registered_status_notifier_items.remove(&notifier);
-            if removed {
-                self.sender
-                    .send(NotifierItemMessage::Remove {
-                        address: notifier_address.to_string(),
+    // Remove the items designated by `notifier_address`: every item of an owner given its unique
+    // name, a single item given `owner/path` or the well-known name it was registered with.
+    // Returns the removed items, formatted as in `RegisteredStatusNotifierItems`.
+    pub async fn remove_notifier(
+        &mut self,
+        notifier_address: &str,
+    ) -> crate::error::Result<Vec<String>> {
+        let (owner, path) = match self.well_known_names.get(notifier_address) {
+            Some((owner, path)) => (owner.clone(), Some(path.clone())),
+            None => match notifier_address.split_once('/') {
+                Some((owner, path)) => (owner.to_string(), Some(format!("/{path}"))),
+                None => (notifier_address.to_string(), None),
+            },
+        };
+
+        let Some(paths) = self.registered_status_notifier_items.get_mut(&owner) else {
+            return Ok(vec![]);
+        };
+
+        let removed: Vec<NotifierAddress> = match path {
+            Some(path) => {
+                if !paths.remove(&path) {
+                    return Ok(vec![]);
+                }
+                self.well_known_names
+                    .retain(|_, registered| *registered != (owner.clone(), path.clone()));
+                vec![NotifierAddress {
+                    destination: owner.clone(),
+                    path,
+                }]
+            }
+            None => {
+                let removed = std::mem::take(paths)
+                    .into_iter()
+                    .map(|path| NotifierAddress {
+                        destination: owner.clone(),
+                        path,
                     })
-                    .expect("Failed to dispatch notifier item removed message");
+                    .collect();
+                self.well_known_names
+                    .retain(|_, (registered_owner, _)| *registered_owner != owner);
+                removed
             }
+        };
+
+        if paths.is_empty() {
+            self.registered_status_notifier_items.remove(&owner);
+        }
+
+        // Items are known to the registry by their owner and path, each of them is removed
+        for address in &removed {
+            self.registry
+                .item_removed(&ItemAddress::from_bus(address))
+                .await?;
+        }
+
+        Ok(removed.iter().map(NotifierAddress::to_string).collect())
+    }
+
+    // Remove a host which left the bus, `IsStatusNotifierHostRegistered` turns false with the last one
+    pub async fn remove_host(&mut self, name: &str, ctxt: &SignalContext<'_>) -> Result<()> {
+        if !self.status_notifier_hosts.remove(name) {
+            return Ok(());
+        }
+
+        tracing::info!("StatusNotifierHost unregistered: '{}'", name);
+        let _ = self.events.send(WatcherEvent::HostLost(name.to_string()));
+        Self::status_notifier_host_unregistered(ctxt).await?;
+
+        if self.status_notifier_hosts.is_empty() {
+            self.is_status_notifier_host_registered = false;
+            self.is_status_notifier_host_registered_changed(ctxt)
+                .await?;
         }
 
         Ok(())
     }
+
+    // Register the items persisted by a previous watcher, before any host starts watching them
+    pub(crate) fn adopt(&mut self, addresses: Vec<NotifierAddress>) {
+        for address in addresses {
+            tracing::info!("Adopting persisted StatusNotifierItem '{address}'");
+            self.registered_status_notifier_items
+                .entry(address.destination)
+                .or_default()
+                .insert(address.path);
+        }
+
+        self.persist();
+    }
+
+    // Hosts attached to this watcher rely on PropertiesChanged to refresh the registered items
+    async fn registered_items_changed(&self, ctxt: &SignalContext<'_>) {
+        self.persist();
+        if let Err(err) = self.registered_status_notifier_items_changed(ctxt).await {
+            tracing::error!("Failed to notify the registered items change: {err:?}");
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(persisted) = &self.persisted {
+            persisted.store(self.registered_services());
+        }
+    }
+
+    // The registered items, formatted as in `RegisteredStatusNotifierItems`
+    fn registered_services(&self) -> Vec<String> {
+        self.registered_status_notifier_items
+            .iter()
+            .flat_map(|(owner, paths)| paths.iter().map(move |path| format!("{owner}{path}")))
+            .collect()
+    }
+
+    // Registrations carry either the object path of an item owned by the sender, or the bus name
+    // of an item exported at the default path. Well-known names are resolved to their unique owner
+    // so proxies are always built against a valid destination, the name is returned along the item.
+    async fn resolve_service(
+        connection: &Connection,
+        sender: &str,
+        service: &str,
+    ) -> fdo::Result<(String, String, Option<String>)> {
+        if service.starts_with('/') {
+            ObjectPath::try_from(service).map_err(|err| invalid_service(service, err))?;
+            return Ok((sender.to_string(), service.to_string(), None));
+        }
+
+        if service.starts_with(':') {
+            UniqueName::try_from(service).map_err(|err| invalid_service(service, err))?;
+            return Ok((service.to_string(), DEFAULT_ITEM_PATH.to_string(), None));
+        }
+
+        let name = WellKnownName::try_from(service)
+            .map(BusName::WellKnown)
+            .map_err(|err| invalid_service(service, err))?;
+        let owner = DBusProxy::new(connection)
+            .await?
+            .get_name_owner(name)
+            .await?;
+        Ok((
+            owner.to_string(),
+            DEFAULT_ITEM_PATH.to_string(),
+            Some(service.to_string()),
+        ))
+    }
 }
 
 #[allow(dead_code)]
@@ -57,43 +206,107 @@ impl DbusNotifierWatcher {
         &mut self,
         service: &str,
         #[zbus(signal_context)] ctxt: SignalContext<'_>,
-    ) {
+    ) -> fdo::Result<()> {
+        // Hosts are dropped when their name leaves the bus, so it must be a valid bus name
+        BusName::try_from(service).map_err(|err| invalid_service(service, err))?;
+
         tracing::info!("StatusNotifierHost registered: '{}'", service);
-        self.status_notifier_hosts.insert(service.to_string());
-        self.is_status_notifier_host_registered = true;
-        self.is_status_notifier_host_registered_changed(&ctxt)
-            .await
-            .unwrap();
+        if !self.status_notifier_hosts.insert(service.to_string()) {
+            return Ok(());
+        }
+        let _ = self
+            .events
+            .send(WatcherEvent::HostRegistered(service.to_string()));
+
+        if let Err(err) = Self::status_notifier_host_registered(&ctxt).await {
+            tracing::error!("Failed to notify the StatusNotifierHost registration: {err:?}");
+        }
+
+        if !self.is_status_notifier_host_registered {
+            self.is_status_notifier_host_registered = true;
+            if let Err(err) = self.is_status_notifier_host_registered_changed(&ctxt).await {
+                tracing::error!("Failed to notify the StatusNotifierHost registration: {err:?}");
+            }
+        }
+
+        Ok(())
     }
 
     async fn register_status_notifier_item(
         &mut self,
         service: &str,
         #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
         #[zbus(signal_context)] ctxt: SignalContext<'_>,
-    ) {
+    ) -> fdo::Result<()> {
         let address = header
-            .sender()
-            .expect("Failed to get message sender in header")
+            .sender()?
             .map(|name| name.to_string())
-            .expect("Failed to get unique name for notifier");
+            .ok_or_else(|| fdo::Error::Failed("Missing sender in message header".to_string()))?;
 
-        let notifier_item = format!("{}{}", address, service);
+        let (owner, path, well_known_name) =
+            Self::resolve_service(connection, &address, service).await?;
+        let notifier_item = format!("{owner}{path}");
 
-        self.registered_status_notifier_items
-            .insert(notifier_item.clone());
+        if let Some(name) = well_known_name {
+            self.well_known_names
+                .insert(name, (owner.clone(), path.clone()));
+        }
+        let inserted = self
+            .registered_status_notifier_items
+            .entry(owner)
+            .or_default()
+            .insert(path);
 
         tracing::info!("StatusNotifierItem registered: '{}'", notifier_item);
+        let _ = self
+            .events
+            .send(WatcherEvent::ItemRegistered(notifier_item.clone()));
 
-        Self::status_notifier_item_registered(&ctxt, &notifier_item)
-            .await
-            .unwrap();
+        if let Err(err) = Self::status_notifier_item_registered(&ctxt, &notifier_item).await {
+            tracing::error!("Failed to notify the registration of {notifier_item}: {err:?}");
+        }
+
+        if inserted {
+            self.registered_items_changed(&ctxt).await;
+        }
+
+        Ok(())
     }
 
-    async fn unregister_status_notifier_item(&mut self, service: &str) {
-        self.remove_notifier(service)
+    async fn unregister_status_notifier_item(
+        &mut self,
+        service: &str,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        // Like registrations, a bare object path designates an item of the sender
+        let service = match header.sender()? {
+            Some(sender) if service.starts_with('/') => format!("{sender}{service}"),
+            _ => service.to_string(),
+        };
+
+        // Also called by the watcher itself with the unique name of owners leaving the bus
+        let removed = self
+            .remove_notifier(&service)
             .await
-            .expect("Failed to unregister StatusNotifierItem")
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+
+        if !removed.is_empty() {
+            self.registered_items_changed(&ctxt).await;
+        }
+
+        for notifier_item in removed {
+            tracing::info!("StatusNotifierItem unregistered: '{}'", notifier_item);
+            let _ = self
+                .events
+                .send(WatcherEvent::ItemUnregistered(notifier_item.clone()));
+            if let Err(err) = Self::status_notifier_item_unregistered(&ctxt, &notifier_item).await {
+                tracing::error!("Failed to notify the removal of {notifier_item}: {err:?}");
+            }
+        }
+
+        Ok(())
     }
 
     #[dbus_interface(signal)]
@@ -124,9 +337,6 @@ impl DbusNotifierWatcher {
 
     #[dbus_interface(property)]
     fn registered_status_notifier_items(&self) -> Vec<String> {
-        self.registered_status_notifier_items
-            .iter()
-            .cloned()
-            .collect()
+        self.registered_services()
     }
 }
@@ -0,0 +1,18 @@
+//! # DBus interface proxy for: `org.freedesktop.portal.Settings`
+//!
+//! Only the members used to follow the desktop icon theme are declared.
+
+use zbus::dbus_proxy;
+use zbus::zvariant::{OwnedValue, Value};
+
+#[dbus_proxy(
+    interface = "org.freedesktop.portal.Settings",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+pub(crate) trait Settings {
+    fn read(&self, namespace: &str, key: &str) -> zbus::Result<OwnedValue>;
+
+    #[dbus_proxy(signal)]
+    fn setting_changed(&self, namespace: &str, key: &str, value: Value<'_>) -> zbus::Result<()>;
+}
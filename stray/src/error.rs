@@ -1,23 +1,142 @@
 use crate::NotifierItemMessage;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::broadcast;
 
 pub type Result<T> = std::result::Result<T, StatusNotifierWatcherError>;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum StatusNotifierWatcherError {
-    #[error("Dbus connection error")]
+    #[error("Dbus error: {0}")]
     DbusError(#[from] zbus::Error),
-    #[error("Invalid DBus interface name")]
+    #[error("Invalid DBus name: {0}")]
     InterfaceNameError(#[from] zbus::names::Error),
-    #[error("Failed to call DBus standard interface method")]
+    #[error("Failed to call DBus standard interface method: {0}")]
     DBusStandardInterfaceError(#[from] zbus::fdo::Error),
-    #[error("Serialization error")]
+    #[error("Serialization error: {0}")]
     ZvariantError(#[from] zbus::zvariant::Error),
+    #[error("Json serialization error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// A DBus call to an item failed.
+    #[error("{method} call to {address} failed: {source}")]
+    ItemCallFailed {
+        /// The dbus address of the item
+        address: String,
+        /// The DBus method involved, ex: `GetLayout`
+        method: String,
+        /// The underlying error
+        source: Box<StatusNotifierWatcherError>,
+    },
+    /// A DBus call did not complete in time, see [`crate::StatusNotifierWatcherBuilder::call_timeout`].
+    #[error(
+        "{} call to {} did not complete within {timeout:?}",
+        method.as_deref().unwrap_or("DBus"),
+        address.as_deref().unwrap_or("the bus")
+    )]
+    Timeout {
+        /// The dbus address of the item, if the call was made to an item
+        address: Option<String>,
+        /// The DBus method involved, ex: `GetLayout`
+        method: Option<String>,
+        /// The configured call timeout
+        timeout: Duration,
+    },
     #[error("Service path {0} was not understood")]
     DbusAddressError(String),
+    #[error("Invalid menu object path {0}")]
+    InvalidMenuPath(String),
     #[error("Failed to broadcast message to notifier hosts")]
-    BroadCastSendError(#[from] broadcast::error::SendError<NotifierItemMessage>),
+    BroadCastSendError(Box<broadcast::error::SendError<NotifierItemMessage>>),
     #[error("Error receiving broadcast message")]
     BroadCastRecvError(#[from] broadcast::error::RecvError),
+    #[error("The watcher registry is no longer running")]
+    RegistryClosed,
+    #[error("The signal router is no longer running")]
+    RouterClosed,
+    #[error("The command dispatcher is no longer running")]
+    DispatcherClosed,
+    #[error("Timed out waiting for an item to handle a command")]
+    CommandTimeout,
+    #[error("Too many commands pending for item {0}")]
+    CommandQueueFull(String),
+    #[error("The watcher is not running, see StatusNotifierWatcher::run")]
+    NotStarted,
+    #[error("The notifier host is paused")]
+    HostPaused,
+    #[error("Invalid StatusNotifierItem: {0}")]
+    InvalidItem(String),
+    #[error("Item {0} does not expose a menu")]
+    NoMenu(String),
+    #[error("Property {0} is not writable on this item")]
+    UnsupportedProperty(String),
+    #[cfg(feature = "replay")]
+    #[error("Failed to read or write the recording")]
+    RecordingIoError(#[from] std::io::Error),
+    #[cfg(feature = "png")]
+    #[error("Failed to encode pixmap: {0}")]
+    PngError(#[from] png::EncodingError),
+    #[cfg(feature = "png")]
+    #[error("Failed to write icon file: {0}")]
+    IconFileError(std::io::Error),
+    #[cfg(feature = "image")]
+    #[error("Invalid pixmap: {0}")]
+    InvalidPixmap(String),
+    #[cfg(feature = "resvg")]
+    #[error("Failed to rasterize SVG icon: {0}")]
+    SvgError(String),
+    #[cfg(feature = "test-util")]
+    #[error("Smoke test failed: {0}")]
+    SmokeTestFailed(String),
+}
+
+impl StatusNotifierWatcherError {
+    /// Returns true if a DBus call did not complete in time.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, StatusNotifierWatcherError::Timeout { .. })
+    }
+
+    /// Returns the dbus address of the item which caused this error, if known.
+    pub fn item_address(&self) -> Option<&str> {
+        match self {
+            StatusNotifierWatcherError::ItemCallFailed { address, .. } => Some(address),
+            StatusNotifierWatcherError::Timeout { address, .. } => address.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<broadcast::error::SendError<NotifierItemMessage>> for StatusNotifierWatcherError {
+    fn from(err: broadcast::error::SendError<NotifierItemMessage>) -> Self {
+        StatusNotifierWatcherError::BroadCastSendError(Box::new(err))
+    }
+}
+
+// Attach the item address and the DBus method to the errors of a call to an item
+pub(crate) trait ItemContext<T> {
+    fn item_context(self, address: &str, method: &str) -> Result<T>;
+}
+
+impl<T> ItemContext<T> for Result<T> {
+    fn item_context(self, address: &str, method: &str) -> Result<T> {
+        self.map_err(|err| match err {
+            StatusNotifierWatcherError::Timeout {
+                address: None,
+                timeout,
+                ..
+            } => StatusNotifierWatcherError::Timeout {
+                address: Some(address.to_string()),
+                method: Some(method.to_string()),
+                timeout,
+            },
+            // Keep the context of the innermost call
+            err @ (StatusNotifierWatcherError::ItemCallFailed { .. }
+            | StatusNotifierWatcherError::Timeout { .. }) => err,
+            err => StatusNotifierWatcherError::ItemCallFailed {
+                address: address.to_string(),
+                method: method.to_string(),
+                source: Box::new(err),
+            },
+        })
+    }
 }
@@ -0,0 +1,584 @@
+#[cfg(feature = "resvg")]
+use crate::error::StatusNotifierWatcherError;
+#[cfg(feature = "resvg")]
+use crate::message::tray::IconPixmap;
+use crate::message::tray::StatusNotifierItem;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+const DEFAULT_SIZE: u16 = 24;
+const FALLBACK_THEME: &str = "hicolor";
+const EXTENSIONS: [&str; 3] = ["png", "svg", "xpm"];
+const PIXMAP_DIRS: [&str; 1] = ["/usr/share/pixmaps"];
+const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// Resolve the icons of the items to files on disk, following the
+/// [freedesktop icon theme specification](https://specifications.freedesktop.org/icon-theme-spec/icon-theme-spec-latest.html).
+/// The `IconThemePath` of the item is searched first, then the configured theme and the themes
+/// it inherits from, the fallback theme and finally `/usr/share/pixmaps`.
+/// Parsed themes and resolved icons are cached, clones of the lookup share the caches and the theme.
+///
+/// ```rust, no_run
+/// # use stray::icon_loader::IconLookup;
+/// # use stray::message::tray::StatusNotifierItem;
+/// # struct Image;
+/// # impl Image {
+/// #     fn set_from_file(&self, _: std::path::PathBuf) {}
+/// # }
+/// # fn example(item: StatusNotifierItem, image: Image) {
+/// let lookup = IconLookup::default().theme("Adwaita").size(32);
+/// if let Some(path) = lookup.find_item_icon(&item) {
+///     image.set_from_file(path);
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct IconLookup {
+    theme: Arc<RwLock<Option<String>>>,
+    fallback_theme: String,
+    size: u16,
+    scale: u16,
+    themes: Arc<Mutex<HashMap<String, Option<Arc<IconTheme>>>>>,
+    resolved: Arc<Mutex<LruCache<IconKey, Option<PathBuf>>>>,
+}
+
+// Everything the resolved file depends on, the status of an item is covered by the name
+// since `find_item_attention_icon` looks up its `AttentionIconName`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct IconKey {
+    name: String,
+    icon_theme_path: Option<String>,
+    theme: Option<String>,
+    fallback_theme: String,
+    size: u16,
+    scale: u16,
+}
+
+impl Default for IconLookup {
+    fn default() -> Self {
+        IconLookup {
+            theme: Arc::default(),
+            fallback_theme: FALLBACK_THEME.to_string(),
+            size: DEFAULT_SIZE,
+            scale: 1,
+            themes: Arc::default(),
+            resolved: Arc::new(Mutex::new(LruCache::new(DEFAULT_CACHE_CAPACITY))),
+        }
+    }
+}
+
+impl IconLookup {
+    /// Look icons up in the theme `name` first, usually the theme configured for the desktop.
+    /// Only the fallback theme is searched by default.
+    pub fn theme(mut self, name: impl Into<String>) -> Self {
+        self.theme = Arc::new(RwLock::new(Some(name.into())));
+        self
+    }
+
+    /// Switch the theme of this lookup and of its clones, ex: when the desktop icon theme changed.
+    /// The themes are parsed again since icon themes are usually installed along with the switch.
+    pub fn set_theme(&self, name: Option<String>) {
+        if let Ok(mut theme) = self.theme.write() {
+            if *theme == name {
+                return;
+            }
+            *theme = name;
+        }
+        self.invalidate_all();
+    }
+
+    /// The theme icons are looked up in first, if any.
+    pub fn current_theme(&self) -> Option<String> {
+        self.theme.read().ok().and_then(|theme| theme.clone())
+    }
+
+    /// The theme searched when the icon is missing from the configured theme and its parents,
+    /// defaults to `hicolor`.
+    pub fn fallback_theme(mut self, name: impl Into<String>) -> Self {
+        self.fallback_theme = name.into();
+        self
+    }
+
+    /// The size of the icon in the tray, in logical pixels, defaults to `24`.
+    /// Icons of the closest size are returned when none match.
+    pub fn size(mut self, size: u16) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// The scale factor of the output the icon is displayed on, defaults to `1`.
+    pub fn scale(mut self, scale: u16) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+
+    /// How many resolved icons are kept, the least recently used ones are evicted first.
+    /// Defaults to `256`, `0` disables the cache.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.resolved = Arc::new(Mutex::new(LruCache::new(capacity)));
+        self
+    }
+
+    /// Forget the resolved icons of the item, call it when receiving an update of an item
+    /// following a `NewIcon` or `NewAttentionIcon` signal: applications sometimes rewrite the
+    /// file of the icon in their `IconThemePath` while keeping its name.
+    pub fn invalidate_item(&self, item: &StatusNotifierItem) {
+        let names = [&item.icon_name, &item.attention_icon_name];
+        self.invalidate(|key| {
+            key.icon_theme_path.is_some() && key.icon_theme_path == item.icon_theme_path
+                || names
+                    .iter()
+                    .any(|name| name.as_deref() == Some(key.name.as_str()))
+        });
+    }
+
+    /// Forget the parsed themes and the resolved icons, call it when the icon theme of the desktop
+    /// changed or icon themes were installed or removed.
+    pub fn invalidate_all(&self) {
+        if let Ok(mut themes) = self.themes.lock() {
+            themes.clear();
+        }
+        self.invalidate(|_| true);
+    }
+
+    fn invalidate(&self, predicate: impl Fn(&IconKey) -> bool) {
+        if let Ok(mut resolved) = self.resolved.lock() {
+            resolved.retain(|key| !predicate(key));
+        }
+    }
+
+    /// Returns the file of the `IconName` of the item. Items only carrying an `IconPixmap`
+    /// have no icon on disk.
+    pub fn find_item_icon(&self, item: &StatusNotifierItem) -> Option<PathBuf> {
+        let name = item.icon_name.as_deref()?;
+        self.find_icon(name, item.icon_theme_path.as_deref())
+    }
+
+    /// Returns the file of the `AttentionIconName` of the item.
+    pub fn find_item_attention_icon(&self, item: &StatusNotifierItem) -> Option<PathBuf> {
+        let name = item.attention_icon_name.as_deref()?;
+        self.find_icon(name, item.icon_theme_path.as_deref())
+    }
+
+    /// Returns the file of the icon `name`, searching `icon_theme_path` first. Some applications
+    /// set an absolute path as icon name, it is returned as is if the file exists.
+    pub fn find_icon(&self, name: &str, icon_theme_path: Option<&str>) -> Option<PathBuf> {
+        if name.is_empty() {
+            return None;
+        }
+
+        let key = IconKey {
+            name: name.to_string(),
+            icon_theme_path: icon_theme_path.map(str::to_string),
+            theme: self.current_theme(),
+            fallback_theme: self.fallback_theme.clone(),
+            size: self.size,
+            scale: self.scale,
+        };
+
+        let cached = self
+            .resolved
+            .lock()
+            .ok()
+            .and_then(|mut resolved| resolved.get(&key));
+        // A cached file removed meanwhile is resolved again
+        if let Some(cached) =
+            cached.filter(|path| path.as_ref().map_or(true, |path| path.is_file()))
+        {
+            return cached;
+        }
+
+        let icon = self.resolve(name, icon_theme_path, key.theme.as_deref());
+        if let Ok(mut resolved) = self.resolved.lock() {
+            resolved.insert(key, icon.clone());
+        }
+
+        icon
+    }
+
+    fn resolve(
+        &self,
+        name: &str,
+        icon_theme_path: Option<&str>,
+        theme: Option<&str>,
+    ) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return path.is_file().then(|| path.to_path_buf());
+        }
+
+        let mut base_dirs = base_dirs();
+        if let Some(theme_path) = icon_theme_path.filter(|path| !path.is_empty()) {
+            let theme_path = PathBuf::from(theme_path);
+            // The theme path either holds the icons directly or is the root of a theme
+            if let Some(icon) = find_in_dir(&theme_path, name) {
+                return Some(icon);
+            }
+            base_dirs.insert(0, theme_path);
+        }
+
+        let mut visited = vec![];
+        let themes = theme.into_iter().chain(Some(self.fallback_theme.as_str()));
+        for theme in themes {
+            if let Some(icon) = self.find_in_theme(theme, name, &base_dirs, &mut visited) {
+                return Some(icon);
+            }
+        }
+
+        PIXMAP_DIRS
+            .iter()
+            .find_map(|dir| find_in_dir(Path::new(dir), name))
+    }
+
+    /// Render the SVG icon at `path` to a pixmap of the configured size and scale, for toolkits
+    /// without SVG support. The aspect ratio of the icon is preserved and it is centered.
+    ///
+    /// ```rust, no_run
+    /// # use stray::icon_loader::{is_svg, IconLookup};
+    /// # use stray::message::tray::StatusNotifierItem;
+    /// # fn example(lookup: IconLookup, item: StatusNotifierItem) -> stray::error::Result<()> {
+    /// let icon = lookup.find_item_icon(&item).filter(|path| is_svg(path));
+    /// let pixmap = icon.map(|path| lookup.rasterize_svg(path)).transpose()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "resvg")]
+    pub fn rasterize_svg(&self, path: impl AsRef<Path>) -> crate::error::Result<IconPixmap> {
+        use resvg::usvg::TreeParsing;
+        use resvg::{tiny_skia, usvg};
+
+        let svg_error = |err: &dyn std::fmt::Display| {
+            StatusNotifierWatcherError::SvgError(format!("{}: {err}", path.as_ref().display()))
+        };
+
+        let data = std::fs::read(path.as_ref()).map_err(|err| svg_error(&err))?;
+        let tree = usvg::Tree::from_data(&data, &usvg::Options::default())
+            .map_err(|err| svg_error(&err))?;
+        let tree = resvg::Tree::from_usvg(&tree);
+
+        let size = u32::from(self.size) * u32::from(self.scale);
+        let mut pixmap =
+            tiny_skia::Pixmap::new(size, size).ok_or_else(|| svg_error(&"invalid icon size"))?;
+
+        let scale = size as f32 / tree.size.width().max(tree.size.height());
+        let offset_x = (size as f32 - tree.size.width() * scale) / 2.0;
+        let offset_y = (size as f32 - tree.size.height() * scale) / 2.0;
+        let transform =
+            tiny_skia::Transform::from_scale(scale, scale).post_translate(offset_x, offset_y);
+        tree.render(transform, &mut pixmap.as_mut());
+
+        // tiny-skia renders premultiplied RGBA, pixmaps hold straight ARGB
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let pixel = pixel.demultiply();
+                [pixel.alpha(), pixel.red(), pixel.green(), pixel.blue()]
+            })
+            .collect();
+
+        Ok(IconPixmap {
+            width: size as i32,
+            height: size as i32,
+            pixels,
+        })
+    }
+
+    // Search the theme, then the themes it inherits from
+    fn find_in_theme(
+        &self,
+        theme: &str,
+        name: &str,
+        base_dirs: &[PathBuf],
+        visited: &mut Vec<String>,
+    ) -> Option<PathBuf> {
+        if visited.iter().any(|visited| visited == theme) {
+            return None;
+        }
+        visited.push(theme.to_string());
+
+        let icon_theme = self.load_theme(theme)?;
+
+        if let Some(icon) = icon_theme.find(name, self.size, self.scale, base_dirs) {
+            return Some(icon);
+        }
+
+        icon_theme
+            .inherits
+            .iter()
+            .find_map(|parent| self.find_in_theme(parent, name, base_dirs, visited))
+    }
+
+    // Themes are only read from the system directories, so the themes shipped in the
+    // `IconThemePath` of an item never shadow the cached ones
+    fn load_theme(&self, theme: &str) -> Option<Arc<IconTheme>> {
+        let mut themes = self.themes.lock().ok()?;
+        themes
+            .entry(theme.to_string())
+            .or_insert_with(|| IconTheme::load(theme, &base_dirs()).map(Arc::new))
+            .clone()
+    }
+}
+
+// The directories holding icon themes, in lookup order
+fn base_dirs() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".local/share")));
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+
+    home.map(|home| home.join(".icons"))
+        .into_iter()
+        .chain(data_home.map(|dir| dir.join("icons")))
+        .chain(
+            data_dirs
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| Path::new(dir).join("icons")),
+        )
+        .collect()
+}
+
+// A least recently used cache, evicting in linear time which is fine for a few hundred icons
+#[derive(Debug)]
+struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+}
+
+impl<K: Hash + Eq, V: Clone> LruCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.tick;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // Ticks are unique, they identify the entry to evict
+            let oldest = self.entries.values().map(|(_, last_used)| *last_used).min();
+            self.entries
+                .retain(|_, (_, last_used)| Some(*last_used) != oldest);
+        }
+
+        self.tick += 1;
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        self.entries.retain(|key, _| keep(key));
+    }
+}
+
+/// Returns true if the icon at `path` is an SVG file, which most toolkits cannot load from a
+/// plain image path, see [`IconLookup::rasterize_svg`].
+pub fn is_svg(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "svg")
+}
+
+fn find_in_dir(dir: &Path, name: &str) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| dir.join(format!("{name}.{extension}")))
+        .find(|path| path.is_file())
+}
+
+#[derive(Debug)]
+struct IconTheme {
+    name: String,
+    inherits: Vec<String>,
+    directories: Vec<ThemeDirectory>,
+}
+
+#[derive(Debug)]
+struct ThemeDirectory {
+    path: String,
+    size: u16,
+    scale: u16,
+    kind: DirectoryKind,
+}
+
+#[derive(Debug)]
+enum DirectoryKind {
+    Fixed,
+    Scalable { min_size: u16, max_size: u16 },
+    Threshold(u16),
+}
+
+impl IconTheme {
+    // Read the `index.theme` of the theme from the first base directory holding it
+    fn load(name: &str, base_dirs: &[PathBuf]) -> Option<IconTheme> {
+        let index = base_dirs
+            .iter()
+            .map(|dir| dir.join(name).join("index.theme"))
+            .find(|index| index.is_file())?;
+
+        match std::fs::read_to_string(&index) {
+            Ok(content) => Some(IconTheme::parse(name, &content)),
+            Err(err) => {
+                tracing::warn!("Failed to read icon theme {}: {err}", index.display());
+                None
+            }
+        }
+    }
+
+    fn parse(name: &str, content: &str) -> IconTheme {
+        let mut sections: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+        let mut section = None;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                section = Some(name);
+                continue;
+            }
+
+            if let (Some(section), Some((key, value))) = (section, line.split_once('=')) {
+                sections
+                    .entry(section)
+                    .or_default()
+                    .insert(key.trim(), value.trim());
+            }
+        }
+
+        let theme = sections.get("Icon Theme");
+        let list = |key: &str| -> Vec<String> {
+            theme
+                .and_then(|theme| theme.get(key))
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let mut directory_names = list("Directories");
+        directory_names.extend(list("ScaledDirectories"));
+
+        let directories = directory_names
+            .into_iter()
+            .filter_map(|path| {
+                let entries = sections.get(path.as_str())?;
+                let number = |key: &str| entries.get(key).and_then(|value| value.parse().ok());
+                let size = number("Size")?;
+                let kind = match entries.get("Type").copied() {
+                    Some("Fixed") => DirectoryKind::Fixed,
+                    Some("Scalable") => DirectoryKind::Scalable {
+                        min_size: number("MinSize").unwrap_or(size),
+                        max_size: number("MaxSize").unwrap_or(size),
+                    },
+                    _ => DirectoryKind::Threshold(number("Threshold").unwrap_or(2)),
+                };
+
+                Some(ThemeDirectory {
+                    path,
+                    size,
+                    scale: number("Scale").unwrap_or(1),
+                    kind,
+                })
+            })
+            .collect();
+
+        IconTheme {
+            name: name.to_string(),
+            inherits: list("Inherits"),
+            directories,
+        }
+    }
+
+    // An exact size match wins, otherwise the icon of the closest size is returned
+    fn find(&self, name: &str, size: u16, scale: u16, base_dirs: &[PathBuf]) -> Option<PathBuf> {
+        let mut closest: Option<(u32, PathBuf)> = None;
+        for directory in &self.directories {
+            let icon = base_dirs.iter().find_map(|base_dir| {
+                find_in_dir(&base_dir.join(&self.name).join(&directory.path), name)
+            });
+
+            let Some(icon) = icon else {
+                continue;
+            };
+
+            if directory.matches(size, scale) {
+                return Some(icon);
+            }
+
+            let distance = directory.distance(size, scale);
+            if closest
+                .as_ref()
+                .map_or(true, |(closest, _)| distance < *closest)
+            {
+                closest = Some((distance, icon));
+            }
+        }
+
+        closest.map(|(_, icon)| icon)
+    }
+}
+
+impl ThemeDirectory {
+    fn matches(&self, size: u16, scale: u16) -> bool {
+        if self.scale != scale {
+            return false;
+        }
+
+        match self.kind {
+            DirectoryKind::Fixed => self.size == size,
+            DirectoryKind::Scalable { min_size, max_size } => (min_size..=max_size).contains(&size),
+            DirectoryKind::Threshold(threshold) => {
+                self.size.saturating_sub(threshold) <= size
+                    && size <= self.size.saturating_add(threshold)
+            }
+        }
+    }
+
+    fn distance(&self, size: u16, scale: u16) -> u32 {
+        let wanted = u32::from(size) * u32::from(scale);
+        let scaled = |size: u16| u32::from(size) * u32::from(self.scale);
+
+        match self.kind {
+            DirectoryKind::Fixed => scaled(self.size).abs_diff(wanted),
+            DirectoryKind::Scalable { min_size, max_size } => {
+                distance_to_range(wanted, scaled(min_size), scaled(max_size))
+            }
+            DirectoryKind::Threshold(threshold) => {
+                let min = scaled(self.size.saturating_sub(threshold));
+                let max = scaled(self.size.saturating_add(threshold));
+                distance_to_range(wanted, min, max)
+            }
+        }
+    }
+}
+
+// How far `size` lies outside of `min..=max`
+fn distance_to_range(size: u32, min: u32, max: u32) -> u32 {
+    min.saturating_sub(size) + size.saturating_sub(max)
+}
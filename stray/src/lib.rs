@@ -1,6 +1,8 @@
 #![doc = include_str ! ("../README.md")]
 
 pub use tokio;
+pub use tokio_util::sync::CancellationToken;
+pub use zbus;
 use zbus::names::InterfaceName;
 
 use crate::dbus::dbusmenu_proxy::MenuLayout;
@@ -12,8 +14,40 @@ mod notifier_host;
 mod notifier_watcher;
 
 pub mod error;
-/// Messages sent and received by the [`SystemTray`]
+/// Resolve the icons of the items to files on disk
+#[cfg(feature = "icon-loader")]
+pub mod icon_loader;
+/// Messages sent and received by the [`StatusNotifierWatcher`]
 pub mod message;
+/// Record the messages received by a notifier host and replay them without any tray application
+#[cfg(feature = "replay")]
+pub mod replay;
+/// Validate a stray build against the local dbus daemon without any tray application
+#[cfg(feature = "test-util")]
+pub mod test_util;
+/// Keep track of the items of the tray from the messages received by a notifier host
+pub mod tray_state;
 
-pub use message::NotifierItemMessage;
-pub use notifier_watcher::StatusNotifierWatcher;
+/// The raw dbus proxies of the items and their menus, see [`ItemHandle::raw_item_proxy`]
+pub mod proxy {
+    pub use crate::dbus::dbusmenu_proxy::{
+        DBusMenuProxy, GroupProperties, MenuLayout, SubMenuLayout,
+    };
+    pub use crate::dbus::notifier_item_proxy::{StatusNotifierItemProxy, ToolTip};
+}
+
+pub use message::{
+    Bus, Conformance, ItemIdentity, NotifierItemMessage, OverflowPolicy, WatcherEvent,
+};
+pub use notifier_host::filter::ItemFilter;
+pub use notifier_host::NotifierHost;
+pub use notifier_host::item_stream::ItemStream;
+pub use notifier_watcher::builder::StatusNotifierWatcherBuilder;
+#[cfg(feature = "sink")]
+pub use notifier_watcher::command_sink::CommandSink;
+pub use notifier_watcher::events::WatcherEventStream;
+pub use notifier_watcher::item_handle::ItemHandle;
+pub use notifier_watcher::notifier_address::NotifierAddress;
+pub use notifier_watcher::projection::Projection;
+pub use notifier_watcher::{StatusNotifierWatcher, WatcherMode};
+pub use tray_state::TrayState;
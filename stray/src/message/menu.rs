@@ -1,40 +1,255 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
 use std::str;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use zbus::zvariant::{OwnedValue, Structure, Value};
+use zbus::zvariant::{Array, OwnedValue, Structure, Value};
+use zbus::Connection;
 
-use crate::dbus::dbusmenu_proxy::MenuLayout;
+use crate::dbus::dbusmenu_proxy::{DBusMenuProxy, MenuLayout};
+use crate::error::{ItemContext, Result as StrayResult};
+use crate::message::{ItemAddress, MenuPath};
+use crate::notifier_watcher::call_timeout::with_timeout;
 
 /// A menu that should be displayed when clicking corresponding tray icon
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TrayMenu {
-    /// The unique identifier of the menu
+    /// The layout revision of the menu, the application increments it whenever the layout changes
     pub id: u32,
     /// A recursive list of submenus
     pub submenus: Vec<MenuItem>,
+    /// The direction the menu text should be displayed in
+    pub text_direction: TextDirection,
+    /// Whether the application requests attention for its menu
+    pub status: MenuStatus,
+    /// Additional paths to look for the menu item icons in
+    pub icon_theme_path: Vec<String>,
+}
+
+/// A handle to the menu of a [`crate::message::tray::StatusNotifierItem`], sent along with
+/// each [`crate::NotifierItemMessage::Update`].
+///
+/// The menu is fetched in the background by the watcher, [`MenuHandle::cached`] returns it
+/// if it is already available while [`MenuHandle::load`] falls back to fetching it on demand.
+/// This way item updates are not delayed by slow menus and hosts only need to wait for the
+/// menus they are about to display.
+#[derive(Debug, Clone)]
+pub struct MenuHandle {
+    pub(crate) address: ItemAddress,
+    pub(crate) path: Option<MenuPath>,
+    pub(crate) depth: i32,
+    pub(crate) layout: Option<Arc<TrayMenu>>,
+    pub(crate) max_children: Option<usize>,
+    pub(crate) call_timeout: Option<Duration>,
+    pub(crate) stale: bool,
+    // `None` for menus replayed from a recording
+    pub(crate) connection: Option<Connection>,
+}
+
+impl MenuHandle {
+    pub(crate) fn new(
+        address: ItemAddress,
+        path: Option<MenuPath>,
+        depth: i32,
+        max_children: Option<usize>,
+        call_timeout: Option<Duration>,
+        connection: Connection,
+    ) -> Self {
+        MenuHandle {
+            address,
+            path,
+            depth,
+            layout: None,
+            max_children,
+            call_timeout,
+            stale: false,
+            connection: Some(connection),
+        }
+    }
+
+    // A handle to a recorded menu layout, not backed by any dbus connection
+    #[cfg(any(test, feature = "replay"))]
+    pub(crate) fn offline(
+        address: ItemAddress,
+        path: Option<MenuPath>,
+        layout: Option<TrayMenu>,
+    ) -> Self {
+        MenuHandle {
+            address,
+            path,
+            depth: 0,
+            layout: layout.map(Arc::new),
+            max_children: None,
+            call_timeout: None,
+            stale: false,
+            connection: None,
+        }
+    }
+
+    /// DBus path of the menu, `None` if the item does not expose any menu.
+    pub fn path(&self) -> Option<&MenuPath> {
+        self.path.as_ref()
+    }
+
+    /// Returns the menu layout if it was already fetched by the watcher.
+    pub fn cached(&self) -> Option<&TrayMenu> {
+        self.layout.as_deref()
+    }
+
+    /// Same as [`MenuHandle::cached`], returns the layout shared between the hosts so it can be
+    /// kept around without cloning the whole menu tree.
+    pub fn shared(&self) -> Option<Arc<TrayMenu>> {
+        self.layout.clone()
+    }
+
+    /// Returns true if the cached layout comes from a previous session of the application, see
+    /// [`crate::StatusNotifierWatcherBuilder::menu_cache_dir`]. A fresh update follows once the live menu is fetched.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Returns the menu layout, fetching it from the item if it is not cached yet.
+    /// Returns `None` if the item does not expose any menu.
+    pub async fn load(&self) -> StrayResult<Option<TrayMenu>> {
+        if let Some(menu) = &self.layout {
+            return Ok(Some(menu.as_ref().clone()));
+        }
+
+        let Some(dbus_menu_proxy) = self.proxy().await? else {
+            return Ok(None);
+        };
+
+        // Lazily populated menus are only valid after AboutToShow
+        let _ = with_timeout(self.call_timeout, dbus_menu_proxy.about_to_show(0)).await;
+        let layout = self.get_layout(&dbus_menu_proxy, 0).await?;
+        Ok(Some(self.to_tray_menu(&dbus_menu_proxy, layout).await?))
+    }
+
+    /// Notify the application that the menu item `id` is about to be shown, `0` being the root menu.
+    /// Many applications populate their menus lazily and require this call before the layout is valid.
+    /// Returns the refreshed menu layout if the application reported that it was updated.
+    pub async fn about_to_show(&self, id: i32) -> StrayResult<Option<TrayMenu>> {
+        let Some(dbus_menu_proxy) = self.proxy().await? else {
+            return Ok(None);
+        };
+
+        if !with_timeout(self.call_timeout, dbus_menu_proxy.about_to_show(id)).await? {
+            return Ok(None);
+        }
+
+        let layout = self.get_layout(&dbus_menu_proxy, 0).await?;
+        Ok(Some(self.to_tray_menu(&dbus_menu_proxy, layout).await?))
+    }
+
+    /// Fetch the children of the menu item `parent_id`, this allows to lazily populate large
+    /// submenus only when the user actually opens them instead of materializing the whole menu up front.
+    /// Returns an empty list if the item does not expose any menu.
+    pub async fn submenu(&self, parent_id: i32) -> StrayResult<Vec<MenuItem>> {
+        self.page(parent_id, 0).await
+    }
+
+    /// Fetch the children of the menu item `parent_id` starting at `offset`. When a
+    /// [`crate::StatusNotifierWatcherBuilder::max_menu_children`] limit is configured at most that many
+    /// children are returned, followed by an overflow marker (see [`MenuItem::overflow`]) if more remain.
+    pub async fn page(&self, parent_id: i32, offset: usize) -> StrayResult<Vec<MenuItem>> {
+        let Some(dbus_menu_proxy) = self.proxy().await? else {
+            return Ok(vec![]);
+        };
+
+        let _ = with_timeout(self.call_timeout, dbus_menu_proxy.about_to_show(parent_id)).await;
+        let layout = self.get_layout(&dbus_menu_proxy, parent_id).await?;
+
+        let mut submenu = vec![];
+        for value in &layout.fields.submenus {
+            submenu.push(MenuItem::try_from(value)?);
+        }
+
+        Ok(match self.max_children {
+            None => submenu.into_iter().skip(offset).collect(),
+            Some(max_children) => {
+                let mut page = paginate(submenu, parent_id, offset, max_children);
+                page.iter_mut()
+                    .for_each(|item| item.paginate_children(max_children));
+                page
+            }
+        })
+    }
+
+    async fn get_layout(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+        parent_id: i32,
+    ) -> StrayResult<MenuLayout> {
+        with_timeout(
+            self.call_timeout,
+            dbus_menu_proxy.get_layout(parent_id, self.depth, &[]),
+        )
+        .await
+        .item_context(&self.address, "GetLayout")
+    }
+
+    async fn to_tray_menu(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+        layout: MenuLayout,
+    ) -> StrayResult<TrayMenu> {
+        let mut menu = TrayMenu::try_from(layout)?;
+        menu.read_properties(dbus_menu_proxy).await;
+        if let Some(max_children) = self.max_children {
+            menu.paginate(max_children);
+        }
+
+        Ok(menu)
+    }
+
+    async fn proxy(&self) -> StrayResult<Option<DBusMenuProxy<'_>>> {
+        let (Some(path), Some(connection)) = (&self.path, &self.connection) else {
+            return Ok(None);
+        };
+
+        let dbus_menu_proxy = DBusMenuProxy::builder(connection)
+            .destination(self.address.destination())?
+            .path(path.as_str())?
+            .build()
+            .await?;
+
+        Ok(Some(dbus_menu_proxy))
+    }
+}
+
+impl Serialize for MenuHandle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.layout.as_deref().serialize(serializer)
+    }
 }
 
 /// Represent an entry in a menu as described in [com.canonical.dbusmenu](https://github.com/AyatanaIndicators/libdbusmenu/blob/4d03141aea4e2ad0f04ab73cf1d4f4bcc4a19f6c/libdbusmenu-glib/dbus-menu.xml#L75)
 /// This implementation currently support a sub section of the spec, if you feel something is missing don't hesitate to submit an issue.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MenuItem {
     /// Unique numeric id
     pub id: i32,
-    /// If the menu item has children this property should be set to "submenu"
-    pub children_display: Option<String>,
+    /// Whether the item has children, which should be displayed as a submenu
+    pub children_display: ChildrenDisplay,
     /// Text of the item,
     pub label: String,
+    /// Description of the item for screen readers, when it differs from the label
+    pub accessible_desc: Option<String>,
     /// Whether the item can be activated or not.
     pub enabled: bool,
     /// True if the item is visible in the menu.
     pub visible: bool,
     /// Icon name of the item, following the freedesktop.org icon spec.
     pub icon_name: Option<String>,
-    /// Describe the current state of a "togglable" item. Can be one of:
-    ///   - Some(true): on
-    ///   - Some(false): off
-    ///   - None: indeterminate
+    /// PNG data of the icon, to be used when [`MenuItem::icon_name`] is not set or can't be found in the icon theme.
+    pub icon_data: Option<Vec<u8>>,
+    /// Describe the current state of a "togglable" item, sent as an int by the application:
+    ///   - 1: [`ToggleState::On`]
+    ///   - 0: [`ToggleState::Off`]
+    ///   - -1 or any other value: [`ToggleState::Indeterminate`]
     pub toggle_state: ToggleState,
     /// How the menuitem feels the information it's displaying to the
     /// user should be presented.
@@ -43,31 +258,52 @@ pub struct MenuItem {
     pub menu_type: MenuType,
     /// How the menuitem feels the information it's displaying to the user should be presented.
     pub disposition: Disposition,
+    /// Keyboard shortcuts of the item, most items have at most one.
+    pub shortcut: Vec<Accelerator>,
     /// A submenu for this item, typically this would ve revealed to the user by hovering the current item
     pub submenu: Vec<MenuItem>,
+    /// Set on the marker item standing for the children hidden by the
+    /// [`crate::StatusNotifierWatcherBuilder::max_menu_children`] limit. Marker items are not part of the
+    /// application menu and must not be sent back in commands, use [`MenuHandle::page`] to fetch the hidden entries.
+    pub overflow: Option<MenuOverflow>,
+}
+
+/// Describe the menu entries hidden behind an overflow marker item
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub struct MenuOverflow {
+    /// Id of the parent menu item, `0` being the root menu.
+    pub parent_id: i32,
+    /// Index of the first hidden entry, to be used with [`MenuHandle::page`].
+    pub offset: usize,
+    /// Number of hidden entries
+    pub hidden: usize,
 }
 
 impl Default for MenuItem {
     fn default() -> Self {
         Self {
             id: 0,
-            children_display: None,
+            children_display: ChildrenDisplay::None,
             label: "".to_string(),
+            accessible_desc: None,
             enabled: true,
             visible: true,
             icon_name: None,
+            icon_data: None,
             toggle_state: ToggleState::Indeterminate,
             toggle_type: ToggleType::CannotBeToggled,
             menu_type: MenuType::Standard,
             disposition: Disposition::Normal,
+            shortcut: vec![],
             submenu: vec![],
+            overflow: None,
         }
     }
 }
 
 /// How the menuitem feels the information it's displaying to the
 /// user should be presented.
-#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum ToggleType {
     /// Item is an independent togglable item
     Checkmark,
@@ -79,7 +315,7 @@ pub enum ToggleType {
 }
 
 /// Either a standard menu item or a separator
-#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum MenuType {
     ///  a separator
     Separator,
@@ -87,9 +323,36 @@ pub enum MenuType {
     Standard,
 }
 
+/// How the children of a menu item should be displayed, see the dbusmenu `children-display` property
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum ChildrenDisplay {
+    /// The item has children to display in a submenu
+    Submenu,
+    /// The item has no children
+    None,
+}
+
+/// The direction of the menu text, see the dbusmenu `TextDirection` property
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum TextDirection {
+    /// Left to right
+    LeftToRight,
+    /// Right to left
+    RightToLeft,
+}
+
+/// The dbusmenu `Status` property
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum MenuStatus {
+    /// The menu should be displayed normally
+    Normal,
+    /// The application requests the menu to be displayed in a way it gets the user attention
+    Notice,
+}
+
 /// How the menuitem feels the information it's displaying to the
 /// user should be presented.
-#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum Disposition {
     /// a standard menu item
     Normal,
@@ -101,8 +364,38 @@ pub enum Disposition {
     Alert,
 }
 
+/// A keyboard shortcut such as `Control+Shift+q`, parsed from the dbusmenu `shortcut` property.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct Accelerator {
+    /// The modifiers to hold, one of "Control", "Alt", "Shift" and "Super"
+    pub modifiers: Vec<String>,
+    /// The key to press, ex: "q", "Delete"
+    pub key: String,
+}
+
+impl Accelerator {
+    // The last entry of a dbusmenu key chord is the key, the previous ones are modifiers
+    fn from_chord(mut chord: Vec<String>) -> Option<Self> {
+        let key = chord.pop()?;
+        Some(Accelerator {
+            modifiers: chord,
+            key,
+        })
+    }
+}
+
+impl fmt::Display for Accelerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{modifier}+")?;
+        }
+
+        write!(f, "{}", self.key)
+    }
+}
+
 /// Describe the current state of a "togglable" item.
-#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum ToggleState {
     /// This item is toggled
     On,
@@ -150,16 +443,477 @@ impl FromStr for Disposition {
     }
 }
 
+impl FromStr for ChildrenDisplay {
+    type Err = zbus::zvariant::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "submenu" => Ok(ChildrenDisplay::Submenu),
+            "" => Ok(ChildrenDisplay::None),
+            _ => Err(zbus::zvariant::Error::IncorrectType),
+        }
+    }
+}
+
+impl FromStr for TextDirection {
+    type Err = zbus::zvariant::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ltr" => Ok(TextDirection::LeftToRight),
+            "rtl" => Ok(TextDirection::RightToLeft),
+            _ => Err(zbus::zvariant::Error::IncorrectType),
+        }
+    }
+}
+
+impl FromStr for MenuStatus {
+    type Err = zbus::zvariant::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(MenuStatus::Normal),
+            "notice" => Ok(MenuStatus::Notice),
+            _ => Err(zbus::zvariant::Error::IncorrectType),
+        }
+    }
+}
+
 impl From<bool> for ToggleState {
     fn from(value: bool) -> Self {
         if value {
             ToggleState::On
         } else {
-            ToggleState::Indeterminate
+            ToggleState::Off
         }
     }
 }
 
+impl From<i32> for ToggleState {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => ToggleState::Off,
+            1 => ToggleState::On,
+            _ => ToggleState::Indeterminate,
+        }
+    }
+}
+
+/// The differences between two revisions of a menu, see [`TrayMenu::diff`].
+/// Items are identified by their dbusmenu id and listed in depth first order.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct MenuDiff {
+    /// Items only present in the new menu
+    pub added: Vec<i32>,
+    /// Items only present in the old menu
+    pub removed: Vec<i32>,
+    /// Items present in both menus whose properties or position changed
+    pub changed: Vec<i32>,
+}
+
+impl MenuDiff {
+    /// Returns true if both menus are identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// The parent id and index of each item of a menu, along with the item itself
+type MenuIndex<'a> = HashMap<i32, (i32, usize, &'a MenuItem)>;
+
+fn index_items<'a>(parent: i32, items: &'a [MenuItem], index: &mut MenuIndex<'a>) {
+    for (position, item) in items.iter().enumerate() {
+        // Overflow markers are not part of the application menu
+        if item.overflow.is_some() {
+            continue;
+        }
+
+        index.insert(item.id, (parent, position, item));
+        index_items(item.id, &item.submenu, index);
+    }
+}
+
+/// Depth first iterator over a menu tree, see [`TrayMenu::iter`] and [`MenuItem::iter`].
+#[derive(Debug, Clone)]
+pub struct MenuIter<'a> {
+    stack: Vec<&'a MenuItem>,
+}
+
+impl<'a> MenuIter<'a> {
+    fn new(items: &'a [MenuItem]) -> Self {
+        MenuIter {
+            stack: items.iter().rev().collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MenuIter<'a> {
+    type Item = &'a MenuItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.stack.pop()?;
+        self.stack.extend(item.submenu.iter().rev());
+        Some(item)
+    }
+}
+
+// Keep at most `max_children` entries starting at `offset`, followed by an overflow marker if more remain
+fn paginate(
+    children: Vec<MenuItem>,
+    parent_id: i32,
+    offset: usize,
+    max_children: usize,
+) -> Vec<MenuItem> {
+    let total = children.len();
+    let mut page: Vec<MenuItem> = children
+        .into_iter()
+        .skip(offset)
+        .take(max_children)
+        .collect();
+
+    let next_offset = offset + page.len();
+    if next_offset < total {
+        let hidden = total - next_offset;
+        page.push(MenuItem {
+            id: -1,
+            label: format!("… {hidden} more"),
+            overflow: Some(MenuOverflow {
+                parent_id,
+                offset: next_offset,
+                hidden,
+            }),
+            ..MenuItem::default()
+        });
+    }
+
+    page
+}
+
+impl TrayMenu {
+    /// Compute the items added, removed and changed between two revisions of a menu. Frontends
+    /// building native widget trees can use it to update them in place instead of rebuilding the whole menu.
+    pub fn diff(old: &TrayMenu, new: &TrayMenu) -> MenuDiff {
+        let mut old_index = MenuIndex::new();
+        let mut new_index = MenuIndex::new();
+        index_items(0, &old.submenus, &mut old_index);
+        index_items(0, &new.submenus, &mut new_index);
+
+        let mut diff = MenuDiff::default();
+        for item in old.iter().filter(|item| item.overflow.is_none()) {
+            if !new_index.contains_key(&item.id) {
+                diff.removed.push(item.id);
+            }
+        }
+
+        for item in new.iter().filter(|item| item.overflow.is_none()) {
+            match old_index.get(&item.id) {
+                None => diff.added.push(item.id),
+                Some((parent, position, old_item)) => {
+                    let (new_parent, new_position, _) = new_index[&item.id];
+                    if (*parent, *position) != (new_parent, new_position)
+                        || !old_item.same_properties(item)
+                    {
+                        diff.changed.push(item.id);
+                    }
+                }
+            }
+        }
+
+        diff
+    }
+
+    /// Find the menu item with the given dbusmenu id anywhere in the menu tree.
+    pub fn find_by_id(&self, id: i32) -> Option<&MenuItem> {
+        self.submenus.iter().find_map(|item| item.find_by_id(id))
+    }
+
+    /// Iterate over all the items of the menu tree, depth first.
+    pub fn iter(&self) -> MenuIter<'_> {
+        MenuIter::new(&self.submenus)
+    }
+
+    /// Returns the ids of the items leading to the item `id`, from the top level item to the item itself.
+    /// This is typically used to open the submenus containing an item.
+    pub fn path_to(&self, id: i32) -> Option<Vec<i32>> {
+        self.submenus.iter().find_map(|item| item.path_to(id))
+    }
+
+    // Read the com.canonical.dbusmenu interface properties, missing ones keep their default value
+    pub(crate) async fn read_properties(&mut self, dbus_menu_proxy: &DBusMenuProxy<'_>) {
+        if let Ok(direction) = dbus_menu_proxy.text_direction().await {
+            self.text_direction =
+                TextDirection::from_str(&direction).unwrap_or(TextDirection::LeftToRight);
+        }
+
+        if let Ok(status) = dbus_menu_proxy.status().await {
+            self.status = MenuStatus::from_str(&status).unwrap_or(MenuStatus::Normal);
+        }
+
+        if let Ok(icon_theme_path) = dbus_menu_proxy.icon_theme_path().await {
+            self.icon_theme_path = icon_theme_path;
+        }
+    }
+
+    // Truncate every level of the menu to `max_children` entries
+    pub(crate) fn paginate(&mut self, max_children: usize) {
+        self.submenus = paginate(std::mem::take(&mut self.submenus), 0, 0, max_children);
+        self.submenus
+            .iter_mut()
+            .for_each(|item| item.paginate_children(max_children));
+    }
+
+    // Patch the menu in place with the content of an `ItemsPropertiesUpdated` signal
+    pub(crate) fn apply_properties_update(
+        &mut self,
+        updated_props: &[(i32, HashMap<&str, Value<'_>>)],
+        removed_props: &[(i32, Vec<&str>)],
+    ) {
+        for (id, props) in updated_props {
+            if let Some(item) = self.find_mut(*id) {
+                for (name, value) in props {
+                    item.set_property(name, value);
+                }
+            }
+        }
+
+        for (id, names) in removed_props {
+            if let Some(item) = self.find_mut(*id) {
+                for name in names {
+                    item.reset_property(name);
+                }
+            }
+        }
+    }
+
+    // Replace all the properties of the item `id`, as received from `GetGroupProperties`
+    pub(crate) fn replace_properties(&mut self, id: i32, props: &HashMap<String, OwnedValue>) {
+        if let Some(item) = self.find_mut(id) {
+            let mut refreshed = MenuItem {
+                id,
+                submenu: std::mem::take(&mut item.submenu),
+                ..MenuItem::default()
+            };
+
+            for (name, value) in props {
+                refreshed.set_property(name, value);
+            }
+
+            *item = refreshed;
+        }
+    }
+
+    // Replace the item `id` and its children, returns false if the item is not part of the menu
+    pub(crate) fn replace_item(&mut self, item: MenuItem) -> bool {
+        match self.find_mut(item.id) {
+            Some(current) => {
+                *current = item;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Apply the toggle state the item `id` is expected to have once clicked: checkmarks are flipped
+    // and radio items are selected, unselecting the other radio items of the same submenu.
+    // Returns false if the item can't be toggled.
+    pub(crate) fn toggle(&mut self, id: i32) -> bool {
+        let Some(path) = self.path_to(id) else {
+            return false;
+        };
+
+        let siblings = match path.len() {
+            1 => &mut self.submenus,
+            len => match self.find_mut(path[len - 2]) {
+                Some(parent) => &mut parent.submenu,
+                None => return false,
+            },
+        };
+
+        let Some(toggle_type) = siblings
+            .iter()
+            .find(|item| item.id == id)
+            .map(|item| item.toggle_type)
+        else {
+            return false;
+        };
+
+        match toggle_type {
+            ToggleType::Checkmark => {
+                for item in siblings.iter_mut().filter(|item| item.id == id) {
+                    item.toggle_state = match item.toggle_state {
+                        ToggleState::On => ToggleState::Off,
+                        _ => ToggleState::On,
+                    };
+                }
+            }
+            ToggleType::Radio => {
+                for item in siblings
+                    .iter_mut()
+                    .filter(|item| item.toggle_type == ToggleType::Radio)
+                {
+                    item.toggle_state = if item.id == id {
+                        ToggleState::On
+                    } else {
+                        ToggleState::Off
+                    };
+                }
+            }
+            ToggleType::CannotBeToggled => return false,
+        }
+
+        true
+    }
+
+    fn find_mut(&mut self, id: i32) -> Option<&mut MenuItem> {
+        self.submenus.iter_mut().find_map(|item| item.find_mut(id))
+    }
+}
+
+impl MenuItem {
+    // Compare every property but the submenu
+    fn same_properties(&self, other: &MenuItem) -> bool {
+        self.id == other.id
+            && self.children_display == other.children_display
+            && self.label == other.label
+            && self.accessible_desc == other.accessible_desc
+            && self.enabled == other.enabled
+            && self.visible == other.visible
+            && self.icon_name == other.icon_name
+            && self.icon_data == other.icon_data
+            && self.toggle_state == other.toggle_state
+            && self.toggle_type == other.toggle_type
+            && self.menu_type == other.menu_type
+            && self.disposition == other.disposition
+            && self.shortcut == other.shortcut
+    }
+
+    /// Find the item with the given dbusmenu id among this item and its descendants.
+    pub fn find_by_id(&self, id: i32) -> Option<&MenuItem> {
+        if self.id == id {
+            return Some(self);
+        }
+
+        self.submenu.iter().find_map(|item| item.find_by_id(id))
+    }
+
+    /// Iterate over this item and all its descendants, depth first.
+    pub fn iter(&self) -> MenuIter<'_> {
+        MenuIter::new(std::slice::from_ref(self))
+    }
+
+    /// Returns the ids of the items leading to the item `id`, starting with this item.
+    pub fn path_to(&self, id: i32) -> Option<Vec<i32>> {
+        if self.id == id {
+            return Some(vec![id]);
+        }
+
+        self.submenu.iter().find_map(|item| {
+            item.path_to(id).map(|mut path| {
+                path.insert(0, self.id);
+                path
+            })
+        })
+    }
+
+    fn paginate_children(&mut self, max_children: usize) {
+        self.submenu = paginate(std::mem::take(&mut self.submenu), self.id, 0, max_children);
+        self.submenu
+            .iter_mut()
+            .for_each(|item| item.paginate_children(max_children));
+    }
+
+    fn find_mut(&mut self, id: i32) -> Option<&mut MenuItem> {
+        if self.id == id {
+            return Some(self);
+        }
+
+        self.submenu.iter_mut().find_map(|item| item.find_mut(id))
+    }
+
+    // Update a single property, as received in `ItemsPropertiesUpdated`
+    fn set_property(&mut self, name: &str, value: &Value<'_>) {
+        match (name, value) {
+            ("label", Value::Str(label)) => self.label = label.as_str().replace('_', ""),
+            ("enabled", Value::Bool(enabled)) => self.enabled = *enabled,
+            ("visible", Value::Bool(visible)) => self.visible = *visible,
+            ("icon-name", Value::Str(icon_name)) => self.icon_name = Some(icon_name.to_string()),
+            ("icon-data", Value::Array(icon_data)) => self.icon_data = Some(bytes(icon_data)),
+            ("shortcut", Value::Array(shortcut)) => self.shortcut = accelerators(shortcut),
+            ("children-display", Value::Str(display)) => {
+                self.children_display =
+                    ChildrenDisplay::from_str(display.as_str()).unwrap_or(ChildrenDisplay::None)
+            }
+            ("accessible-desc", Value::Str(desc)) => self.accessible_desc = Some(desc.to_string()),
+            ("disposition", Value::Str(disposition)) => {
+                if let Ok(disposition) = Disposition::from_str(disposition.as_str()) {
+                    self.disposition = disposition;
+                }
+            }
+            ("toggle-type", Value::Str(toggle_type)) => {
+                self.toggle_type = ToggleType::from_str(toggle_type.as_str())
+                    .unwrap_or(ToggleType::CannotBeToggled)
+            }
+            ("toggle-state", Value::I32(state)) => self.toggle_state = ToggleState::from(*state),
+            ("toggle-state", Value::Bool(state)) => self.toggle_state = ToggleState::from(*state),
+            ("type", Value::Str(menu_type)) => {
+                self.menu_type =
+                    MenuType::from_str(menu_type.as_str()).unwrap_or(MenuType::Standard)
+            }
+            (name, _) => tracing::debug!("Ignoring update of menu item property '{name}'"),
+        }
+    }
+
+    // Restore the default value of a property, as received in `ItemsPropertiesUpdated`
+    fn reset_property(&mut self, name: &str) {
+        let default = MenuItem::default();
+        match name {
+            "label" => self.label = default.label,
+            "enabled" => self.enabled = default.enabled,
+            "visible" => self.visible = default.visible,
+            "icon-name" => self.icon_name = default.icon_name,
+            "icon-data" => self.icon_data = default.icon_data,
+            "shortcut" => self.shortcut = default.shortcut,
+            "children-display" => self.children_display = default.children_display,
+            "accessible-desc" => self.accessible_desc = default.accessible_desc,
+            "disposition" => self.disposition = default.disposition,
+            "toggle-type" => self.toggle_type = default.toggle_type,
+            "toggle-state" => self.toggle_state = default.toggle_state,
+            "type" => self.menu_type = default.menu_type,
+            name => tracing::debug!("Ignoring removal of menu item property '{name}'"),
+        }
+    }
+}
+
+// Collect an `ay` dbus array
+fn bytes(array: &Array) -> Vec<u8> {
+    array
+        .iter()
+        .filter_map(|value| match value {
+            Value::U8(byte) => Some(*byte),
+            _ => None,
+        })
+        .collect()
+}
+
+// Parse an `aas` list of key chords
+fn accelerators(array: &Array) -> Vec<Accelerator> {
+    array
+        .iter()
+        .filter_map(|chord| match chord {
+            Value::Array(chord) => Accelerator::from_chord(
+                chord
+                    .iter()
+                    .filter_map(|key| match key {
+                        Value::Str(key) => Some(key.to_string()),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
 impl TryFrom<MenuLayout> for TrayMenu {
     type Error = zbus::zvariant::Error;
 
@@ -173,6 +927,9 @@ impl TryFrom<MenuLayout> for TrayMenu {
         Ok(TrayMenu {
             id: value.id,
             submenus,
+            text_direction: TextDirection::LeftToRight,
+            status: MenuStatus::Normal,
+            icon_theme_path: vec![],
         })
     }
 }
@@ -183,7 +940,7 @@ impl TryFrom<&OwnedValue> for MenuItem {
     fn try_from(value: &OwnedValue) -> Result<Self, Self::Error> {
         let structure = value
             .downcast_ref::<Structure>()
-            .expect("Expected a layout");
+            .ok_or(zbus::zvariant::Error::IncorrectType)?;
 
         let mut fields = structure.fields().iter();
         let mut menu = MenuItem::default();
@@ -194,8 +951,14 @@ impl TryFrom<&OwnedValue> for MenuItem {
 
         if let Some(Value::Dict(dict)) = fields.next() {
             menu.children_display = dict
-                .get::<str, str>("children_display")?
-                .map(str::to_string);
+                .get::<str, str>("children-display")
+                .ok()
+                .flatten()
+                .map(ChildrenDisplay::from_str)
+                .and_then(Result::ok)
+                .unwrap_or(ChildrenDisplay::None);
+
+            menu.accessible_desc = dict.get::<str, str>("accessible-desc")?.map(str::to_string);
 
             // see: https://github.com/AyatanaIndicators/libdbusmenu/blob/4d03141aea4e2ad0f04ab73cf1d4f4bcc4a19f6c/libdbusmenu-glib/dbus-menu.xml#L75
             menu.label = dict
@@ -213,6 +976,19 @@ impl TryFrom<&OwnedValue> for MenuItem {
 
             menu.icon_name = dict.get::<str, str>("icon-name")?.map(str::to_string);
 
+            menu.icon_data = dict
+                .get::<str, Array>("icon-data")
+                .ok()
+                .flatten()
+                .map(bytes);
+
+            menu.shortcut = dict
+                .get::<str, Array>("shortcut")
+                .ok()
+                .flatten()
+                .map(accelerators)
+                .unwrap_or_default();
+
             if let Some(disposition) = dict
                 .get::<str, str>("disposition")
                 .ok()
@@ -223,12 +999,17 @@ impl TryFrom<&OwnedValue> for MenuItem {
                 menu.disposition = disposition;
             }
 
-            menu.toggle_state = dict
-                .get::<str, bool>("toggle-state")
-                .ok()
-                .flatten()
-                .map(|value| ToggleState::from(*value))
-                .unwrap_or(ToggleState::Indeterminate);
+            // The spec sends an int, some implementations a boolean
+            let toggle_state = dict.get::<str, i32>("toggle-state").ok().flatten();
+            menu.toggle_state = match toggle_state {
+                Some(state) => ToggleState::from(*state),
+                None => dict
+                    .get::<str, bool>("toggle-state")
+                    .ok()
+                    .flatten()
+                    .map(|state| ToggleState::from(*state))
+                    .unwrap_or(ToggleState::Indeterminate),
+            };
 
             menu.toggle_type = dict
                 .get::<str, str>("toggle-type")
@@ -261,3 +1042,188 @@ impl TryFrom<&OwnedValue> for MenuItem {
         Ok(menu)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: i32, submenu: Vec<MenuItem>) -> MenuItem {
+        MenuItem {
+            id,
+            label: format!("item {id}"),
+            submenu,
+            ..MenuItem::default()
+        }
+    }
+
+    fn menu(submenus: Vec<MenuItem>) -> TrayMenu {
+        TrayMenu {
+            id: 1,
+            submenus,
+            text_direction: TextDirection::LeftToRight,
+            status: MenuStatus::Normal,
+            icon_theme_path: vec![],
+        }
+    }
+
+    fn ids(items: &[MenuItem]) -> Vec<i32> {
+        items.iter().map(|item| item.id).collect()
+    }
+
+    fn parse_accelerators(chords: Vec<Vec<&str>>) -> Vec<Accelerator> {
+        let value = Value::from(chords);
+        accelerators(value.downcast_ref::<Array>().unwrap())
+    }
+
+    #[test]
+    fn should_keep_the_modifiers_order() {
+        let shortcut = parse_accelerators(vec![vec!["Shift", "Control", "q"], vec!["Delete"]]);
+
+        assert_eq!(
+            shortcut,
+            vec![
+                Accelerator {
+                    modifiers: vec!["Shift".to_string(), "Control".to_string()],
+                    key: "q".to_string(),
+                },
+                Accelerator {
+                    modifiers: vec![],
+                    key: "Delete".to_string(),
+                },
+            ]
+        );
+        assert_eq!(shortcut[0].to_string(), "Shift+Control+q");
+        assert_eq!(shortcut[1].to_string(), "Delete");
+    }
+
+    #[test]
+    fn should_keep_unknown_keys() {
+        let shortcut = parse_accelerators(vec![vec!["Hyper", "XF86AudioPlay"]]);
+
+        assert_eq!(shortcut[0].modifiers, vec!["Hyper".to_string()]);
+        assert_eq!(shortcut[0].key, "XF86AudioPlay");
+    }
+
+    #[test]
+    fn should_skip_empty_chords() {
+        assert!(parse_accelerators(vec![]).is_empty());
+        assert!(parse_accelerators(vec![vec![]]).is_empty());
+
+        let value = Value::from(vec![vec![1i32, 2]]);
+        assert!(accelerators(value.downcast_ref::<Array>().unwrap()).is_empty());
+    }
+
+    fn parse_item(props: HashMap<&str, Value<'_>>) -> MenuItem {
+        let children: Vec<Value<'_>> = vec![];
+        let value = OwnedValue::from(Value::from((7i32, props, children)));
+        MenuItem::try_from(&value).unwrap()
+    }
+
+    #[test]
+    fn should_parse_toggle_states() {
+        for (state, expected) in [
+            (1, ToggleState::On),
+            (0, ToggleState::Off),
+            (-1, ToggleState::Indeterminate),
+        ] {
+            let item = parse_item(HashMap::from([("toggle-state", Value::I32(state))]));
+            assert_eq!(item.toggle_state, expected);
+
+            let mut updated = MenuItem::default();
+            updated.set_property("toggle-state", &Value::I32(state));
+            assert_eq!(updated.toggle_state, expected);
+        }
+
+        let item = parse_item(HashMap::from([("toggle-state", Value::Bool(true))]));
+        assert_eq!(item.toggle_state, ToggleState::On);
+
+        let item = parse_item(HashMap::new());
+        assert_eq!(item.id, 7);
+        assert_eq!(item.toggle_state, ToggleState::Indeterminate);
+    }
+
+    #[test]
+    fn should_diff_menus() {
+        let old = menu(vec![
+            item(1, vec![item(11, vec![]), item(12, vec![])]),
+            item(2, vec![]),
+            item(3, vec![]),
+        ]);
+
+        let mut renamed = item(3, vec![]);
+        renamed.label = "renamed".to_string();
+        let new = menu(vec![
+            item(2, vec![]),
+            item(1, vec![item(12, vec![]), item(13, vec![])]),
+            renamed,
+        ]);
+
+        let diff = TrayMenu::diff(&old, &new);
+        assert_eq!(diff.added, vec![13]);
+        assert_eq!(diff.removed, vec![11]);
+        assert_eq!(diff.changed, vec![2, 1, 12, 3]);
+        assert!(TrayMenu::diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn should_find_nested_items() {
+        let menu = menu(vec![
+            item(1, vec![item(11, vec![item(111, vec![])])]),
+            item(2, vec![item(21, vec![])]),
+        ]);
+
+        assert_eq!(menu.find_by_id(111).map(|item| item.id), Some(111));
+        assert_eq!(menu.find_by_id(21).map(|item| item.id), Some(21));
+        assert!(menu.find_by_id(3).is_none());
+
+        assert_eq!(menu.path_to(111), Some(vec![1, 11, 111]));
+        assert_eq!(menu.path_to(2), Some(vec![2]));
+        assert_eq!(menu.path_to(3), None);
+
+        let visited: Vec<i32> = menu.iter().map(|item| item.id).collect();
+        assert_eq!(visited, vec![1, 11, 111, 2, 21]);
+        let visited: Vec<i32> = menu.submenus[0].iter().map(|item| item.id).collect();
+        assert_eq!(visited, vec![1, 11, 111]);
+    }
+
+    #[test]
+    fn should_paginate_every_level() {
+        let children = (11..16).map(|id| item(id, vec![])).collect();
+        let mut menu = menu(vec![item(1, children), item(2, vec![]), item(3, vec![])]);
+
+        menu.paginate(2);
+
+        assert_eq!(ids(&menu.submenus), vec![1, 2, -1]);
+        assert_eq!(
+            menu.submenus[2].overflow,
+            Some(MenuOverflow {
+                parent_id: 0,
+                offset: 2,
+                hidden: 1,
+            })
+        );
+
+        let children = &menu.submenus[0].submenu;
+        assert_eq!(ids(children), vec![11, 12, -1]);
+        assert_eq!(
+            children[2].overflow,
+            Some(MenuOverflow {
+                parent_id: 1,
+                offset: 2,
+                hidden: 3,
+            })
+        );
+
+        // Markers are not part of the application menu
+        assert!(TrayMenu::diff(&menu, &menu).is_empty());
+    }
+
+    #[test]
+    fn should_not_paginate_short_menus() {
+        let page = paginate(vec![item(1, vec![]), item(2, vec![])], 0, 0, 2);
+        assert_eq!(ids(&page), vec![1, 2]);
+
+        let page = paginate(vec![item(1, vec![]), item(2, vec![])], 0, 2, 2);
+        assert!(page.is_empty());
+    }
+}
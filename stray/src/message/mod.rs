@@ -1,6 +1,15 @@
-use crate::message::menu::TrayMenu;
-use crate::message::tray::StatusNotifierItem;
-use serde::Serialize;
+use crate::error::StatusNotifierWatcherError;
+use crate::message::menu::MenuHandle;
+use crate::message::tray::{SpecViolation, StatusNotifierItem};
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use zbus::zvariant::{ObjectPath, OwnedValue};
+use zbus::{Connection, ConnectionBuilder};
 
 /// Implementation of [com.canonical.dbusmenu](https://github.com/AyatanaIndicators/libdbusmenu/blob/4d03141aea4e2ad0f04ab73cf1d4f4bcc4a19f6c/libdbusmenu-glib/dbus-menu.xml#L75)
 pub mod menu;
@@ -15,21 +24,447 @@ pub enum NotifierItemMessage {
         /// The address of the NotifierItem on dbus, this will be required
         /// to request the activation of a manu entry via [`NotifierItemCommand::MenuItemClicked`]
         /// and remove the item when it is closed by the user.
-        address: String,
+        address: ItemAddress,
+        /// The key identifying this item, depending on the configured [`ItemIdentity`]
+        /// this is either the dbus address or the item `Id` and `WindowId` properties.
+        key: ItemKey,
         /// the status [`StatusNotifierItem`] and its metadata, to build a system tray ui
         /// the minimal would be to display it's icon and use it's menu address to send menu activation
-        /// requests.
-        item: Box<StatusNotifierItem>,
-        /// The menu layout of the item.
-        menu: Option<TrayMenu>,
+        /// requests. The item is shared between all the hosts receiving this message.
+        item: Arc<StatusNotifierItem>,
+        /// A handle to the menu layout of the item.
+        menu: MenuHandle,
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+    /// A [`StatusNotifierItem`] was rejected because it does not conform to the specification,
+    /// this is only sent in [`Conformance::Strict`] mode. Hosts should handle it like a removal.
+    Rejected {
+        /// The dbus address of the item
+        address: ItemAddress,
+        /// Every deviation found in the item properties
+        violations: Vec<SpecViolation>,
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+    /// A command sent without waiting for its outcome failed, UIs can use this to give feedback
+    /// to the user. The dispatcher keeps processing the following commands.
+    CommandFailed {
+        /// The dbus address of the item the command was sent to
+        address: ItemAddress,
+        /// The name of the failed [`NotifierItemCommand`] variant, ex: `MenuItemClicked`
+        command: String,
+        /// Description of the error
+        error: String,
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+    /// An item could not be watched, its properties or its menu could not be read. The item may
+    /// never appear or stop being updated, UIs can use this to tell the user why an icon is missing.
+    Error {
+        /// The dbus address of the failing item
+        address: ItemAddress,
+        /// Description of the error
+        error: String,
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+    /// The connection to the session bus was lost, ex: when the dbus daemon restarted. Every item
+    /// is gone, hosts should clear their state. Items are sent again as they register on the new bus.
+    Reset {
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+    /// Another process took the `org.kde.StatusNotifierWatcher` name over. The known items are kept,
+    /// stray now watches the items registered to the new watcher, see [`crate::WatcherMode::HostOnly`].
+    WatcherLost {
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
     },
     /// A [`StatusNotifierItem`] has been removed from the tray
     Remove {
         /// The dbus address of the item, it serves as an unique identifier.
-        address: String,
+        address: ItemAddress,
+        /// The key identifying this item, see [`NotifierItemMessage::Update`]
+        key: ItemKey,
+        /// Sequence number and emission time of this message.
+        stamp: MessageStamp,
+    },
+}
+
+impl NotifierItemMessage {
+    /// Returns the address of the item this message is about, `None` for [`NotifierItemMessage::Reset`]
+    /// and [`NotifierItemMessage::WatcherLost`].
+    pub fn address(&self) -> Option<&ItemAddress> {
+        match self {
+            NotifierItemMessage::Update { address, .. }
+            | NotifierItemMessage::Rejected { address, .. }
+            | NotifierItemMessage::CommandFailed { address, .. }
+            | NotifierItemMessage::Error { address, .. }
+            | NotifierItemMessage::Remove { address, .. } => Some(address),
+            NotifierItemMessage::Reset { .. } | NotifierItemMessage::WatcherLost { .. } => None,
+        }
+    }
+
+    /// Returns the sequence number and emission time of this message.
+    pub fn stamp(&self) -> &MessageStamp {
+        match self {
+            NotifierItemMessage::Update { stamp, .. } => stamp,
+            NotifierItemMessage::Rejected { stamp, .. } => stamp,
+            NotifierItemMessage::CommandFailed { stamp, .. } => stamp,
+            NotifierItemMessage::Error { stamp, .. } => stamp,
+            NotifierItemMessage::Reset { stamp } => stamp,
+            NotifierItemMessage::WatcherLost { stamp } => stamp,
+            NotifierItemMessage::Remove { stamp, .. } => stamp,
+        }
+    }
+
+    fn stamp_mut(&mut self) -> &mut MessageStamp {
+        match self {
+            NotifierItemMessage::Update { stamp, .. } => stamp,
+            NotifierItemMessage::Rejected { stamp, .. } => stamp,
+            NotifierItemMessage::CommandFailed { stamp, .. } => stamp,
+            NotifierItemMessage::Error { stamp, .. } => stamp,
+            NotifierItemMessage::Reset { stamp } => stamp,
+            NotifierItemMessage::WatcherLost { stamp } => stamp,
+            NotifierItemMessage::Remove { stamp, .. } => stamp,
+        }
+    }
+}
+
+/// Lifecycle events of the StatusNotifierWatcher, see [`crate::StatusNotifierWatcher::events`].
+/// Unlike [`NotifierItemMessage`] they carry no item content.
+#[derive(Debug, Serialize, Clone, Eq, PartialEq)]
+pub enum WatcherEvent {
+    /// An item was registered, `service` is formatted as in `RegisteredStatusNotifierItems`,
+    /// ex: `:1.52/StatusNotifierItem`
+    ItemRegistered(String),
+    /// A registered item was unregistered or its owner left the bus
+    ItemUnregistered(String),
+    /// A StatusNotifierHost registered to the watcher, only sent while the watcher serves
+    /// `org.kde.StatusNotifierWatcher`
+    HostRegistered(String),
+    /// A registered StatusNotifierHost left the bus
+    HostLost(String),
+    /// Another process took `org.kde.StatusNotifierWatcher` over, see [`crate::WatcherMode::HostOnly`]
+    WatcherNameLost,
+}
+
+/// Attached to every [`NotifierItemMessage`], this allows consumers to detect reordering,
+/// measure latency and discard stale updates.
+#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+pub struct MessageStamp {
+    /// Monotonic sequence number, strictly increasing with each message emitted by a watcher.
+    /// Each [`crate::StatusNotifierWatcher`] numbers its messages on its own.
+    pub seq: u64,
+    /// The time at which the message was emitted.
+    pub timestamp: SystemTime,
+}
+
+impl MessageStamp {
+    // Placeholder of the messages built before being emitted, see `MessageSequence::stamp`
+    pub(crate) fn unstamped() -> Self {
+        MessageStamp {
+            seq: 0,
+            timestamp: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+// The sequence of the messages emitted by a single watcher. It is owned by the registry, which
+// stamps every message right before broadcasting it so the sequence follows the broadcast order.
+#[derive(Debug, Default)]
+pub(crate) struct MessageSequence {
+    next: u64,
+}
+
+impl MessageSequence {
+    pub(crate) fn stamp(&mut self, mut message: NotifierItemMessage) -> NotifierItemMessage {
+        *message.stamp_mut() = MessageStamp {
+            seq: self.next,
+            timestamp: SystemTime::now(),
+        };
+        self.next += 1;
+        message
+    }
+
+    // The sequence number of the latest stamped message, `None` before the first one
+    pub(crate) fn latest(&self) -> Option<u64> {
+        self.next.checked_sub(1)
+    }
+}
+
+/// The dbus address and object path of a [`StatusNotifierItem`], formatted as in
+/// `RegisteredStatusNotifierItems`, ex: `:1.52/StatusNotifierItem`. An application may export
+/// several items, each of them gets its own address.
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct ItemAddress(String);
+
+impl ItemAddress {
+    /// Returns an error if `address` is not a valid bus name, optionally followed by the item
+    /// object path. A bus name alone designates the item exported at `/StatusNotifierItem`.
+    pub fn new(address: impl Into<String>) -> Result<Self, StatusNotifierWatcherError> {
+        let address = NotifierAddress::from_notifier_service(&address.into())?;
+        Ok(ItemAddress::from_bus(&address))
+    }
+
+    // Addresses received from the bus are valid by construction
+    pub(crate) fn from_bus(address: &NotifierAddress) -> Self {
+        ItemAddress(address.to_string())
+    }
+
+    /// Returns the address as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the bus name owning the item, ex: `:1.52`.
+    pub fn destination(&self) -> &str {
+        self.split().0
+    }
+
+    /// Returns the object path of the item, ex: `/StatusNotifierItem`.
+    pub fn path(&self) -> &str {
+        self.split().1
+    }
+
+    pub(crate) fn notifier_address(&self) -> NotifierAddress {
+        let (destination, path) = self.split();
+        NotifierAddress {
+            destination: destination.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    // Addresses always carry an object path, see `ItemAddress::new`
+    fn split(&self) -> (&str, &str) {
+        let index = self.0.find('/').unwrap_or(self.0.len());
+        self.0.split_at(index)
+    }
+}
+
+/// The dbus object path of a [`crate::message::menu::TrayMenu`], ex: `/MenuBar`
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct MenuPath(String);
+
+impl MenuPath {
+    /// Returns an error if `path` is not a valid dbus object path.
+    pub fn new(path: impl Into<String>) -> Result<Self, StatusNotifierWatcherError> {
+        let path = path.into();
+        match ObjectPath::try_from(path.as_str()) {
+            Ok(_) => Ok(MenuPath(path)),
+            Err(_) => Err(StatusNotifierWatcherError::InvalidMenuPath(path)),
+        }
+    }
+
+    // Paths received from the bus are valid by construction
+    pub(crate) fn from_bus(path: impl Into<String>) -> Self {
+        MenuPath(path.into())
+    }
+
+    /// Returns the path as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+macro_rules! impl_string_newtype {
+    ($name:ident) => {
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = StatusNotifierWatcherError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = StatusNotifierWatcherError;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                $name::new(value)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = StatusNotifierWatcherError;
+
+            fn try_from(value: &str) -> Result<Self, Self::Error> {
+                $name::new(value)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+impl_string_newtype!(ItemAddress);
+impl_string_newtype!(MenuPath);
+
+/// How items are identified in [`NotifierItemMessage`], see [`crate::StatusNotifierWatcherBuilder::identity`]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum ItemIdentity {
+    /// Items are keyed by their dbus address, an application restart looks like a new item.
+    #[default]
+    Address,
+    /// Items are keyed by their `Id` and `WindowId` properties, which are stable across
+    /// application restarts.
+    Id,
+}
+
+/// Orientation of a [`NotifierItemCommand::Scroll`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ScrollOrientation {
+    /// Horizontal scroll
+    Horizontal,
+    /// Vertical scroll
+    Vertical,
+}
+
+impl ScrollOrientation {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ScrollOrientation::Horizontal => "horizontal",
+            ScrollOrientation::Vertical => "vertical",
+        }
+    }
+}
+
+/// How deviations from the StatusNotifierItem specification are handled,
+/// see [`crate::StatusNotifierWatcherBuilder::conformance`]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Conformance {
+    /// Non-conforming items are rejected, hosts receive a [`NotifierItemMessage::Rejected`]
+    /// with the detailed violations. This is meant for tray application developers.
+    Strict,
+    /// Deviations are papered over and logged as warnings.
+    #[default]
+    Warn,
+    /// Deviations are silently papered over.
+    Lenient,
+}
+
+/// What happens when the queue of a notifier host is full,
+/// see [`crate::StatusNotifierWatcherBuilder::host_queue`]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// The oldest queued message is dropped to make room for the new one.
+    #[default]
+    DropOldest,
+    /// The new message replaces the queued message about the same item, since only the latest
+    /// state of each item matters to the UI. Falls back to dropping the oldest message.
+    CoalescePerItem,
+    /// Wait for the host to receive a message, the watcher keeps up to `capacity` more messages in
+    /// the meantime. If the host stays blocked for too long, the messages missed once they are full
+    /// are reported with [`crate::error::StatusNotifierWatcherError::BroadCastRecvError`].
+    Block,
+}
+
+/// The bus the watcher, its hosts and the items live on,
+/// see [`crate::StatusNotifierWatcherBuilder::bus`]
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub enum Bus {
+    /// The session bus of the user, from `DBUS_SESSION_BUS_ADDRESS`.
+    #[default]
+    Session,
+    /// The system bus, for kiosk setups and greeters running without a user session.
+    System,
+    /// The bus at the given address, ex: `unix:path=/run/user/1000/bus`.
+    Address(String),
+}
+
+impl Bus {
+    pub(crate) fn connection_builder(&self) -> zbus::Result<ConnectionBuilder<'static>> {
+        match self {
+            Bus::Session => ConnectionBuilder::session(),
+            Bus::System => ConnectionBuilder::system(),
+            Bus::Address(address) => ConnectionBuilder::address(address.as_str()),
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> zbus::Result<Connection> {
+        self.connection_builder()?.build().await
+    }
+}
+
+/// The key of an item in [`NotifierItemMessage`]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq)]
+pub enum ItemKey {
+    /// The dbus address of the item
+    Address(ItemAddress),
+    /// The `Id` and `WindowId` properties of the item
+    Id {
+        /// The item `Id` property
+        id: String,
+        /// The item `WindowId` property
+        window_id: Option<i32>,
     },
 }
 
+impl ItemKey {
+    pub(crate) fn new(
+        identity: ItemIdentity,
+        address: &ItemAddress,
+        item: &StatusNotifierItem,
+    ) -> Self {
+        match identity {
+            ItemIdentity::Address => ItemKey::Address(address.clone()),
+            ItemIdentity::Id => ItemKey::Id {
+                id: item.id.clone(),
+                window_id: item.window_id,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ItemKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemKey::Address(address) => write!(f, "{address}"),
+            ItemKey::Id {
+                id,
+                window_id: Some(window_id),
+            } => write!(f, "{id}-{window_id}"),
+            ItemKey::Id {
+                id,
+                window_id: None,
+            } => write!(f, "{id}"),
+        }
+    }
+}
+
 /// Command to send to a [`StatusNotifierItem`]
 #[derive(Debug)]
 pub enum NotifierItemCommand {
@@ -38,8 +473,165 @@ pub enum NotifierItemCommand {
         /// Unique identifier of the item, see: [`crate::message::menu::MenuItem`]
         submenu_id: i32,
         /// DBus path of the menu item, (see: [`StatusNotifierItem`])
-        menu_path: String,
+        menu_path: MenuPath,
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+    },
+    /// Primary click on a tray icon, routed according to the item
+    /// [`crate::message::tray::ActivationPolicy`]. Nothing is sent on the bus for
+    /// [`crate::message::tray::ActivationPolicy::OpenMenu`] items, the host is expected to
+    /// display the menu it received with the last [`NotifierItemMessage::Update`].
+    ItemClicked {
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+        /// Horizontal screen coordinate of the click
+        x: i32,
+        /// Vertical screen coordinate of the click
+        y: i32,
+        /// An XDG activation token obtained by the host from the compositor, passed to the item
+        /// with `ProvideXdgActivationToken` so the application is allowed to raise its window on Wayland.
+        activation_token: Option<String>,
+    },
+    /// Call `org.kde.StatusNotifierItem.Activate` on the item, triggering the application primary action
+    /// regardless of its [`crate::message::tray::ActivationPolicy`].
+    Activate {
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+        /// Horizontal screen coordinate of the click
+        x: i32,
+        /// Vertical screen coordinate of the click
+        y: i32,
+        /// An XDG activation token obtained by the host from the compositor, passed to the item
+        /// with `ProvideXdgActivationToken` so the application is allowed to raise its window on Wayland.
+        activation_token: Option<String>,
+    },
+    /// Call `org.kde.StatusNotifierItem.Scroll` on the item, typically sent on mouse wheel events
+    /// over the tray icon.
+    Scroll {
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+        /// The amount of scroll
+        delta: i32,
+        /// The scroll direction
+        orientation: ScrollOrientation,
+    },
+    /// Set a property on an item, this is typically used with vendor properties such as
+    /// `XAyatanaLabel` for the few indicators accepting label updates from hosts.
+    /// The command fails with [`crate::error::StatusNotifierWatcherError::UnsupportedProperty`]
+    /// if the item does not declare the property as writable.
+    SetProperty {
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+        /// Name of the property, ex: `XAyatanaLabel`
+        name: String,
+        /// The new value of the property
+        value: OwnedValue,
+    },
+    /// Notify the application that a menu or submenu is displayed. Some applications
+    /// rebuild their menu entries when receiving this event.
+    MenuOpened {
+        /// Unique identifier of the opened menu, `0` being the root menu, see: [`crate::message::menu::MenuItem`]
+        submenu_id: i32,
+        /// DBus path of the menu item, (see: [`StatusNotifierItem`])
+        menu_path: MenuPath,
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+    },
+    /// Notify the application that a menu or submenu is no longer displayed.
+    MenuClosed {
+        /// Unique identifier of the closed menu, `0` being the root menu, see: [`crate::message::menu::MenuItem`]
+        submenu_id: i32,
+        /// DBus path of the menu item, (see: [`StatusNotifierItem`])
+        menu_path: MenuPath,
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+    },
+    /// Notify the application that the pointer is over a menu item.
+    MenuItemHovered {
+        /// Unique identifier of the item, see: [`crate::message::menu::MenuItem`]
+        submenu_id: i32,
+        /// DBus path of the menu item, (see: [`StatusNotifierItem`])
+        menu_path: MenuPath,
+        /// Dbus address of the [`StatusNotifierItem`]
+        notifier_address: ItemAddress,
+    },
+    /// Send an arbitrary `com.canonical.dbusmenu.Event`, for applications expecting
+    /// event ids or data the other menu commands don't provide.
+    MenuEvent {
+        /// Unique identifier of the item, see: [`crate::message::menu::MenuItem`]
+        submenu_id: i32,
+        /// DBus path of the menu item, (see: [`StatusNotifierItem`])
+        menu_path: MenuPath,
         /// Dbus address of the [`StatusNotifierItem`]
-        notifier_address: String,
+        notifier_address: ItemAddress,
+        /// The event type, ex: `clicked`, `hovered`, `opened` or `closed`
+        event_id: String,
+        /// Event specific data
+        data: OwnedValue,
     },
 }
+
+impl NotifierItemCommand {
+    // The dbus address of the item targeted by the command
+    pub(crate) fn notifier_address(&self) -> &ItemAddress {
+        match self {
+            NotifierItemCommand::MenuItemClicked {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::ItemClicked {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::Activate {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::Scroll {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::SetProperty {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::MenuOpened {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::MenuClosed {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::MenuItemHovered {
+                notifier_address, ..
+            }
+            | NotifierItemCommand::MenuEvent {
+                notifier_address, ..
+            } => notifier_address,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NotifierItemCommand::MenuItemClicked { .. } => "MenuItemClicked",
+            NotifierItemCommand::ItemClicked { .. } => "ItemClicked",
+            NotifierItemCommand::Activate { .. } => "Activate",
+            NotifierItemCommand::Scroll { .. } => "Scroll",
+            NotifierItemCommand::SetProperty { .. } => "SetProperty",
+            NotifierItemCommand::MenuOpened { .. } => "MenuOpened",
+            NotifierItemCommand::MenuClosed { .. } => "MenuClosed",
+            NotifierItemCommand::MenuItemHovered { .. } => "MenuItemHovered",
+            NotifierItemCommand::MenuEvent { .. } => "MenuEvent",
+        }
+    }
+
+    // The DBus method called on the item, `ItemClicked` may call `ContextMenu` instead
+    pub(crate) fn dbus_method(&self) -> &'static str {
+        match self {
+            NotifierItemCommand::MenuItemClicked { .. }
+            | NotifierItemCommand::MenuOpened { .. }
+            | NotifierItemCommand::MenuClosed { .. }
+            | NotifierItemCommand::MenuItemHovered { .. }
+            | NotifierItemCommand::MenuEvent { .. } => "Event",
+            NotifierItemCommand::ItemClicked { .. } | NotifierItemCommand::Activate { .. } => {
+                "Activate"
+            }
+            NotifierItemCommand::Scroll { .. } => "Scroll",
+            NotifierItemCommand::SetProperty { .. } => "Set",
+        }
+    }
+}
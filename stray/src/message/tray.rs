@@ -1,9 +1,14 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use zbus::zvariant::{Array, ObjectPath, OwnedValue, Structure};
+use zbus::zvariant::{Array, ObjectPath, OwnedValue, Structure, Value};
+
+use crate::message::MenuPath;
 
 type DBusProperties = HashMap<String, OwnedValue>;
 
@@ -17,7 +22,7 @@ struct PropsWrapper(DBusProperties);
 /// Note that this implementation is not feature complete. It only contains the minimal data
 /// needed to build a system tray and display tray menus. If you feel something important is
 /// should be added please reach out.
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatusNotifierItem {
     /// It's a name that should be unique for this application and consistent between sessions,
     /// such as the application name itself.
@@ -44,10 +49,76 @@ pub struct StatusNotifierItem {
     /// DBus path to an object which should implement the com.canonical.dbusmenu interface
     /// This can be used to retrieve the wigdet menu via gtk/qt libdbusmenu implementation
     /// Instead of building it from the raw data
-    pub menu: Option<String>,
+    pub menu: Option<MenuPath>,
+    /// The item only supports the context menu, the visualization should prefer showing the menu
+    /// instead of calling `Activate` on primary clicks. See [`StatusNotifierItem::activation_policy`].
+    pub item_is_menu: bool,
+    /// The windowing-system dependent identifier for a window, the application can choose one
+    /// of its windows to be available through this property or just set 0 if it's not interested.
+    pub window_id: Option<i32>,
+    /// Text displayed next to the icon by Ayatana hosts, from the `XAyatanaLabel` vendor property.
+    pub label: Option<String>,
+    /// The longest string the label is expected to hold, from the `XAyatanaLabelGuide` vendor property.
+    /// Hosts can use it to reserve space and avoid the tray moving around when the label changes.
+    pub label_guide: Option<String>,
+    /// Position hint of the item, from the `XAyatanaOrderingIndex` vendor property. See [`StatusNotifierItem::sort_key`].
+    pub ordering_index: Option<u32>,
+    /// The icon of the item written to disk, only set when the watcher materializes icons,
+    /// see [`crate::StatusNotifierWatcherBuilder::materialize_icons`].
+    #[serde(default)]
+    pub icon_file: Option<PathBuf>,
+}
+
+/// What a primary click on the tray icon should do, derived from the `ItemIsMenu`
+/// and `Menu` properties of the item.
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ActivationPolicy {
+    /// The item only supports a menu and exposes a dbusmenu, the host should open it.
+    OpenMenu,
+    /// The item supports activation, `Activate` should be called on the item.
+    Activate,
+    /// The item only supports a menu but does not expose any dbusmenu, `ContextMenu`
+    /// should be called so the application can display its own menu.
+    ContextMenu,
+}
+
+impl ActivationPolicy {
+    pub(crate) fn new(item_is_menu: bool, has_menu: bool) -> Self {
+        match (item_is_menu, has_menu) {
+            (false, _) => ActivationPolicy::Activate,
+            (true, true) => ActivationPolicy::OpenMenu,
+            (true, false) => ActivationPolicy::ContextMenu,
+        }
+    }
+}
+
+impl StatusNotifierItem {
+    /// Returns the [`ActivationPolicy`] of this item, this is what hosts should do when
+    /// the tray icon is clicked.
+    pub fn activation_policy(&self) -> ActivationPolicy {
+        ActivationPolicy::new(self.item_is_menu, self.menu.is_some())
+    }
+
+    /// Returns a key to display items in a stable order: by [`Category`], then by
+    /// `XAyatanaOrderingIndex`, items without index coming last, and finally by `Id`.
+    pub fn sort_key(&self) -> SortKey {
+        SortKey {
+            category: self.category,
+            ordering_index: self.ordering_index.unwrap_or(u32::MAX),
+            id: self.id.clone(),
+        }
+    }
+}
+
+/// The ordering key of an item, see [`StatusNotifierItem::sort_key`].
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct SortKey {
+    category: Category,
+    ordering_index: u32,
+    id: String,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub enum Status {
     /// The item doesn't convey important information to the user, it can be considered an
@@ -55,6 +126,20 @@ pub enum Status {
     Passive,
     /// The item is active, is more important that the item will be shown in some way to the user.
     Active,
+    /// The item carries really important information for the user, such as battery charge running out
+    /// and is wants to incentive the direct user intervention. Visualizations should emphasize in some
+    /// way the items with NeedsAttention status.
+    NeedsAttention,
+}
+
+impl Status {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Status::Passive => "Passive",
+            Status::Active => "Active",
+            Status::NeedsAttention => "NeedsAttention",
+        }
+    }
 }
 
 impl FromStr for Status {
@@ -62,8 +147,9 @@ impl FromStr for Status {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "Passive" => Ok(Status::Active),
-            "Active" => Ok(Status::Passive),
+            "Passive" => Ok(Status::Passive),
+            "Active" => Ok(Status::Active),
+            "NeedsAttention" => Ok(Status::NeedsAttention),
             other => Err(anyhow!(
                 "Unknown 'Status' for status notifier item {}",
                 other
@@ -72,8 +158,9 @@ impl FromStr for Status {
     }
 }
 
-/// Describes the category of this item.
-#[derive(Serialize, Debug, Clone)]
+/// Describes the category of this item. Categories are ordered in the order they are declared,
+/// see [`StatusNotifierItem::sort_key`].
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 #[serde(rename_all = "PascalCase")]
 pub enum Category {
     /// The item describes the status of a generic application, for instance the current state
@@ -92,6 +179,17 @@ pub enum Category {
     Hardware,
 }
 
+impl Category {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Category::ApplicationStatus => "ApplicationStatus",
+            Category::Communications => "Communications",
+            Category::SystemServices => "SystemServices",
+            Category::Hardware => "Hardware",
+        }
+    }
+}
+
 impl FromStr for Category {
     type Err = anyhow::Error;
 
@@ -102,7 +200,7 @@ impl FromStr for Category {
             "SystemServices" => Ok(Category::SystemServices),
             "Hardware" => Ok(Category::Hardware),
             other => Err(anyhow!(
-                "Unknown 'Status' for status notifier item {}",
+                "Unknown 'Category' for status notifier item {}",
                 other
             )),
         }
@@ -113,52 +211,414 @@ impl FromStr for Category {
 pub struct IconPixmap {
     pub width: i32,
     pub height: i32,
-    pub pixels: Vec<u8>,
+    /// ARGB32 pixels in network byte order, shared between the clones of the pixmap
+    pub pixels: Arc<[u8]>,
 }
 
 impl IconPixmap {
-    fn from_array(a: &Array<'_>) -> Option<Vec<Self>> {
+    /// The number of bytes `pixels` should hold for the declared size,
+    /// `None` if the size is negative or overflows.
+    pub fn byte_len(&self) -> Option<usize> {
+        let width = usize::try_from(self.width).ok()?;
+        let height = usize::try_from(self.height).ok()?;
+        width.checked_mul(height)?.checked_mul(4)
+    }
+
+    /// Whether the pixels match the declared size of the pixmap.
+    pub fn is_valid(&self) -> bool {
+        self.byte_len() == Some(self.pixels.len())
+    }
+
+    /// Scale down the pixmap so that neither its width nor its height exceed `max_size`,
+    /// preserving the aspect ratio. Pixmaps already fitting are returned unchanged.
+    /// Returns `None` if the pixels don't match the size of the pixmap.
+    #[cfg(feature = "resize")]
+    pub fn downscale(&self, max_size: u32) -> Option<IconPixmap> {
+        if !self.is_valid() {
+            return None;
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        if self.fits(max_size) || width == 0 || height == 0 {
+            return Some(self.clone());
+        }
+
+        let max_size = max_size as usize;
+        let scale = max_size as f64 / width.max(height) as f64;
+        let new_width = ((width as f64 * scale).round() as usize).clamp(1, max_size);
+        let new_height = ((height as f64 * scale).round() as usize).clamp(1, max_size);
+        let mut pixels = Vec::with_capacity(new_width * new_height * 4);
+
+        // Nearest neighbour sampling, good enough for tray icons
+        for y in 0..new_height {
+            let source_y = y * height / new_height;
+            for x in 0..new_width {
+                let source_x = x * width / new_width;
+                let index = (source_y * width + source_x) * 4;
+                pixels.extend_from_slice(&self.pixels[index..index + 4]);
+            }
+        }
+
+        Some(IconPixmap {
+            width: new_width as i32,
+            height: new_height as i32,
+            pixels: pixels.into(),
+        })
+    }
+
+    /// Convert the pixels to RGBA with straight alpha, the layout expected by most toolkits
+    /// (`gdk_pixbuf::Pixbuf`, `iced::widget::image::Handle::from_pixels`, `wgpu::TextureFormat::Rgba8Unorm`).
+    /// Returns an empty buffer if the pixels don't match the size of the pixmap.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.convert(|[a, r, g, b]| [r, g, b, a])
+    }
+
+    /// Convert the pixels to RGBA with premultiplied alpha, for renderers blending
+    /// premultiplied textures (ex: `wgpu` with `CompositeAlphaMode::PreMultiplied`).
+    /// Returns an empty buffer if the pixels don't match the size of the pixmap.
+    pub fn to_rgba_premultiplied(&self) -> Vec<u8> {
+        let premultiply =
+            |channel: u8, alpha: u8| ((channel as u16 * alpha as u16 + 127) / 255) as u8;
+
+        self.convert(|[a, r, g, b]| [premultiply(r, a), premultiply(g, a), premultiply(b, a), a])
+    }
+
+    // Map each ARGB32 pixel, the network byte order makes the layout independent of the host endianness
+    // The size is set by the item, it is never trusted to size the output buffer
+    fn convert(&self, pixel: impl Fn([u8; 4]) -> [u8; 4]) -> Vec<u8> {
+        if !self.is_valid() {
+            return vec![];
+        }
+
+        let mut rgba = Vec::with_capacity(self.pixels.len());
+        for argb in self.pixels.chunks_exact(4) {
+            rgba.extend_from_slice(&pixel([argb[0], argb[1], argb[2], argb[3]]));
+        }
+
+        rgba
+    }
+
+    /// Pick the pixmap to display at `target` pixels: the smallest one at least as large as the
+    /// target, so it is only ever scaled down, otherwise the largest one available.
+    ///
+    /// ```rust, no_run
+    /// # use stray::message::tray::{IconPixmap, StatusNotifierItem};
+    /// # fn example(item: StatusNotifierItem) {
+    /// let icon = item.icon_pixmap.as_deref().and_then(|pixmaps| IconPixmap::select_best(pixmaps, 24));
+    /// # }
+    /// ```
+    pub fn select_best(pixmaps: &[IconPixmap], target: u32) -> Option<&IconPixmap> {
+        let size = |pixmap: &IconPixmap| pixmap.width.max(pixmap.height).max(0) as u32;
+        let (large_enough, smaller): (Vec<_>, Vec<_>) = pixmaps
+            .iter()
+            .filter(|pixmap| size(pixmap) > 0)
+            .partition(|pixmap| size(pixmap) >= target);
+
+        large_enough
+            .into_iter()
+            .min_by_key(|pixmap| size(pixmap))
+            .or_else(|| smaller.into_iter().max_by_key(|pixmap| size(pixmap)))
+    }
+
+    /// Encode the pixmap as a PNG image, for consumers which can only display image files (ex: eww).
+    #[cfg(feature = "png")]
+    pub fn to_png_bytes(&self) -> crate::error::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.encode_png(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Encode the pixmap as a PNG image and write it to `path`, replacing any existing file.
+    #[cfg(feature = "png")]
+    pub fn write_png(&self, path: impl AsRef<std::path::Path>) -> crate::error::Result<()> {
+        let file = std::fs::File::create(path)
+            .map_err(crate::error::StatusNotifierWatcherError::IconFileError)?;
+        self.encode_png(std::io::BufWriter::new(file))
+    }
+
+    #[cfg(feature = "png")]
+    fn encode_png(&self, writer: impl std::io::Write) -> crate::error::Result<()> {
+        if !self.is_valid() {
+            return Err(crate::error::StatusNotifierWatcherError::InvalidPixmap(
+                format!(
+                    "{}x{} pixmap with {} bytes",
+                    self.width,
+                    self.height,
+                    self.pixels.len()
+                ),
+            ));
+        }
+
+        let mut encoder =
+            png::Encoder::new(writer, self.width.max(0) as u32, self.height.max(0) as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_rgba())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Pick the pixmap to display at `size` logical pixels on an output with the given `scale`
+    /// factor, see [`IconPixmap::select_best`]. On a 2x display a 24px icon selects a 48px pixmap.
+    pub fn select_best_scaled(
+        pixmaps: &[IconPixmap],
+        size: u32,
+        scale: u32,
+    ) -> Option<&IconPixmap> {
+        IconPixmap::select_best(pixmaps, size.saturating_mul(scale.max(1)))
+    }
+
+    /// Returns true if neither the width nor the height of this pixmap exceed `max_size`.
+    pub fn fits(&self, max_size: u32) -> bool {
+        self.width.max(0) as u32 <= max_size && self.height.max(0) as u32 <= max_size
+    }
+
+    // Keep the single pixmap closest to `max_size`: the largest one fitting, otherwise the smallest one
+    // downscaled when the `resize` feature is enabled. The other sizes are dropped to bound the item memory.
+    pub(crate) fn cap_size(pixmaps: Vec<IconPixmap>, max_size: u32) -> Vec<IconPixmap> {
+        let area = |pixmap: &IconPixmap| pixmap.width.max(0) as i64 * pixmap.height.max(0) as i64;
+        #[cfg_attr(not(feature = "resize"), allow(unused_variables))]
+        let (fitting, oversized): (Vec<_>, Vec<_>) = pixmaps
+            .into_iter()
+            .partition(|pixmap| pixmap.fits(max_size));
+
+        if let Some(closest) = fitting.into_iter().max_by_key(area) {
+            return vec![closest];
+        }
+
+        #[cfg(feature = "resize")]
+        let capped = oversized
+            .into_iter()
+            .min_by_key(area)
+            .and_then(|pixmap| pixmap.downscale(max_size));
+        #[cfg(not(feature = "resize"))]
+        let capped: Option<IconPixmap> = None;
+
+        capped.into_iter().collect()
+    }
+
+    // Returns `None` if the array is not a valid `a(iiay)` pixmap list
+    pub(crate) fn from_array(a: &Array<'_>) -> Option<Vec<Self>> {
         let mut pixmaps = vec![];
 
-        a.iter().for_each(|b| {
-            let s = b.downcast_ref::<Structure>();
-            let fields = s.unwrap().fields();
-            let width = fields[0].downcast_ref::<i32>().unwrap();
-            let height = fields[1].downcast_ref::<i32>().unwrap();
-            let pixel_values = fields[2].downcast_ref::<Array>().unwrap().get();
-            let mut pixels = vec![];
-            pixel_values.iter().for_each(|p| {
-                pixels.push(*p.downcast_ref::<u8>().unwrap());
-            });
+        for b in a.iter() {
+            let fields = b.downcast_ref::<Structure>()?.fields();
+            let width = fields.first()?.downcast_ref::<i32>()?;
+            let height = fields.get(1)?.downcast_ref::<i32>()?;
+            let pixels = pixel_bytes(fields.get(2)?.downcast_ref::<Array>()?)?;
+
             pixmaps.push(IconPixmap {
                 width: *width,
                 height: *height,
                 pixels,
             })
-        });
+        }
 
         Some(pixmaps)
     }
 }
 
+// Copy the bytes of an `ay` array, its element type is checked once for the whole array
+fn pixel_bytes(array: &Array<'_>) -> Option<Arc<[u8]>> {
+    if array.element_signature().as_str() != "y" {
+        return None;
+    }
+
+    let mut pixels = Vec::with_capacity(array.len());
+    for value in array.get() {
+        let Value::U8(byte) = value else {
+            return None;
+        };
+        pixels.push(*byte);
+    }
+
+    Some(pixels.into())
+}
+
+#[cfg(feature = "image")]
+impl TryFrom<&IconPixmap> for image::RgbaImage {
+    type Error = crate::error::StatusNotifierWatcherError;
+
+    /// Fails if the pixels don't match the size of the pixmap.
+    fn try_from(pixmap: &IconPixmap) -> crate::error::Result<Self> {
+        let invalid = || {
+            crate::error::StatusNotifierWatcherError::InvalidPixmap(format!(
+                "{}x{} pixmap with {} bytes",
+                pixmap.width,
+                pixmap.height,
+                pixmap.pixels.len()
+            ))
+        };
+
+        let width = u32::try_from(pixmap.width).map_err(|_| invalid())?;
+        let height = u32::try_from(pixmap.height).map_err(|_| invalid())?;
+        if !pixmap.is_valid() {
+            return Err(invalid());
+        }
+
+        image::RgbaImage::from_raw(width, height, pixmap.to_rgba()).ok_or_else(invalid)
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<&image::RgbaImage> for IconPixmap {
+    fn from(image: &image::RgbaImage) -> Self {
+        let pixels = image
+            .pixels()
+            .flat_map(|pixel| {
+                let [r, g, b, a] = pixel.0;
+                [a, r, g, b]
+            })
+            .collect();
+
+        IconPixmap {
+            width: image.width() as i32,
+            height: image.height() as i32,
+            pixels,
+        }
+    }
+}
+
+/// A deviation from the StatusNotifierItem specification found while parsing an item,
+/// see [`crate::message::Conformance`].
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SpecViolation {
+    /// The offending property
+    pub property: String,
+    /// What is wrong with it
+    pub description: String,
+}
+
+impl SpecViolation {
+    fn new(property: &str, description: impl ToString) -> Self {
+        SpecViolation {
+            property: property.to_string(),
+            description: description.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SpecViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.property, self.description)
+    }
+}
+
+// Every property read by `StatusNotifierItem::parse`, used when the properties are fetched one by one
+pub(crate) const ITEM_PROPERTIES: &[&str] = &[
+    "Id",
+    "Category",
+    "Status",
+    "Title",
+    "IconName",
+    "IconAccessibleDesc",
+    "AttentionIconName",
+    "IconThemePath",
+    "IconPixmap",
+    "Menu",
+    "ItemIsMenu",
+    "WindowId",
+    "XAyatanaLabel",
+    "XAyatanaLabelGuide",
+    "XAyatanaOrderingIndex",
+];
+
+// The dbus signature of the properties defined by the specification
+const PROPERTY_SIGNATURES: &[(&str, &str)] = &[
+    ("Id", "s"),
+    ("Category", "s"),
+    ("Status", "s"),
+    ("Title", "s"),
+    ("IconName", "s"),
+    ("IconAccessibleDesc", "s"),
+    ("AttentionIconName", "s"),
+    ("IconThemePath", "s"),
+    ("IconPixmap", "a(iiay)"),
+    ("Menu", "o"),
+    ("ItemIsMenu", "b"),
+    ("WindowId", "i"),
+];
+
+impl StatusNotifierItem {
+    // Parse the item properties, deviations from the spec are papered over and reported instead
+    // of failing. Only a missing `Id` is fatal since the item can't be identified without it.
+    pub(crate) fn parse(props: DBusProperties) -> anyhow::Result<(Self, Vec<SpecViolation>)> {
+        let props = PropsWrapper(props);
+        let id = props
+            .get_string("Id")
+            .ok_or_else(|| anyhow!("StatusNotifier item should have an id"))?;
+
+        let mut violations: Vec<SpecViolation> = PROPERTY_SIGNATURES
+            .iter()
+            .filter_map(|(name, expected)| {
+                let signature = props.0.get(*name)?.value_signature().to_string();
+                (signature != *expected).then(|| {
+                    SpecViolation::new(
+                        name,
+                        format!("expected type '{expected}', got '{signature}'"),
+                    )
+                })
+            })
+            .collect();
+
+        let category = props.get_category().unwrap_or_else(|err| {
+            violations.push(SpecViolation::new("Category", err));
+            Category::ApplicationStatus
+        });
+
+        let status = props.get_status().unwrap_or_else(|err| {
+            violations.push(SpecViolation::new("Status", err));
+            Status::Active
+        });
+
+        let icon_pixmap = props.get_icon_pixmap();
+        if icon_pixmap.is_none() && props.0.contains_key("IconPixmap") {
+            violations.push(SpecViolation::new("IconPixmap", "malformed pixmap"));
+        }
+
+        let icon_name = props.get_string("IconName").filter(|name| !name.is_empty());
+        if icon_name.is_none() && icon_pixmap.as_ref().map_or(true, Vec::is_empty) {
+            violations.push(SpecViolation::new(
+                "IconName",
+                "neither IconName nor IconPixmap is set",
+            ));
+        }
+
+        let item = StatusNotifierItem {
+            id,
+            title: props.get_string("Title"),
+            category,
+            icon_name,
+            status,
+            icon_accessible_desc: props.get_string("IconAccessibleDesc"),
+            attention_icon_name: props.get_string("AttentionIconName"),
+            icon_theme_path: props.get_string("IconThemePath"),
+            icon_pixmap,
+            menu: props.get_object_path("Menu").map(MenuPath::from_bus),
+            item_is_menu: props.get_bool("ItemIsMenu").unwrap_or(false),
+            window_id: props.get_i32("WindowId"),
+            label: props.get_string("XAyatanaLabel"),
+            label_guide: props.get_string("XAyatanaLabelGuide"),
+            ordering_index: props.get_u32("XAyatanaOrderingIndex"),
+            icon_file: None,
+        };
+
+        Ok((item, violations))
+    }
+}
+
+/// Strict conversion, any deviation from the specification is an error.
 impl TryFrom<DBusProperties> for StatusNotifierItem {
     type Error = anyhow::Error;
     fn try_from(props: HashMap<String, OwnedValue>) -> anyhow::Result<Self> {
-        let props = PropsWrapper(props);
-        match props.get_string("Id") {
-            None => Err(anyhow!("StatusNotifier item should have an id")),
-            Some(id) => Ok(StatusNotifierItem {
-                id,
-                title: props.get_string("Title"),
-                category: props.get_category()?,
-                icon_name: props.get_string("IconName"),
-                status: props.get_status()?,
-                icon_accessible_desc: props.get_string("IconAccessibleDesc"),
-                attention_icon_name: props.get_string("AttentionIconName"),
-                icon_theme_path: props.get_string("IconThemePath"),
-                icon_pixmap: props.get_icon_pixmap(),
-                menu: props.get_object_path("Menu"),
-            }),
+        let (item, violations) = StatusNotifierItem::parse(props)?;
+        match violations.first() {
+            Some(violation) => Err(anyhow!("Invalid StatusNotifierItem, {violation}")),
+            None => Ok(item),
         }
     }
 }
@@ -170,6 +630,24 @@ impl PropsWrapper {
             .and_then(|value| value.downcast_ref::<str>().map(|value| value.to_string()))
     }
 
+    fn get_bool(&self, key: &str) -> Option<bool> {
+        self.0
+            .get(key)
+            .and_then(|value| value.downcast_ref::<bool>().copied())
+    }
+
+    fn get_i32(&self, key: &str) -> Option<i32> {
+        self.0
+            .get(key)
+            .and_then(|value| value.downcast_ref::<i32>().copied())
+    }
+
+    fn get_u32(&self, key: &str) -> Option<u32> {
+        self.0
+            .get(key)
+            .and_then(|value| value.downcast_ref::<u32>().copied())
+    }
+
     fn get_object_path(&self, key: &str) -> Option<String> {
         self.0.get(key).and_then(|value| {
             value
@@ -199,3 +677,189 @@ impl PropsWrapper {
             .unwrap_or(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_pixmaps_from_arrays() {
+        let pixmaps = vec![(2i32, 1i32, vec![0xffu8, 1, 2, 3, 0x80, 4, 5, 6])];
+        let value = Value::from(pixmaps);
+        let array = value.downcast_ref::<Array>().unwrap();
+
+        let pixmaps = IconPixmap::from_array(array).unwrap();
+        assert_eq!(pixmaps.len(), 1);
+        assert_eq!((pixmaps[0].width, pixmaps[0].height), (2, 1));
+        assert_eq!(&pixmaps[0].pixels[..], &[0xff, 1, 2, 3, 0x80, 4, 5, 6]);
+    }
+
+    #[cfg(feature = "resize")]
+    #[test]
+    fn should_downscale_pixmaps() {
+        let pixels: Vec<u8> = (0..4 * 2 * 4).collect();
+        let pixmap = IconPixmap {
+            width: 4,
+            height: 2,
+            pixels: pixels.into(),
+        };
+
+        let downscaled = pixmap.downscale(2).unwrap();
+        assert_eq!((downscaled.width, downscaled.height), (2, 1));
+        assert_eq!(&downscaled.pixels[..], &[0, 1, 2, 3, 8, 9, 10, 11]);
+    }
+
+    #[cfg(feature = "resize")]
+    #[test]
+    fn should_not_downscale_truncated_pixmaps() {
+        let truncated = IconPixmap {
+            width: 4,
+            height: 4,
+            pixels: vec![0; 4 * 4 * 4 - 1].into(),
+        };
+        let oversized = IconPixmap {
+            width: i32::MAX,
+            height: i32::MAX,
+            pixels: vec![0; 4].into(),
+        };
+
+        assert!(truncated.downscale(2).is_none());
+        assert!(oversized.downscale(2).is_none());
+        assert!(IconPixmap::cap_size(vec![truncated], 2).is_empty());
+    }
+
+    #[test]
+    fn should_convert_argb_to_rgba() {
+        let pixmap = IconPixmap {
+            width: 3,
+            height: 1,
+            pixels: vec![0x80, 0xff, 0x40, 0x01, 0xff, 1, 2, 3, 0, 10, 20, 30].into(),
+        };
+
+        assert_eq!(
+            pixmap.to_rgba(),
+            vec![0xff, 0x40, 0x01, 0x80, 1, 2, 3, 0xff, 10, 20, 30, 0]
+        );
+
+        // Channels are rounded to the nearest value: 0x40 * 0x80 / 0xff = 32.13, 1 * 0x80 / 0xff = 0.5
+        assert_eq!(
+            pixmap.to_rgba_premultiplied(),
+            vec![0x80, 32, 1, 0x80, 1, 2, 3, 0xff, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn should_not_convert_pixmaps_not_matching_their_size() {
+        let truncated = IconPixmap {
+            width: 2,
+            height: 1,
+            pixels: vec![0xff, 1, 2, 3, 0xff].into(),
+        };
+        let oversized = IconPixmap {
+            width: 30000,
+            height: 30000,
+            pixels: vec![0xff, 1, 2, 3].into(),
+        };
+        let overflowing = IconPixmap {
+            width: i32::MAX,
+            height: i32::MAX,
+            pixels: vec![0xff, 1, 2, 3].into(),
+        };
+
+        for pixmap in [truncated, oversized, overflowing] {
+            assert!(!pixmap.is_valid());
+            assert!(pixmap.to_rgba().is_empty());
+            assert!(pixmap.to_rgba_premultiplied().is_empty());
+            #[cfg(feature = "png")]
+            assert!(pixmap.to_png_bytes().is_err());
+        }
+    }
+
+    fn pixmap(size: i32) -> IconPixmap {
+        IconPixmap {
+            width: size,
+            height: size,
+            pixels: vec![0; (size * size * 4) as usize].into(),
+        }
+    }
+
+    fn selected(pixmap: Option<&IconPixmap>) -> Option<i32> {
+        pixmap.map(|pixmap| pixmap.width)
+    }
+
+    #[test]
+    fn should_select_the_best_pixmap() {
+        let pixmaps = vec![pixmap(64), pixmap(16), pixmap(32), pixmap(0)];
+
+        // Exact match
+        assert_eq!(selected(IconPixmap::select_best(&pixmaps, 32)), Some(32));
+        // Next larger one, scaled down
+        assert_eq!(selected(IconPixmap::select_best(&pixmaps, 24)), Some(32));
+        assert_eq!(selected(IconPixmap::select_best(&pixmaps, 8)), Some(16));
+        // Fallback to the largest one
+        assert_eq!(selected(IconPixmap::select_best(&pixmaps, 128)), Some(64));
+        assert!(IconPixmap::select_best(&[], 24).is_none());
+        assert!(IconPixmap::select_best(&[pixmap(0)], 24).is_none());
+    }
+
+    #[test]
+    fn should_select_the_best_scaled_pixmap() {
+        let pixmaps = vec![pixmap(24), pixmap(48), pixmap(96)];
+
+        assert_eq!(
+            selected(IconPixmap::select_best_scaled(&pixmaps, 24, 2)),
+            Some(48)
+        );
+        assert_eq!(
+            selected(IconPixmap::select_best_scaled(&pixmaps, 24, 0)),
+            Some(24)
+        );
+        assert_eq!(
+            selected(IconPixmap::select_best_scaled(&pixmaps, 64, 3)),
+            Some(96)
+        );
+        assert!(IconPixmap::select_best_scaled(&[], 24, 2).is_none());
+    }
+
+    #[test]
+    fn should_cap_the_pixmap_size() {
+        let capped = IconPixmap::cap_size(vec![pixmap(16), pixmap(64), pixmap(32)], 32);
+        assert_eq!(capped.iter().map(|p| p.width).collect::<Vec<_>>(), vec![32]);
+
+        let capped = IconPixmap::cap_size(vec![pixmap(128), pixmap(64)], 32);
+        #[cfg(feature = "resize")]
+        assert_eq!(capped.iter().map(|p| p.width).collect::<Vec<_>>(), vec![32]);
+        #[cfg(not(feature = "resize"))]
+        assert!(capped.is_empty());
+    }
+
+    #[test]
+    fn should_reject_pixels_which_are_not_bytes() {
+        let pixmaps = vec![(1i32, 1i32, vec![0xffi32, 1, 2, 3])];
+        let value = Value::from(pixmaps);
+        let array = value.downcast_ref::<Array>().unwrap();
+
+        assert!(IconPixmap::from_array(array).is_none());
+    }
+
+    #[test]
+    fn should_sort_by_category_then_index_then_id() {
+        let item = |id: &str, category, ordering_index| StatusNotifierItem {
+            category,
+            ordering_index,
+            ..crate::notifier_watcher::registry::tests::item(id)
+        };
+        let mut items = [
+            item("c", Category::Hardware, Some(0)),
+            item("b", Category::ApplicationStatus, None),
+            item("a", Category::ApplicationStatus, None),
+            item("d", Category::ApplicationStatus, Some(2)),
+            item("e", Category::ApplicationStatus, Some(1)),
+            item("f", Category::Communications, None),
+        ];
+
+        items.sort_by_key(StatusNotifierItem::sort_key);
+        let ids: Vec<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, vec!["e", "d", "a", "b", "f", "c"]);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::Result;
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::watcher_proxy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use zbus::Connection;
+
+// The connections of the live notifier hosts, keyed by their well-known name.
+// They are bound to the bus, `session::supervise` reconnects them when the bus restarts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostConnections(Arc<Mutex<HashMap<String, watch::Sender<Connection>>>>);
+
+impl HostConnections {
+    // Connect a new host, it stays registered until the returned connection is dropped
+    pub(crate) async fn connect(
+        &self,
+        config: &WatcherConfig,
+        name: &str,
+    ) -> Result<HostConnection> {
+        let conn = connect_host(config, name).await?;
+        let (sender, receiver) = watch::channel(conn);
+        self.lock().insert(name.to_string(), sender);
+        Ok(HostConnection {
+            name: name.to_string(),
+            hosts: self.clone(),
+            conn: receiver,
+        })
+    }
+
+    // Claim the name of every live host on the new bus and register them again to the watcher,
+    // the hosts of a dead connection are unknown to the new watcher
+    pub(crate) async fn reconnect(&self, config: &WatcherConfig) {
+        let names: Vec<String> = {
+            let mut hosts = self.lock();
+            hosts.retain(|_, sender| !sender.is_closed());
+            hosts.keys().cloned().collect()
+        };
+
+        for name in names {
+            match connect_host(config, &name).await {
+                Ok(conn) => {
+                    if let Some(sender) = self.lock().get(&name) {
+                        let _ = sender.send_replace(conn);
+                    }
+                }
+                Err(err) => tracing::warn!("Failed to register host {name} again: {err}"),
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, watch::Sender<Connection>>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+// The connection of a single host. Dropping it removes the host from `HostConnections`,
+// which closes the connection and releases the host name on the bus.
+#[derive(Debug)]
+pub(crate) struct HostConnection {
+    name: String,
+    hosts: HostConnections,
+    conn: watch::Receiver<Connection>,
+}
+
+impl HostConnection {
+    pub(crate) fn get(&self) -> Connection {
+        self.conn.borrow().clone()
+    }
+
+    // A connection left out of `HostConnections`, it is not reconnected when the bus restarts
+    #[cfg(test)]
+    pub(crate) fn detached(conn: Connection) -> Self {
+        HostConnection {
+            name: String::new(),
+            hosts: HostConnections::default(),
+            conn: watch::channel(conn).1,
+        }
+    }
+}
+
+impl Drop for HostConnection {
+    fn drop(&mut self) {
+        self.hosts.lock().remove(&self.name);
+    }
+}
+
+// Claim the well-known name of a host on a dedicated connection and register it to the watcher
+async fn connect_host(config: &WatcherConfig, name: &str) -> Result<Connection> {
+    let conn = config.bus.connection_builder()?.name(name)?.build().await?;
+
+    watcher_proxy(&conn, &config.watcher_name)
+        .await?
+        .register_status_notifier_host(name)
+        .await?;
+
+    Ok(conn)
+}
@@ -0,0 +1,139 @@
+use crate::message::tray::{Category, Status};
+use crate::StatusNotifierItem;
+use std::fmt;
+use std::sync::Arc;
+
+/// Select the items a notifier host receives, see [`crate::StatusNotifierWatcher::create_filtered_notifier_host`].
+///
+/// ```rust
+/// # use stray::ItemFilter;
+/// # use stray::message::tray::Category;
+/// // Hardware indicators in one widget, everything else in another
+/// let hardware = ItemFilter::category(Category::Hardware);
+/// let apps = ItemFilter::custom(|item| item.category != Category::Hardware);
+/// ```
+#[derive(Clone)]
+pub struct ItemFilter(Arc<dyn Fn(&StatusNotifierItem) -> bool + Send + Sync>);
+
+impl ItemFilter {
+    /// Only keep the items of the given [`Category`].
+    pub fn category(category: Category) -> Self {
+        Self::custom(move |item| item.category == category)
+    }
+
+    /// Only keep the items with the given [`Status`].
+    pub fn status(status: Status) -> Self {
+        Self::custom(move |item| item.status == status)
+    }
+
+    /// Only keep the items whose `Id` matches `pattern`, where `*` matches any sequence of characters
+    /// and `?` any single character.
+    pub fn id(pattern: impl Into<String>) -> Self {
+        let pattern = pattern.into();
+        Self::custom(move |item| glob_match(&pattern, &item.id))
+    }
+
+    /// Only keep the items matching an arbitrary predicate.
+    pub fn custom(predicate: impl Fn(&StatusNotifierItem) -> bool + Send + Sync + 'static) -> Self {
+        ItemFilter(Arc::new(predicate))
+    }
+
+    /// Only keep the items matching both filters.
+    pub fn and(self, other: ItemFilter) -> Self {
+        Self::custom(move |item| self.matches(item) && other.matches(item))
+    }
+
+    /// Keep the items matching any of the two filters.
+    pub fn or(self, other: ItemFilter) -> Self {
+        Self::custom(move |item| self.matches(item) || other.matches(item))
+    }
+
+    /// Returns true if `item` passes the filter.
+    pub fn matches(&self, item: &StatusNotifierItem) -> bool {
+        (self.0)(item)
+    }
+}
+
+impl fmt::Debug for ItemFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ItemFilter")
+    }
+}
+
+// Match `text` against `pattern`, `*` matches any sequence of characters and `?` a single one.
+// On mismatch, the latest `*` is retried on one more character of `text`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_any_sequence_with_star() {
+        assert!(glob_match("nm-*-applet", "nm-network-applet"));
+        assert!(glob_match("nm-*-applet", "nm--applet"));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn should_match_a_single_character_with_question_mark() {
+        assert!(glob_match("chrome_status_icon_?", "chrome_status_icon_1"));
+        assert!(glob_match("??", "ab"));
+        assert!(!glob_match("??", "a"));
+        assert!(!glob_match("??", "abc"));
+        assert!(glob_match("é?", "éà"));
+    }
+
+    #[test]
+    fn should_match_leading_and_trailing_star() {
+        assert!(glob_match("*-applet", "nm-applet"));
+        assert!(glob_match("nm-*", "nm-applet"));
+        assert!(glob_match("*applet*", "nm-applet-1"));
+        assert!(!glob_match("*-applet", "nm-applet-1"));
+        assert!(!glob_match("nm-*", "blueman"));
+    }
+
+    #[test]
+    fn should_only_match_empty_text_with_empty_pattern() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "nm-applet"));
+    }
+
+    #[test]
+    fn should_not_match_other_ids() {
+        assert!(glob_match("nm-applet", "nm-applet"));
+        assert!(!glob_match("nm-applet", "nm-applet2"));
+        assert!(!glob_match("nm-applet", "blueman"));
+        assert!(!glob_match("a*b*c", "a-b-b"));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::ItemAddress;
+use crate::notifier_host::NotifierHost;
+use crate::NotifierItemMessage;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+
+/// A [`Stream`] of the messages concerning a single item, see [`NotifierHost::watch_item`].
+/// The stream ends after the item removal.
+#[derive(Debug)]
+pub struct ItemStream {
+    address: ItemAddress,
+    messages: BroadcastStream<NotifierItemMessage>,
+    removed: bool,
+}
+
+impl NotifierHost {
+    /// Returns a stream of the messages concerning the item at `address` only, starting with the
+    /// next update of the item. The messages received with [`NotifierHost::recv`] are not affected.
+    pub fn watch_item(&self, address: &ItemAddress) -> ItemStream {
+        ItemStream {
+            address: address.clone(),
+            messages: BroadcastStream::new(self.tx.subscribe()),
+            removed: false,
+        }
+    }
+}
+
+impl Stream for ItemStream {
+    type Item = Result<NotifierItemMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.removed {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            let message = match ready!(Pin::new(&mut self.messages).poll_next(cx)) {
+                None => return Poll::Ready(None),
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    return Poll::Ready(Some(Err(StatusNotifierWatcherError::BroadCastRecvError(
+                        broadcast::error::RecvError::Lagged(skipped),
+                    ))));
+                }
+                Some(Ok(message)) => message,
+            };
+
+            // A reset removes every item
+            let reset = matches!(message, NotifierItemMessage::Reset { .. });
+            if !reset && message.address() != Some(&self.address) {
+                continue;
+            }
+
+            if reset
+                || matches!(
+                    message,
+                    NotifierItemMessage::Remove { .. } | NotifierItemMessage::Rejected { .. }
+                )
+            {
+                self.removed = true;
+            }
+
+            return Poll::Ready(Some(Ok(message)));
+        }
+    }
+}
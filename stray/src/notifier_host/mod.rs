@@ -1,51 +1,376 @@
-use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
 use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::{ItemAddress, ItemKey, NotifierItemCommand};
+use crate::notifier_host::connections::HostConnection;
+use crate::notifier_host::filter::ItemFilter;
+use crate::notifier_host::queue::HostReceiver;
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::dispatcher::{self, DispatchRequest};
+use crate::notifier_watcher::forward_commands;
+use crate::notifier_watcher::item_handle::ItemHandle;
+use crate::notifier_watcher::registry::RegistryHandle;
 use crate::{NotifierItemMessage, StatusNotifierWatcher};
-use tokio::sync::broadcast;
-use zbus::{Connection, ConnectionBuilder};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use zbus::Connection;
+
+pub(crate) mod connections;
+pub(crate) mod filter;
+pub(crate) mod item_stream;
+pub(crate) mod queue;
 
 pub struct NotifierHost {
     wellknown_name: String,
-    rx: broadcast::Receiver<NotifierItemMessage>,
-    conn: Connection,
+    tx: broadcast::Sender<NotifierItemMessage>,
+    // `None` while the host is paused
+    rx: Option<HostReceiver>,
+    // Messages to deliver before the broadcast ones, filled on resume
+    pending: VecDeque<NotifierItemMessage>,
+    cmd_tx: mpsc::Sender<DispatchRequest>,
+    command_tx: mpsc::Sender<NotifierItemCommand>,
+    config: Arc<WatcherConfig>,
+    registry: RegistryHandle,
+    filter: Option<ItemFilter>,
+    // Items delivered to this host, so their removal can be forwarded
+    visible: HashMap<ItemAddress, ItemKey>,
+    // Replaced when the bus restarts, see `HostConnections::reconnect`
+    conn: HostConnection,
 }
 
 impl StatusNotifierWatcher {
+    /// Register a [StatusNotifierHost](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierHost/)
+    /// to receive the items. Waits for [`StatusNotifierWatcher::run`] to claim the watcher name,
+    /// fails with [`StatusNotifierWatcherError::NotStarted`] if `run` was not called or the watcher
+    /// failed to start.
+    ///
+    /// ```rust, no_run
+    /// # use stray::StatusNotifierWatcher;
+    /// # async fn example() -> stray::error::Result<()> {
+    /// let tray = StatusNotifierWatcher::new(None).await?;
+    /// tokio::spawn(tray.run(stray::CancellationToken::new()));
+    /// let mut host = tray.create_notifier_host("MyHost").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub async fn create_notifier_host(&self, unique_id: &str) -> Result<NotifierHost> {
+        self.started().await?;
         let pid = std::process::id();
         let id = &unique_id;
         let wellknown_name = format!("org.freedesktop.StatusNotifierHost-{pid}-{id}");
 
-        let conn = ConnectionBuilder::session()?
-            .name(wellknown_name.as_str())?
-            .build()
+        let conn = self
+            .config
+            .hosts
+            .connect(&self.config, &wellknown_name)
             .await?;
 
-        let status_notifier_proxy = StatusNotifierWatcherProxy::new(&conn).await?;
-
-        status_notifier_proxy
-            .register_status_notifier_host(&wellknown_name)
-            .await?;
+        // Each host gets its own command channel so bars can dispatch commands independently
+        let (command_tx, command_rx) = mpsc::channel(32);
+        self.config
+            .tasks
+            .spawn(forward_commands(command_rx, self.cmd_tx.clone()));
 
         Ok(NotifierHost {
             wellknown_name,
-            rx: self.tx.subscribe(),
+            tx: self.tx.clone(),
+            rx: Some(HostReceiver::subscribe(&self.tx, &self.config)),
+            pending: VecDeque::new(),
+            cmd_tx: self.cmd_tx.clone(),
+            command_tx,
+            config: self.config.clone(),
+            registry: self.registry.clone(),
+            filter: None,
+            visible: HashMap::new(),
             conn,
         })
     }
+
+    /// Same as [`StatusNotifierWatcher::create_notifier_host`], the host only receives the items
+    /// matching `filter`. When an item stops matching, for instance after a status change, the host
+    /// receives a [`NotifierItemMessage::Remove`] for it.
+    pub async fn create_filtered_notifier_host(
+        &self,
+        unique_id: &str,
+        filter: ItemFilter,
+    ) -> Result<NotifierHost> {
+        let mut host = self.create_notifier_host(unique_id).await?;
+        host.filter = Some(filter);
+        Ok(host)
+    }
 }
 
 impl NotifierHost {
+    /// Receive the next message, fails with [`StatusNotifierWatcherError::HostPaused`] while the
+    /// host is paused.
     pub async fn recv(&mut self) -> Result<NotifierItemMessage> {
-        self.rx
-            .recv()
+        loop {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => match &mut self.rx {
+                    Some(rx) => rx.recv().await?,
+                    None => return Err(StatusNotifierWatcherError::HostPaused),
+                },
+            };
+
+            if let Some(message) = self.filter_message(message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Stop receiving messages, nothing is buffered until [`NotifierHost::resume`] is called.
+    /// This is meant for hidden bars, for instance auto-hide panels or locked sessions.
+    pub fn pause(&mut self) {
+        self.rx = None;
+        self.pending.clear();
+    }
+
+    /// Resume a paused host. The next messages bring it up to date: an update for every current
+    /// item and a removal for the items which disappeared in the meantime.
+    pub async fn resume(&mut self) -> Result<()> {
+        if self.rx.is_some() {
+            return Ok(());
+        }
+
+        // Subscribe first so no change is missed, the messages received meanwhile are older
+        // than the current state, see `MessageStamp`
+        self.rx = Some(HostReceiver::subscribe(&self.tx, &self.config));
+        let visible = self
+            .visible
+            .iter()
+            .map(|(address, key)| (address.clone(), key.clone()))
+            .collect();
+
+        let messages = self.registry.current_state(visible).await?;
+        self.pending.extend(messages);
+        Ok(())
+    }
+
+    /// Returns true if the host is paused, see [`NotifierHost::pause`].
+    pub fn is_paused(&self) -> bool {
+        self.rx.is_none()
+    }
+
+    // Keep track of the items delivered to this host and drop the messages about items
+    // not matching its filter
+    fn filter_message(&mut self, message: NotifierItemMessage) -> Option<NotifierItemMessage> {
+        match message {
+            NotifierItemMessage::Update {
+                ref address,
+                ref key,
+                ref item,
+                stamp,
+                ..
+            } => {
+                let matches = self
+                    .filter
+                    .as_ref()
+                    .map_or(true, |filter| filter.matches(item));
+
+                if matches {
+                    self.visible.insert(address.clone(), key.clone());
+                    Some(message)
+                } else if self.visible.remove(address).is_some() {
+                    // Stands for the update, it keeps its stamp
+                    Some(NotifierItemMessage::Remove {
+                        address: address.clone(),
+                        key: key.clone(),
+                        stamp,
+                    })
+                } else {
+                    None
+                }
+            }
+            NotifierItemMessage::Remove { ref address, .. }
+            | NotifierItemMessage::Rejected { ref address, .. } => {
+                let visible = self.visible.remove(address).is_some();
+                (visible || self.filter.is_none()).then_some(message)
+            }
+            NotifierItemMessage::Reset { .. } => {
+                self.visible.clear();
+                Some(message)
+            }
+            NotifierItemMessage::CommandFailed { .. }
+            | NotifierItemMessage::Error { .. }
+            | NotifierItemMessage::WatcherLost { .. } => Some(message),
+        }
+    }
+
+    /// Returns a sender dedicated to this host, commands sent through it are dispatched to the items
+    /// without waiting for their outcome, failures are reported with [`NotifierItemMessage::CommandFailed`].
+    pub fn commands(&self) -> mpsc::Sender<NotifierItemCommand> {
+        self.command_tx.clone()
+    }
+
+    /// Send a command to the items and wait at most `timeout` for the item to handle it.
+    /// Returns [`StatusNotifierWatcherError::DispatcherClosed`] if the dispatcher is no longer running,
+    /// [`StatusNotifierWatcherError::CommandTimeout`] if the item is too slow to answer and the error
+    /// raised while calling the item otherwise, see [`NotifierHost::send_command_and_wait`].
+    /// This is cancel safe: if the returned future is dropped before the dispatcher accepted
+    /// the command it is not sent.
+    pub async fn send_command_timeout(
+        &self,
+        command: NotifierItemCommand,
+        timeout: Duration,
+    ) -> Result<()> {
+        tokio::time::timeout(timeout, dispatcher::send_and_wait(&self.cmd_tx, command))
             .await
-            .map_err(StatusNotifierWatcherError::from)
+            .unwrap_or(Err(StatusNotifierWatcherError::CommandTimeout))
+    }
+
+    /// Send a command to the items and wait for it to be handled, returning the error raised
+    /// while calling the item if any. Unlike commands sent through the watcher channel,
+    /// this lets UIs know whether a click actually reached the application.
+    pub async fn send_command_and_wait(&self, command: NotifierItemCommand) -> Result<()> {
+        dispatcher::send_and_wait(&self.cmd_tx, command).await
+    }
+
+    /// Returns the bus connection owning the well-known name of this host. Applications can serve
+    /// additional interfaces or build proxies on it instead of opening another connection.
+    /// The host gets a new connection when the bus restarts, call this again rather than keeping it.
+    pub fn connection(&self) -> Connection {
+        self.conn.get()
     }
 
-    /// This is used to drop the StatusNotifierHost and tell Dbus to release the name
+    /// Returns an [`ItemHandle`] to interact with the item at `address`.
+    pub fn item(&self, address: &ItemAddress) -> ItemHandle {
+        ItemHandle::new(
+            address.clone(),
+            self.connection(),
+            self.config.clone(),
+            self.cmd_tx.clone(),
+            self.registry.clone(),
+        )
+    }
+
+    /// Returns false once the command dispatcher has stopped, UIs can use this to disable menu interactions.
+    pub fn is_dispatcher_alive(&self) -> bool {
+        !self.cmd_tx.is_closed()
+    }
+
+    /// Completes when the command dispatcher stops.
+    pub async fn dispatcher_closed(&self) {
+        self.cmd_tx.closed().await
+    }
+
+    /// This is used to drop the StatusNotifierHost and tell Dbus to release the name.
+    /// Dropping the host also releases the name, once its connection is closed.
     pub async fn destroy(self) -> Result<()> {
-        let _ = self.conn.release_name(self.wellknown_name.as_str()).await?;
+        let _ = self
+            .connection()
+            .release_name(self.wellknown_name.as_str())
+            .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::menu::MenuHandle;
+    use crate::notifier_host::connections::HostConnection;
+    use crate::notifier_host::queue::tests::{describe, settle};
+    use crate::notifier_watcher::registry::tests::item;
+    use crate::notifier_watcher::registry::Registry;
+    use zbus::{ConnectionBuilder, Guid};
+
+    const FIRST: &str = ":1.52/StatusNotifierItem";
+    const SECOND: &str = ":1.53/StatusNotifierItem";
+    const THIRD: &str = ":1.54/StatusNotifierItem";
+
+    // A host reading the messages of a running registry, its connection only reaches a peer
+    async fn host() -> NotifierHost {
+        let config = Arc::new(WatcherConfig::default());
+        let (tx, _) = broadcast::channel(8);
+        let (registry, handle, events) = Registry::new(config.clone(), tx.clone());
+        tokio::spawn(registry.run(events));
+
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let (conn, _) = tokio::try_join!(
+            ConnectionBuilder::unix_stream(server)
+                .server(&guid)
+                .p2p()
+                .build(),
+            ConnectionBuilder::unix_stream(client).p2p().build(),
+        )
+        .unwrap();
+
+        let (cmd_tx, _) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+        NotifierHost {
+            wellknown_name: "org.freedesktop.StatusNotifierHost-test".to_string(),
+            rx: Some(HostReceiver::subscribe(&tx, &config)),
+            tx,
+            pending: VecDeque::new(),
+            cmd_tx,
+            command_tx,
+            config,
+            registry: handle,
+            filter: None,
+            visible: HashMap::new(),
+            conn: HostConnection::detached(conn),
+        }
+    }
+
+    async fn update(host: &NotifierHost, service: &str, id: &str) {
+        let address = ItemAddress::new(service).unwrap();
+        let menu = MenuHandle::offline(address.clone(), None, None);
+        host.registry
+            .item_updated(&address, item(id), menu)
+            .await
+            .unwrap();
+    }
+
+    // Every message received until the host has nothing left to read
+    async fn drain(host: &mut NotifierHost) -> Vec<String> {
+        let mut messages = vec![];
+        while let Ok(received) = tokio::time::timeout(Duration::from_millis(50), host.recv()).await
+        {
+            messages.push(describe(&received.unwrap()));
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn should_bring_a_paused_host_up_to_date_on_resume() {
+        let mut host = host().await;
+        update(&host, FIRST, "first").await;
+        update(&host, SECOND, "second").await;
+        assert_eq!(
+            drain(&mut host).await,
+            vec![
+                format!("update {FIRST} first"),
+                format!("update {SECOND} second")
+            ]
+        );
+
+        host.pause();
+        update(&host, FIRST, "renamed").await;
+        host.registry
+            .item_removed(&ItemAddress::new(SECOND).unwrap())
+            .await
+            .unwrap();
+        update(&host, THIRD, "third").await;
+        settle().await;
+        assert!(matches!(
+            host.recv().await,
+            Err(StatusNotifierWatcherError::HostPaused)
+        ));
+
+        // The changes made meanwhile are delivered as the current state of the items
+        host.resume().await.unwrap();
+        let mut messages = drain(&mut host).await;
+        messages[1..].sort();
+        assert_eq!(
+            messages,
+            vec![
+                format!("remove {SECOND}"),
+                format!("update {FIRST} renamed"),
+                format!("update {THIRD} third"),
+            ]
+        );
+    }
+}
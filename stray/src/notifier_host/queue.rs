@@ -0,0 +1,364 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::OverflowPolicy;
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::tasks::WatcherTasks;
+use crate::NotifierItemMessage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+
+// The messages received by a notifier host, either straight from the broadcast channel
+// or through a bounded queue fed by a forwarding task, see `StatusNotifierWatcherBuilder::host_queue`
+#[derive(Debug)]
+pub(crate) enum HostReceiver {
+    Broadcast(broadcast::Receiver<NotifierItemMessage>),
+    Queued(HostQueue),
+}
+
+impl HostReceiver {
+    pub(crate) fn subscribe(
+        tx: &broadcast::Sender<NotifierItemMessage>,
+        config: &WatcherConfig,
+    ) -> Self {
+        match config.host_queue {
+            None => HostReceiver::Broadcast(tx.subscribe()),
+            Some((capacity, policy)) => HostReceiver::Queued(HostQueue::spawn(
+                tx.subscribe(),
+                capacity,
+                policy,
+                &config.tasks,
+            )),
+        }
+    }
+
+    pub(crate) async fn recv(&mut self) -> Result<NotifierItemMessage> {
+        match self {
+            HostReceiver::Broadcast(rx) => {
+                rx.recv().await.map_err(StatusNotifierWatcherError::from)
+            }
+            HostReceiver::Queued(queue) => queue.recv().await,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct HostQueue {
+    shared: Arc<Shared>,
+    forwarder: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // Notified when a message is queued, or the queue is closed
+    pushed: Notify,
+    // Notified when a message is taken out of the queue
+    popped: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    messages: VecDeque<NotifierItemMessage>,
+    // Messages missed by the forwarding task, only possible with `OverflowPolicy::Block`
+    lagged: u64,
+    closed: bool,
+}
+
+impl HostQueue {
+    fn spawn(
+        rx: broadcast::Receiver<NotifierItemMessage>,
+        capacity: usize,
+        policy: OverflowPolicy,
+        tasks: &WatcherTasks,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState::default()),
+            capacity: capacity.max(1),
+            policy,
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        });
+
+        let forwarder = tasks.spawn(forward(rx, shared.clone()));
+        HostQueue { shared, forwarder }
+    }
+
+    async fn recv(&mut self) -> Result<NotifierItemMessage> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if state.lagged > 0 {
+                    let skipped = std::mem::take(&mut state.lagged);
+                    return Err(RecvError::Lagged(skipped).into());
+                }
+
+                if let Some(message) = state.messages.pop_front() {
+                    self.shared.popped.notify_one();
+                    return Ok(message);
+                }
+
+                if state.closed {
+                    return Err(RecvError::Closed.into());
+                }
+            }
+
+            self.shared.pushed.notified().await;
+        }
+    }
+}
+
+impl Drop for HostQueue {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
+
+// Drain the broadcast channel into the host queue, applying the overflow policy when it is full
+async fn forward(mut rx: broadcast::Receiver<NotifierItemMessage>, shared: Arc<Shared>) {
+    loop {
+        match rx.recv().await {
+            Ok(message) => shared.push(message).await,
+            Err(RecvError::Lagged(skipped)) => {
+                shared.lock().lagged += skipped;
+                shared.pushed.notify_one();
+            }
+            Err(RecvError::Closed) => {
+                shared.lock().closed = true;
+                shared.pushed.notify_one();
+                return;
+            }
+        }
+    }
+}
+
+impl Shared {
+    // The state is always left consistent, a panicking holder can't poison it
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn push(&self, message: NotifierItemMessage) {
+        let mut message = Some(message);
+        loop {
+            {
+                let mut state = self.lock();
+                if state.messages.len() < self.capacity {
+                    state.messages.extend(message.take());
+                    self.pushed.notify_one();
+                    return;
+                }
+
+                match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        let dropped = state.messages.pop_front();
+                        tracing::debug!("Host queue is full, dropping {dropped:?}");
+                        state.messages.extend(message.take());
+                        return;
+                    }
+                    OverflowPolicy::CoalescePerItem => {
+                        if let Some(message) = message.take() {
+                            coalesce(&mut state.messages, message);
+                        }
+                        return;
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+
+            self.popped.notified().await;
+        }
+    }
+}
+
+// Replace the queued message about the same item, the queue only needs to hold the latest
+// state of each item. Falls back to dropping the oldest message.
+fn coalesce(messages: &mut VecDeque<NotifierItemMessage>, message: NotifierItemMessage) {
+    let is_state = |message: &NotifierItemMessage| {
+        !matches!(
+            message,
+            NotifierItemMessage::CommandFailed { .. }
+                | NotifierItemMessage::Error { .. }
+                | NotifierItemMessage::Reset { .. }
+                | NotifierItemMessage::WatcherLost { .. }
+        )
+    };
+
+    let queued = messages.iter_mut().find(|queued| {
+        is_state(queued) && is_state(&message) && queued.address() == message.address()
+    });
+
+    match queued {
+        Some(queued) => *queued = message,
+        None => {
+            let dropped = messages.pop_front();
+            tracing::debug!("Host queue is full, dropping {dropped:?}");
+            messages.push_back(message);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::{ItemAddress, ItemKey, MessageStamp};
+
+    const FIRST: &str = ":1.52/StatusNotifierItem";
+    const SECOND: &str = ":1.53/StatusNotifierItem";
+    const THIRD: &str = ":1.54/StatusNotifierItem";
+
+    fn queue(
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> (broadcast::Sender<NotifierItemMessage>, HostQueue) {
+        // Sized like the watcher broadcast channel, see `StatusNotifierWatcher::start`
+        let (tx, rx) = broadcast::channel(capacity);
+        let queue = HostQueue::spawn(rx, capacity, policy, &WatcherTasks::default());
+        (tx, queue)
+    }
+
+    fn failed(service: &str, error: &str) -> NotifierItemMessage {
+        NotifierItemMessage::Error {
+            address: ItemAddress::new(service).unwrap(),
+            error: error.to_string(),
+            stamp: MessageStamp::unstamped(),
+        }
+    }
+
+    fn removed(service: &str) -> NotifierItemMessage {
+        let address = ItemAddress::new(service).unwrap();
+        NotifierItemMessage::Remove {
+            key: ItemKey::Address(address.clone()),
+            address,
+            stamp: MessageStamp::unstamped(),
+        }
+    }
+
+    fn rejected(service: &str) -> NotifierItemMessage {
+        NotifierItemMessage::Rejected {
+            address: ItemAddress::new(service).unwrap(),
+            violations: vec![],
+            stamp: MessageStamp::unstamped(),
+        }
+    }
+
+    // Let the forwarding task drain the broadcast channel
+    pub(crate) async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    pub(crate) fn describe(message: &NotifierItemMessage) -> String {
+        match message {
+            NotifierItemMessage::Update { address, item, .. } => {
+                format!("update {address} {}", item.id)
+            }
+            NotifierItemMessage::Error { address, error, .. } => format!("error {address} {error}"),
+            NotifierItemMessage::Remove { address, .. } => format!("remove {address}"),
+            NotifierItemMessage::Rejected { address, .. } => format!("rejected {address}"),
+            message => format!("{message:?}"),
+        }
+    }
+
+    // Every queued message, along with the lags reported in between
+    async fn drain(queue: &mut HostQueue) -> Vec<String> {
+        let mut messages = vec![];
+        while let Ok(received) =
+            tokio::time::timeout(std::time::Duration::from_millis(50), queue.recv()).await
+        {
+            match received {
+                Ok(message) => messages.push(describe(&message)),
+                Err(StatusNotifierWatcherError::BroadCastRecvError(RecvError::Lagged(skipped))) => {
+                    messages.push(format!("lagged {skipped}"))
+                }
+                Err(err) => panic!("unexpected error {err:?}"),
+            }
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn should_drop_the_oldest_message_when_full() {
+        let (tx, mut queue) = queue(2, OverflowPolicy::DropOldest);
+
+        for (service, error) in [(FIRST, "one"), (SECOND, "two"), (THIRD, "three")] {
+            tx.send(failed(service, error)).unwrap();
+            settle().await;
+        }
+
+        assert_eq!(
+            drain(&mut queue).await,
+            vec![
+                format!("error {SECOND} two"),
+                format!("error {THIRD} three")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_messages_about_the_same_item() {
+        let (tx, mut queue) = queue(2, OverflowPolicy::CoalescePerItem);
+
+        for message in [rejected(FIRST), removed(SECOND), removed(FIRST)] {
+            tx.send(message).unwrap();
+            settle().await;
+        }
+
+        // The latest state of the first item takes the place of the queued one
+        assert_eq!(
+            drain(&mut queue).await,
+            vec![format!("remove {FIRST}"), format!("remove {SECOND}")]
+        );
+
+        // Messages which are not about an item state are never coalesced
+        for (service, error) in [(FIRST, "one"), (FIRST, "two"), (FIRST, "three")] {
+            tx.send(failed(service, error)).unwrap();
+            settle().await;
+        }
+
+        assert_eq!(
+            drain(&mut queue).await,
+            vec![format!("error {FIRST} two"), format!("error {FIRST} three")]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_block_until_the_host_receives() {
+        let (tx, mut queue) = queue(2, OverflowPolicy::Block);
+
+        // The queue and the broadcast channel hold `capacity` messages each, nothing is lost
+        // while the host does not read
+        let errors = ["one", "two", "three", "four"];
+        for error in errors {
+            tx.send(failed(FIRST, error)).unwrap();
+            settle().await;
+        }
+
+        let expected: Vec<String> = errors
+            .iter()
+            .map(|error| format!("error {FIRST} {error}"))
+            .collect();
+        assert_eq!(drain(&mut queue).await, expected);
+
+        // Once both are full, along with the message the forwarder waits to queue,
+        // the missed messages are reported
+        let errors = ["five", "six", "seven", "eight", "nine", "ten"];
+        for error in errors {
+            tx.send(failed(FIRST, error)).unwrap();
+            settle().await;
+        }
+
+        let mut received = drain(&mut queue).await;
+        let lagged = received.iter().position(|message| message == "lagged 1");
+        received.remove(lagged.expect("the host should lag"));
+        let expected: Vec<String> = errors
+            .iter()
+            .filter(|error| **error != "eight")
+            .map(|error| format!("error {FIRST} {error}"))
+            .collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::error::Result;
+use crate::message::tray::ITEM_PROPERTIES;
+use crate::message::{
+    Bus, Conformance, ItemIdentity, NotifierItemCommand, OverflowPolicy, WatcherEvent,
+};
+use crate::notifier_host::connections::HostConnections;
+use crate::notifier_watcher::call_timeout::DEFAULT_CALL_TIMEOUT;
+use crate::notifier_watcher::fetch_limiter::FetchLimiter;
+use crate::notifier_watcher::persisted_items::PersistedItems;
+use crate::notifier_watcher::tasks::WatcherTasks;
+use crate::notifier_watcher::WATCHER_NAME;
+use crate::StatusNotifierWatcher;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+const DEFAULT_MENU_DEPTH: i32 = 10;
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Options shared by all the watcher tasks
+#[derive(Debug, Clone)]
+pub(crate) struct WatcherConfig {
+    pub(crate) identity: ItemIdentity,
+    pub(crate) menu_depth: i32,
+    pub(crate) item_menu_depth: HashMap<String, i32>,
+    pub(crate) max_pixmap_size: Option<u32>,
+    pub(crate) icon_size: Option<(u32, u32)>,
+    pub(crate) max_menu_children: Option<usize>,
+    pub(crate) status_dwell: Option<Duration>,
+    pub(crate) item_status_dwell: HashMap<String, Duration>,
+    pub(crate) menu_cache_dir: Option<PathBuf>,
+    pub(crate) persisted_items: Option<PersistedItems>,
+    pub(crate) forward_to: Option<String>,
+    #[cfg(feature = "png")]
+    pub(crate) materialize_icons: Option<u32>,
+    #[cfg(feature = "icon-loader")]
+    pub(crate) icon_theme_lookup: Option<crate::icon_loader::IconLookup>,
+    pub(crate) bus: Bus,
+    pub(crate) watcher_name: String,
+    pub(crate) protocol_version: i32,
+    pub(crate) conformance: Conformance,
+    pub(crate) debounce: Option<Duration>,
+    pub(crate) host_queue: Option<(usize, OverflowPolicy)>,
+    pub(crate) fetch_limiter: FetchLimiter,
+    pub(crate) call_timeout: Option<Duration>,
+    pub(crate) skip_icon_pixmaps: bool,
+    pub(crate) property_whitelist: Option<Vec<String>>,
+    pub(crate) retry_attempts: u32,
+    pub(crate) retry_interval: Duration,
+    pub(crate) health_check_interval: Option<Duration>,
+    pub(crate) tasks: WatcherTasks,
+    pub(crate) events: broadcast::Sender<WatcherEvent>,
+    pub(crate) hosts: HostConnections,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            identity: ItemIdentity::default(),
+            menu_depth: DEFAULT_MENU_DEPTH,
+            item_menu_depth: HashMap::new(),
+            max_pixmap_size: None,
+            icon_size: None,
+            max_menu_children: None,
+            status_dwell: None,
+            item_status_dwell: HashMap::new(),
+            menu_cache_dir: None,
+            persisted_items: None,
+            forward_to: None,
+            #[cfg(feature = "png")]
+            materialize_icons: None,
+            #[cfg(feature = "icon-loader")]
+            icon_theme_lookup: None,
+            bus: Bus::default(),
+            watcher_name: WATCHER_NAME.to_string(),
+            protocol_version: 0,
+            conformance: Conformance::default(),
+            debounce: None,
+            host_queue: None,
+            fetch_limiter: FetchLimiter::default(),
+            call_timeout: Some(DEFAULT_CALL_TIMEOUT),
+            skip_icon_pixmaps: false,
+            property_whitelist: None,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            health_check_interval: Some(DEFAULT_HEALTH_CHECK_INTERVAL),
+            tasks: WatcherTasks::default(),
+            events: broadcast::channel(32).0,
+            hosts: HostConnections::default(),
+        }
+    }
+}
+
+impl WatcherConfig {
+    // The GetLayout recursion depth to use for the given item id
+    pub(crate) fn menu_depth(&self, item_id: &str) -> i32 {
+        self.item_menu_depth
+            .get(item_id)
+            .copied()
+            .unwrap_or(self.menu_depth)
+    }
+
+    // Whether the item property `name` should be fetched at all
+    pub(crate) fn wants_property(&self, name: &str) -> bool {
+        let whitelisted = self
+            .property_whitelist
+            .as_ref()
+            .map_or(true, |names| names.iter().any(|allowed| allowed == name));
+
+        whitelisted && !(self.skip_icon_pixmaps && name.ends_with("Pixmap"))
+    }
+
+    // Properties.GetAll can only be used when every property is wanted
+    pub(crate) fn fetch_all_properties(&self) -> bool {
+        !self.skip_icon_pixmaps && self.property_whitelist.is_none()
+    }
+
+    // The properties to fetch one by one when Properties.GetAll can't be used
+    pub(crate) fn fetched_properties(&self) -> Vec<&str> {
+        let names: Vec<&str> = match &self.property_whitelist {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => ITEM_PROPERTIES.to_vec(),
+        };
+
+        names
+            .into_iter()
+            .filter(|name| self.wants_property(name))
+            .collect()
+    }
+
+    // The minimum time a status must be held before the next transition for the given item id
+    pub(crate) fn status_dwell(&self, item_id: &str) -> Option<Duration> {
+        self.item_status_dwell
+            .get(item_id)
+            .copied()
+            .or(self.status_dwell)
+    }
+}
+
+/// Configure and start a [`StatusNotifierWatcher`], see [`StatusNotifierWatcher::builder`].
+#[derive(Debug, Default)]
+pub struct StatusNotifierWatcherBuilder {
+    pub(crate) config: WatcherConfig,
+}
+
+impl StatusNotifierWatcherBuilder {
+    /// Choose how items are keyed in [`crate::NotifierItemMessage`], defaults to [`ItemIdentity::Address`].
+    pub fn identity(mut self, identity: ItemIdentity) -> Self {
+        self.config.identity = identity;
+        self
+    }
+
+    /// The recursion depth used when fetching menu layouts, defaults to 10.
+    /// Use `-1` to fetch the whole menu tree.
+    pub fn menu_depth(mut self, depth: i32) -> Self {
+        self.config.menu_depth = depth;
+        self
+    }
+
+    /// Override the menu recursion depth for the item with the given `Id` property.
+    pub fn item_menu_depth(mut self, item_id: impl Into<String>, depth: i32) -> Self {
+        self.config.item_menu_depth.insert(item_id.into(), depth);
+        self
+    }
+
+    /// Declare the size the hosts display icons at, in logical pixels, and the scale factor of
+    /// their output. Items then only carry the pixmap best matching `size * scale`, the smallest
+    /// one at least that large, so icons are not blurry on HiDPI displays. Materialized icons are
+    /// looked up in the icon themes at this scale, see [`Self::materialize_icons`].
+    pub fn icon_size(mut self, size: u32, scale: u32) -> Self {
+        self.config.icon_size = Some((size, scale.max(1)));
+        self
+    }
+
+    /// Limit the size of the icon pixmaps broadcast to the hosts. Only the pixmap closest to `size`
+    /// is kept: the largest one fitting, otherwise the smallest one downscaled when the `resize`
+    /// feature is enabled. Items publishing only oversized pixmaps have none without this feature.
+    /// The original pixmaps can still be retrieved with [`StatusNotifierWatcher::original_icon_pixmap`].
+    pub fn max_pixmap_size(mut self, size: u32) -> Self {
+        self.config.max_pixmap_size = Some(size);
+        self
+    }
+
+    /// Limit the number of entries displayed at each menu level. Hidden entries are replaced by a
+    /// single marker item carrying a [`crate::message::menu::MenuOverflow`], so simple frontends can
+    /// render "… 120 more" instead of freezing on giant menus.
+    pub fn max_menu_children(mut self, max_children: usize) -> Self {
+        self.config.max_menu_children = Some(max_children);
+        self
+    }
+
+    /// Hold each item [`crate::message::tray::Status`] for at least `dwell` before broadcasting the
+    /// next transition. This smooths out applications rapidly toggling between `Active` and `Passive`,
+    /// only the latest status is published once the dwell time is elapsed.
+    pub fn status_dwell(mut self, dwell: Duration) -> Self {
+        self.config.status_dwell = Some(dwell);
+        self
+    }
+
+    /// Override the status dwell time for the item with the given `Id` property,
+    /// use [`Duration::ZERO`] to disable it for this item.
+    pub fn item_status_dwell(mut self, item_id: impl Into<String>, dwell: Duration) -> Self {
+        self.config.item_status_dwell.insert(item_id.into(), dwell);
+        self
+    }
+
+    /// Persist the last menu of each application in `dir`, keyed by item `Id`. When an application
+    /// registers again its cached menu is delivered right away, flagged with [`crate::message::menu::MenuHandle::is_stale`],
+    /// while the live menu is fetched. This avoids empty menus right after the bar starts.
+    pub fn menu_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.menu_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Record the registered items in the file at `path` and adopt them again when the watcher
+    /// starts. Items still exported on the bus show up without their application registering
+    /// again, which many never do after the bar restarts. Items of applications which exited
+    /// meanwhile are dropped. Only used while serving `org.kde.StatusNotifierWatcher`.
+    pub fn persist_items(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.persisted_items = Some(PersistedItems::new(path.into()));
+        self
+    }
+
+    /// Choose the bus to serve the watcher on, defaults to [`Bus::Session`]. The notifier hosts
+    /// and the items must live on the same bus.
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.config.bus = bus;
+        self
+    }
+
+    /// Serve the watcher under `name` instead of `org.kde.StatusNotifierWatcher`, ex:
+    /// `org.kde.StatusNotifierWatcher.seat1`. Several watchers can then run in the same process
+    /// or on the same bus, for integration tests and multi-seat daemons. Only applications and
+    /// hosts looking the watcher up under this name see its items.
+    pub fn watcher_name(mut self, name: impl Into<String>) -> Self {
+        self.config.watcher_name = name.into();
+        self
+    }
+
+    /// Set the `ProtocolVersion` served by the watcher, defaults to `0`. From version `1` the
+    /// watcher also claims `org.freedesktop.StatusNotifierWatcher` for the applications looking it
+    /// up under that name, unless a custom [`Self::watcher_name`] is used.
+    pub fn protocol_version(mut self, version: i32) -> Self {
+        self.config.protocol_version = version;
+        self
+    }
+
+    /// Forward the watched items to the StatusNotifierWatcher of the bus at `address`, ex:
+    /// `unix:path=/run/user/1000/bus`. This lets a bar running on a nested session show up in the
+    /// tray of the outer session. Properties, `Activate` and `Scroll` are forwarded, menus are not.
+    pub fn forward_to(mut self, address: impl Into<String>) -> Self {
+        self.config.forward_to = Some(address.into());
+        self
+    }
+
+    /// Write the icon of every item to a file and set its path in
+    /// [`crate::message::tray::StatusNotifierItem::icon_file`], for text-based bars which can only
+    /// display image files. Pixmaps closest to `size` are encoded to PNG files in
+    /// `$XDG_RUNTIME_DIR`, named icons are resolved in the icon themes when the `icon-loader` feature
+    /// is enabled, following the desktop theme with [`Self::follow_icon_theme`]. The files are
+    /// removed along with their item and when the watcher stops.
+    #[cfg(feature = "png")]
+    pub fn materialize_icons(mut self, size: u32) -> Self {
+        self.config.materialize_icons = Some(size);
+        self
+    }
+
+    /// Keep the theme of `lookup` in sync with the icon theme of the desktop, as reported by the
+    /// `org.freedesktop.portal.Settings` portal. When it changes, the resolved icons are forgotten
+    /// and every item is broadcast again so hosts can resolve its icon with the new theme.
+    ///
+    /// ```rust, no_run
+    /// # use stray::icon_loader::IconLookup;
+    /// # use stray::StatusNotifierWatcher;
+    /// # async fn example() -> stray::error::Result<()> {
+    /// let lookup = IconLookup::default();
+    /// let watcher = StatusNotifierWatcher::builder()
+    ///     .follow_icon_theme(lookup.clone())
+    ///     .build(None)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "icon-loader")]
+    pub fn follow_icon_theme(mut self, lookup: crate::icon_loader::IconLookup) -> Self {
+        self.config.icon_theme_lookup = Some(lookup);
+        self
+    }
+
+    /// Choose how items deviating from the specification are handled, defaults to [`Conformance::Warn`].
+    pub fn conformance(mut self, conformance: Conformance) -> Self {
+        self.config.conformance = conformance;
+        self
+    }
+
+    /// Coalesce the changes of an item happening within `window` into a single
+    /// [`crate::NotifierItemMessage::Update`], sent at the end of the window. This avoids several
+    /// renders per frame with applications updating their icon and title in rapid succession.
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.config.debounce = Some(window);
+        self
+    }
+
+    /// Give each notifier host its own queue of `capacity` messages, handled according to `policy`
+    /// when the host does not keep up. By default hosts read the shared broadcast channel and
+    /// receive a [`crate::error::StatusNotifierWatcherError::BroadCastRecvError`] when they lag behind.
+    pub fn host_queue(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.config.host_queue = Some((capacity, policy));
+        self
+    }
+
+    /// Limit the number of property and menu layout fetches running at the same time across all items.
+    /// With many applications already registered at startup, this avoids stampeding the session bus.
+    pub fn max_concurrent_fetches(mut self, max_fetches: usize) -> Self {
+        self.config.fetch_limiter = FetchLimiter::new(max_fetches);
+        self
+    }
+
+    /// The maximum time to wait for an application to answer a DBus call, defaults to 25 seconds.
+    /// An item which does not answer in time has its update skipped and the failure is logged,
+    /// the other items keep being served. Pass `None` to wait indefinitely.
+    pub fn call_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.call_timeout = timeout.into();
+        self
+    }
+
+    /// Never fetch the `IconPixmap`, `AttentionIconPixmap` and `OverlayIconPixmap` properties, for bars
+    /// relying on `IconName` and an icon theme only. Pixmaps can weigh hundreds of kilobytes per item,
+    /// the other properties are then fetched one by one instead of with a single `GetAll` call.
+    pub fn skip_icon_pixmaps(mut self) -> Self {
+        self.config.skip_icon_pixmaps = true;
+        self
+    }
+
+    /// Only fetch the given item properties, with one targeted `Get` call each, for instance
+    /// `["IconName", "Status", "Menu"]` for a minimal text bar. Vendor properties can be listed as well.
+    /// `Id` is always fetched since items can't be identified without it, the other properties
+    /// are left to their default value.
+    pub fn fetch_properties<I, S>(mut self, properties: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut properties: Vec<String> = properties.into_iter().map(Into::into).collect();
+        if !properties.iter().any(|name| name == "Id") {
+            properties.insert(0, "Id".to_string());
+        }
+
+        self.config.property_whitelist = Some(properties);
+        self
+    }
+
+    /// Retry connecting to a newly registered item up to `attempts` times, waiting `interval` after
+    /// the first failure and doubling it after each following one, up to 30 seconds.
+    /// Defaults to 3 attempts, 250ms apart.
+    /// Use `1` attempt to give up on the first failure.
+    pub fn retry(mut self, attempts: u32, interval: Duration) -> Self {
+        self.config.retry_attempts = attempts.max(1);
+        self.config.retry_interval = interval;
+        self
+    }
+
+    /// Check every `interval` that the owner of each item is still on the bus, and remove the items
+    /// whose owner is gone. This catches applications which crashed while their `NameOwnerChanged`
+    /// signal could not be observed, ex: during a bus reconnection. Defaults to 30 seconds,
+    /// pass `None` to disable the check.
+    pub fn health_check_interval(mut self, interval: impl Into<Option<Duration>>) -> Self {
+        self.config.health_check_interval = interval.into();
+        self
+    }
+
+    /// Build the [`StatusNotifierWatcher`] with this configuration, it starts once
+    /// [`StatusNotifierWatcher::run`] is polled. Commands sent through `cmd_rx` are
+    /// dispatched to the items, pass `None` to only use [`StatusNotifierWatcher::send_command`].
+    pub async fn build(
+        self,
+        cmd_rx: impl Into<Option<mpsc::Receiver<NotifierItemCommand>>>,
+    ) -> Result<StatusNotifierWatcher> {
+        StatusNotifierWatcher::start(self.config, cmd_rx.into()).await
+    }
+}
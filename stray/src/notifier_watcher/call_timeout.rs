@@ -0,0 +1,40 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use std::future::Future;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(25);
+
+// Bound a dbus call, zbus 3 does not time out method calls on its own
+pub(crate) async fn with_timeout<T, E>(
+    timeout: Option<Duration>,
+    call: impl Future<Output = std::result::Result<T, E>>,
+) -> Result<T>
+where
+    E: Into<StatusNotifierWatcherError>,
+{
+    let Some(timeout) = timeout else {
+        return call.await.map_err(Into::into);
+    };
+
+    match tokio::time::timeout(timeout, call).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(StatusNotifierWatcherError::Timeout {
+            address: None,
+            method: None,
+            timeout,
+        }),
+    }
+}
+
+// Watchers skip the update when an item does not answer in time, instead of stopping,
+// the next signal of the item triggers a new call
+pub(crate) fn skip_timeout<T>(result: Result<T>, destination: &str) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.is_timeout() => {
+            tracing::warn!("{destination} did not answer in time, skipping update: {err}");
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,65 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::NotifierItemCommand;
+use crate::StatusNotifierWatcher;
+use futures_sink::Sink;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_util::sync::PollSender;
+
+/// A [`Sink`] of [`NotifierItemCommand`], so UI event streams can be forwarded straight to the items:
+///
+/// ```rust, no_run
+/// # use futures::Stream;
+/// # use stray::message::NotifierItemCommand;
+/// # async fn example(
+/// #     tray: stray::StatusNotifierWatcher,
+/// #     ui_events: impl Stream<Item = NotifierItemCommand>,
+/// # ) -> stray::error::Result<()> {
+/// use futures::StreamExt;
+///
+/// ui_events.map(Ok).forward(tray.command_sink()).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Like [`StatusNotifierWatcher::send_command`], failed commands are reported to the notifier hosts
+/// with [`crate::NotifierItemMessage::CommandFailed`].
+#[derive(Debug)]
+pub struct CommandSink {
+    sender: PollSender<NotifierItemCommand>,
+}
+
+impl StatusNotifierWatcher {
+    /// Returns a [`CommandSink`] sending commands to the items.
+    pub fn command_sink(&self) -> CommandSink {
+        CommandSink {
+            sender: PollSender::new(self.command_sender()),
+        }
+    }
+}
+
+impl Sink<NotifierItemCommand> for CommandSink {
+    type Error = StatusNotifierWatcherError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.sender
+            .poll_reserve(cx)
+            .map_err(|_| StatusNotifierWatcherError::DispatcherClosed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, command: NotifierItemCommand) -> Result<()> {
+        self.sender
+            .send_item(command)
+            .map_err(|_| StatusNotifierWatcherError::DispatcherClosed)
+    }
+
+    // Commands are handed to the dispatcher as soon as they are sent, there is nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.sender.close();
+        Poll::Ready(Ok(()))
+    }
+}
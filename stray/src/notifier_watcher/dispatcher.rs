@@ -0,0 +1,546 @@
+use crate::dbus::dbusmenu_proxy::DBusMenuProxy;
+use crate::dbus::notifier_item_proxy::StatusNotifierItemProxy;
+use crate::error::{ItemContext, Result, StatusNotifierWatcherError};
+use crate::message::tray::ActivationPolicy;
+use crate::message::{ItemAddress, MenuPath, NotifierItemCommand};
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::notifier_watcher::session::BusSession;
+use crate::notifier_watcher::tasks::WatcherTasks;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use zbus::fdo::{IntrospectableProxy, PropertiesProxy};
+use zbus::names::InterfaceName;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+// The commands waiting to be dispatched to a single item, more are rejected
+const ITEM_QUEUE: usize = 16;
+// The dispatching task of an item stops after being idle for this long
+const ITEM_IDLE: Duration = Duration::from_secs(30);
+
+// The commands waiting for the task of an item
+struct ItemQueue {
+    queue: mpsc::Sender<DispatchRequest>,
+    task: JoinHandle<()>,
+}
+
+// A command along with an optional channel to report its outcome
+#[derive(Debug)]
+pub(crate) struct DispatchRequest {
+    pub(crate) command: NotifierItemCommand,
+    pub(crate) reply: Option<oneshot::Sender<Result<()>>>,
+}
+
+// Send a command to the dispatcher and wait for its outcome
+pub(crate) async fn send_and_wait(
+    cmd_tx: &mpsc::Sender<DispatchRequest>,
+    command: NotifierItemCommand,
+) -> Result<()> {
+    let (reply, outcome) = oneshot::channel();
+    let request = DispatchRequest {
+        command,
+        reply: Some(reply),
+    };
+
+    cmd_tx
+        .send(request)
+        .await
+        .map_err(|_| StatusNotifierWatcherError::DispatcherClosed)?;
+
+    outcome
+        .await
+        .map_err(|_| StatusNotifierWatcherError::DispatcherClosed)?
+}
+
+// Forward UI commands to the items and their dbus menus
+pub(crate) struct CommandDispatcher {
+    // Commands are sent on the current connection, it changes when the session bus restarts
+    session: watch::Receiver<BusSession>,
+    // Failures of commands sent without a reply channel are broadcast by the registry
+    registry: RegistryHandle,
+    call_timeout: Option<Duration>,
+    // Items are resolved through the watcher owning this name
+    watcher_name: String,
+    tasks: WatcherTasks,
+}
+
+impl CommandDispatcher {
+    pub(crate) fn new(
+        session: watch::Receiver<BusSession>,
+        registry: RegistryHandle,
+        call_timeout: Option<Duration>,
+        watcher_name: String,
+        tasks: WatcherTasks,
+    ) -> Self {
+        CommandDispatcher {
+            session,
+            registry,
+            call_timeout,
+            watcher_name,
+            tasks,
+        }
+    }
+
+    fn connection(&self) -> Connection {
+        self.session.borrow().connection.clone()
+    }
+
+    // Each item gets its own task dispatching its commands in order, an item which never answers
+    // must not block the commands sent to the other items
+    pub(crate) async fn run(self, mut requests: mpsc::Receiver<DispatchRequest>) {
+        let dispatcher = Arc::new(self);
+        let mut items: HashMap<ItemAddress, ItemQueue> = HashMap::new();
+        while let Some(request) = requests.recv().await {
+            let address = request.command.notifier_address().clone();
+            let (request, previous) = match items.remove(&address) {
+                Some(item) => match item.queue.try_send(request) {
+                    Ok(()) => {
+                        items.insert(address, item);
+                        continue;
+                    }
+                    Err(TrySendError::Full(DispatchRequest { command, reply })) => {
+                        items.insert(address.clone(), item);
+                        let err = StatusNotifierWatcherError::CommandQueueFull(address.to_string());
+                        dispatcher
+                            .report(address, command.name(), reply, Err(err))
+                            .await;
+                        continue;
+                    }
+                    // The task of the item stopped while idle, it may still be dispatching
+                    // the commands queued while closing
+                    Err(TrySendError::Closed(request)) => (request, Some(item.task)),
+                },
+                None => (request, None),
+            };
+
+            // Keep the closing tasks, a new task for the same item must wait for them
+            items.retain(|_, item| !item.task.is_finished());
+            let (queue, queued) = mpsc::channel(ITEM_QUEUE);
+            let _ = queue.try_send(request);
+            let task = dispatcher
+                .tasks
+                .spawn(dispatcher.clone().dispatch_item(queued, previous));
+            items.insert(address, ItemQueue { queue, task });
+        }
+    }
+
+    // Dispatch the commands of a single item in order, until it is idle. The commands are only
+    // dispatched once the `previous` task of the item is done, so they never run concurrently.
+    async fn dispatch_item(
+        self: Arc<Self>,
+        mut queued: mpsc::Receiver<DispatchRequest>,
+        previous: Option<JoinHandle<()>>,
+    ) {
+        if let Some(previous) = previous {
+            let _ = previous.await;
+        }
+
+        loop {
+            match tokio::time::timeout(ITEM_IDLE, queued.recv()).await {
+                Ok(Some(request)) => self.handle(request).await,
+                Ok(None) => return,
+                Err(_) => {
+                    // The commands queued while closing are still dispatched
+                    queued.close();
+                    while let Ok(request) = queued.try_recv() {
+                        self.handle(request).await;
+                    }
+
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle(&self, DispatchRequest { command, reply }: DispatchRequest) {
+        let address = command.notifier_address().clone();
+        let name = command.name();
+        let method = command.dbus_method();
+        let result = with_timeout(self.call_timeout, self.dispatch(command))
+            .await
+            .item_context(&address, method);
+        self.report(address, name, reply, result).await;
+    }
+
+    // A failing command does not stop the dispatcher, the error is sent back to the caller
+    // or broadcast to the notifier hosts
+    async fn report(
+        &self,
+        address: ItemAddress,
+        name: &str,
+        reply: Option<oneshot::Sender<Result<()>>>,
+        result: Result<()>,
+    ) {
+        match reply {
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => {
+                if let Err(err) = result {
+                    tracing::error!("Failed to dispatch UI command {name}: {err:?}");
+                    let _ = self
+                        .registry
+                        .command_failed(address, name, err.to_string())
+                        .await;
+                }
+            }
+        }
+    }
+
+    async fn dispatch(&self, command: NotifierItemCommand) -> Result<()> {
+        let connection = self.connection();
+        match command {
+            NotifierItemCommand::MenuItemClicked {
+                submenu_id: id,
+                menu_path,
+                notifier_address,
+            } => {
+                self.menu_event(
+                    notifier_address,
+                    menu_path,
+                    id,
+                    "clicked",
+                    &empty_event_data(),
+                )
+                .await?;
+            }
+            NotifierItemCommand::MenuOpened {
+                submenu_id: id,
+                menu_path,
+                notifier_address,
+            } => {
+                self.menu_event(
+                    notifier_address,
+                    menu_path,
+                    id,
+                    "opened",
+                    &empty_event_data(),
+                )
+                .await?;
+            }
+            NotifierItemCommand::MenuClosed {
+                submenu_id: id,
+                menu_path,
+                notifier_address,
+            } => {
+                self.menu_event(
+                    notifier_address,
+                    menu_path,
+                    id,
+                    "closed",
+                    &empty_event_data(),
+                )
+                .await?;
+            }
+            NotifierItemCommand::MenuItemHovered {
+                submenu_id: id,
+                menu_path,
+                notifier_address,
+            } => {
+                self.menu_event(
+                    notifier_address,
+                    menu_path,
+                    id,
+                    "hovered",
+                    &empty_event_data(),
+                )
+                .await?;
+            }
+            NotifierItemCommand::MenuEvent {
+                submenu_id: id,
+                menu_path,
+                notifier_address,
+                event_id,
+                data,
+            } => {
+                self.menu_event(notifier_address, menu_path, id, &event_id, &data)
+                    .await?;
+            }
+            NotifierItemCommand::ItemClicked {
+                notifier_address,
+                x,
+                y,
+                activation_token,
+            } => {
+                let address = NotifierAddress::resolve(
+                    &connection,
+                    &self.watcher_name,
+                    &notifier_address,
+                    self.call_timeout,
+                )
+                .await?;
+                let notifier_item_proxy = StatusNotifierItemProxy::builder(&connection)
+                    .destination(address.destination)?
+                    .path(address.path)?
+                    .build()
+                    .await?;
+
+                let item_is_menu = notifier_item_proxy.item_is_menu().await.unwrap_or(false);
+                let has_menu = notifier_item_proxy.menu().await.is_ok();
+
+                match ActivationPolicy::new(item_is_menu, has_menu) {
+                    ActivationPolicy::Activate => {
+                        provide_activation_token(&notifier_item_proxy, activation_token).await;
+                        notifier_item_proxy.activate(x, y).await?
+                    }
+                    ActivationPolicy::ContextMenu => notifier_item_proxy
+                        .context_menu(x, y)
+                        .await
+                        .map_err(StatusNotifierWatcherError::from)
+                        .item_context(&notifier_address, "ContextMenu")?,
+                    ActivationPolicy::OpenMenu => {
+                        tracing::debug!("{notifier_address} is a menu only item, nothing to send")
+                    }
+                }
+            }
+            NotifierItemCommand::Activate {
+                notifier_address,
+                x,
+                y,
+                activation_token,
+            } => {
+                let address = NotifierAddress::resolve(
+                    &connection,
+                    &self.watcher_name,
+                    &notifier_address,
+                    self.call_timeout,
+                )
+                .await?;
+                let notifier_item_proxy = StatusNotifierItemProxy::builder(&connection)
+                    .destination(address.destination)?
+                    .path(address.path)?
+                    .build()
+                    .await?;
+
+                provide_activation_token(&notifier_item_proxy, activation_token).await;
+                notifier_item_proxy.activate(x, y).await?;
+            }
+            NotifierItemCommand::Scroll {
+                notifier_address,
+                delta,
+                orientation,
+            } => {
+                let address = NotifierAddress::resolve(
+                    &connection,
+                    &self.watcher_name,
+                    &notifier_address,
+                    self.call_timeout,
+                )
+                .await?;
+                StatusNotifierItemProxy::builder(&connection)
+                    .destination(address.destination)?
+                    .path(address.path)?
+                    .build()
+                    .await?
+                    .scroll(delta, orientation.as_str())
+                    .await?;
+            }
+            NotifierItemCommand::SetProperty {
+                notifier_address,
+                name,
+                value,
+            } => {
+                let address = NotifierAddress::resolve(
+                    &connection,
+                    &self.watcher_name,
+                    &notifier_address,
+                    self.call_timeout,
+                )
+                .await?;
+                let introspection = IntrospectableProxy::builder(&connection)
+                    .destination(address.destination.as_str())?
+                    .path(address.path.as_str())?
+                    .build()
+                    .await?
+                    .introspect()
+                    .await?;
+
+                let interface = writable_property_interface(&introspection, &name)
+                    .ok_or_else(|| StatusNotifierWatcherError::UnsupportedProperty(name.clone()))?;
+
+                PropertiesProxy::builder(&connection)
+                    .destination(address.destination.as_str())?
+                    .path(address.path.as_str())?
+                    .build()
+                    .await?
+                    .set(InterfaceName::try_from(interface)?, &name, &value)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Send a com.canonical.dbusmenu.Event, see: https://github.com/AyatanaIndicators/libdbusmenu/blob/master/libdbusmenu-glib/dbus-menu.xml
+    async fn menu_event(
+        &self,
+        notifier_address: ItemAddress,
+        menu_path: MenuPath,
+        id: i32,
+        event_id: &str,
+        data: &Value<'_>,
+    ) -> Result<()> {
+        let dbus_menu_proxy = DBusMenuProxy::builder(&self.connection())
+            .destination(notifier_address.destination())?
+            .path(menu_path.as_str())?
+            .build()
+            .await?;
+
+        dbus_menu_proxy
+            .event(id, event_id, data, event_timestamp())
+            .await?;
+
+        Ok(())
+    }
+}
+
+// The spec does not define any data for the standard events, like libdbusmenu-qt we send an empty string
+fn empty_event_data() -> Value<'static> {
+    Value::from("")
+}
+
+// Events carry a millisecond timestamp, wrapping like X11 server time
+fn event_timestamp() -> u32 {
+    chrono::offset::Local::now().timestamp_millis() as u32
+}
+
+// Hand the activation token to the item before activating it, items that don't implement
+// `ProvideXdgActivationToken` are still activated, they just might not get the focus
+async fn provide_activation_token(
+    notifier_item_proxy: &StatusNotifierItemProxy<'_>,
+    activation_token: Option<String>,
+) {
+    if let Some(token) = activation_token {
+        if let Err(err) = notifier_item_proxy
+            .provide_xdg_activation_token(&token)
+            .await
+        {
+            tracing::debug!("Failed to provide xdg activation token: {err}");
+        }
+    }
+}
+
+// Find the interface declaring `property` as writable in the introspection xml of an item
+fn writable_property_interface(introspection: &str, property: &str) -> Option<String> {
+    let mut interface = None;
+
+    for tag in introspection.split('<').filter_map(xml_start_tag) {
+        let attribute = |name: &str| {
+            tag.attributes
+                .iter()
+                .find(|(attribute, _)| *attribute == name)
+                .map(|(_, value)| *value)
+        };
+
+        match tag.name {
+            "interface" => interface = attribute("name"),
+            "property" => {
+                let writable = matches!(attribute("access"), Some("readwrite" | "write"));
+                if writable && attribute("name") == Some(property) {
+                    return interface.map(str::to_string);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+struct XmlTag<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, &'a str)>,
+}
+
+// Parse the start tag at the beginning of `text`, the content following a `<`. Attributes may be
+// single or double quoted and separated by any whitespace, `None` for malformed tags.
+fn xml_start_tag(text: &str) -> Option<XmlTag<'_>> {
+    let tag = &text[..text.find('>')?];
+    let name_end = tag
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or(tag.len());
+    let (name, mut rest) = tag.split_at(name_end);
+
+    let mut attributes = vec![];
+    while let Some(equals) = rest.find('=') {
+        let attribute = rest[..equals].trim();
+        rest = rest[equals + 1..].trim_start();
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        rest = &rest[1..];
+        let end = rest.find(quote)?;
+        attributes.push((attribute, &rest[..end]));
+        rest = &rest[end + 1..];
+    }
+
+    Some(XmlTag { name, attributes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg type="s" name="interface_name" direction="in"/>
+    </method>
+  </interface>
+  <interface name="org.kde.StatusNotifierItem">
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="readwrite"/>
+    <property name="Status" type="s" access="write"/>
+  </interface>
+  <interface name='com.canonical.indicator.application'>
+    <property
+        name='XAyatanaLabel'
+        type='s'
+        access = 'readwrite' />
+  </interface>
+</node>"#;
+
+    #[test]
+    fn should_find_readwrite_properties() {
+        assert_eq!(
+            writable_property_interface(INTROSPECTION, "Title").as_deref(),
+            Some("org.kde.StatusNotifierItem")
+        );
+    }
+
+    #[test]
+    fn should_find_write_only_properties() {
+        assert_eq!(
+            writable_property_interface(INTROSPECTION, "Status").as_deref(),
+            Some("org.kde.StatusNotifierItem")
+        );
+    }
+
+    #[test]
+    fn should_ignore_read_only_and_unknown_properties() {
+        assert_eq!(writable_property_interface(INTROSPECTION, "Id"), None);
+        assert_eq!(writable_property_interface(INTROSPECTION, "Menu"), None);
+    }
+
+    #[test]
+    fn should_parse_single_quoted_attributes_across_lines() {
+        assert_eq!(
+            writable_property_interface(INTROSPECTION, "XAyatanaLabel").as_deref(),
+            Some("com.canonical.indicator.application")
+        );
+    }
+
+    #[test]
+    fn should_reject_malformed_tags() {
+        assert!(xml_start_tag(r#"property name="Title"/>"#).is_some());
+        assert!(xml_start_tag(r#"property name="Title>"#).is_none());
+        assert!(xml_start_tag(r#"property name="Title""#).is_none());
+        assert!(xml_start_tag("property name=Title>").is_none());
+    }
+}
@@ -0,0 +1,41 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::WatcherEvent;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+
+/// A [`Stream`] of the watcher lifecycle events, see [`crate::StatusNotifierWatcher::events`].
+/// The stream ends once the watcher and its tasks have stopped.
+#[derive(Debug)]
+pub struct WatcherEventStream {
+    events: BroadcastStream<WatcherEvent>,
+}
+
+impl WatcherEventStream {
+    pub(crate) fn new(rx: broadcast::Receiver<WatcherEvent>) -> Self {
+        WatcherEventStream {
+            events: BroadcastStream::new(rx),
+        }
+    }
+}
+
+impl Stream for WatcherEventStream {
+    type Item = Result<WatcherEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let event = match ready!(Pin::new(&mut self.events).poll_next(cx)) {
+            None => None,
+            Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                Some(Err(StatusNotifierWatcherError::BroadCastRecvError(
+                    broadcast::error::RecvError::Lagged(skipped),
+                )))
+            }
+            Some(Ok(event)) => Some(Ok(event)),
+        };
+
+        Poll::Ready(event)
+    }
+}
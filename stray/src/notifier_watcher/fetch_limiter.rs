@@ -0,0 +1,22 @@
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Limit the number of concurrent property and layout fetches across all watchers,
+// see `StatusNotifierWatcherBuilder::max_concurrent_fetches`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FetchLimiter(Option<Arc<Semaphore>>);
+
+impl FetchLimiter {
+    pub(crate) fn new(max_fetches: usize) -> Self {
+        FetchLimiter(Some(Arc::new(Semaphore::new(max_fetches.max(1)))))
+    }
+
+    // Wait for a fetch slot, the slot is released when the returned permit is dropped
+    pub(crate) async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.0 {
+            // The semaphore is never closed
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::dbus::forwarded_item_service::ForwardedItem;
+use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
+use crate::error::Result;
+use crate::message::tray::StatusNotifierItem;
+use crate::message::{ItemAddress, NotifierItemCommand};
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::notifier_watcher::tasks::WatcherTasks;
+use crate::NotifierItemMessage;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use zbus::{Connection, ConnectionBuilder};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+
+// Mirror the items watched by stray on the StatusNotifierWatcher of another bus, ex: the outer
+// session of a nested compositor. Each item gets its own connection to the other bus, dropping it
+// lets the other watcher notice the removal like it would for any application leaving the bus.
+pub(crate) struct Forwarder {
+    // The address of the other bus, ex: `unix:path=/run/user/1000/bus`
+    bus_address: String,
+    commands: mpsc::Sender<NotifierItemCommand>,
+    registry: RegistryHandle,
+    tasks: WatcherTasks,
+    items: HashMap<ItemAddress, Forwarded>,
+    // Connections to the other bus are set up in their own task so a slow bus doesn't stall
+    // the forwarding of the other items
+    connected_tx: mpsc::Sender<(ItemAddress, Result<Connection>)>,
+    connected_rx: mpsc::Receiver<(ItemAddress, Result<Connection>)>,
+}
+
+enum Forwarded {
+    // Waiting for the connection to the other bus, holds the latest state of the item
+    Connecting(Arc<StatusNotifierItem>),
+    Connected(Connection),
+}
+
+impl Forwarder {
+    pub(crate) fn new(
+        bus_address: String,
+        commands: mpsc::Sender<NotifierItemCommand>,
+        registry: RegistryHandle,
+        tasks: WatcherTasks,
+    ) -> Self {
+        let (connected_tx, connected_rx) = mpsc::channel(8);
+        Forwarder {
+            bus_address,
+            commands,
+            registry,
+            tasks,
+            items: HashMap::new(),
+            connected_tx,
+            connected_rx,
+        }
+    }
+
+    pub(crate) async fn run(mut self, mut messages: broadcast::Receiver<NotifierItemMessage>) {
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Ok(message) => self.handle(message).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Forwarder lagged behind, skipped {skipped} messages, resyncing");
+                        self.resync().await;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                Some((address, connection)) = self.connected_rx.recv() => {
+                    self.connected(address, connection).await;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: NotifierItemMessage) {
+        match message {
+            NotifierItemMessage::Update { address, item, .. } => self.forward(address, item).await,
+            NotifierItemMessage::Remove { address, .. }
+            | NotifierItemMessage::Rejected { address, .. } => {
+                self.items.remove(&address);
+            }
+            NotifierItemMessage::Reset { .. } => self.items.clear(),
+            NotifierItemMessage::CommandFailed { .. }
+            | NotifierItemMessage::Error { .. }
+            | NotifierItemMessage::WatcherLost { .. } => {}
+        }
+    }
+
+    // The missed messages may have removed items, forward the current state of the registry
+    // and drop the items it no longer holds
+    async fn resync(&mut self) {
+        let snapshot = match self.registry.snapshot().await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                tracing::warn!("Failed to resync the forwarded items: {err}");
+                return;
+            }
+        };
+
+        self.items
+            .retain(|address, _| snapshot.contains_key(address));
+
+        for (address, entry) in snapshot {
+            self.forward(address, entry.item).await;
+        }
+    }
+
+    async fn forward(&mut self, address: ItemAddress, item: Arc<StatusNotifierItem>) {
+        match self.items.get_mut(&address) {
+            Some(Forwarded::Connected(connection)) => {
+                if let Err(err) = update(connection, item).await {
+                    tracing::warn!("Failed to forward {address} to {}: {err}", self.bus_address);
+                }
+            }
+            Some(Forwarded::Connecting(latest)) => *latest = item,
+            None => {
+                self.items
+                    .insert(address.clone(), Forwarded::Connecting(item.clone()));
+
+                let bus_address = self.bus_address.clone();
+                let commands = self.commands.clone();
+                let connected = self.connected_tx.clone();
+                self.tasks.spawn(async move {
+                    let connection = connect(&bus_address, &address, item, commands).await;
+                    let _ = connected.send((address, connection)).await;
+                });
+            }
+        }
+    }
+
+    // Keep the connection of an item unless it was removed in the meantime, the item may have
+    // changed while connecting
+    async fn connected(&mut self, address: ItemAddress, connection: Result<Connection>) {
+        let latest = match self.items.get(&address) {
+            Some(Forwarded::Connecting(latest)) => latest.clone(),
+            _ => return,
+        };
+
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(err) => {
+                tracing::warn!("Failed to forward {address} to {}: {err}", self.bus_address);
+                self.items.remove(&address);
+                return;
+            }
+        };
+
+        tracing::info!("Forwarding {address} to {}", self.bus_address);
+        if let Err(err) = update(&connection, latest).await {
+            tracing::warn!("Failed to forward {address} to {}: {err}", self.bus_address);
+        }
+
+        self.items.insert(address, Forwarded::Connected(connection));
+    }
+}
+
+// Serve the item on its own connection to the other bus and register it to the watcher there
+async fn connect(
+    bus_address: &str,
+    address: &ItemAddress,
+    item: Arc<StatusNotifierItem>,
+    commands: mpsc::Sender<NotifierItemCommand>,
+) -> Result<Connection> {
+    let forwarded = ForwardedItem::new(address.clone(), item, commands);
+    let connection = ConnectionBuilder::address(bus_address)?
+        .serve_at(ITEM_PATH, forwarded)?
+        .build()
+        .await?;
+
+    StatusNotifierWatcherProxy::new(&connection)
+        .await?
+        .register_status_notifier_item(ITEM_PATH)
+        .await?;
+
+    Ok(connection)
+}
+
+async fn update(connection: &Connection, item: Arc<StatusNotifierItem>) -> Result<()> {
+    let forwarded = connection
+        .object_server()
+        .interface::<_, ForwardedItem>(ITEM_PATH)
+        .await?;
+    forwarded
+        .get_mut()
+        .await
+        .update(item, forwarded.signal_context())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
+use crate::error::Result;
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::notifier_watcher::signal_router::SignalRouterHandle;
+use crate::notifier_watcher::{watcher_proxy, WatcherMode};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use zbus::fdo::DBusProxy;
+use zbus::names::BusName;
+use zbus::Connection;
+
+// Periodically remove the items whose owner left the bus without us noticing,
+// see `StatusNotifierWatcherBuilder::health_check_interval`
+pub(crate) struct HealthCheck {
+    connection: Connection,
+    registry: RegistryHandle,
+    router: SignalRouterHandle,
+    call_timeout: Option<Duration>,
+    watcher_name: String,
+    mode: watch::Receiver<WatcherMode>,
+}
+
+impl HealthCheck {
+    pub(crate) fn new(
+        connection: Connection,
+        registry: RegistryHandle,
+        router: SignalRouterHandle,
+        call_timeout: Option<Duration>,
+        watcher_name: String,
+        mode: watch::Receiver<WatcherMode>,
+    ) -> Self {
+        HealthCheck {
+            connection,
+            registry,
+            router,
+            call_timeout,
+            watcher_name,
+            mode,
+        }
+    }
+
+    pub(crate) async fn run(self, interval: Duration) -> Result<()> {
+        let dbus_proxy = DBusProxy::new(&self.connection).await?;
+        let watcher_proxy = watcher_proxy(&self.connection, &self.watcher_name).await?;
+
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, items were just fetched
+        ticks.tick().await;
+
+        loop {
+            ticks.tick().await;
+            self.sweep(&dbus_proxy, &watcher_proxy).await?;
+        }
+    }
+
+    async fn sweep(
+        &self,
+        dbus_proxy: &DBusProxy<'_>,
+        watcher_proxy: &StatusNotifierWatcherProxy<'_>,
+    ) -> Result<()> {
+        let mut dead = vec![];
+        for address in self.registry.snapshot().await?.into_keys() {
+            let Ok(name) = BusName::try_from(address.destination()) else {
+                continue;
+            };
+
+            // Items which do not answer are left alone, only a missing owner is conclusive
+            match with_timeout(self.call_timeout, dbus_proxy.name_has_owner(name)).await {
+                Ok(false) => dead.push(address),
+                Ok(true) => {}
+                Err(err) => tracing::debug!("Failed to check the owner of {address}: {err}"),
+            }
+        }
+
+        if dead.is_empty() {
+            return Ok(());
+        }
+
+        // Unregister the items from our watcher service first, it removes them from the registry.
+        // The watcher of another process is left alone.
+        for address in &dead {
+            tracing::info!("Removing {address}, its owner left the bus");
+            if *self.mode.borrow() == WatcherMode::HostOnly {
+                continue;
+            }
+
+            if let Err(err) = with_timeout(
+                self.call_timeout,
+                watcher_proxy.unregister_status_notifier_item(address.as_str()),
+            )
+            .await
+            {
+                tracing::warn!("Failed to unregister {address}: {err}");
+            }
+        }
+
+        // Items found through `StatusNotifierWatcher::refresh` may not be known to the service
+        let remaining = self.registry.snapshot().await?;
+        for address in dead {
+            if remaining.contains_key(&address) {
+                self.registry.item_removed(&address).await?;
+            }
+
+            self.router.unsubscribe(&address.notifier_address()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::menu::MenuHandle;
+    use crate::message::ItemAddress;
+    use crate::notifier_watcher::builder::WatcherConfig;
+    use crate::notifier_watcher::registry::tests::item;
+    use crate::notifier_watcher::registry::Registry;
+    use crate::notifier_watcher::signal_router::SignalRouter;
+    use crate::NotifierItemMessage;
+    use std::sync::Arc;
+    use tokio::sync::broadcast;
+    use zbus::{dbus_interface, ConnectionBuilder, Guid};
+
+    const ALIVE: &str = ":1.52/StatusNotifierItem";
+    const DEAD: &str = ":1.53/StatusNotifierItem";
+
+    // Stands for the bus daemon, only the owner of `ALIVE` is still on the bus
+    struct Bus;
+
+    #[dbus_interface(name = "org.freedesktop.DBus")]
+    impl Bus {
+        fn name_has_owner(&self, name: &str) -> bool {
+            name == ":1.52"
+        }
+    }
+
+    // A connection whose peer answers the calls made to the bus daemon
+    async fn bus_connection() -> (Connection, Connection) {
+        let (server, client) = tokio::net::UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        tokio::try_join!(
+            ConnectionBuilder::unix_stream(client).p2p().build(),
+            ConnectionBuilder::unix_stream(server)
+                .server(&guid)
+                .p2p()
+                .serve_at("/org/freedesktop/DBus", Bus)
+                .unwrap()
+                .build(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_remove_the_items_whose_owner_left_the_bus() {
+        let (connection, _bus) = bus_connection().await;
+        let (sender, mut messages) = broadcast::channel(8);
+        let (registry, handle, events) = Registry::new(Arc::new(WatcherConfig::default()), sender);
+        tokio::spawn(registry.run(events));
+        let (_router, router, _router_events) = SignalRouter::new(connection.clone());
+
+        for service in [ALIVE, DEAD] {
+            let address = ItemAddress::new(service).unwrap();
+            let menu = MenuHandle::offline(address.clone(), None, None);
+            handle
+                .item_updated(&address, item(service), menu)
+                .await
+                .unwrap();
+            assert!(matches!(
+                messages.recv().await,
+                Ok(NotifierItemMessage::Update { .. })
+            ));
+        }
+
+        // The watcher of another process is left alone, the registry removes the item itself
+        let (_mode, mode) = watch::channel(WatcherMode::HostOnly);
+        let health_check = HealthCheck::new(
+            connection,
+            handle.clone(),
+            router,
+            None,
+            "org.kde.StatusNotifierWatcher".to_string(),
+            mode,
+        );
+        tokio::spawn(health_check.run(Duration::from_millis(10)));
+
+        let Ok(NotifierItemMessage::Remove { address, .. }) = messages.recv().await else {
+            panic!("expected the removal of the dead item");
+        };
+        assert_eq!(address.as_str(), DEAD);
+        assert_eq!(
+            handle
+                .snapshot()
+                .await
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![ItemAddress::new(ALIVE).unwrap()]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use crate::error::StatusNotifierWatcherError;
+#[cfg(feature = "icon-loader")]
+use crate::icon_loader::IconLookup;
+use crate::message::tray::{IconPixmap, StatusNotifierItem};
+use crate::message::ItemAddress;
+use crate::notifier_watcher::registry::RegistryHandle;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::mpsc;
+
+// Pixmaps are set by the items, larger ones are downscaled with the `resize` feature or skipped,
+// so an item can't make the watcher encode a huge image
+const MAX_ICON_FILE_SIZE: u32 = 512;
+
+// Write the icon of every item to a file, for text-based bars (eww, yambar) which can only
+// display image files. Pixmaps are encoded to PNG, named icons are resolved in the icon themes
+// when the `icon-loader` feature is enabled. The directory is removed when the watcher stops.
+#[derive(Debug)]
+pub(crate) struct IconFiles {
+    dir: PathBuf,
+    size: u32,
+    scale: u32,
+    #[cfg(feature = "icon-loader")]
+    lookup: IconLookup,
+    // The pixmap file written for each item address, removed once replaced
+    written: HashMap<ItemAddress, PathBuf>,
+}
+
+impl IconFiles {
+    pub(crate) fn new(watcher_name: &str, size: u32, scale: u32) -> Self {
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+
+        IconFiles {
+            dir: runtime_dir
+                .join(format!("stray-{}", std::process::id()))
+                .join(watcher_name),
+            size,
+            scale,
+            #[cfg(feature = "icon-loader")]
+            lookup: IconLookup::default()
+                .size(size.min(u16::MAX as u32) as u16)
+                .scale(scale.min(u16::MAX as u32) as u16),
+            written: HashMap::new(),
+        }
+    }
+
+    // Resolve named icons with `lookup`, so they follow its theme
+    #[cfg(feature = "icon-loader")]
+    pub(crate) fn with_lookup(mut self, lookup: IconLookup) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    // The file to display for the item, `None` if it has no usable icon
+    pub(crate) fn materialize(
+        &mut self,
+        address: &ItemAddress,
+        item: &StatusNotifierItem,
+    ) -> Option<PathBuf> {
+        #[cfg(feature = "icon-loader")]
+        if let Some(path) = self.lookup.find_item_icon(item) {
+            self.remove(address);
+            return Some(path);
+        }
+
+        let pixmap = select_pixmap(item.icon_pixmap.as_deref()?, self.size, self.scale)?;
+        let path = self.dir.join(file_name(address, &pixmap));
+
+        if self.written.get(address) == Some(&path) && path.is_file() {
+            return Some(path);
+        }
+
+        if let Err(err) = self.write(&path, &pixmap) {
+            tracing::warn!("Failed to write the icon of {address}: {err}");
+            return None;
+        }
+
+        self.remove(address);
+        self.written.insert(address.clone(), path.clone());
+        Some(path)
+    }
+
+    // Delete the pixmap file written for the item, if any
+    pub(crate) fn remove(&mut self, address: &ItemAddress) {
+        if let Some(path) = self.written.remove(address) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for (_, path) in self.written.drain() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    // Write to a temporary file first, readers polling the directory never see a partial image
+    fn write(&self, path: &Path, pixmap: &IconPixmap) -> crate::error::Result<()> {
+        std::fs::create_dir_all(&self.dir).map_err(StatusNotifierWatcherError::IconFileError)?;
+        let partial = path.with_extension("png.part");
+        pixmap.write_png(&partial)?;
+        std::fs::rename(&partial, path).map_err(StatusNotifierWatcherError::IconFileError)?;
+        Ok(())
+    }
+
+    // Materialize the icons off the registry, see `IconWorker`
+    pub(crate) fn into_worker(self, registry: RegistryHandle) -> (IconFilesHandle, IconWorker) {
+        let (requests, received) = mpsc::unbounded_channel();
+        let worker = IconWorker {
+            files: Arc::new(Mutex::new(self)),
+            requests: received,
+            registry,
+        };
+
+        (IconFilesHandle(requests), worker)
+    }
+}
+
+#[derive(Debug)]
+enum IconRequest {
+    Materialize {
+        address: ItemAddress,
+        item: Box<StatusNotifierItem>,
+        generation: u64,
+    },
+    Remove {
+        address: ItemAddress,
+    },
+    Clear,
+}
+
+// Sends the icons to materialize to the `IconWorker`, never blocks the registry
+#[derive(Debug, Clone)]
+pub(crate) struct IconFilesHandle(mpsc::UnboundedSender<IconRequest>);
+
+impl IconFilesHandle {
+    // The resulting file is reported with `RegistryEvent::IconMaterialized`, along with `generation`
+    pub(crate) fn materialize(
+        &self,
+        address: &ItemAddress,
+        item: &StatusNotifierItem,
+        generation: u64,
+    ) {
+        let _ = self.0.send(IconRequest::Materialize {
+            address: address.clone(),
+            item: Box::new(item.clone()),
+            generation,
+        });
+    }
+
+    pub(crate) fn remove(&self, address: &ItemAddress) {
+        let _ = self.0.send(IconRequest::Remove {
+            address: address.clone(),
+        });
+    }
+
+    pub(crate) fn clear(&self) {
+        let _ = self.0.send(IconRequest::Clear);
+    }
+}
+
+// Encoding pixmaps and looking icons up in the themes hit the disk, the requests are handled
+// in order on the blocking thread pool and the files reported back to the registry
+#[derive(Debug)]
+pub(crate) struct IconWorker {
+    files: Arc<Mutex<IconFiles>>,
+    requests: mpsc::UnboundedReceiver<IconRequest>,
+    registry: RegistryHandle,
+}
+
+impl IconWorker {
+    pub(crate) async fn run(mut self) {
+        while let Some(request) = self.requests.recv().await {
+            let files = self.files.clone();
+            let handled = tokio::task::spawn_blocking(move || {
+                // The files are left consistent by a panicking request
+                let mut files = files.lock().unwrap_or_else(PoisonError::into_inner);
+                match request {
+                    IconRequest::Materialize {
+                        address,
+                        item,
+                        generation,
+                    } => {
+                        let icon_file = files.materialize(&address, &item);
+                        Some((address, icon_file, generation))
+                    }
+                    IconRequest::Remove { address } => {
+                        files.remove(&address);
+                        None
+                    }
+                    IconRequest::Clear => {
+                        files.clear();
+                        None
+                    }
+                }
+            })
+            .await;
+
+            let (address, icon_file, generation) = match handled {
+                Ok(Some(materialized)) => materialized,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!("Failed to materialize an icon: {err}");
+                    continue;
+                }
+            };
+
+            if self
+                .registry
+                .icon_materialized(&address, icon_file, generation)
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for IconFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+        // Shared by the watchers of the process, only removed by the last one
+        if let Some(process_dir) = self.dir.parent() {
+            let _ = std::fs::remove_dir(process_dir);
+        }
+    }
+}
+
+// The pixmap to encode for the icon size, `None` if the item has no valid pixmap
+fn select_pixmap(pixmaps: &[IconPixmap], size: u32, scale: u32) -> Option<IconPixmap> {
+    let valid: Vec<IconPixmap> = pixmaps
+        .iter()
+        .filter(|pixmap| pixmap.is_valid())
+        .cloned()
+        .collect();
+    let pixmap = IconPixmap::select_best_scaled(&valid, size, scale)?.clone();
+    let max_size = MAX_ICON_FILE_SIZE.max(size.saturating_mul(scale.max(1)));
+
+    IconPixmap::cap_size(vec![pixmap], max_size).pop()
+}
+
+// Files are named after the content of the pixmap, so a changed icon gets a new path
+// and consumers caching images by path pick the change up
+fn file_name(address: &ItemAddress, pixmap: &IconPixmap) -> String {
+    let mut hasher = DefaultHasher::new();
+    (pixmap.width, pixmap.height, &pixmap.pixels[..]).hash(&mut hasher);
+    let address: String = address
+        .as_str()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    format!("{address}-{:016x}.png", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixmap(size: i32, len: usize) -> IconPixmap {
+        IconPixmap {
+            width: size,
+            height: size,
+            pixels: vec![0; len].into(),
+        }
+    }
+
+    #[test]
+    fn should_skip_pixmaps_not_matching_their_size() {
+        let truncated = pixmap(32, 4);
+        let valid = pixmap(16, 16 * 16 * 4);
+
+        let selected = select_pixmap(&[truncated.clone(), valid], 32, 1);
+        assert_eq!(selected.map(|pixmap| pixmap.width), Some(16));
+        assert!(select_pixmap(&[truncated], 32, 1).is_none());
+        assert!(select_pixmap(&[pixmap(i32::MAX, 4)], 32, 1).is_none());
+    }
+
+    #[test]
+    fn should_cap_the_size_of_encoded_pixmaps() {
+        let size = MAX_ICON_FILE_SIZE as i32 * 2;
+        let huge = pixmap(size, (size * size * 4) as usize);
+
+        let selected = select_pixmap(&[huge], 24, 1);
+
+        #[cfg(feature = "resize")]
+        assert_eq!(
+            selected.map(|pixmap| pixmap.width),
+            Some(MAX_ICON_FILE_SIZE as i32)
+        );
+        #[cfg(not(feature = "resize"))]
+        assert!(selected.is_none());
+    }
+}
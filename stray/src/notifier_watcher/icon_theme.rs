@@ -0,0 +1,68 @@
+use crate::dbus::settings_portal_proxy::SettingsProxy;
+use crate::error::Result;
+use crate::icon_loader::IconLookup;
+use crate::notifier_watcher::registry::RegistryHandle;
+use tokio_stream::StreamExt;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+const INTERFACE_NAMESPACE: &str = "org.gnome.desktop.interface";
+const ICON_THEME_KEY: &str = "icon-theme";
+
+// Keep the theme of `lookup` in sync with the desktop icon theme, read from the settings portal
+// which relays gsettings and the XSettings of most desktops. Every item is broadcast again
+// on change so hosts resolve their icons with the new theme.
+pub(crate) async fn follow_icon_theme(lookup: IconLookup, registry: RegistryHandle) {
+    if let Err(err) = watch_settings(&lookup, &registry).await {
+        tracing::warn!("Stopped following the desktop icon theme: {err}");
+    }
+}
+
+async fn watch_settings(lookup: &IconLookup, registry: &RegistryHandle) -> Result<()> {
+    // The portal always lives on the session bus, whichever bus the watcher is served on
+    let connection = Connection::session().await?;
+    let settings = SettingsProxy::new(&connection).await?;
+    let mut changes = settings.receive_setting_changed().await?;
+
+    match settings.read(INTERFACE_NAMESPACE, ICON_THEME_KEY).await {
+        Ok(value) => {
+            if let Some(theme) = theme_name(&value) {
+                apply_theme(lookup, registry, theme).await?;
+            }
+        }
+        Err(err) => tracing::debug!("Failed to read the desktop icon theme: {err}"),
+    }
+
+    while let Some(change) = changes.next().await {
+        let args = change.args()?;
+        if args.namespace() != &INTERFACE_NAMESPACE || args.key() != &ICON_THEME_KEY {
+            continue;
+        }
+
+        if let Some(theme) = theme_name(args.value()) {
+            apply_theme(lookup, registry, theme).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_theme(lookup: &IconLookup, registry: &RegistryHandle, theme: String) -> Result<()> {
+    if lookup.current_theme().as_deref() == Some(theme.as_str()) {
+        return Ok(());
+    }
+
+    tracing::info!("Desktop icon theme changed to {theme}");
+    lookup.set_theme(Some(theme));
+
+    registry.republish().await
+}
+
+// Older portals wrap the value of `Read` in an extra variant
+fn theme_name(value: &Value<'_>) -> Option<String> {
+    match value {
+        Value::Str(theme) if !theme.is_empty() => Some(theme.to_string()),
+        Value::Value(value) => theme_name(value),
+        _ => None,
+    }
+}
@@ -0,0 +1,230 @@
+use crate::dbus::dbusmenu_proxy::DBusMenuProxy;
+use crate::dbus::notifier_item_proxy::StatusNotifierItemProxy;
+use crate::error::{ItemContext, Result, StatusNotifierWatcherError};
+use crate::message::menu::{MenuHandle, TrayMenu};
+use crate::message::tray::ActivationPolicy;
+use crate::message::{ItemAddress, NotifierItemCommand, ScrollOrientation};
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::dispatcher::{self, DispatchRequest};
+use crate::notifier_watcher::item_watcher::ITEM_INTERFACES;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::{InterfaceName, StatusNotifierItem};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use zbus::fdo::PropertiesProxy;
+use zbus::Connection;
+
+/// A handle to a single [`StatusNotifierItem`], obtained with [`crate::StatusNotifierWatcher::item`]
+/// or from a notifier host. Commands are routed through the watcher dispatcher and each method
+/// returns once the item handled the call.
+#[derive(Debug, Clone)]
+pub struct ItemHandle {
+    address: ItemAddress,
+    connection: Connection,
+    config: Arc<WatcherConfig>,
+    cmd_tx: mpsc::Sender<DispatchRequest>,
+    registry: RegistryHandle,
+}
+
+impl ItemHandle {
+    pub(crate) fn new(
+        address: ItemAddress,
+        connection: Connection,
+        config: Arc<WatcherConfig>,
+        cmd_tx: mpsc::Sender<DispatchRequest>,
+        registry: RegistryHandle,
+    ) -> Self {
+        ItemHandle {
+            address,
+            connection,
+            config,
+            cmd_tx,
+            registry,
+        }
+    }
+
+    /// The dbus address of the item, as found in [`crate::NotifierItemMessage::Update`].
+    pub fn address(&self) -> &ItemAddress {
+        &self.address
+    }
+
+    /// Trigger the application primary action, see [`NotifierItemCommand::Activate`].
+    pub async fn activate(&self, x: i32, y: i32) -> Result<()> {
+        self.send(NotifierItemCommand::Activate {
+            notifier_address: self.address.clone(),
+            x,
+            y,
+            activation_token: None,
+        })
+        .await
+    }
+
+    /// Same as [`ItemHandle::activate`], providing the XDG activation token obtained from the
+    /// compositor so the application can raise its window on Wayland.
+    pub async fn activate_with_token(&self, x: i32, y: i32, token: &str) -> Result<()> {
+        self.send(NotifierItemCommand::Activate {
+            notifier_address: self.address.clone(),
+            x,
+            y,
+            activation_token: Some(token.to_string()),
+        })
+        .await
+    }
+
+    /// Do what a primary click on the tray icon should do according to the item
+    /// [`ActivationPolicy`]: the item is activated unless `ItemIsMenu` is set, in which case the
+    /// application is asked to show its own menu if it exposes no dbusmenu. When
+    /// [`ActivationPolicy::OpenMenu`] is returned nothing is sent and the host should open the menu.
+    pub async fn activate_default(&self, x: i32, y: i32) -> Result<ActivationPolicy> {
+        let policy = self.properties().await?.activation_policy();
+        if policy != ActivationPolicy::OpenMenu {
+            self.send(NotifierItemCommand::ItemClicked {
+                notifier_address: self.address.clone(),
+                x,
+                y,
+                activation_token: None,
+            })
+            .await?;
+        }
+
+        Ok(policy)
+    }
+
+    /// Forward a mouse wheel event to the item, see [`NotifierItemCommand::Scroll`].
+    pub async fn scroll(&self, delta: i32, orientation: ScrollOrientation) -> Result<()> {
+        self.send(NotifierItemCommand::Scroll {
+            notifier_address: self.address.clone(),
+            delta,
+            orientation,
+        })
+        .await
+    }
+
+    /// Returns the raw proxy of the item `org.kde.StatusNotifierItem` interface, to call methods or
+    /// read properties stray doesn't model. Calls made through it are not bounded by the configured
+    /// call timeout.
+    pub async fn raw_item_proxy(&self) -> Result<StatusNotifierItemProxy<'static>> {
+        let address = NotifierAddress::resolve(
+            &self.connection,
+            &self.config.watcher_name,
+            &self.address,
+            self.config.call_timeout,
+        )
+        .await?;
+
+        Ok(StatusNotifierItemProxy::builder(&self.connection)
+            .destination(address.destination)?
+            .path(address.path)?
+            .build()
+            .await?)
+    }
+
+    /// Returns the raw proxy of the item `com.canonical.dbusmenu` interface, or `None` if the
+    /// item does not export any menu.
+    pub async fn raw_menu_proxy(&self) -> Result<Option<DBusMenuProxy<'static>>> {
+        let item_proxy = self.raw_item_proxy().await?;
+        let menu_path = with_timeout(self.config.call_timeout, item_proxy.menu())
+            .await
+            .item_context(&self.address, "Get")?;
+
+        if menu_path.as_str() == "/" || menu_path.as_str() == "/NO_DBUSMENU" {
+            return Ok(None);
+        }
+
+        let destination = item_proxy.destination().to_owned();
+        let proxy = DBusMenuProxy::builder(&self.connection)
+            .destination(destination)?
+            .path(menu_path)?
+            .build()
+            .await?;
+
+        Ok(Some(proxy))
+    }
+
+    /// Fetch the current properties of the item.
+    pub async fn properties(&self) -> Result<StatusNotifierItem> {
+        let props = with_timeout(self.config.call_timeout, async {
+            let address = NotifierAddress::resolve(
+                &self.connection,
+                &self.config.watcher_name,
+                &self.address,
+                self.config.call_timeout,
+            )
+            .await?;
+            let dbus_properties_proxy = PropertiesProxy::builder(&self.connection)
+                .destination(address.destination.as_str())?
+                .path(address.path.as_str())?
+                .build()
+                .await?;
+
+            // Fall back to the freedesktop interface name when the item does not use the KDE one
+            let mut first = None;
+            for interface in ITEM_INTERFACES {
+                let interface = InterfaceName::from_static_str(interface)?;
+                match dbus_properties_proxy.get_all(interface).await {
+                    Ok(props) if !props.is_empty() => return Ok(props),
+                    result => {
+                        first.get_or_insert(result);
+                    }
+                }
+            }
+
+            first
+                .unwrap_or_else(|| Ok(HashMap::new()))
+                .map_err(StatusNotifierWatcherError::from)
+        })
+        .await
+        .item_context(&self.address, "GetAll")?;
+
+        let (item, _) = StatusNotifierItem::parse(props)
+            .map_err(|err| StatusNotifierWatcherError::InvalidItem(err.to_string()))?;
+
+        Ok(item)
+    }
+
+    /// Fetch the menu of the item, `None` if it does not expose any.
+    pub async fn menu(&self) -> Result<Option<TrayMenu>> {
+        let item = self.properties().await?;
+        MenuHandle::new(
+            self.address.clone(),
+            item.menu,
+            self.config.menu_depth(&item.id),
+            self.config.max_menu_children,
+            self.config.call_timeout,
+            self.connection.clone(),
+        )
+        .load()
+        .await
+    }
+
+    /// Activate the menu item `id`, see [`NotifierItemCommand::MenuItemClicked`].
+    pub async fn click_menu_item(&self, id: i32) -> Result<()> {
+        let item = self.properties().await?;
+        let menu_path = item
+            .menu
+            .ok_or_else(|| StatusNotifierWatcherError::NoMenu(self.address.to_string()))?;
+
+        self.send(NotifierItemCommand::MenuItemClicked {
+            submenu_id: id,
+            menu_path,
+            notifier_address: self.address.clone(),
+        })
+        .await
+    }
+
+    /// Toggle a [`crate::message::menu::ToggleType::Checkmark`] menu item or select a
+    /// [`crate::message::menu::ToggleType::Radio`] one. Once the application handled the click,
+    /// the new [`crate::message::menu::ToggleState`] is published right away and replaced by the
+    /// actual one on the next menu layout update. Items that can't be toggled are only clicked.
+    pub async fn toggle_menu_item(&self, id: i32) -> Result<()> {
+        self.click_menu_item(id).await?;
+        self.registry.menu_item_toggled(&self.address, id).await
+    }
+
+    async fn send(&self, command: NotifierItemCommand) -> Result<()> {
+        dispatcher::send_and_wait(&self.cmd_tx, command).await
+    }
+}
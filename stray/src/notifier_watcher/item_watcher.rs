@@ -0,0 +1,589 @@
+use crate::error::{ItemContext, Result};
+use crate::message::menu::MenuHandle;
+use crate::message::tray::{IconPixmap, ITEM_PROPERTIES};
+use crate::message::{Conformance, ItemAddress, MenuPath};
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::call_timeout::{skip_timeout, with_timeout};
+use crate::notifier_watcher::menu_watcher::MenuWatcher;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use crate::notifier_watcher::panic_guard::{catch_panic, next_restart_interval};
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::notifier_watcher::signal_router::{ItemSignals, SignalRouterHandle};
+use crate::{InterfaceName, StatusNotifierItem};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_stream::{StreamExt, StreamMap};
+use zbus::fdo::{PropertiesChanged, PropertiesProxy};
+use zbus::zvariant::{OwnedValue, Value};
+use zbus::{CacheProperties, Connection, PropertyStream, Proxy, ProxyBuilder};
+
+pub(crate) const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+// The interface name used by the freedesktop draft of the specification, some items only export this one
+pub(crate) const FREEDESKTOP_ITEM_INTERFACE: &str = "org.freedesktop.StatusNotifierItem";
+pub(crate) const ITEM_INTERFACES: [&str; 2] = [ITEM_INTERFACE, FREEDESKTOP_ITEM_INTERFACE];
+
+// The properties announced by each StatusNotifierItem signal which `StatusNotifierItem::parse`
+// reads, `NewStatus` carries its value. Signals announcing only unparsed properties, like the
+// overlay icon or the tooltip, don't trigger any call.
+fn signal_properties(signal: &str) -> Option<&'static [&'static str]> {
+    match signal {
+        "NewIcon" => Some(&["IconName", "IconPixmap", "IconAccessibleDesc"]),
+        "NewAttentionIcon" => Some(&["AttentionIconName"]),
+        "NewOverlayIcon" | "NewToolTip" => Some(&[]),
+        "NewTitle" => Some(&["Title"]),
+        "XAyatanaNewLabel" => Some(&["XAyatanaLabel", "XAyatanaLabelGuide"]),
+        _ => None,
+    }
+}
+
+// The proxies used to read the properties of an item. zbus keeps the property cache of `cached`
+// up to date from PropertiesChanged, the cache is populated with Properties.GetAll so it is `None`
+// when only some properties are fetched, see `WatcherConfig::fetch_all_properties`.
+// `properties` fetches the properties announced by the StatusNotifierItem signals, which don't
+// carry any value and are unknown to the cache.
+struct ItemProxies {
+    properties: PropertiesProxy<'static>,
+    cached: Option<Proxy<'static>>,
+}
+
+// Whether `name` is read by `StatusNotifierItem::parse` and wanted by the configuration
+fn is_parsed(config: &WatcherConfig, name: &str) -> bool {
+    ITEM_PROPERTIES.contains(&name) && config.wants_property(name)
+}
+
+// Watch the properties of a single StatusNotifierItem and report them to the registry,
+// the item menu is delegated to a `MenuWatcher` restarted whenever the menu path changes.
+pub(crate) struct ItemWatcher {
+    address: NotifierAddress,
+    // The key of the item in the registry, an application may own several items
+    item_address: ItemAddress,
+    connection: Connection,
+    config: Arc<WatcherConfig>,
+    registry: RegistryHandle,
+    router: SignalRouterHandle,
+    menu_watcher: Option<(MenuPath, JoinHandle<()>)>,
+    // Bumped by `StatusNotifierWatcher::refresh` to re-fetch the item and its menu
+    refresh: watch::Receiver<u64>,
+    // The item interface exported by the application, one of `ITEM_INTERFACES`
+    interface: &'static str,
+}
+
+impl ItemWatcher {
+    pub(crate) fn new(
+        address: NotifierAddress,
+        connection: Connection,
+        config: Arc<WatcherConfig>,
+        registry: RegistryHandle,
+        router: SignalRouterHandle,
+        refresh: watch::Receiver<u64>,
+    ) -> Self {
+        ItemWatcher {
+            item_address: ItemAddress::from_bus(&address),
+            address,
+            connection,
+            config,
+            registry,
+            router,
+            menu_watcher: None,
+            refresh,
+            interface: ITEM_INTERFACE,
+        }
+    }
+
+    // Spawn the watcher, the registry aborts it when the item is removed
+    pub(crate) async fn start(self) -> Result<()> {
+        let registry = self.registry.clone();
+        let item_address = self.item_address.clone();
+        let watcher = self.spawn();
+        registry.watcher_started(&item_address, watcher).await
+    }
+
+    // A panicking watcher is restarted with an exponential backoff
+    fn spawn(mut self) -> JoinHandle<()> {
+        self.config.tasks.clone().spawn(async move {
+            let item_address = self.item_address.clone();
+            let mut restart_interval = self.config.retry_interval;
+            loop {
+                let error = match catch_panic(self.run()).await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => {
+                        tracing::error!("Item watcher error, dbus-address={item_address}: {err:?}");
+                        let _ = self.registry.item_failed(&item_address, err).await;
+                        return;
+                    }
+                    Err(panic) => panic,
+                };
+
+                tracing::error!(
+                    "Item watcher panicked, dbus-address={item_address}, restarting in {restart_interval:?}: {error}"
+                );
+                self.stop_menu_watcher();
+                let _ = self
+                    .registry
+                    .item_failed(&item_address, format!("item watcher panicked: {error}"))
+                    .await;
+
+                tokio::time::sleep(restart_interval).await;
+                restart_interval = next_restart_interval(restart_interval);
+            }
+        })
+    }
+
+    // Listen for item signals and PropertiesChanged on DBus and send an update on change
+    async fn run(&mut self) -> Result<()> {
+        let (proxies, mut signals, props) = self.connect_with_retry().await?;
+        let result = self.watch(&proxies, &mut signals, props).await;
+
+        self.stop_menu_watcher();
+        result
+    }
+
+    // Applications may briefly disappear right after registering their item,
+    // retry connecting to the item with an exponential backoff before giving up
+    async fn connect_with_retry(
+        &mut self,
+    ) -> Result<(ItemProxies, ItemSignals, HashMap<String, OwnedValue>)> {
+        let mut attempt = 1;
+        let mut interval = self.config.retry_interval;
+        loop {
+            match self.connect().await {
+                Ok(connected) => return Ok(connected),
+                Err(err) if attempt < self.config.retry_attempts => {
+                    tracing::debug!(
+                        "Failed to connect to {} (attempt {attempt}), retrying in {interval:?}: {err}",
+                        self.address.destination
+                    );
+                    tokio::time::sleep(interval).await;
+                    interval = next_restart_interval(interval);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Build the item proxy, subscribe to its signals and fetch its properties
+    async fn connect(&mut self) -> Result<(ItemProxies, ItemSignals, HashMap<String, OwnedValue>)> {
+        // Connect to DBus.Properties, signals are received through the signal router
+        // so the proxy does not need to subscribe to anything
+        let dbus_properties_proxy = PropertiesProxy::builder(&self.connection)
+            .destination(self.address.destination.clone())?
+            .path(self.address.path.clone())?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+
+        // Subscribe before fetching the properties so no change is missed
+        let signals = with_timeout(
+            self.config.call_timeout,
+            self.router.subscribe(&self.address),
+        )
+        .await?;
+
+        let (cached, props) = self.detect_interface(&dbus_properties_proxy).await?;
+        let proxies = ItemProxies {
+            properties: dbus_properties_proxy,
+            cached,
+        };
+
+        Ok((proxies, signals, props))
+    }
+
+    // Fetch the properties from the KDE item interface, falling back to the freedesktop one
+    // when the item does not expose any property on it
+    async fn detect_interface(
+        &mut self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+    ) -> Result<(Option<Proxy<'static>>, HashMap<String, OwnedValue>)> {
+        let mut first = None;
+        for interface in ITEM_INTERFACES {
+            self.interface = interface;
+            match self.fetch_initial(dbus_properties_proxy).await {
+                Ok((cached, props)) if !props.is_empty() => return Ok((cached, props)),
+                Err(err) if err.is_timeout() => return Err(err),
+                result => {
+                    first.get_or_insert(result);
+                }
+            }
+        }
+
+        self.interface = ITEM_INTERFACE;
+        first.unwrap_or_else(|| Ok((None, HashMap::new())))
+    }
+
+    // Build the caching proxy of the item interface and read the properties from its cache,
+    // or fetch the configured properties one by one when Properties.GetAll can't be used
+    async fn fetch_initial(
+        &self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+    ) -> Result<(Option<Proxy<'static>>, HashMap<String, OwnedValue>)> {
+        if !self.config.fetch_all_properties() {
+            let props = self.get_all(dbus_properties_proxy).await?;
+            return Ok((None, props));
+        }
+
+        let builder = ProxyBuilder::<Proxy<'static>>::new_bare(&self.connection)
+            .destination(self.address.destination.clone())?
+            .path(self.address.path.clone())?
+            .interface(self.interface)?
+            .cache_properties(CacheProperties::Yes);
+
+        // The cache is populated before the proxy is built
+        let _permit = self.config.fetch_limiter.acquire().await;
+        let cached = with_timeout(self.config.call_timeout, builder.build())
+            .await
+            .item_context(&self.address.destination, "GetAll")?;
+
+        let props = self.cached_properties(&cached);
+        Ok((Some(cached), props))
+    }
+
+    // The parsed properties held by the cache of the item proxy
+    fn cached_properties(&self, cached: &Proxy<'_>) -> HashMap<String, OwnedValue> {
+        ITEM_PROPERTIES
+            .iter()
+            .filter(|name| is_parsed(&self.config, name))
+            .filter_map(|name| {
+                let value = cached.cached_property_raw(name)?;
+                Some((name.to_string(), OwnedValue::from(&*value)))
+            })
+            .collect()
+    }
+
+    // A stream of the changes of each parsed property, notified once zbus updated its cache
+    async fn property_changes(
+        &self,
+        cached: Option<&Proxy<'static>>,
+    ) -> StreamMap<&'static str, PropertyStream<'static, OwnedValue>> {
+        let mut changes = StreamMap::new();
+        let Some(cached) = cached else {
+            return changes;
+        };
+
+        for name in ITEM_PROPERTIES {
+            if is_parsed(&self.config, name) {
+                changes.insert(*name, cached.receive_property_changed(name).await);
+            }
+        }
+
+        changes
+    }
+
+    // Publish the initial properties, then:
+    // - fetch the properties announced by the StatusNotifierItem signals, which don't carry any value,
+    //   the whole set of properties is only fetched again for unknown signals,
+    // - read the properties changed by PropertiesChanged from the proxy cache, zbus only fetches
+    //   the invalidated ones. Without a cache the values carried by the signal are patched in,
+    //   and only the invalidated properties are fetched,
+    // - fetch the whole set of properties again when signals overflowed the router channel,
+    // - and on refresh, restarting the menu watcher to re-fetch the menu.
+    async fn watch(
+        &mut self,
+        proxies: &ItemProxies,
+        signals: &mut ItemSignals,
+        mut props: HashMap<String, OwnedValue>,
+    ) -> Result<()> {
+        let dbus_properties_proxy = &proxies.properties;
+        let mut changes = self.property_changes(proxies.cached.as_ref()).await;
+        self.publish(props.clone()).await?;
+
+        let mut refresh = self.refresh.clone();
+        let mut refresh_open = true;
+        loop {
+            tokio::select! {
+                changed = refresh.changed(), if refresh_open => {
+                    // The watcher handle was dropped, hosts may still be listening
+                    if changed.is_err() {
+                        refresh_open = false;
+                        continue;
+                    }
+
+                    let all = self.get_all(dbus_properties_proxy).await;
+                    let Some(all) = skip_timeout(all, &self.address.destination)? else {
+                        continue;
+                    };
+
+                    props = all;
+                    self.stop_menu_watcher();
+                }
+                _ = signals.overflow.notified() => {
+                    // Drain the queued signals, the full fetch supersedes them
+                    while signals.messages.try_recv().is_ok() {}
+
+                    let all = self.get_all(dbus_properties_proxy).await;
+                    let Some(all) = skip_timeout(all, &self.address.destination)? else {
+                        continue;
+                    };
+
+                    props = all;
+                }
+                Some((name, changed)) = changes.next(), if !changes.is_empty() => {
+                    let value = with_timeout(self.config.call_timeout, changed.get())
+                        .await
+                        .item_context(&self.address.destination, "Get");
+                    let Some(value) = skip_timeout(value, &self.address.destination)? else {
+                        continue;
+                    };
+
+                    props.insert(name.to_string(), value);
+                }
+                signal = signals.messages.recv() => {
+                    let Some(signal) = signal else {
+                        return Ok(());
+                    };
+
+                    let patched = match PropertiesChanged::from_message(signal.clone()) {
+                        // Applied to the proxy cache by zbus, see `changes`
+                        Some(_) if proxies.cached.is_some() => continue,
+                        Some(signal) => self.patch_properties(dbus_properties_proxy, &mut props, signal).await,
+                        None => self
+                            .fetch_signaled(dbus_properties_proxy, &mut props, &signal)
+                            .await,
+                    };
+
+                    if skip_timeout(patched, &self.address.destination)? != Some(true) {
+                        continue;
+                    }
+                }
+            }
+
+            self.publish(props.clone()).await?;
+        }
+    }
+
+    // Call Properties.GetAll on the item interface, or Get on each configured property
+    // when only some of them are wanted
+    async fn get_all(
+        &self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+    ) -> Result<HashMap<String, OwnedValue>> {
+        if !self.config.fetch_all_properties() {
+            let mut props = HashMap::new();
+            for name in self.config.fetched_properties() {
+                if let Some(value) = self.get(dbus_properties_proxy, name).await? {
+                    props.insert(name.to_string(), value);
+                }
+            }
+
+            return Ok(props);
+        }
+
+        let interface = InterfaceName::from_static_str(self.interface)?;
+        let _permit = self.config.fetch_limiter.acquire().await;
+        with_timeout(
+            self.config.call_timeout,
+            dbus_properties_proxy.get_all(interface),
+        )
+        .await
+        .item_context(&self.address.destination, "GetAll")
+    }
+
+    // Get a single property of the item interface, `None` if the item does not expose it
+    async fn get(
+        &self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+        name: &str,
+    ) -> Result<Option<OwnedValue>> {
+        let interface = InterfaceName::from_static_str(self.interface)?;
+        let _permit = self.config.fetch_limiter.acquire().await;
+        match with_timeout(
+            self.config.call_timeout,
+            dbus_properties_proxy.get(interface, name),
+        )
+        .await
+        {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.is_timeout() => Err(err).item_context(&self.address.destination, "Get"),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Fetch the properties announced by a StatusNotifierItem signal, instead of all of them,
+    // returns false if the signal announced no parsed property
+    async fn fetch_signaled(
+        &self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+        props: &mut HashMap<String, OwnedValue>,
+        signal: &zbus::Message,
+    ) -> Result<bool> {
+        let member = signal.member();
+        let member = member
+            .as_ref()
+            .map(|member| member.as_str())
+            .unwrap_or_default();
+
+        if member == "NewStatus" {
+            if let Ok(status) = signal.body::<String>() {
+                props.insert("Status".to_string(), OwnedValue::from(Value::from(status)));
+                return Ok(true);
+            }
+        }
+
+        let Some(names) = signal_properties(member) else {
+            *props = self.get_all(dbus_properties_proxy).await?;
+            return Ok(true);
+        };
+
+        let names: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| is_parsed(&self.config, name))
+            .collect();
+
+        for name in &names {
+            match self.get(dbus_properties_proxy, name).await? {
+                Some(value) => props.insert(name.to_string(), value),
+                None => props.remove(*name),
+            };
+        }
+
+        tracing::debug!(
+            "Fetched {} properties of {} on {member}",
+            names.len(),
+            self.address.destination
+        );
+
+        Ok(!names.is_empty())
+    }
+
+    // Apply a PropertiesChanged signal to the properties when the item proxy has no cache, returns
+    // false if it targets another interface or only changes unparsed properties
+    async fn patch_properties(
+        &self,
+        dbus_properties_proxy: &PropertiesProxy<'_>,
+        props: &mut HashMap<String, OwnedValue>,
+        signal: PropertiesChanged,
+    ) -> Result<bool> {
+        let args = signal.args()?;
+        if args.interface_name().as_str() != self.interface {
+            return Ok(false);
+        }
+
+        let mut patched = 0;
+        for (name, value) in args.changed_properties() {
+            if is_parsed(&self.config, name) {
+                props.insert(name.to_string(), OwnedValue::from(value));
+                patched += 1;
+            }
+        }
+
+        for name in args.invalidated_properties() {
+            if !is_parsed(&self.config, name) {
+                continue;
+            }
+
+            match self.get(dbus_properties_proxy, name).await? {
+                Some(value) => props.insert(name.to_string(), value),
+                None => props.remove(*name),
+            };
+            patched += 1;
+        }
+
+        tracing::debug!(
+            "Patched {patched} properties of {}",
+            self.address.destination
+        );
+
+        Ok(patched > 0)
+    }
+
+    // Send an update to the registry
+    async fn publish(&mut self, props: HashMap<String, OwnedValue>) -> Result<()> {
+        // Only send item that maps correctly to our internal StatusNotifierItem representation
+        match StatusNotifierItem::parse(props) {
+            Ok((mut item, violations)) => {
+                if !violations.is_empty() {
+                    let item_address = &self.item_address;
+                    match self.config.conformance {
+                        Conformance::Strict => {
+                            tracing::error!(
+                                "Rejecting non-conforming StatusNotifierItem, dbus-address={item_address}: {violations:?}"
+                            );
+                            return self.registry.item_rejected(item_address, violations).await;
+                        }
+                        Conformance::Warn => {
+                            for violation in &violations {
+                                tracing::warn!("Non-conforming StatusNotifierItem, dbus-address={item_address}: {violation}");
+                            }
+                        }
+                        Conformance::Lenient => {}
+                    }
+                }
+
+                if let Some((size, scale)) = self.config.icon_size {
+                    item.icon_pixmap = item.icon_pixmap.map(|pixmaps| {
+                        IconPixmap::select_best_scaled(&pixmaps, size, scale)
+                            .cloned()
+                            .into_iter()
+                            .collect()
+                    });
+                }
+
+                if let Some(max_size) = self.config.max_pixmap_size {
+                    item.icon_pixmap = item
+                        .icon_pixmap
+                        .map(|pixmaps| IconPixmap::cap_size(pixmaps, max_size));
+                }
+
+                self.update_menu_watcher(&item);
+                let menu = MenuHandle::new(
+                    self.item_address.clone(),
+                    item.menu.clone(),
+                    self.config.menu_depth(&item.id),
+                    self.config.max_menu_children,
+                    self.config.call_timeout,
+                    self.connection.clone(),
+                );
+
+                self.registry
+                    .item_updated(&self.item_address, item, menu)
+                    .await
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Invalid StatusNotifierItem, dbus-address={}: {err}",
+                    self.item_address
+                );
+                self.registry.item_failed(&self.item_address, err).await
+            }
+        }
+    }
+
+    fn update_menu_watcher(&mut self, item: &StatusNotifierItem) {
+        let current = self.menu_watcher.as_ref().map(|(path, _)| path);
+        if current == item.menu.as_ref() {
+            return;
+        }
+
+        self.stop_menu_watcher();
+
+        if let Some(menu_path) = &item.menu {
+            let watcher = MenuWatcher::new(
+                self.item_address.clone(),
+                menu_path.clone(),
+                self.config.menu_depth(&item.id),
+                self.config.clone(),
+                self.connection.clone(),
+                self.registry.clone(),
+            );
+
+            self.menu_watcher = Some((menu_path.clone(), watcher.spawn()));
+        }
+    }
+
+    fn stop_menu_watcher(&mut self) {
+        if let Some((_, handle)) = self.menu_watcher.take() {
+            handle.abort();
+        }
+    }
+}
+
+// Dropping a `JoinHandle` does not stop its task, the menu watcher is aborted
+// along with an aborted item watcher
+impl Drop for ItemWatcher {
+    fn drop(&mut self) {
+        self.stop_menu_watcher();
+    }
+}
@@ -0,0 +1,94 @@
+use crate::message::menu::TrayMenu;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// A menu persisted to the cache directory
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedMenu {
+    item_id: String,
+    menu: TrayMenu,
+}
+
+// Keep the last known menu of each application, keyed by item `Id`, so it can be delivered
+// before the live menu is fetched when the application registers again.
+#[derive(Debug)]
+pub(crate) struct MenuCache {
+    dir: PathBuf,
+    menus: HashMap<String, TrayMenu>,
+}
+
+impl MenuCache {
+    // Load the menus persisted in `dir`, invalid entries are ignored
+    pub(crate) fn load(dir: PathBuf) -> Self {
+        let mut menus = HashMap::new();
+        let entries = std::fs::read_dir(&dir).into_iter().flatten().flatten();
+
+        for entry in entries {
+            let path = entry.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+
+            let cached = std::fs::read(&path)
+                .ok()
+                .and_then(|content| serde_json::from_slice::<CachedMenu>(&content).ok());
+
+            match cached {
+                Some(cached) => {
+                    menus.insert(cached.item_id, cached.menu);
+                }
+                None => tracing::warn!("Ignoring invalid cached menu {}", path.display()),
+            }
+        }
+
+        tracing::debug!("Loaded {} cached menus from {}", menus.len(), dir.display());
+        MenuCache { dir, menus }
+    }
+
+    pub(crate) fn get(&self, item_id: &str) -> Option<&TrayMenu> {
+        self.menus.get(item_id)
+    }
+
+    // Remember the menu and persist it in the background
+    pub(crate) fn store(&mut self, item_id: &str, menu: &TrayMenu) {
+        self.menus.insert(item_id.to_string(), menu.clone());
+
+        let path = self.dir.join(file_name(item_id));
+        let cached = CachedMenu {
+            item_id: item_id.to_string(),
+            menu: menu.clone(),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = write(&path, &cached) {
+                tracing::warn!("Failed to cache menu in {}: {err}", path.display());
+            }
+        });
+    }
+}
+
+fn write(path: &Path, cached: &CachedMenu) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    std::fs::write(path, serde_json::to_vec(cached)?)?;
+    Ok(())
+}
+
+// Item ids are chosen by applications, only keep characters that are safe in a file name
+fn file_name(item_id: &str) -> String {
+    let name: String = item_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("{}.json", name.trim_start_matches('.'))
+}
@@ -0,0 +1,319 @@
+use crate::dbus::dbusmenu_proxy::DBusMenuProxy;
+use crate::error::{ItemContext, Result};
+use crate::message::menu::{MenuItem, TrayMenu};
+use crate::message::{ItemAddress, MenuPath};
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::panic_guard::{catch_panic, next_restart_interval};
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::MenuLayout;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use zbus::Connection;
+
+// Fetch the dbusmenu layout of an item and report it to the registry whenever it changes
+pub(crate) struct MenuWatcher {
+    item_address: ItemAddress,
+    menu_path: MenuPath,
+    menu_depth: i32,
+    config: Arc<WatcherConfig>,
+    connection: Connection,
+    registry: RegistryHandle,
+}
+
+impl MenuWatcher {
+    pub(crate) fn new(
+        item_address: ItemAddress,
+        menu_path: MenuPath,
+        menu_depth: i32,
+        config: Arc<WatcherConfig>,
+        connection: Connection,
+        registry: RegistryHandle,
+    ) -> Self {
+        MenuWatcher {
+            item_address,
+            menu_path,
+            menu_depth,
+            config,
+            connection,
+            registry,
+        }
+    }
+
+    // A panicking watcher is restarted with an exponential backoff
+    pub(crate) fn spawn(self) -> JoinHandle<()> {
+        self.config.tasks.clone().spawn(async move {
+            let item_address = &self.item_address;
+            let mut restart_interval = self.config.retry_interval;
+            loop {
+                let error = match catch_panic(self.run()).await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => {
+                        tracing::error!("Menu watcher error, dbus-address={item_address}: {err:?}");
+                        let _ = self
+                            .registry
+                            .item_failed(item_address, format!("menu watcher stopped: {err}"))
+                            .await;
+                        return;
+                    }
+                    Err(panic) => panic,
+                };
+
+                tracing::error!(
+                    "Menu watcher panicked, dbus-address={item_address}, restarting in {restart_interval:?}: {error}"
+                );
+                let _ = self
+                    .registry
+                    .item_failed(item_address, format!("menu watcher panicked: {error}"))
+                    .await;
+
+                tokio::time::sleep(restart_interval).await;
+                restart_interval = next_restart_interval(restart_interval);
+            }
+        })
+    }
+
+    async fn run(&self) -> Result<()> {
+        let dbus_menu_proxy = DBusMenuProxy::builder(&self.connection)
+            .destination(self.item_address.destination())?
+            .path(self.menu_path.as_str())?
+            .build()
+            .await?;
+
+        let mut layout_updated = dbus_menu_proxy.receive_layout_updated().await?;
+        let mut properties_updated = dbus_menu_proxy.receive_items_properties_updated().await?;
+        let mut status_changed = dbus_menu_proxy.receive_status_changed().await;
+
+        // Lazily populated menus are only valid after AboutToShow, errors are ignored
+        // since many applications do not implement it.
+        let _ = with_timeout(self.config.call_timeout, dbus_menu_proxy.about_to_show(0)).await;
+
+        // A broken menu must not take the item down, the item is delivered without a menu
+        // and the layout is fetched again on the next menu signal
+        let mut failing = false;
+        let (mut revision, mut menu) = self
+            .try_fetch_layout(&dbus_menu_proxy, &mut failing)
+            .await
+            .unwrap_or_default();
+        self.send_menu(&menu).await?;
+
+        loop {
+            tokio::select! {
+                signal = layout_updated.next() => {
+                    let Some(signal) = signal else {
+                        return Ok(());
+                    };
+
+                    let Ok(args) = signal.args() else {
+                        tracing::debug!("Invalid LayoutUpdated signal from {}", self.item_address);
+                        continue;
+                    };
+
+                    // Some applications do not track revisions and always send 0
+                    let new_revision = *args.revision();
+                    if new_revision != 0 && new_revision <= revision {
+                        tracing::debug!(
+                            "Skipping menu layout update, revision {new_revision} is not newer than {revision}"
+                        );
+                        continue;
+                    }
+
+                    // Only refresh the updated subtree when it is already known
+                    let parent = *args.parent();
+                    let refreshed = match menu.as_mut() {
+                        Some(menu) if parent != 0 && !failing => {
+                            // Fall back to fetching the whole layout
+                            self.refresh_subtree(&dbus_menu_proxy, menu, parent)
+                                .await
+                                .unwrap_or_else(|err| {
+                                    tracing::debug!("Failed to refresh menu subtree of {}: {err}", self.item_address);
+                                    false
+                                })
+                        }
+                        _ => false,
+                    };
+
+                    if refreshed {
+                        revision = revision.max(new_revision);
+                    } else {
+                        let Some(layout) = self.try_fetch_layout(&dbus_menu_proxy, &mut failing).await else {
+                            continue;
+                        };
+
+                        (revision, menu) = layout;
+                    }
+                }
+                signal = properties_updated.next() => {
+                    let Some(signal) = signal else {
+                        return Ok(());
+                    };
+
+                    let Ok(args) = signal.args() else {
+                        tracing::debug!("Invalid ItemsPropertiesUpdated signal from {}", self.item_address);
+                        continue;
+                    };
+
+                    // Patch the known layout in place instead of fetching it again
+                    match menu.as_mut() {
+                        Some(menu) if !failing => {
+                            menu.apply_properties_update(args.updated_props(), args.removed_props());
+
+                            // Some applications only report the ids of the updated items
+                            let ids: Vec<i32> = args
+                                .updated_props()
+                                .iter()
+                                .filter(|(_, props)| props.is_empty())
+                                .map(|(id, _)| *id)
+                                .collect();
+
+                            if !ids.is_empty() {
+                                if let Err(err) = self.refresh_items(&dbus_menu_proxy, menu, &ids).await {
+                                    tracing::debug!("Failed to refresh menu items of {}: {err}", self.item_address);
+                                }
+                            }
+                        }
+                        _ => {
+                            let Some(layout) = self.try_fetch_layout(&dbus_menu_proxy, &mut failing).await else {
+                                continue;
+                            };
+
+                            (revision, menu) = layout;
+                        }
+                    }
+                }
+                changed = status_changed.next() => {
+                    if changed.is_none() {
+                        return Ok(());
+                    }
+
+                    match menu.as_mut() {
+                        Some(menu) if !failing => menu.read_properties(&dbus_menu_proxy).await,
+                        _ => {
+                            let Some(layout) = self.try_fetch_layout(&dbus_menu_proxy, &mut failing).await else {
+                                continue;
+                            };
+
+                            (revision, menu) = layout;
+                        }
+                    }
+                }
+            }
+
+            self.send_menu(&menu).await?;
+        }
+    }
+
+    // Send the menu to the registry, truncated to the configured number of children
+    async fn send_menu(&self, menu: &Option<TrayMenu>) -> Result<()> {
+        let mut menu = menu.clone();
+        if let (Some(menu), Some(max_children)) = (menu.as_mut(), self.config.max_menu_children) {
+            menu.paginate(max_children);
+        }
+
+        self.registry.menu_updated(&self.item_address, menu).await
+    }
+
+    // Fetch the properties of the given items only
+    async fn refresh_items(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+        menu: &mut TrayMenu,
+        ids: &[i32],
+    ) -> Result<()> {
+        let _permit = self.config.fetch_limiter.acquire().await;
+        let (_, items) = with_timeout(
+            self.config.call_timeout,
+            dbus_menu_proxy.get_group_properties(ids, &[]),
+        )
+        .await
+        .item_context(&self.item_address, "GetGroupProperties")?;
+        for (id, props) in &items {
+            menu.replace_properties(*id, props);
+        }
+
+        Ok(())
+    }
+
+    // Fetch the layout of the item `parent` and its children, returns false if the item is unknown
+    async fn refresh_subtree(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+        menu: &mut TrayMenu,
+        parent: i32,
+    ) -> Result<bool> {
+        let layout = {
+            let _permit = self.config.fetch_limiter.acquire().await;
+            with_timeout(
+                self.config.call_timeout,
+                dbus_menu_proxy.get_layout(parent, self.menu_depth, &[]),
+            )
+            .await
+            .item_context(&self.item_address, "GetLayout")?
+        };
+
+        let mut item = MenuItem {
+            id: parent,
+            ..MenuItem::default()
+        };
+
+        for value in &layout.fields.submenus {
+            item.submenu.push(MenuItem::try_from(value)?);
+        }
+
+        let replaced = menu.replace_item(item);
+        if replaced {
+            menu.replace_properties(parent, &layout.fields.fields);
+        }
+
+        Ok(replaced)
+    }
+
+    // Fetch the menu layout, failures are logged once until a fetch succeeds again
+    async fn try_fetch_layout(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+        failing: &mut bool,
+    ) -> Option<(u32, Option<TrayMenu>)> {
+        match self.fetch_layout(dbus_menu_proxy).await {
+            Ok(layout) => {
+                *failing = false;
+                Some(layout)
+            }
+            Err(err) if *failing => {
+                tracing::debug!("Failed to fetch menu of {}: {err}", self.item_address);
+                None
+            }
+            Err(err) => {
+                tracing::warn!("Failed to fetch menu of {}: {err}", self.item_address);
+                *failing = true;
+                let _ = self
+                    .registry
+                    .item_failed(&self.item_address, format!("failed to fetch menu: {err}"))
+                    .await;
+                None
+            }
+        }
+    }
+
+    // Fetch the menu layout along with its revision
+    async fn fetch_layout(
+        &self,
+        dbus_menu_proxy: &DBusMenuProxy<'_>,
+    ) -> Result<(u32, Option<TrayMenu>)> {
+        let _permit = self.config.fetch_limiter.acquire().await;
+        let menu: MenuLayout = with_timeout(
+            self.config.call_timeout,
+            dbus_menu_proxy.get_layout(0, self.menu_depth, &[]),
+        )
+        .await
+        .item_context(&self.item_address, "GetLayout")?;
+        let revision = menu.id;
+        let mut menu = TrayMenu::try_from(menu).ok();
+        if let Some(menu) = menu.as_mut() {
+            menu.read_properties(dbus_menu_proxy).await;
+        }
+
+        Ok((revision, menu))
+    }
+}
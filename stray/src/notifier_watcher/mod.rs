@@ -1,19 +1,58 @@
-use crate::dbus::dbusmenu_proxy::DBusMenuProxy;
-use crate::dbus::notifier_item_proxy::StatusNotifierItemProxy;
 use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
-use crate::error::Result;
-use crate::message::menu::TrayMenu;
-use crate::message::NotifierItemCommand;
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::tray::IconPixmap;
+use crate::message::{ItemAddress, NotifierItemCommand, WatcherEvent};
+use crate::notifier_watcher::builder::{StatusNotifierWatcherBuilder, WatcherConfig};
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::dispatcher::{CommandDispatcher, DispatchRequest};
+use crate::notifier_watcher::events::WatcherEventStream;
+use crate::notifier_watcher::forwarder::Forwarder;
+use crate::notifier_watcher::item_handle::ItemHandle;
+use crate::notifier_watcher::item_watcher::{ItemWatcher, ITEM_INTERFACES};
 use crate::notifier_watcher::notifier_address::NotifierAddress;
-use crate::{
-    DbusNotifierWatcher, InterfaceName, MenuLayout, NotifierItemMessage, StatusNotifierItem,
-};
-use tokio::sync::{broadcast, mpsc};
+use crate::notifier_watcher::persisted_items::alive_items;
+use crate::notifier_watcher::registry::{Registry, RegistryHandle};
+use crate::notifier_watcher::session::BusSession;
+use crate::notifier_watcher::signal_router::SignalRouterHandle;
+use crate::notifier_watcher::tasks::PendingStartup;
+use crate::tray_state::TrayEntry;
+use crate::{DbusNotifierWatcher, InterfaceName, NotifierItemMessage};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio_stream::StreamExt;
-use zbus::fdo::PropertiesProxy;
-use zbus::{Connection, ConnectionBuilder};
-
+use tokio_util::sync::CancellationToken;
+use zbus::fdo::{DBusProxy, PropertiesProxy};
+use zbus::zvariant::Array;
+use zbus::Connection;
+
+pub(crate) mod builder;
+pub(crate) mod call_timeout;
+#[cfg(feature = "sink")]
+pub(crate) mod command_sink;
+pub(crate) mod dispatcher;
+pub(crate) mod events;
+pub(crate) mod fetch_limiter;
+pub(crate) mod forwarder;
+pub(crate) mod health_check;
+#[cfg(feature = "png")]
+pub(crate) mod icon_files;
+#[cfg(feature = "icon-loader")]
+pub(crate) mod icon_theme;
+pub(crate) mod item_handle;
+pub(crate) mod item_watcher;
+pub(crate) mod menu_cache;
+pub(crate) mod menu_watcher;
 pub(crate) mod notifier_address;
+pub(crate) mod panic_guard;
+pub(crate) mod persisted_items;
+pub(crate) mod projection;
+pub(crate) mod registry;
+pub(crate) mod session;
+pub(crate) mod signal_router;
+pub(crate) mod tasks;
 
 /// Wrap the implementation of [org.freedesktop.StatusNotifierWatcher](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierWatcher/)
 /// and [org.freedesktop.StatusNotifierHost](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierHost/).
@@ -21,315 +60,757 @@ pub(crate) mod notifier_address;
 pub struct StatusNotifierWatcher {
     pub(crate) tx: broadcast::Sender<NotifierItemMessage>,
     _rx: broadcast::Receiver<NotifierItemMessage>,
+    pub(crate) cmd_tx: mpsc::Sender<DispatchRequest>,
+    command_tx: mpsc::Sender<NotifierItemCommand>,
+    pub(crate) config: Arc<WatcherConfig>,
+    pub(crate) registry: RegistryHandle,
+    refresh: watch::Sender<u64>,
+    // The current session bus connection, replaced when the bus restarts
+    pub(crate) session: watch::Receiver<BusSession>,
+    startup: PendingStartup,
+    // Turns true once `run` claimed the watcher name, the sender is dropped if the startup failed
+    started: watch::Receiver<bool>,
 }
 
 impl StatusNotifierWatcher {
-    /// Creates a new system stray and register a [StatusNotifierWatcher](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierWatcher/) and [StatusNotifierHost](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierHost/) on dbus.
-    /// Once created you can receive [`StatusNotifierItem`]. Once created you can start to poll message
-    /// using the [`Stream`] implementation.
-    pub async fn new(cmd_rx: mpsc::Receiver<NotifierItemCommand>) -> Result<StatusNotifierWatcher> {
-        let (tx, rx) = broadcast::channel(5);
+    /// Creates a new system stray connected to the session bus. The [StatusNotifierWatcher](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierWatcher/)
+    /// and [StatusNotifierHost](https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierHost/)
+    /// are only registered on dbus once [`StatusNotifierWatcher::run`] is polled, then you can receive
+    /// the [`StatusNotifierItem`] messages from a host, see [`StatusNotifierWatcher::create_notifier_host`].
+    /// Until `run` is called, the methods which need the running watcher fail with
+    /// [`StatusNotifierWatcherError::NotStarted`].
+    /// Commands sent through `cmd_rx` are dispatched to the items, pass `None` to only use
+    /// [`StatusNotifierWatcher::send_command`].
+    pub async fn new(
+        cmd_rx: impl Into<Option<mpsc::Receiver<NotifierItemCommand>>>,
+    ) -> Result<StatusNotifierWatcher> {
+        Self::builder().build(cmd_rx).await
+    }
 
-        {
-            tracing::info!("Starting notifier watcher");
-            let tx = tx.clone();
+    /// Returns a [`StatusNotifierWatcherBuilder`] to configure the watcher before starting it.
+    pub fn builder() -> StatusNotifierWatcherBuilder {
+        StatusNotifierWatcherBuilder::default()
+    }
 
-            tokio::spawn(async move {
-                start_notifier_watcher(tx)
-                    .await
-                    .expect("Unexpected StatusNotifierError");
+    // The watcher is made of several subsystems communicating through channels:
+    // - the `Registry` owns the state of all items and broadcast messages to the hosts,
+    // - one `ItemWatcher` per item, and one `MenuWatcher` per item menu report changes to the registry,
+    // - the `SignalRouter` receives the signals of all items on a single stream and forwards them to their `ItemWatcher`,
+    // - the `HealthCheck` periodically removes the items whose owner left the bus,
+    // - the `CommandDispatcher` forwards UI commands to the items.
+    // Everything bound to the bus connection is restarted when the connection is lost, see `session::supervise`.
+    // The subsystems are only built here, they are spawned by `StatusNotifierWatcher::run`.
+    pub(crate) async fn start(
+        config: WatcherConfig,
+        cmd_rx: Option<mpsc::Receiver<NotifierItemCommand>>,
+    ) -> Result<StatusNotifierWatcher> {
+        // Hosts with their own queue lag once both their queue and the broadcast channel are full,
+        // size the channel after the queue so a blocking host really holds `host_queue` messages
+        let capacity = config
+            .host_queue
+            .map_or(BROADCAST_CAPACITY, |(capacity, _)| {
+                capacity.max(BROADCAST_CAPACITY)
             });
-        }
-
-        tokio::spawn(async move {
-            dispatch_ui_command(cmd_rx)
-                .await
-                .expect("Unexpected error while dispatching UI command");
+        let (tx, rx) = broadcast::channel(capacity);
+        let config = Arc::new(config);
+        let (registry, registry_handle, registry_rx) = Registry::new(config.clone(), tx.clone());
+
+        let (refresh, refresh_rx) = watch::channel(0);
+        let (session, session_tasks) = BusSession::connect(&config).await?;
+        let (sessions, session) = watch::channel(session);
+
+        let dispatcher = CommandDispatcher::new(
+            session.clone(),
+            registry_handle.clone(),
+            config.call_timeout,
+            config.watcher_name.clone(),
+            config.tasks.clone(),
+        );
+        let (cmd_tx, dispatcher_rx) = mpsc::channel(32);
+
+        // Forward the commands sent by the caller, notifier hosts get their own sender to the dispatcher
+        let (command_tx, command_rx) = mpsc::channel(32);
+        let forwarder = config.forward_to.clone().map(|bus_address| {
+            (
+                Forwarder::new(
+                    bus_address,
+                    command_tx.clone(),
+                    registry_handle.clone(),
+                    config.tasks.clone(),
+                ),
+                tx.subscribe(),
+            )
         });
 
-        Ok(StatusNotifierWatcher { tx, _rx: rx })
-    }
-}
-
-// Forward UI command to the Dbus menu proxy
-async fn dispatch_ui_command(mut cmd_rx: mpsc::Receiver<NotifierItemCommand>) -> Result<()> {
-    let connection = Connection::session().await?;
-
-    while let Some(command) = cmd_rx.recv().await {
-        match command {
-            NotifierItemCommand::MenuItemClicked {
-                submenu_id: id,
-                menu_path,
-                notifier_address,
-            } => {
-                let dbus_menu_proxy = DBusMenuProxy::builder(&connection)
-                    .destination(notifier_address)
-                    .unwrap()
-                    .path(menu_path)
-                    .unwrap()
-                    .build()
+        let (started_tx, started) = watch::channel(false);
+        let startup = {
+            let config = config.clone();
+            let registry_handle = registry_handle.clone();
+            let cmd_tx = cmd_tx.clone();
+            async move {
+                config.tasks.spawn(registry.run(registry_rx));
+
+                let session = sessions.borrow().clone();
+                let closed = session_tasks
+                    .spawn(&session, &registry_handle, &config, &refresh_rx)
                     .await?;
+                config.tasks.spawn(session::supervise(
+                    sessions,
+                    closed,
+                    registry_handle.clone(),
+                    config.clone(),
+                    refresh_rx,
+                ));
+
+                config.tasks.spawn(dispatcher.run(dispatcher_rx));
+                config
+                    .tasks
+                    .spawn(forward_commands(command_rx, cmd_tx.clone()));
+                if let Some(cmd_rx) = cmd_rx {
+                    config.tasks.spawn(forward_commands(cmd_rx, cmd_tx));
+                }
+
+                if let Some((forwarder, messages)) = forwarder {
+                    config.tasks.spawn(forwarder.run(messages));
+                }
+
+                #[cfg(feature = "icon-loader")]
+                if let Some(lookup) = config.icon_theme_lookup.clone() {
+                    config
+                        .tasks
+                        .spawn(icon_theme::follow_icon_theme(lookup, registry_handle));
+                }
+
+                let _ = started_tx.send(true);
+                Ok(())
+            }
+        };
 
-                dbus_menu_proxy
-                    .event(
-                        id,
-                        "clicked",
-                        &zbus::zvariant::Value::I32(32),
-                        chrono::offset::Local::now().timestamp_subsec_micros(),
-                    )
-                    .await?;
+        Ok(StatusNotifierWatcher {
+            tx,
+            _rx: rx,
+            cmd_tx,
+            command_tx,
+            config,
+            registry: registry_handle,
+            refresh,
+            session,
+            startup: PendingStartup::new(startup),
+            started,
+        })
+    }
+
+    /// Claim the watcher name, start the watcher tasks and stop them when `token` is cancelled.
+    /// Nothing runs until the returned future is polled, it completes once every task has stopped,
+    /// or with an error if the watcher failed to start. This lets services embedding stray choose
+    /// when the watcher starts and shut it down cleanly.
+    ///
+    /// `run` must be called before creating notifier hosts, [`StatusNotifierWatcher::create_notifier_host`]
+    /// waits for the watcher name to be claimed.
+    ///
+    /// ```rust, no_run
+    /// # use stray::StatusNotifierWatcher;
+    /// # async fn example() -> stray::error::Result<()> {
+    /// let token = stray::CancellationToken::new();
+    /// let watcher = StatusNotifierWatcher::new(None).await?;
+    /// tokio::spawn(watcher.run(token.clone()));
+    /// // ...
+    /// token.cancel();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run(
+        &self,
+        token: CancellationToken,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let tasks = self.config.tasks.clone();
+        let startup = self.startup.take();
+        async move {
+            if let Some(startup) = startup {
+                if let Err(err) = startup.await {
+                    tasks.shutdown().await;
+                    return Err(err);
+                }
             }
+
+            token.cancelled().await;
+            tracing::info!("Stopping notifier watcher");
+            tasks.shutdown().await;
+            Ok(())
         }
     }
 
-    Ok(())
-}
-
-async fn start_notifier_watcher(sender: broadcast::Sender<NotifierItemMessage>) -> Result<()> {
-    let watcher = DbusNotifierWatcher::new(sender.clone());
+    // Wait for `run` to claim the watcher name, hosts registering earlier would reach no watcher
+    // or the watcher of another process
+    pub(crate) async fn started(&self) -> Result<()> {
+        if !self.startup.is_taken() {
+            return Err(StatusNotifierWatcherError::NotStarted);
+        }
 
-    let connection = ConnectionBuilder::session()?
-        .name("org.kde.StatusNotifierWatcher")?
-        .serve_at("/StatusNotifierWatcher", watcher)?
-        .build()
-        .await?;
+        self.started
+            .clone()
+            .wait_for(|started| *started)
+            .await
+            .map(|_| ())
+            .map_err(|_| StatusNotifierWatcherError::NotStarted)
+    }
 
-    let status_notifier_removed = {
-        let connection = connection.clone();
-        tokio::spawn(async move {
-            status_notifier_removed_handle(connection).await?;
-            Result::<()>::Ok(())
-        })
-    };
+    /// Returns whether this watcher serves the `org.kde.StatusNotifierWatcher` name or fell back
+    /// to the watcher of another process because the name was already taken.
+    /// Reports [`WatcherMode::Watcher`] until [`StatusNotifierWatcher::run`] claimed the name.
+    pub fn mode(&self) -> WatcherMode {
+        *self.session.borrow().mode.borrow()
+    }
 
-    let status_notifier =
-        tokio::spawn(async move { status_notifier_handle(connection, sender).await.unwrap() });
+    /// Returns the items registered to the watcher, formatted as `:1.52/StatusNotifierItem`.
+    /// In [`WatcherMode::HostOnly`] they are read from the external watcher.
+    pub async fn registered_items(&self) -> Result<Vec<String>> {
+        let proxy = self.current_watcher_proxy().await?;
+        with_timeout(
+            self.config.call_timeout,
+            proxy.registered_status_notifier_items(),
+        )
+        .await
+    }
 
-    tokio::spawn(async move {
-        let (r1, r2) = tokio::join!(status_notifier, status_notifier_removed,);
-        if let Err(err) = r1 {
-            tracing::error!("Status notifier error: {err:?}")
+    /// Returns the well-known names of the hosts registered to the watcher. External watchers
+    /// don't publish their hosts, `None` is returned in [`WatcherMode::HostOnly`].
+    pub async fn registered_hosts(&self) -> Result<Option<Vec<String>>> {
+        if self.mode() == WatcherMode::HostOnly {
+            return Ok(None);
         }
 
-        if let Err(err) = r2 {
-            tracing::error!("Status notifier removed error: {err:?}")
-        }
-    });
+        let connection = self.session.borrow().connection.clone();
+        let watcher = connection
+            .object_server()
+            .interface::<_, DbusNotifierWatcher>(WATCHER_PATH)
+            .await?;
+        let hosts = watcher
+            .get()
+            .await
+            .status_notifier_hosts
+            .iter()
+            .cloned()
+            .collect();
 
-    Ok(())
-}
+        Ok(Some(hosts))
+    }
 
-// Listen for 'NameOwnerChanged' on DBus whenever a service is removed
-// send 'UnregisterStatusNotifierItem' request to 'StatusNotifierWatcher' via dbus
-async fn status_notifier_removed_handle(connection: Connection) -> Result<()> {
-    let dbus_proxy = zbus::fdo::DBusProxy::new(&connection).await.unwrap();
+    /// Returns the `ProtocolVersion` property of the watcher, the configured one, see
+    /// [`StatusNotifierWatcherBuilder::protocol_version`], or the version of the external watcher
+    /// in [`WatcherMode::HostOnly`].
+    pub async fn protocol_version(&self) -> Result<i32> {
+        let proxy = self.current_watcher_proxy().await?;
+        with_timeout(self.config.call_timeout, proxy.protocol_version()).await
+    }
 
-    let mut changed = dbus_proxy
-        .receive_name_owner_changed()
+    /// Returns the `IsStatusNotifierHostRegistered` property of the watcher.
+    pub async fn is_host_registered(&self) -> Result<bool> {
+        let proxy = self.current_watcher_proxy().await?;
+        with_timeout(
+            self.config.call_timeout,
+            proxy.is_status_notifier_host_registered(),
+        )
         .await
-        .expect("fail to receive Dbus NameOwnerChanged");
+    }
 
-    while let Some(signal) = changed.next().await {
-        let args = signal.args().expect("Failed to get signal args");
-        let old = args.old_owner();
-        let new = args.new_owner();
-
-        if old.is_some() && new.is_none() {
-            let old_owner: String = old.as_ref().unwrap().to_string();
-            let watcher_proxy = StatusNotifierWatcherProxy::new(&connection)
-                .await
-                .expect("Failed to open StatusNotifierWatcherProxy");
-
-            if let Err(err) = watcher_proxy
-                .unregister_status_notifier_item(&old_owner)
-                .await
+    // A proxy to the watcher on the current connection, ours or the external one
+    async fn current_watcher_proxy(&self) -> Result<StatusNotifierWatcherProxy<'static>> {
+        let connection = self.session.borrow().connection.clone();
+        Ok(watcher_proxy(&connection, &self.config.watcher_name).await?)
+    }
+
+    /// Returns the bus connection the watcher currently runs on. Applications can serve additional
+    /// interfaces or build proxies on it instead of opening another connection. The connection is
+    /// replaced when the bus restarts, see [`NotifierItemMessage::Reset`], call this again afterwards.
+    pub fn connection(&self) -> Connection {
+        self.session.borrow().connection.clone()
+    }
+
+    /// Returns a stream of the watcher lifecycle events: item registrations and removals, hosts
+    /// joining and leaving, and the loss of the watcher name. Only the events emitted after the
+    /// call are received.
+    pub fn events(&self) -> WatcherEventStream {
+        WatcherEventStream::new(self.config.events.subscribe())
+    }
+
+    /// Returns the current state of every known item, without subscribing to the messages.
+    pub async fn items(&self) -> Result<BTreeMap<ItemAddress, TrayEntry>> {
+        self.started().await?;
+        self.registry.snapshot().await
+    }
+
+    /// Re-read the registered items, re-fetch the properties and menu of every item and broadcast
+    /// them again. Items which are no longer registered are removed. This is useful to resync a UI
+    /// after reloading its configuration or recovering from an error.
+    pub async fn refresh(&self) -> Result<()> {
+        self.started().await?;
+        let BusSession {
+            connection, router, ..
+        } = self.session.borrow().clone();
+        let proxy = watcher_proxy(&connection, &self.config.watcher_name).await?;
+        let registered: Vec<NotifierAddress> = with_timeout(
+            self.config.call_timeout,
+            proxy.registered_status_notifier_items(),
+        )
+        .await?
+        .iter()
+        .filter_map(|service| NotifierAddress::from_notifier_service(service).ok())
+        .collect();
+
+        let known = self.registry.snapshot().await?;
+        for address in known.keys() {
+            if !registered
+                .iter()
+                .any(|registered| ItemAddress::from_bus(registered) == *address)
             {
-                tracing::error!("Failed to unregister status notifier: {err:?}")
+                self.registry.item_removed(address).await?;
+            }
+        }
+
+        // Watched items re-fetch their properties and menu, and broadcast them even if unchanged
+        self.registry.resync().await?;
+        self.refresh.send_modify(|revision| *revision += 1);
+
+        // Start watching the items we missed
+        for address in registered {
+            if !known.contains_key(&ItemAddress::from_bus(&address)) {
+                ItemWatcher::new(
+                    address,
+                    connection.clone(),
+                    self.config.clone(),
+                    self.registry.clone(),
+                    router.clone(),
+                    self.refresh.subscribe(),
+                )
+                .start()
+                .await?;
             }
         }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Send a command to the items without waiting for its outcome, failures are reported to the
+    /// notifier hosts with [`NotifierItemMessage::CommandFailed`].
+    pub async fn send_command(&self, command: NotifierItemCommand) -> Result<()> {
+        self.started().await?;
+        self.command_tx
+            .send(command)
+            .await
+            .map_err(|_| StatusNotifierWatcherError::DispatcherClosed)
+    }
 
-// 1. Start StatusNotifierHost on DBus
-// 2. Query already registered StatusNotifier, call GetAll to update the UI  and  listen for property changes via Dbus.PropertiesChanged
-// 3. subscribe to StatusNotifierWatcher.RegisteredStatusNotifierItems
-// 4. Whenever a new notifier is registered repeat steps 2
-// FIXME : Move this to HOST
-async fn status_notifier_handle(
-    connection: Connection,
-    sender: broadcast::Sender<NotifierItemMessage>,
-) -> Result<()> {
-    let status_notifier_proxy = StatusNotifierWatcherProxy::new(&connection).await?;
+    /// Returns a sender to issue commands from other tasks, like [`StatusNotifierWatcher::send_command`].
+    pub fn command_sender(&self) -> mpsc::Sender<NotifierItemCommand> {
+        self.command_tx.clone()
+    }
+
+    /// Returns an [`ItemHandle`] to interact with the item at `address`.
+    pub fn item(&self, address: &ItemAddress) -> ItemHandle {
+        ItemHandle::new(
+            address.clone(),
+            self.session.borrow().connection.clone(),
+            self.config.clone(),
+            self.cmd_tx.clone(),
+            self.registry.clone(),
+        )
+    }
+
+    /// Fetch the icon pixmaps of the item at `address` as published by the application,
+    /// regardless of the [`StatusNotifierWatcherBuilder::max_pixmap_size`] limit.
+    pub async fn original_icon_pixmap(&self, address: &str) -> Result<Option<Vec<IconPixmap>>> {
+        let connection = self.session.borrow().connection.clone();
+        let pixmap = with_timeout(self.config.call_timeout, async {
+            let address = NotifierAddress::resolve(
+                &connection,
+                &self.config.watcher_name,
+                address,
+                self.config.call_timeout,
+            )
+            .await?;
+            let proxy = PropertiesProxy::builder(&connection)
+                .destination(address.destination)?
+                .path(address.path)?
+                .build()
+                .await?;
+
+            // Fall back to the freedesktop interface name when the item does not use the KDE one
+            let mut first = None;
+            for interface in ITEM_INTERFACES {
+                let interface = InterfaceName::from_static_str(interface)?;
+                match proxy.get(interface, "IconPixmap").await {
+                    Ok(pixmap) => return Ok(pixmap),
+                    Err(err) => {
+                        first.get_or_insert(err);
+                    }
+                }
+            }
 
-    let notifier_items: Vec<String> = status_notifier_proxy
-        .registered_status_notifier_items()
+            Err(first.map(StatusNotifierWatcherError::from).unwrap_or(
+                StatusNotifierWatcherError::UnsupportedProperty("IconPixmap".to_string()),
+            ))
+        })
         .await?;
 
-    tracing::info!("Got {} notifier items", notifier_items.len());
+        Ok(pixmap
+            .downcast_ref::<Array>()
+            .and_then(IconPixmap::from_array))
+    }
+}
 
-    // Start watching for all registered notifier items
-    for service in notifier_items.iter() {
-        let service = NotifierAddress::from_notifier_service(service);
-        if let Ok(notifier_address) = service {
-            let connection = connection.clone();
-            let sender = sender.clone();
-            watch_notifier_props(notifier_address, connection, sender).await?;
+// Wrap the commands received on `commands` into dispatch requests
+pub(crate) async fn forward_commands(
+    mut commands: mpsc::Receiver<NotifierItemCommand>,
+    cmd_tx: mpsc::Sender<DispatchRequest>,
+) {
+    while let Some(command) = commands.recv().await {
+        let request = DispatchRequest {
+            command,
+            reply: None,
+        };
+
+        if cmd_tx.send(request).await.is_err() {
+            tracing::error!("Command dispatcher is closed, dropping UI commands");
+            break;
         }
     }
+}
 
-    // Listen for new notifier items
-    let mut new_notifier = status_notifier_proxy
-        .receive_status_notifier_item_registered()
-        .await?;
+/// Whether stray serves the `org.kde.StatusNotifierWatcher` name itself, see [`StatusNotifierWatcher::mode`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatcherMode {
+    /// Stray owns the watcher name and serves the StatusNotifierWatcher interface.
+    Watcher,
+    /// Another process, ex: Plasma or another bar, already owns the watcher name or took it over,
+    /// see [`NotifierItemMessage::WatcherLost`]. Stray only watches the items registered to it.
+    HostOnly,
+}
 
-    while let Some(notifier) = new_notifier.next().await {
-        let args = notifier.args()?;
-        let service: &str = args.service();
-        tracing::info!(
-            "StatusNotifierItemRegistered signal received service={}",
-            service
-        );
+pub(crate) const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+// The name some applications following the freedesktop proposal look the watcher up with
+const FREEDESKTOP_WATCHER_NAME: &str = "org.freedesktop.StatusNotifierWatcher";
+// The first protocol version served under the freedesktop name as well
+const FREEDESKTOP_NAME_PROTOCOL_VERSION: i32 = 1;
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const BROADCAST_CAPACITY: usize = 5;
+
+// A proxy to the watcher owning `name` on the bus, see `StatusNotifierWatcherBuilder::watcher_name`
+pub(crate) async fn watcher_proxy(
+    connection: &Connection,
+    name: &str,
+) -> zbus::Result<StatusNotifierWatcherProxy<'static>> {
+    StatusNotifierWatcherProxy::builder(connection)
+        .destination(name.to_string())?
+        .build()
+        .await
+}
 
-        let service = NotifierAddress::from_notifier_service(service);
-        if let Ok(notifier_address) = service {
+// Serve the StatusNotifierWatcher interface, or fall back to the watcher already running on the bus
+async fn start_notifier_watcher(
+    registry: RegistryHandle,
+    router: SignalRouterHandle,
+    config: Arc<WatcherConfig>,
+    refresh: watch::Receiver<u64>,
+    mode_tx: watch::Sender<WatcherMode>,
+) -> Result<()> {
+    let persisted = config.persisted_items.clone();
+    let watcher = DbusNotifierWatcher::new(
+        registry.clone(),
+        config.events.clone(),
+        persisted.clone(),
+        config.protocol_version,
+    );
+
+    let served = config
+        .bus
+        .connection_builder()?
+        .name(config.watcher_name.as_str())?
+        .serve_at(WATCHER_PATH, watcher)?
+        .build()
+        .await;
+
+    let (connection, mode) = match served {
+        Ok(connection) => (connection, WatcherMode::Watcher),
+        Err(zbus::Error::NameTaken) => {
+            let name = &config.watcher_name;
+            tracing::info!("{name} is already owned, running as a notifier host only");
+            (config.bus.connect().await?, WatcherMode::HostOnly)
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    match mode {
+        WatcherMode::Watcher => {
+            if config.protocol_version >= FREEDESKTOP_NAME_PROTOCOL_VERSION
+                && config.watcher_name == WATCHER_NAME
+            {
+                if let Err(err) = connection.request_name(FREEDESKTOP_WATCHER_NAME).await {
+                    tracing::debug!("Failed to acquire {FREEDESKTOP_WATCHER_NAME}: {err}");
+                }
+            }
+        }
+        WatcherMode::HostOnly => {
+            let proxy = watcher_proxy(&connection, &config.watcher_name).await?;
+            match with_timeout(config.call_timeout, proxy.protocol_version()).await {
+                Ok(version) => tracing::info!("Attached to a watcher speaking protocol {version}"),
+                Err(err) => tracing::warn!("Failed to read the watcher protocol version: {err}"),
+            }
+        }
+    }
+
+    if let (WatcherMode::Watcher, Some(persisted)) = (mode, persisted) {
+        let alive = alive_items(&connection, persisted.load()).await;
+        connection
+            .object_server()
+            .interface::<_, DbusNotifierWatcher>(WATCHER_PATH)
+            .await?
+            .get_mut()
+            .await
+            .adopt(alive);
+    }
+
+    mode_tx.send_replace(mode);
+    match mode {
+        WatcherMode::Watcher => {
             let connection = connection.clone();
-            let sender = sender.clone();
-            tokio::spawn(async move {
-                watch_notifier_props(notifier_address, connection, sender).await?;
-                Result::<()>::Ok(())
+            let registry = registry.clone();
+            let watcher_config = config.clone();
+            config.tasks.spawn(async move {
+                if let Err(err) = serve_watcher(connection, registry, watcher_config, mode_tx).await
+                {
+                    tracing::error!("Status notifier watcher error: {err:?}")
+                }
+            });
+        }
+        WatcherMode::HostOnly => {
+            let connection = connection.clone();
+            let registry = registry.clone();
+            let watcher_config = config.clone();
+            config.tasks.spawn(async move {
+                if let Err(err) = watch_external_watcher(connection, registry, watcher_config).await
+                {
+                    tracing::error!("Status notifier unregistered error: {err:?}")
+                }
             });
         }
     }
+    config.tasks.clone().spawn(async move {
+        if let Err(err) =
+            status_notifier_handle(connection, registry, router, config, refresh).await
+        {
+            tracing::error!("Status notifier error: {err:?}")
+        }
+    });
 
     Ok(())
 }
 
-// Listen for PropertiesChanged on DBus and send an update request on change
-async fn watch_notifier_props(
-    address_parts: NotifierAddress,
+// Unregister the items whose owner left the bus until another process takes the watcher name over,
+// then stop serving the orphaned interface and only watch the items registered to the new owner
+async fn serve_watcher(
     connection: Connection,
-    sender: broadcast::Sender<NotifierItemMessage>,
+    registry: RegistryHandle,
+    config: Arc<WatcherConfig>,
+    mode: watch::Sender<WatcherMode>,
 ) -> Result<()> {
-    tokio::spawn(async move {
-        // Connect to DBus.Properties
-        let dbus_properties_proxy = zbus::fdo::PropertiesProxy::builder(&connection)
-            .destination(address_parts.destination.as_str())?
-            .path(address_parts.path.as_str())?
-            .build()
-            .await?;
+    let name = config.watcher_name.as_str();
+    let dbus_proxy = DBusProxy::new(&connection).await?;
+    let mut name_lost = dbus_proxy.receive_name_lost().await?;
+    let lost = async {
+        while let Some(signal) = name_lost.next().await {
+            if signal.args().is_ok_and(|args| args.name().as_str() == name) {
+                return true;
+            }
+        }
 
-        // call Properties.GetAll once and send an update to the UI
-        fetch_properties_and_update(
-            sender.clone(),
-            &dbus_properties_proxy,
-            address_parts.destination.clone(),
-            connection.clone(),
-        )
-        .await?;
+        false
+    };
 
-        // Connect to the notifier proxy to watch for properties change
-        let notifier_item_proxy = StatusNotifierItemProxy::builder(&connection)
-            .destination(address_parts.destination.as_str())?
-            .path(address_parts.path.as_str())?
-            .build()
-            .await?;
+    tokio::select! {
+        result = watch_removed_notifiers(connection.clone(), name, config.call_timeout) => return result,
+        lost = lost => if !lost {
+            return Ok(());
+        },
+    }
 
-        let mut props_changed = notifier_item_proxy.receive_all_signals().await?;
+    tracing::warn!("Another process took {name} over, running as a notifier host only");
+    connection
+        .object_server()
+        .remove::<DbusNotifierWatcher, _>(WATCHER_PATH)
+        .await?;
+    let _ = connection.release_name(FREEDESKTOP_WATCHER_NAME).await;
+    let _ = mode.send(WatcherMode::HostOnly);
+    registry.watcher_lost().await?;
+    let _ = config.events.send(WatcherEvent::WatcherNameLost);
 
-        // Whenever a property change query all props and update the UI
-        while props_changed.next().await.is_some() {
-            fetch_properties_and_update(
-                sender.clone(),
-                &dbus_properties_proxy,
-                address_parts.destination.clone(),
-                connection.clone(),
-            )
-            .await?;
-        }
+    watch_external_watcher(connection, registry, config).await
+}
 
-        Result::<()>::Ok(())
-    });
+// The external watcher unregisters the items, forward the removals to the registry
+// and its registration signals to the watcher events
+async fn watch_external_watcher(
+    connection: Connection,
+    registry: RegistryHandle,
+    config: Arc<WatcherConfig>,
+) -> Result<()> {
+    let events = &config.events;
+    let watcher_proxy = watcher_proxy(&connection, &config.watcher_name).await?;
+    let mut registered = watcher_proxy
+        .receive_status_notifier_item_registered()
+        .await?;
+    let mut unregistered = watcher_proxy
+        .receive_status_notifier_item_unregistered()
+        .await?;
 
-    Ok(())
+    loop {
+        tokio::select! {
+            Some(signal) = registered.next() => match signal.args() {
+                Ok(args) => {
+                    let _ = events.send(WatcherEvent::ItemRegistered(args.service().to_string()));
+                }
+                Err(err) => tracing::warn!("Malformed StatusNotifierItemRegistered signal: {err}"),
+            },
+            Some(signal) = unregistered.next() => {
+                let args = match signal.args() {
+                    Ok(args) => args,
+                    Err(err) => {
+                        tracing::warn!("Malformed StatusNotifierItemUnregistered signal: {err}");
+                        continue;
+                    }
+                };
+
+                let _ = events.send(WatcherEvent::ItemUnregistered(args.service().to_string()));
+                if let Ok(address) = NotifierAddress::from_notifier_service(args.service()) {
+                    registry.item_removed(&ItemAddress::from_bus(&address)).await?;
+                }
+            }
+            else => return Ok(()),
+        }
+    }
 }
 
-// Fetch Properties from DBus proxy and send an update to the UI channel
-async fn fetch_properties_and_update(
-    sender: broadcast::Sender<NotifierItemMessage>,
-    dbus_properties_proxy: &PropertiesProxy<'_>,
-    item_address: String,
+// Listen for 'NameOwnerChanged' on DBus whenever a service is removed
+// send 'UnregisterStatusNotifierItem' request to 'StatusNotifierWatcher' via dbus
+// and drop the hosts registered under the vanished name
+async fn watch_removed_notifiers(
     connection: Connection,
+    name: &str,
+    call_timeout: Option<Duration>,
 ) -> Result<()> {
-    let interface = InterfaceName::from_static_str("org.kde.StatusNotifierItem")?;
-    let props = dbus_properties_proxy.get_all(interface).await?;
-    let item = StatusNotifierItem::try_from(props);
-
-    // Only send item that maps correctly to our internal StatusNotifierItem representation
-    if let Ok(item) = item {
-        let menu = match &item.menu {
-            None => None,
-            Some(menu_address) => watch_menu(
-                item_address.clone(),
-                item.clone(),
-                connection.clone(),
-                menu_address.clone(),
-                sender.clone(),
-            )
-            .await
-            .ok(),
+    let dbus_proxy = DBusProxy::new(&connection).await?;
+    let watcher_proxy = watcher_proxy(&connection, name).await?;
+    let watcher = connection
+        .object_server()
+        .interface::<_, DbusNotifierWatcher>(WATCHER_PATH)
+        .await?;
+    let mut changed = dbus_proxy.receive_name_owner_changed().await?;
+
+    while let Some(signal) = changed.next().await {
+        let args = match signal.args() {
+            Ok(args) => args,
+            Err(err) => {
+                tracing::warn!("Malformed NameOwnerChanged signal: {err}");
+                continue;
+            }
         };
 
-        tracing::info!("StatusNotifierItem updated, dbus-address={item_address}");
+        if let (Some(old_owner), None) = (args.old_owner().as_ref(), args.new_owner().as_ref()) {
+            if let Err(err) = with_timeout(
+                call_timeout,
+                watcher_proxy.unregister_status_notifier_item(old_owner.as_str()),
+            )
+            .await
+            {
+                tracing::error!("Failed to unregister status notifier: {err:?}")
+            }
 
-        sender
-            .send(NotifierItemMessage::Update {
-                address: item_address.to_string(),
-                item: Box::new(item),
-                menu,
-            })
-            .expect("Failed to dispatch NotifierItemMessage");
+            let mut watcher_ref = watcher.get_mut().await;
+            for name in [args.name().as_str(), old_owner.as_str()] {
+                if let Err(err) = watcher_ref
+                    .remove_host(name, watcher.signal_context())
+                    .await
+                {
+                    tracing::error!("Failed to unregister status notifier host: {err:?}")
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn watch_menu(
-    item_address: String,
-    item: StatusNotifierItem,
+// 1. subscribe to StatusNotifierWatcher.StatusNotifierItemRegistered
+// 2. Query already registered StatusNotifier and start an `ItemWatcher` for each of them
+// 3. Whenever a new notifier is registered start a new `ItemWatcher`
+async fn status_notifier_handle(
     connection: Connection,
-    menu_address: String,
-    sender: broadcast::Sender<NotifierItemMessage>,
-) -> Result<TrayMenu> {
-    let dbus_menu_proxy = DBusMenuProxy::builder(&connection)
-        .destination(item_address.as_str())?
-        .path(menu_address.as_str())?
-        .build()
+    registry: RegistryHandle,
+    router: SignalRouterHandle,
+    config: Arc<WatcherConfig>,
+    refresh: watch::Receiver<u64>,
+) -> Result<()> {
+    let status_notifier_proxy = watcher_proxy(&connection, &config.watcher_name).await?;
+
+    // Subscribe first so an item registering meanwhile is not missed, at worst it is watched twice
+    // and the registry only keeps its latest watcher
+    let mut new_notifier = status_notifier_proxy
+        .receive_status_notifier_item_registered()
         .await?;
 
-    let menu: MenuLayout = dbus_menu_proxy.get_layout(0, 10, &[]).await.unwrap();
+    let notifier_items: Vec<String> = with_timeout(
+        config.call_timeout,
+        status_notifier_proxy.registered_status_notifier_items(),
+    )
+    .await?;
 
-    tokio::spawn(async move {
-        let dbus_menu_proxy = DBusMenuProxy::builder(&connection)
-            .destination(item_address.as_str())?
-            .path(menu_address.as_str())?
-            .build()
+    tracing::info!("Got {} notifier items", notifier_items.len());
+
+    // Start watching for all registered notifier items
+    for service in notifier_items.iter() {
+        let service = NotifierAddress::from_notifier_service(service);
+        if let Ok(notifier_address) = service {
+            ItemWatcher::new(
+                notifier_address,
+                connection.clone(),
+                config.clone(),
+                registry.clone(),
+                router.clone(),
+                refresh.clone(),
+            )
+            .start()
             .await?;
+        }
+    }
 
-        let mut props_changed = dbus_menu_proxy.receive_all_signals().await?;
+    while let Some(notifier) = new_notifier.next().await {
+        let args = match notifier.args() {
+            Ok(args) => args,
+            Err(err) => {
+                tracing::warn!("Malformed StatusNotifierItemRegistered signal: {err}");
+                continue;
+            }
+        };
+        let service: &str = args.service();
+        tracing::info!(
+            "StatusNotifierItemRegistered signal received service={}",
+            service
+        );
 
-        while props_changed.next().await.is_some() {
-            let menu: MenuLayout = dbus_menu_proxy.get_layout(0, 10, &[]).await.unwrap();
-            let menu = TrayMenu::try_from(menu).ok();
-            sender.send(NotifierItemMessage::Update {
-                address: item_address.to_string(),
-                item: Box::new(item.clone()),
-                menu,
-            })?;
+        let service = NotifierAddress::from_notifier_service(service);
+        if let Ok(notifier_address) = service {
+            ItemWatcher::new(
+                notifier_address,
+                connection.clone(),
+                config.clone(),
+                registry.clone(),
+                router.clone(),
+                refresh.clone(),
+            )
+            .start()
+            .await?;
         }
-        anyhow::Result::<(), anyhow::Error>::Ok(())
-    });
+    }
 
-    TrayMenu::try_from(menu).map_err(Into::into)
+    Ok(())
 }
@@ -1,10 +1,37 @@
 use crate::error;
 use crate::error::StatusNotifierWatcherError;
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::watcher_proxy;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use zbus::names::BusName;
+use zbus::zvariant::ObjectPath;
+use zbus::Connection;
 
-// A helper to convert RegisterStatusNotifier calls to
-// StatusNotifier address parts
-#[derive(Debug)]
-pub(crate) struct NotifierAddress {
+const DEFAULT_ITEM_PATH: &str = "/StatusNotifierItem";
+
+/// The destination and object path of a StatusNotifierItem, parsed from the services listed in
+/// `RegisteredStatusNotifierItems` or carried by the `StatusNotifierItemRegistered` signal.
+///
+/// ```rust
+/// # fn main() -> stray::error::Result<()> {
+/// use stray::NotifierAddress;
+///
+/// let address: NotifierAddress = ":1.1234/org/ayatana/NotificationItem/nm".parse()?;
+/// assert_eq!(address.destination(), ":1.1234");
+/// assert_eq!(address.path(), "/org/ayatana/NotificationItem/nm");
+///
+/// let address: NotifierAddress = "org.kde.StatusNotifierItem-42-1".parse()?;
+/// assert_eq!(address.path(), "/StatusNotifierItem");
+///
+/// // The destination of a bare object path is only known to the watcher
+/// assert!(NotifierAddress::from_notifier_service("/StatusNotifierItem").is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct NotifierAddress {
     // Notifier destination on the bus, ex: ":1.522"
     pub(crate) destination: String,
     // The notifier object path, ex: "/org/ayatana/NotificationItem/Element1"
@@ -12,21 +39,146 @@ pub(crate) struct NotifierAddress {
 }
 
 impl NotifierAddress {
-    pub(crate) fn from_notifier_service(service: &str) -> error::Result<Self> {
-        if let Some((destination, path)) = service.split_once('/') {
-            Ok(NotifierAddress {
-                destination: destination.to_string(),
-                path: format!("/{}", path),
-            })
-        } else if service.starts_with(':') {
-            Ok(NotifierAddress {
-                destination: service[0..6].to_string(),
-                path: "/StatusNotifierItem".to_string(),
-            })
-        } else {
-            Err(StatusNotifierWatcherError::DbusAddressError(
-                service.to_string(),
-            ))
+    /// Parse a registered service, either a bus name followed by the item object path, ex:
+    /// `:1.522/org/ayatana/NotificationItem/Element1`, or a bus name alone for items exported at
+    /// `/StatusNotifierItem`. Returns [`StatusNotifierWatcherError::DbusAddressError`] if the bus
+    /// name or the object path is invalid.
+    pub fn from_notifier_service(service: &str) -> error::Result<Self> {
+        let invalid = || StatusNotifierWatcherError::DbusAddressError(service.to_string());
+        let (destination, path) = match service.find('/') {
+            Some(0) => return Err(invalid()),
+            Some(index) => service.split_at(index),
+            None => (service, DEFAULT_ITEM_PATH),
+        };
+
+        BusName::try_from(destination).map_err(|_| invalid())?;
+        ObjectPath::try_from(path).map_err(|_| invalid())?;
+
+        Ok(NotifierAddress {
+            destination: destination.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Returns the bus name owning the item, ex: `:1.522`.
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    /// Returns the object path of the item, ex: `/StatusNotifierItem`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Find the registered StatusNotifierItem at the given address, a bus name alone designates
+    // the first item registered by its owner
+    pub(crate) async fn resolve(
+        connection: &Connection,
+        watcher_name: &str,
+        notifier_address: &str,
+        call_timeout: Option<Duration>,
+    ) -> error::Result<Self> {
+        if notifier_address.contains('/') {
+            return NotifierAddress::from_notifier_service(notifier_address);
+        }
+
+        let status_notifier_proxy = watcher_proxy(connection, watcher_name).await?;
+
+        with_timeout(
+            call_timeout,
+            status_notifier_proxy.registered_status_notifier_items(),
+        )
+        .await?
+        .iter()
+        .filter_map(|service| NotifierAddress::from_notifier_service(service).ok())
+        .find(|address| address.destination == notifier_address)
+        .ok_or_else(|| StatusNotifierWatcherError::DbusAddressError(notifier_address.to_string()))
+    }
+}
+
+impl FromStr for NotifierAddress {
+    type Err = StatusNotifierWatcherError;
+
+    fn from_str(service: &str) -> error::Result<Self> {
+        NotifierAddress::from_notifier_service(service)
+    }
+}
+
+// Formatted as in `RegisteredStatusNotifierItems`
+impl fmt::Display for NotifierAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.destination, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_unique_name_and_path() {
+        let address =
+            NotifierAddress::from_notifier_service(":1.1234/org/ayatana/NotificationItem/nm")
+                .unwrap();
+
+        assert_eq!(address.destination(), ":1.1234");
+        assert_eq!(address.path(), "/org/ayatana/NotificationItem/nm");
+        assert_eq!(
+            address.to_string(),
+            ":1.1234/org/ayatana/NotificationItem/nm"
+        );
+    }
+
+    #[test]
+    fn should_default_unique_name_to_item_path() {
+        let address = NotifierAddress::from_notifier_service(":1.52").unwrap();
+
+        assert_eq!(address.destination(), ":1.52");
+        assert_eq!(address.path(), "/StatusNotifierItem");
+    }
+
+    #[test]
+    fn should_default_well_known_name_to_item_path() {
+        let address =
+            NotifierAddress::from_notifier_service("org.kde.StatusNotifierItem-42-1").unwrap();
+
+        assert_eq!(address.destination(), "org.kde.StatusNotifierItem-42-1");
+        assert_eq!(address.path(), "/StatusNotifierItem");
+    }
+
+    #[test]
+    fn should_reject_path_only() {
+        // The owner of a bare object path is only known to the watcher which received it
+        let result = NotifierAddress::from_notifier_service("/StatusNotifierItem");
+
+        assert!(matches!(
+            result,
+            Err(StatusNotifierWatcherError::DbusAddressError(service)) if service == "/StatusNotifierItem"
+        ));
+    }
+
+    #[test]
+    fn should_reject_malformed_services() {
+        for service in [
+            "",
+            ":1.52/StatusNotifierItem/",
+            ":1.52//StatusNotifierItem",
+            ":1.52/org/kde/Item-1",
+            "not a bus name",
+            "org..kde/StatusNotifierItem",
+        ] {
+            assert!(
+                NotifierAddress::from_notifier_service(service).is_err(),
+                "{service:?} should be rejected"
+            );
         }
     }
+
+    #[test]
+    fn should_round_trip_through_display() {
+        let service = ":1.7/StatusNotifierItem";
+        let address: NotifierAddress = service.parse().unwrap();
+
+        assert_eq!(address.to_string(), service);
+    }
 }
@@ -0,0 +1,86 @@
+use std::any::Any;
+use std::future::{poll_fn, Future};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::task::Poll;
+use std::time::Duration;
+
+const MAX_RESTART_INTERVAL: Duration = Duration::from_secs(30);
+
+// Resolve to the panic message when `future` panics instead of unwinding into the runtime,
+// a misbehaving item must not take the other items down. The future is dropped after a panic.
+pub(crate) async fn catch_panic<F: Future>(future: F) -> Result<F::Output, String> {
+    let mut future = Box::pin(future);
+    poll_fn(
+        move |cx| match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic_message(panic.as_ref()))),
+        },
+    )
+    .await
+}
+
+// Double the time to wait before restarting a task which panicked again, or retrying a failed
+// connection. Capped to 30 seconds, intervals configured above that are kept as is.
+pub(crate) fn next_restart_interval(interval: Duration) -> Duration {
+    interval
+        .saturating_mul(2)
+        .min(MAX_RESTART_INTERVAL.max(interval))
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn should_restart_a_panicking_task_with_a_capped_delay() {
+        tokio::time::pause();
+        let started = Instant::now();
+        let mut restart_interval = Duration::from_secs(5);
+        let mut restarts = vec![];
+        let mut runs = 0;
+
+        // Restarted like the item and menu watchers until the task completes
+        loop {
+            runs += 1;
+            let run = runs;
+            let task = async move {
+                if run < 6 {
+                    panic!("run {run} failed");
+                }
+                run
+            };
+
+            match catch_panic(task).await {
+                Ok(run) => {
+                    assert_eq!(run, 6);
+                    break;
+                }
+                Err(panic) => assert_eq!(panic, format!("run {run} failed")),
+            }
+
+            tokio::time::sleep(restart_interval).await;
+            restarts.push(started.elapsed().as_secs());
+            restart_interval = next_restart_interval(restart_interval);
+        }
+
+        assert_eq!(restarts, vec![5, 15, 35, 65, 95]);
+    }
+
+    #[test]
+    fn should_keep_restart_intervals_configured_above_the_cap() {
+        let interval = MAX_RESTART_INTERVAL * 2;
+        assert_eq!(next_restart_interval(interval), interval);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::error::Result;
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::item_watcher::ITEM_INTERFACES;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use zbus::fdo::PropertiesProxy;
+use zbus::names::InterfaceName;
+use zbus::{CacheProperties, Connection};
+
+// Applications which exited fail right away, this only bounds the startup on frozen ones
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Keep the registered items in a file, so they can be adopted again when the watcher restarts.
+// Applications only register once and most of them never do it again when a new watcher shows up.
+// Shared by the sessions of a watcher, so writes never overlap across a bus restart.
+#[derive(Debug, Clone)]
+pub(crate) struct PersistedItems {
+    path: PathBuf,
+    writer: Arc<Mutex<Writer>>,
+}
+
+#[derive(Debug, Default)]
+struct Writer {
+    // The services to write next, only the latest ones are kept
+    latest: Option<Vec<String>>,
+    // Whether a blocking task is writing the file
+    writing: bool,
+}
+
+impl PersistedItems {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        PersistedItems {
+            path,
+            writer: Arc::default(),
+        }
+    }
+
+    // The services registered when the previous watcher stopped, formatted as in
+    // `RegisteredStatusNotifierItems`. A missing or invalid file holds no item.
+    pub(crate) fn load(&self) -> Vec<String> {
+        let Ok(content) = std::fs::read(&self.path) else {
+            return vec![];
+        };
+
+        serde_json::from_slice(&content).unwrap_or_else(|err| {
+            tracing::warn!(
+                "Ignoring invalid persisted items {}: {err}",
+                self.path.display()
+            );
+            vec![]
+        })
+    }
+
+    // Persist the registered services in the background. Writes are done one at a time, the
+    // services stored meanwhile replace each other and only the latest ones are written next.
+    pub(crate) fn store(&self, services: Vec<String>) {
+        {
+            let mut writer = self.lock();
+            writer.latest = Some(services);
+            if writer.writing {
+                return;
+            }
+
+            writer.writing = true;
+        }
+
+        let persisted = self.clone();
+        tokio::task::spawn_blocking(move || loop {
+            // Checked under the same lock as `store`, so the latest services are never left unwritten
+            let services = {
+                let mut writer = persisted.lock();
+                match writer.latest.take() {
+                    Some(services) => services,
+                    None => {
+                        writer.writing = false;
+                        return;
+                    }
+                }
+            };
+
+            if let Err(err) = write(&persisted.path, &services) {
+                tracing::warn!(
+                    "Failed to persist items in {}: {err}",
+                    persisted.path.display()
+                );
+            }
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Write to a temporary file first and rename it, a crash never leaves a truncated file
+fn write(path: &Path, services: &[String]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    std::fs::write(&partial, serde_json::to_vec(services)?)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+// Keep the persisted items still exported on the bus, an item is alive if it answers a property
+// read on one of the item interfaces. Items of applications which exited meanwhile are dropped.
+// Items are pinged concurrently, a few frozen applications only delay the startup by `PING_TIMEOUT`.
+pub(crate) async fn alive_items(
+    connection: &Connection,
+    services: Vec<String>,
+) -> Vec<NotifierAddress> {
+    let mut pings = JoinSet::new();
+    for service in services {
+        let Ok(address) = NotifierAddress::from_notifier_service(&service) else {
+            continue;
+        };
+
+        let connection = connection.clone();
+        pings.spawn(async move {
+            let alive = ping(&connection, &address).await;
+            (service, address, alive)
+        });
+    }
+
+    let mut alive = vec![];
+    while let Some(pinged) = pings.join_next().await {
+        let Ok((service, address, pinged)) = pinged else {
+            continue;
+        };
+
+        match pinged {
+            Ok(true) => alive.push(address),
+            Ok(false) => tracing::debug!("Persisted item {service} is gone"),
+            Err(err) => tracing::debug!("Persisted item {service} did not answer: {err}"),
+        }
+    }
+
+    alive
+}
+
+async fn ping(connection: &Connection, address: &NotifierAddress) -> Result<bool> {
+    let proxy = PropertiesProxy::builder(connection)
+        .destination(address.destination.as_str())?
+        .path(address.path.as_str())?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+
+    for interface in ITEM_INTERFACES {
+        let id = proxy.get(InterfaceName::try_from(interface)?, "Id");
+        if with_timeout(Some(PING_TIMEOUT), id).await.is_ok() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_write_the_latest_services() {
+        let dir = std::env::temp_dir().join(format!("stray-persisted-{}", std::process::id()));
+        let persisted = PersistedItems::new(dir.join("items.json"));
+
+        for count in 1..=20 {
+            let services = (0..count)
+                .map(|item| format!(":1.{item}/StatusNotifierItem"))
+                .collect();
+            persisted.store(services);
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while persisted.lock().writing {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the items were not written");
+
+        assert_eq!(persisted.load().len(), 20);
+        assert!(!dir.join("items.json.part").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
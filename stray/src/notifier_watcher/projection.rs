@@ -0,0 +1,206 @@
+use crate::dbus::notifier_item_proxy::StatusNotifierItemProxy;
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::notifier_watcher::call_timeout::with_timeout;
+use crate::notifier_watcher::item_watcher::ITEM_INTERFACE;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use crate::{InterfaceName, StatusNotifierWatcher};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use zbus::fdo::PropertiesProxy;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+/// A typed view over a user chosen subset of an item properties, see [`StatusNotifierWatcher::projection`].
+#[derive(Debug)]
+pub struct Projection<T> {
+    rx: mpsc::Receiver<Result<T>>,
+    task: JoinHandle<()>,
+}
+
+impl<T> Projection<T> {
+    /// Receive the current projection, then a new one whenever one of the projected properties changes.
+    /// Fails with [`broadcast::error::RecvError::Closed`] once the item is gone.
+    pub async fn recv(&mut self) -> Result<T> {
+        self.rx
+            .recv()
+            .await
+            .unwrap_or(Err(StatusNotifierWatcherError::BroadCastRecvError(
+                broadcast::error::RecvError::Closed,
+            )))
+    }
+}
+
+impl<T> Drop for Projection<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl StatusNotifierWatcher {
+    /// Project the `properties` of the item at `address` into a user defined type. Only the requested
+    /// properties are fetched, including vendor ones, and they are deserialized from a map keyed by the
+    /// dbus property names. Properties the item does not expose are omitted, use `Option` fields for them.
+    ///
+    /// ```rust, no_run
+    /// # use serde::Deserialize;
+    /// # async fn example(tray: stray::StatusNotifierWatcher) -> stray::error::Result<()> {
+    /// #[derive(Deserialize)]
+    /// #[serde(rename_all = "PascalCase")]
+    /// struct Label {
+    ///     title: Option<String>,
+    ///     #[serde(rename = "XAyatanaLabel")]
+    ///     label: Option<String>,
+    /// }
+    ///
+    /// let mut projection = tray.projection::<Label>(":1.52", &["Title", "XAyatanaLabel"]).await?;
+    /// while let Ok(label) = projection.recv().await {
+    ///     // ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn projection<T>(&self, address: &str, properties: &[&str]) -> Result<Projection<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let connection = self.session.borrow().connection.clone();
+        let address = NotifierAddress::resolve(
+            &connection,
+            &self.config.watcher_name,
+            address,
+            self.config.call_timeout,
+        )
+        .await?;
+        let properties: Vec<String> = properties.iter().map(|name| name.to_string()).collect();
+        let call_timeout = self.config.call_timeout;
+        let (tx, rx) = mpsc::channel(8);
+
+        let task = self.config.tasks.spawn(async move {
+            let destination = address.destination.clone();
+            if let Err(err) = watch(connection, address, properties, call_timeout, tx).await {
+                tracing::error!("Projection error, dbus-address={destination}: {err:?}");
+            }
+        });
+
+        Ok(Projection { rx, task })
+    }
+}
+
+// Send a projection, then a new one whenever the item signals a change of one of the projected properties
+async fn watch<T: DeserializeOwned>(
+    connection: Connection,
+    address: NotifierAddress,
+    properties: Vec<String>,
+    call_timeout: Option<Duration>,
+    tx: mpsc::Sender<Result<T>>,
+) -> Result<()> {
+    let dbus_properties_proxy = PropertiesProxy::builder(&connection)
+        .destination(address.destination.clone())?
+        .path(address.path.clone())?
+        .build()
+        .await?;
+
+    let notifier_item_proxy = StatusNotifierItemProxy::builder(&connection)
+        .destination(address.destination)?
+        .path(address.path)?
+        .build()
+        .await?;
+
+    let mut item_signals = notifier_item_proxy.receive_all_signals().await?;
+    let mut props_changed = dbus_properties_proxy.receive_properties_changed().await?;
+
+    loop {
+        let projection =
+            with_timeout(call_timeout, project(&dbus_properties_proxy, &properties)).await;
+        if tx.send(projection).await.is_err() {
+            return Ok(());
+        }
+
+        // StatusNotifierItem signals don't tell which property changed, PropertiesChanged does
+        loop {
+            tokio::select! {
+                signal = item_signals.next() => {
+                    if signal.is_none() {
+                        return Ok(());
+                    }
+
+                    break;
+                }
+                signal = props_changed.next() => {
+                    let Some(signal) = signal else {
+                        return Ok(());
+                    };
+
+                    let args = signal.args()?;
+                    let relevant = args.interface_name().as_str() == ITEM_INTERFACE
+                        && properties.iter().any(|name| {
+                            args.changed_properties().contains_key(name.as_str())
+                                || args.invalidated_properties().contains(&name.as_str())
+                        });
+
+                    if relevant {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Get each projected property and deserialize them into `T`
+async fn project<T: DeserializeOwned>(
+    dbus_properties_proxy: &PropertiesProxy<'_>,
+    properties: &[String],
+) -> Result<T> {
+    let interface = InterfaceName::from_static_str(ITEM_INTERFACE)?;
+    let mut values = Map::new();
+    for name in properties {
+        if let Ok(value) = dbus_properties_proxy.get(interface.clone(), name).await {
+            values.insert(name.clone(), to_json(&value));
+        }
+    }
+
+    Ok(serde_json::from_value(serde_json::Value::Object(values))?)
+}
+
+fn to_json(value: &Value<'_>) -> serde_json::Value {
+    match value {
+        Value::U8(v) => (*v).into(),
+        Value::Bool(v) => (*v).into(),
+        Value::I16(v) => (*v).into(),
+        Value::U16(v) => (*v).into(),
+        Value::I32(v) => (*v).into(),
+        Value::U32(v) => (*v).into(),
+        Value::I64(v) => (*v).into(),
+        Value::U64(v) => (*v).into(),
+        Value::F64(v) => Number::from_f64(*v)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Value::Str(v) => v.as_str().into(),
+        Value::Signature(v) => v.as_str().into(),
+        Value::ObjectPath(v) => v.as_str().into(),
+        Value::Value(v) => to_json(v),
+        Value::Array(v) => v.iter().map(to_json).collect(),
+        Value::Dict(v) => {
+            // Only string keyed dictionaries map to json objects
+            let entries: HashMap<String, Value> = match v.clone().try_into() {
+                Ok(entries) => entries,
+                Err(_) => return serde_json::Value::Null,
+            };
+
+            entries
+                .iter()
+                .map(|(key, value)| (key.clone(), to_json(value)))
+                .collect::<Map<_, _>>()
+                .into()
+        }
+        Value::Structure(v) => v.fields().iter().map(to_json).collect(),
+        // File descriptors and gvariant maybes are not meaningful here
+        _ => serde_json::Value::Null,
+    }
+}
@@ -0,0 +1,1212 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::menu::{MenuHandle, TrayMenu};
+use crate::message::tray::{SpecViolation, Status};
+use crate::message::{ItemAddress, ItemKey, MessageSequence, MessageStamp};
+use crate::notifier_watcher::builder::WatcherConfig;
+#[cfg(feature = "png")]
+use crate::notifier_watcher::icon_files::{IconFiles, IconFilesHandle, IconWorker};
+use crate::notifier_watcher::menu_cache::MenuCache;
+use crate::tray_state::TrayEntry;
+use crate::{NotifierItemMessage, StatusNotifierItem};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+// Events sent to the registry by the item and menu watchers
+#[derive(Debug)]
+pub(crate) enum RegistryEvent {
+    ItemUpdated {
+        address: ItemAddress,
+        item: Box<StatusNotifierItem>,
+        menu: MenuHandle,
+    },
+    MenuUpdated {
+        address: ItemAddress,
+        menu: Option<TrayMenu>,
+    },
+    MenuItemToggled {
+        address: ItemAddress,
+        id: i32,
+    },
+    ItemRejected {
+        address: ItemAddress,
+        violations: Vec<SpecViolation>,
+    },
+    ItemRemoved {
+        address: ItemAddress,
+    },
+    WatcherStarted {
+        address: ItemAddress,
+        watcher: JoinHandle<()>,
+    },
+    ItemFailed {
+        address: ItemAddress,
+        error: String,
+    },
+    // A command sent without a reply channel failed, see `CommandDispatcher`
+    CommandFailed {
+        address: ItemAddress,
+        command: String,
+        error: String,
+    },
+    // The icon of the item was written to `icon_file`, see `IconWorker`
+    #[cfg(feature = "png")]
+    IconMaterialized {
+        address: ItemAddress,
+        icon_file: Option<std::path::PathBuf>,
+        generation: u64,
+    },
+    // Broadcast the next update of every item, even if identical to the previous one
+    Resync,
+    // Broadcast the current update of every item right away, ex: after the icon theme changed
+    #[cfg(feature = "icon-loader")]
+    Republish,
+    // Forget every item, the connection to the session bus was lost
+    Reset,
+    // Another process took the watcher name over
+    WatcherLost,
+    Snapshot {
+        reply: oneshot::Sender<BTreeMap<ItemAddress, TrayEntry>>,
+    },
+    CurrentState {
+        visible: Vec<(ItemAddress, ItemKey)>,
+        reply: oneshot::Sender<Vec<NotifierItemMessage>>,
+    },
+}
+
+// A cheap handle used by other subsystems to report changes to the registry
+#[derive(Debug, Clone)]
+pub(crate) struct RegistryHandle(mpsc::Sender<RegistryEvent>);
+
+impl RegistryHandle {
+    pub(crate) async fn item_updated(
+        &self,
+        address: &ItemAddress,
+        item: StatusNotifierItem,
+        menu: MenuHandle,
+    ) -> Result<()> {
+        self.send(RegistryEvent::ItemUpdated {
+            address: address.clone(),
+            item: Box::new(item),
+            menu,
+        })
+        .await
+    }
+
+    pub(crate) async fn menu_updated(
+        &self,
+        address: &ItemAddress,
+        menu: Option<TrayMenu>,
+    ) -> Result<()> {
+        self.send(RegistryEvent::MenuUpdated {
+            address: address.clone(),
+            menu,
+        })
+        .await
+    }
+
+    // Optimistically toggle a menu item until the menu watcher reports the actual layout
+    pub(crate) async fn menu_item_toggled(&self, address: &ItemAddress, id: i32) -> Result<()> {
+        self.send(RegistryEvent::MenuItemToggled {
+            address: address.clone(),
+            id,
+        })
+        .await
+    }
+
+    pub(crate) async fn item_rejected(
+        &self,
+        address: &ItemAddress,
+        violations: Vec<SpecViolation>,
+    ) -> Result<()> {
+        self.send(RegistryEvent::ItemRejected {
+            address: address.clone(),
+            violations,
+        })
+        .await
+    }
+
+    pub(crate) async fn item_removed(&self, address: &ItemAddress) -> Result<()> {
+        self.send(RegistryEvent::ItemRemoved {
+            address: address.clone(),
+        })
+        .await
+    }
+
+    pub(crate) async fn item_failed(
+        &self,
+        address: &ItemAddress,
+        error: impl ToString,
+    ) -> Result<()> {
+        self.send(RegistryEvent::ItemFailed {
+            address: address.clone(),
+            error: error.to_string(),
+        })
+        .await
+    }
+
+    pub(crate) async fn command_failed(
+        &self,
+        address: ItemAddress,
+        command: &str,
+        error: String,
+    ) -> Result<()> {
+        self.send(RegistryEvent::CommandFailed {
+            address,
+            command: command.to_string(),
+            error,
+        })
+        .await
+    }
+
+    #[cfg(feature = "png")]
+    pub(crate) async fn icon_materialized(
+        &self,
+        address: &ItemAddress,
+        icon_file: Option<std::path::PathBuf>,
+        generation: u64,
+    ) -> Result<()> {
+        self.send(RegistryEvent::IconMaterialized {
+            address: address.clone(),
+            icon_file,
+            generation,
+        })
+        .await
+    }
+
+    pub(crate) async fn watcher_started(
+        &self,
+        address: &ItemAddress,
+        watcher: JoinHandle<()>,
+    ) -> Result<()> {
+        self.send(RegistryEvent::WatcherStarted {
+            address: address.clone(),
+            watcher,
+        })
+        .await
+    }
+
+    pub(crate) async fn resync(&self) -> Result<()> {
+        self.send(RegistryEvent::Resync).await
+    }
+
+    #[cfg(feature = "icon-loader")]
+    pub(crate) async fn republish(&self) -> Result<()> {
+        self.send(RegistryEvent::Republish).await
+    }
+
+    pub(crate) async fn reset(&self) -> Result<()> {
+        self.send(RegistryEvent::Reset).await
+    }
+
+    pub(crate) async fn watcher_lost(&self) -> Result<()> {
+        self.send(RegistryEvent::WatcherLost).await
+    }
+
+    // The current state of every known item
+    pub(crate) async fn snapshot(&self) -> Result<BTreeMap<ItemAddress, TrayEntry>> {
+        let (reply, snapshot) = oneshot::channel();
+        self.send(RegistryEvent::Snapshot { reply }).await?;
+        snapshot
+            .await
+            .map_err(|_| StatusNotifierWatcherError::RegistryClosed)
+    }
+
+    // The messages bringing a host up to date: a removal for each of the `visible` items which
+    // are gone and an update for every known item, regardless of what was already broadcast
+    pub(crate) async fn current_state(
+        &self,
+        visible: Vec<(ItemAddress, ItemKey)>,
+    ) -> Result<Vec<NotifierItemMessage>> {
+        let (reply, updates) = oneshot::channel();
+        self.send(RegistryEvent::CurrentState { visible, reply })
+            .await?;
+        updates
+            .await
+            .map_err(|_| StatusNotifierWatcherError::RegistryClosed)
+    }
+
+    async fn send(&self, event: RegistryEvent) -> Result<()> {
+        self.0
+            .send(event)
+            .await
+            .map_err(|_| StatusNotifierWatcherError::RegistryClosed)
+    }
+}
+
+#[derive(Debug)]
+struct RegistryEntry {
+    key: ItemKey,
+    item: StatusNotifierItem,
+    menu: MenuHandle,
+    // The status broadcast to the hosts, it lags behind `item.status` while a transition is held back
+    status: Status,
+    status_since: Instant,
+    // When the held back transition to `item.status` can be published
+    pending_until: Option<Instant>,
+    // The last update broadcast for this item, identical updates are not broadcast again
+    published: Option<PublishedContent>,
+    // When the changes received during the debounce window are broadcast
+    debounce_until: Option<Instant>,
+}
+
+impl RegistryEntry {
+    // The item as seen by the hosts, with the possibly held back status
+    fn published_item(&self) -> StatusNotifierItem {
+        StatusNotifierItem {
+            status: self.status,
+            ..self.item.clone()
+        }
+    }
+
+    fn update_message(&self, address: &ItemAddress) -> NotifierItemMessage {
+        NotifierItemMessage::Update {
+            address: address.clone(),
+            key: self.key.clone(),
+            item: Arc::new(self.published_item()),
+            menu: self.menu.clone(),
+            stamp: MessageStamp::unstamped(),
+        }
+    }
+
+    fn published_content(&self) -> PublishedContent {
+        PublishedContent {
+            key: self.key.clone(),
+            item: self.published_item(),
+            layout: self.menu.layout.clone(),
+            stale: self.menu.stale,
+        }
+    }
+}
+
+// What the hosts saw of an item. Pixmaps and menu layouts are shared and only ever replaced by
+// new allocations, holding on to them makes comparing their pointers enough to detect a change.
+#[derive(Debug)]
+struct PublishedContent {
+    key: ItemKey,
+    item: StatusNotifierItem,
+    layout: Option<Arc<TrayMenu>>,
+    stale: bool,
+}
+
+impl PublishedContent {
+    fn same_as(&self, other: &PublishedContent) -> bool {
+        let same_layout = match (&self.layout, &other.layout) {
+            (Some(layout), Some(other)) => Arc::ptr_eq(layout, other),
+            (layout, other) => layout.is_none() && other.is_none(),
+        };
+
+        self.key == other.key
+            && same_layout
+            && self.stale == other.stale
+            && same_item(&self.item, &other.item)
+    }
+}
+
+fn same_item(item: &StatusNotifierItem, other: &StatusNotifierItem) -> bool {
+    // Destructured so a new field is not silently left out of the comparison
+    let StatusNotifierItem {
+        id,
+        category,
+        status,
+        icon_name,
+        icon_accessible_desc,
+        attention_icon_name,
+        title,
+        icon_theme_path,
+        icon_pixmap,
+        menu,
+        item_is_menu,
+        window_id,
+        label,
+        label_guide,
+        ordering_index,
+        icon_file,
+    } = item;
+
+    let same_pixmaps = match (icon_pixmap, &other.icon_pixmap) {
+        (Some(pixmaps), Some(other)) => {
+            pixmaps.len() == other.len()
+                && pixmaps.iter().zip(other).all(|(pixmap, other)| {
+                    pixmap.width == other.width
+                        && pixmap.height == other.height
+                        && Arc::ptr_eq(&pixmap.pixels, &other.pixels)
+                })
+        }
+        (pixmaps, other) => pixmaps.is_none() && other.is_none(),
+    };
+
+    same_pixmaps
+        && *id == other.id
+        && *category == other.category
+        && *status == other.status
+        && *icon_name == other.icon_name
+        && *icon_accessible_desc == other.icon_accessible_desc
+        && *attention_icon_name == other.attention_icon_name
+        && *title == other.title
+        && *icon_theme_path == other.icon_theme_path
+        && *menu == other.menu
+        && *item_is_menu == other.item_is_menu
+        && *window_id == other.window_id
+        && *label == other.label
+        && *label_guide == other.label_guide
+        && *ordering_index == other.ordering_index
+        && *icon_file == other.icon_file
+}
+
+// Owns the state of every known item, merges item and menu changes
+// and broadcast the resulting messages to the notifier hosts.
+#[derive(Debug)]
+pub(crate) struct Registry {
+    config: Arc<WatcherConfig>,
+    menu_cache: Option<MenuCache>,
+    entries: HashMap<ItemAddress, RegistryEntry>,
+    // The item watcher tasks, aborted when their item is removed
+    watchers: HashMap<ItemAddress, JoinHandle<()>>,
+    sender: broadcast::Sender<NotifierItemMessage>,
+    // Every message is stamped right before being broadcast
+    sequence: MessageSequence,
+    #[cfg(feature = "png")]
+    icon_files: Option<IconFilesHandle>,
+    // Spawned along with the registry
+    #[cfg(feature = "png")]
+    icon_worker: Option<IconWorker>,
+    // The latest icon requested for each item, older materialized icons are ignored
+    #[cfg(feature = "png")]
+    icon_requests: HashMap<ItemAddress, u64>,
+    #[cfg(feature = "png")]
+    icon_generation: u64,
+}
+
+impl Registry {
+    pub(crate) fn new(
+        config: Arc<WatcherConfig>,
+        sender: broadcast::Sender<NotifierItemMessage>,
+    ) -> (Self, RegistryHandle, mpsc::Receiver<RegistryEvent>) {
+        let (tx, rx) = mpsc::channel(32);
+        #[cfg(feature = "png")]
+        let (icon_files, icon_worker) = config
+            .materialize_icons
+            .map(|size| {
+                let scale = config.icon_size.map_or(1, |(_, scale)| scale);
+                let icon_files = IconFiles::new(&config.watcher_name, size, scale);
+                #[cfg(feature = "icon-loader")]
+                let icon_files = match config.icon_theme_lookup.clone() {
+                    Some(lookup) => icon_files.with_lookup(lookup),
+                    None => icon_files,
+                };
+                icon_files.into_worker(RegistryHandle(tx.clone()))
+            })
+            .unzip();
+
+        let registry = Registry {
+            menu_cache: config.menu_cache_dir.clone().map(MenuCache::load),
+            config,
+            entries: HashMap::new(),
+            watchers: HashMap::new(),
+            sender,
+            sequence: MessageSequence::default(),
+            #[cfg(feature = "png")]
+            icon_files,
+            #[cfg(feature = "png")]
+            icon_worker,
+            #[cfg(feature = "png")]
+            icon_requests: HashMap::new(),
+            #[cfg(feature = "png")]
+            icon_generation: 0,
+        };
+
+        (registry, RegistryHandle(tx), rx)
+    }
+
+    pub(crate) async fn run(mut self, mut events: mpsc::Receiver<RegistryEvent>) {
+        #[cfg(feature = "png")]
+        if let Some(icon_worker) = self.icon_worker.take() {
+            self.config.tasks.spawn(icon_worker.run());
+        }
+
+        loop {
+            let deadline = self.next_deadline();
+            let messages: Vec<NotifierItemMessage> = tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => self.apply(event).into_iter().collect(),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.publish_due_updates()
+                }
+            };
+
+            for message in messages {
+                let message = self.sequence.stamp(message);
+                if let Err(err) = self.sender.send(message) {
+                    tracing::warn!("No notifier host to receive message: {err}");
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.entries
+            .values()
+            .flat_map(|entry| [entry.pending_until, entry.debounce_until])
+            .flatten()
+            .min()
+    }
+
+    // Publish the held back status transitions whose dwell time is elapsed
+    // and the updates whose debounce window is over
+    fn publish_due_updates(&mut self) -> Vec<NotifierItemMessage> {
+        let now = Instant::now();
+        let mut ready = vec![];
+        for (address, entry) in self.entries.iter_mut() {
+            let status_due = entry.pending_until.is_some_and(|until| until <= now);
+            if status_due {
+                entry.status = entry.item.status;
+                entry.status_since = now;
+                entry.pending_until = None;
+            }
+
+            let debounce_due = entry.debounce_until.is_some_and(|until| until <= now);
+            if status_due || debounce_due {
+                entry.debounce_until = None;
+                ready.push(address.clone());
+            }
+        }
+
+        ready
+            .iter()
+            .filter_map(|address| self.update_message(address))
+            .collect()
+    }
+
+    // Update the registry state and return the message to broadcast if any
+    fn apply(&mut self, event: RegistryEvent) -> Option<NotifierItemMessage> {
+        match event {
+            RegistryEvent::ItemUpdated {
+                address,
+                item,
+                mut menu,
+            } => {
+                tracing::info!("StatusNotifierItem updated, dbus-address={address}");
+                let item = *item;
+                let key = ItemKey::new(self.config.identity, &address, &item);
+                #[cfg(feature = "png")]
+                let item = self.with_icon_file(&address, item);
+                let now = Instant::now();
+                let mut status = item.status;
+                let mut status_since = now;
+                let mut pending_until = None;
+                let mut published = None;
+                let mut debounce_until = None;
+
+                if let Some(previous) = self.entries.remove(&address) {
+                    published = previous.published;
+                    debounce_until = previous.debounce_until;
+
+                    // Keep the cached layout as long as the menu path does not change
+                    if previous.menu.path == menu.path {
+                        menu.layout = previous.menu.layout;
+                        menu.stale = previous.menu.stale;
+                    }
+
+                    // Hold back status transitions until the previous status dwell time is elapsed
+                    let dwell = self.config.status_dwell(&item.id).unwrap_or_default();
+                    let publish_at = previous.status_since + dwell;
+                    if item.status == previous.status || publish_at > now {
+                        status = previous.status;
+                        status_since = previous.status_since;
+                    }
+
+                    if item.status != previous.status && publish_at > now {
+                        tracing::debug!(
+                            "Holding back status {:?} of {address} for {:?}",
+                            item.status,
+                            publish_at - now
+                        );
+                        pending_until = Some(publish_at);
+                    }
+                } else if let Some(cached) = self
+                    .menu_cache
+                    .as_ref()
+                    .and_then(|cache| cache.get(&item.id))
+                    .filter(|_| menu.path.is_some())
+                {
+                    // Deliver the menu of the previous session until the live one is fetched
+                    menu.layout = Some(Arc::new(cached.clone()));
+                    menu.stale = true;
+                }
+
+                self.entries.insert(
+                    address.clone(),
+                    RegistryEntry {
+                        key,
+                        item,
+                        menu,
+                        status,
+                        status_since,
+                        pending_until,
+                        published,
+                        debounce_until,
+                    },
+                );
+                self.debounced_update(&address)
+            }
+            RegistryEvent::MenuUpdated { address, menu } => match self.entries.get_mut(&address) {
+                Some(entry) => {
+                    if let (Some(cache), Some(menu)) = (self.menu_cache.as_mut(), &menu) {
+                        cache.store(&entry.item.id, menu);
+                    }
+
+                    entry.menu.layout = menu.map(Arc::new);
+                    entry.menu.stale = false;
+                    self.debounced_update(&address)
+                }
+                None => {
+                    tracing::warn!("Received a menu for unknown item, dbus-address={address}");
+                    None
+                }
+            },
+            RegistryEvent::MenuItemToggled { address, id } => {
+                let toggled = self
+                    .entries
+                    .get_mut(&address)
+                    .and_then(|entry| entry.menu.layout.as_mut())
+                    .is_some_and(|layout| Arc::make_mut(layout).toggle(id));
+
+                if toggled {
+                    self.update_message(&address)
+                } else {
+                    tracing::debug!("Menu item {id} of {address} is not togglable");
+                    None
+                }
+            }
+            RegistryEvent::ItemRejected {
+                address,
+                violations,
+            } => {
+                // A rejected item is no longer watched, it can't be published again
+                if let Some(watcher) = self.watchers.remove(&address) {
+                    watcher.abort();
+                }
+
+                self.entries.remove(&address);
+                self.remove_icon_file(&address);
+                Some(NotifierItemMessage::Rejected {
+                    address,
+                    violations,
+                    stamp: MessageStamp::unstamped(),
+                })
+            }
+            RegistryEvent::Resync => {
+                self.entries
+                    .values_mut()
+                    .for_each(|entry| entry.published = None);
+                None
+            }
+            // Published along with the due updates, see `publish_due_updates`
+            #[cfg(feature = "icon-loader")]
+            RegistryEvent::Republish => {
+                let now = Instant::now();
+                for entry in self.entries.values_mut() {
+                    entry.published = None;
+                    entry.debounce_until = Some(now);
+                }
+                None
+            }
+            RegistryEvent::Reset => {
+                self.entries.clear();
+                #[cfg(feature = "png")]
+                if let Some(icon_files) = &self.icon_files {
+                    self.icon_requests.clear();
+                    icon_files.clear();
+                }
+                self.watchers
+                    .drain()
+                    .for_each(|(_, watcher)| watcher.abort());
+                Some(NotifierItemMessage::Reset {
+                    stamp: MessageStamp::unstamped(),
+                })
+            }
+            RegistryEvent::Snapshot { reply } => {
+                let _ = reply.send(self.snapshot());
+                None
+            }
+            RegistryEvent::CurrentState { visible, reply } => {
+                let removals = visible
+                    .into_iter()
+                    .filter(|(address, _)| !self.entries.contains_key(address))
+                    .map(|(address, key)| NotifierItemMessage::Remove {
+                        address,
+                        key,
+                        stamp: MessageStamp::unstamped(),
+                    });
+                let updates = self
+                    .entries
+                    .iter()
+                    .map(|(address, entry)| entry.update_message(address));
+
+                // Only sent to a single host, they are still part of the watcher sequence
+                let messages: Vec<_> = removals.chain(updates).collect();
+                let messages = messages
+                    .into_iter()
+                    .map(|message| self.sequence.stamp(message))
+                    .collect();
+
+                let _ = reply.send(messages);
+                None
+            }
+            RegistryEvent::ItemRemoved { address } => {
+                if let Some(watcher) = self.watchers.remove(&address) {
+                    watcher.abort();
+                }
+
+                self.remove_icon_file(&address);
+                let key = self
+                    .entries
+                    .remove(&address)
+                    .map(|entry| entry.key)
+                    .unwrap_or_else(|| ItemKey::Address(address.clone()));
+
+                Some(NotifierItemMessage::Remove {
+                    address,
+                    key,
+                    stamp: MessageStamp::unstamped(),
+                })
+            }
+            RegistryEvent::WatcherLost => Some(NotifierItemMessage::WatcherLost {
+                stamp: MessageStamp::unstamped(),
+            }),
+            RegistryEvent::WatcherStarted { address, watcher } => {
+                self.watchers.retain(|_, watcher| !watcher.is_finished());
+
+                // The item registered again, only the latest watcher is kept
+                if let Some(previous) = self.watchers.insert(address, watcher) {
+                    previous.abort();
+                }
+
+                None
+            }
+            RegistryEvent::ItemFailed { address, error } => Some(NotifierItemMessage::Error {
+                address,
+                error,
+                stamp: MessageStamp::unstamped(),
+            }),
+            RegistryEvent::CommandFailed {
+                address,
+                command,
+                error,
+            } => Some(NotifierItemMessage::CommandFailed {
+                address,
+                command,
+                error,
+                stamp: MessageStamp::unstamped(),
+            }),
+            #[cfg(feature = "png")]
+            RegistryEvent::IconMaterialized {
+                address,
+                icon_file,
+                generation,
+            } => {
+                // A newer icon is being materialized, or the item is gone
+                if self.icon_requests.get(&address) != Some(&generation) {
+                    return None;
+                }
+
+                self.icon_requests.remove(&address);
+                let entry = self.entries.get_mut(&address)?;
+                if entry.item.icon_file == icon_file {
+                    return None;
+                }
+
+                entry.item.icon_file = icon_file;
+                self.debounced_update(&address)
+            }
+        }
+    }
+
+    // Request the icon of the item to be materialized, see `IconFiles`. The item keeps its current
+    // file until the new one is written
+    #[cfg(feature = "png")]
+    fn with_icon_file(
+        &mut self,
+        address: &ItemAddress,
+        mut item: StatusNotifierItem,
+    ) -> StatusNotifierItem {
+        if let Some(icon_files) = &self.icon_files {
+            self.icon_generation += 1;
+            self.icon_requests
+                .insert(address.clone(), self.icon_generation);
+            icon_files.materialize(address, &item, self.icon_generation);
+            item.icon_file = self
+                .entries
+                .get(address)
+                .and_then(|entry| entry.item.icon_file.clone());
+        }
+        item
+    }
+
+    fn remove_icon_file(&mut self, _address: &ItemAddress) {
+        #[cfg(feature = "png")]
+        if let Some(icon_files) = &self.icon_files {
+            self.icon_requests.remove(_address);
+            icon_files.remove(_address);
+        }
+    }
+
+    // Every entry shares the stamp of the latest message, a snapshot is not a message of its own
+    fn snapshot(&self) -> BTreeMap<ItemAddress, TrayEntry> {
+        let stamp = self.sequence.latest().map(|seq| MessageStamp {
+            seq,
+            timestamp: SystemTime::now(),
+        });
+        self.entries
+            .iter()
+            .map(|(address, entry)| {
+                let entry = TrayEntry {
+                    key: entry.key.clone(),
+                    item: Arc::new(entry.published_item()),
+                    menu: entry.menu.layout.clone(),
+                    stamp,
+                };
+
+                (address.clone(), entry)
+            })
+            .collect()
+    }
+
+    // Build the update message of an item, or hold it back until the end of the debounce window
+    // so a burst of changes is broadcast as a single update
+    fn debounced_update(&mut self, address: &ItemAddress) -> Option<NotifierItemMessage> {
+        let Some(window) = self.config.debounce else {
+            return self.update_message(address);
+        };
+
+        let entry = self.entries.get_mut(address)?;
+        if entry.debounce_until.is_none() {
+            entry.debounce_until = Some(Instant::now() + window);
+        }
+
+        None
+    }
+
+    // Build the update message of an item, unless it is identical to the last one broadcast
+    fn update_message(&mut self, address: &ItemAddress) -> Option<NotifierItemMessage> {
+        let entry = self.entries.get_mut(address)?;
+        let content = entry.published_content();
+        if entry
+            .published
+            .as_ref()
+            .is_some_and(|published| published.same_as(&content))
+        {
+            tracing::trace!("Skipping identical update, dbus-address={address}");
+            return None;
+        }
+
+        entry.published = Some(content);
+        Some(entry.update_message(address))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::tray::{Category, IconPixmap};
+    use std::time::Duration;
+
+    const FIRST: &str = ":1.52/StatusNotifierItem";
+    const SECOND: &str = ":1.52/org/ayatana/NotificationItem/second";
+
+    fn registry() -> Registry {
+        let (sender, _) = broadcast::channel(5);
+        let (registry, _, _) = Registry::new(Arc::new(WatcherConfig::default()), sender);
+        registry
+    }
+
+    fn address(service: &str) -> ItemAddress {
+        ItemAddress::new(service).unwrap()
+    }
+
+    pub(crate) fn item(id: &str) -> StatusNotifierItem {
+        StatusNotifierItem {
+            id: id.to_string(),
+            category: Category::ApplicationStatus,
+            status: Status::Active,
+            icon_name: None,
+            icon_accessible_desc: None,
+            attention_icon_name: None,
+            title: None,
+            icon_theme_path: None,
+            icon_pixmap: None,
+            menu: None,
+            item_is_menu: false,
+            window_id: None,
+            label: None,
+            label_guide: None,
+            ordering_index: None,
+            icon_file: None,
+        }
+    }
+
+    fn updated(service: &str, item: StatusNotifierItem) -> RegistryEvent {
+        RegistryEvent::ItemUpdated {
+            address: address(service),
+            item: Box::new(item),
+            menu: MenuHandle::offline(address(service), None, None),
+        }
+    }
+
+    fn removed(service: &str) -> RegistryEvent {
+        RegistryEvent::ItemRemoved {
+            address: address(service),
+        }
+    }
+
+    // Spawn a registry and return its handle along with the messages it broadcasts
+    fn spawn_registry(
+        config: WatcherConfig,
+    ) -> (RegistryHandle, broadcast::Receiver<NotifierItemMessage>) {
+        let (sender, messages) = broadcast::channel(5);
+        let (registry, handle, events) = Registry::new(Arc::new(config), sender);
+        tokio::spawn(registry.run(events));
+        (handle, messages)
+    }
+
+    fn titled(id: &str, title: &str) -> StatusNotifierItem {
+        StatusNotifierItem {
+            title: Some(title.to_string()),
+            ..item(id)
+        }
+    }
+
+    #[test]
+    fn should_publish_registered_and_updated_items() {
+        let mut registry = registry();
+
+        let Some(NotifierItemMessage::Update {
+            address: first,
+            item: published,
+            ..
+        }) = registry.apply(updated(FIRST, item("first")))
+        else {
+            panic!("expected an update for the registered item");
+        };
+        assert_eq!(first.as_str(), FIRST);
+        assert_eq!(published.id, "first");
+
+        let renamed = StatusNotifierItem {
+            title: Some("First".to_string()),
+            ..item("first")
+        };
+        let Some(NotifierItemMessage::Update {
+            item: published, ..
+        }) = registry.apply(updated(FIRST, renamed))
+        else {
+            panic!("expected an update for the changed item");
+        };
+        assert_eq!(published.title.as_deref(), Some("First"));
+    }
+
+    #[test]
+    fn should_skip_identical_updates() {
+        let mut registry = registry();
+        let pixmap = IconPixmap {
+            width: 1,
+            height: 1,
+            pixels: Arc::from(vec![0xff, 0, 0, 0]),
+        };
+        let first = StatusNotifierItem {
+            icon_pixmap: Some(vec![pixmap.clone()]),
+            ..item("first")
+        };
+
+        assert!(registry.apply(updated(FIRST, first.clone())).is_some());
+        assert!(registry.apply(updated(FIRST, first.clone())).is_none());
+
+        // Pixmaps are compared by allocation, new pixels are always broadcast
+        let repainted = StatusNotifierItem {
+            icon_pixmap: Some(vec![IconPixmap {
+                pixels: Arc::from(vec![0xff, 0, 0, 0]),
+                ..pixmap
+            }]),
+            ..first.clone()
+        };
+        assert!(registry.apply(updated(FIRST, repainted)).is_some());
+
+        // A resync broadcasts the next update even if unchanged
+        assert!(registry.apply(RegistryEvent::Resync).is_none());
+        assert!(registry.apply(updated(FIRST, first)).is_some());
+    }
+
+    #[test]
+    fn should_remove_items() {
+        let mut registry = registry();
+        registry.apply(updated(FIRST, item("first")));
+
+        let Some(NotifierItemMessage::Remove { address, key, .. }) = registry.apply(removed(FIRST))
+        else {
+            panic!("expected a removal");
+        };
+        assert_eq!(address.as_str(), FIRST);
+        assert_eq!(key, ItemKey::Address(ItemAddress::new(FIRST).unwrap()));
+        assert!(registry.snapshot().is_empty());
+    }
+
+    #[test]
+    fn should_keep_the_items_of_a_single_owner_apart() {
+        let mut registry = registry();
+        assert!(registry.apply(updated(FIRST, item("first"))).is_some());
+        assert!(registry.apply(updated(SECOND, item("second"))).is_some());
+        assert_eq!(registry.snapshot().len(), 2);
+
+        registry.apply(removed(SECOND));
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[&address(FIRST)].item.id, "first");
+    }
+
+    #[test]
+    fn should_stamp_snapshots_without_advancing_the_sequence() {
+        let mut registry = registry();
+        registry.apply(updated(FIRST, item("first")));
+        assert_eq!(registry.sequence.latest(), None);
+        assert_eq!(registry.snapshot()[&address(FIRST)].stamp, None);
+
+        for service in [FIRST, SECOND] {
+            let update = registry.apply(updated(service, item(service))).unwrap();
+            registry.sequence.stamp(update);
+        }
+
+        let snapshot = registry.snapshot();
+        let first = snapshot[&address(FIRST)].stamp.unwrap();
+        let second = snapshot[&address(SECOND)].stamp.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.seq, 1);
+        assert_eq!(registry.sequence.latest(), Some(1));
+    }
+
+    #[test]
+    fn should_number_the_messages_of_each_registry_apart() {
+        let mut other = registry();
+        let mut registry = registry();
+
+        let first = registry.apply(updated(FIRST, item("first"))).unwrap();
+        let first = registry.sequence.stamp(first);
+        let other_first = other.apply(updated(FIRST, item("first"))).unwrap();
+        let other_first = other.sequence.stamp(other_first);
+        let second = registry.apply(removed(FIRST)).unwrap();
+        let second = registry.sequence.stamp(second);
+
+        assert_eq!(first.stamp().seq, 0);
+        assert_eq!(other_first.stamp().seq, 0);
+        assert_eq!(second.stamp().seq, 1);
+    }
+
+    #[test]
+    fn should_bring_a_host_up_to_date() {
+        let mut registry = registry();
+        registry.apply(updated(FIRST, item("first")));
+        let (reply, mut messages) = oneshot::channel();
+        let visible = vec![(address(SECOND), ItemKey::Address(address(SECOND)))];
+
+        assert!(registry
+            .apply(RegistryEvent::CurrentState { visible, reply })
+            .is_none());
+
+        let messages = messages.try_recv().unwrap();
+        let seqs: Vec<u64> = messages.iter().map(|message| message.stamp().seq).collect();
+        assert!(matches!(
+            &messages[..],
+            [
+                NotifierItemMessage::Remove { .. },
+                NotifierItemMessage::Update { .. }
+            ]
+        ));
+        assert_eq!(messages[0].address(), Some(&address(SECOND)));
+        assert_eq!(seqs, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_a_burst_of_updates() {
+        tokio::time::pause();
+        let window = Duration::from_millis(50);
+        let (registry, mut messages) = spawn_registry(WatcherConfig {
+            debounce: Some(window),
+            ..WatcherConfig::default()
+        });
+
+        for title in ["one", "two", "three"] {
+            let menu = MenuHandle::offline(address(FIRST), None, None);
+            registry
+                .item_updated(&address(FIRST), titled("first", title), menu)
+                .await
+                .unwrap();
+            tokio::time::sleep(window / 10).await;
+        }
+        assert!(messages.try_recv().is_err());
+
+        // Only the latest state is broadcast once the window is over
+        let Ok(NotifierItemMessage::Update { item, .. }) = messages.recv().await else {
+            panic!("expected an update at the end of the window");
+        };
+        assert_eq!(item.title.as_deref(), Some("three"));
+
+        tokio::time::sleep(window * 2).await;
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_hold_status_transitions_for_the_dwell_time() {
+        tokio::time::pause();
+        let dwell = Duration::from_secs(1);
+        let (registry, mut messages) = spawn_registry(WatcherConfig {
+            status_dwell: Some(dwell),
+            ..WatcherConfig::default()
+        });
+        let first = address(FIRST);
+        let update = |status| {
+            let item = StatusNotifierItem {
+                status,
+                ..item("first")
+            };
+            let menu = MenuHandle::offline(first.clone(), None, None);
+            registry.item_updated(&first, item, menu)
+        };
+
+        update(Status::Active).await.unwrap();
+        let Ok(NotifierItemMessage::Update { item, .. }) = messages.recv().await else {
+            panic!("expected the registered item");
+        };
+        assert_eq!(item.status, Status::Active);
+        let published_at = Instant::now();
+
+        // Only the latest transition is broadcast, once the dwell time is elapsed
+        update(Status::Passive).await.unwrap();
+        tokio::time::sleep(dwell / 2).await;
+        update(Status::NeedsAttention).await.unwrap();
+        assert!(messages.try_recv().is_err());
+
+        let Ok(NotifierItemMessage::Update { item, .. }) = messages.recv().await else {
+            panic!("expected the held back status");
+        };
+        assert_eq!(item.status, Status::NeedsAttention);
+        assert!(published_at.elapsed() >= dwell);
+
+        // Flapping back to the published status within the dwell time is not broadcast
+        update(Status::Active).await.unwrap();
+        update(Status::NeedsAttention).await.unwrap();
+        tokio::time::sleep(dwell * 2).await;
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_abort_the_previous_watcher_on_restart() {
+        let mut registry = registry();
+        let first = tokio::spawn(std::future::pending::<()>());
+        let restarted = tokio::spawn(std::future::pending::<()>());
+        let other = tokio::spawn(std::future::pending::<()>());
+        let (aborted, latest) = (first.abort_handle(), restarted.abort_handle());
+
+        for (service, watcher) in [(FIRST, first), (FIRST, restarted), (SECOND, other)] {
+            let started = RegistryEvent::WatcherStarted {
+                address: address(service),
+                watcher,
+            };
+            assert!(registry.apply(started).is_none());
+        }
+
+        // The item registered again, only its latest watcher is kept
+        tokio::task::yield_now().await;
+        assert!(aborted.is_finished());
+        assert!(!latest.is_finished());
+        assert_eq!(registry.watchers.len(), 2);
+
+        registry.apply(removed(FIRST));
+        tokio::task::yield_now().await;
+        assert!(latest.is_finished());
+        assert_eq!(registry.watchers.len(), 1);
+        assert!(registry.watchers.contains_key(&address(SECOND)));
+    }
+
+    #[tokio::test]
+    async fn should_abort_the_watcher_of_rejected_items() {
+        let mut registry = registry();
+        let watcher = tokio::spawn(std::future::pending::<()>());
+        let aborted = watcher.abort_handle();
+
+        registry.apply(RegistryEvent::WatcherStarted {
+            address: address(FIRST),
+            watcher,
+        });
+        registry.apply(updated(FIRST, item("first")));
+
+        let rejected = registry.apply(RegistryEvent::ItemRejected {
+            address: address(FIRST),
+            violations: vec![],
+        });
+
+        assert!(matches!(
+            rejected,
+            Some(NotifierItemMessage::Rejected { .. })
+        ));
+        tokio::task::yield_now().await;
+        assert!(aborted.is_finished());
+        assert!(registry.watchers.is_empty());
+        assert!(registry.entries.is_empty());
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn should_patch_the_materialized_icon_file() {
+        let (sender, _) = broadcast::channel(5);
+        let config = WatcherConfig {
+            materialize_icons: Some(24),
+            ..WatcherConfig::default()
+        };
+        let (mut registry, _, _) = Registry::new(Arc::new(config), sender);
+
+        // The item is published right away, its icon file follows
+        let Some(NotifierItemMessage::Update {
+            item: published, ..
+        }) = registry.apply(updated(FIRST, item("first")))
+        else {
+            panic!("expected an update for the registered item");
+        };
+        assert_eq!(published.icon_file, None);
+        registry.apply(updated(FIRST, item("first")));
+
+        // The icon requested by the first update is outdated
+        let materialized = |generation: u64| RegistryEvent::IconMaterialized {
+            address: address(FIRST),
+            icon_file: Some(format!("/tmp/first-{generation}.png").into()),
+            generation,
+        };
+        assert!(registry.apply(materialized(1)).is_none());
+
+        let Some(NotifierItemMessage::Update {
+            item: published, ..
+        }) = registry.apply(materialized(2))
+        else {
+            panic!("expected an update with the icon file");
+        };
+        assert_eq!(published.icon_file, Some("/tmp/first-2.png".into()));
+
+        // The next update keeps the file until its icon is materialized
+        let Some(NotifierItemMessage::Update {
+            item: published, ..
+        }) = registry.apply(updated(
+            FIRST,
+            StatusNotifierItem {
+                title: Some("First".to_string()),
+                ..item("first")
+            },
+        ))
+        else {
+            panic!("expected an update for the changed item");
+        };
+        assert_eq!(published.icon_file, Some("/tmp/first-2.png".into()));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::error::Result;
+use crate::notifier_watcher::builder::WatcherConfig;
+use crate::notifier_watcher::health_check::HealthCheck;
+use crate::notifier_watcher::registry::RegistryHandle;
+use crate::notifier_watcher::signal_router::{RouterEvent, SignalRouter, SignalRouterHandle};
+use crate::notifier_watcher::{start_notifier_watcher, WatcherMode};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use zbus::Connection;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+// The subsystems bound to a session bus connection, rebuilt by `supervise` when the bus restarts.
+// Long-lived subsystems read the current session from a `watch` channel.
+#[derive(Debug, Clone)]
+pub(crate) struct BusSession {
+    pub(crate) connection: Connection,
+    pub(crate) router: SignalRouterHandle,
+    // Changes to `HostOnly` when another process takes the watcher name over
+    pub(crate) mode: watch::Receiver<WatcherMode>,
+}
+
+impl BusSession {
+    // Connect to the session bus without claiming any name or spawning any task,
+    // the session is served once the returned `SessionTasks` are spawned
+    pub(crate) async fn connect(config: &WatcherConfig) -> Result<(BusSession, SessionTasks)> {
+        let connection = config.bus.connect().await?;
+        let (router, router_handle, router_rx) = SignalRouter::new(connection.clone());
+        // Reports `Watcher` until the watcher name is claimed
+        let (mode_tx, mode) = watch::channel(WatcherMode::Watcher);
+
+        let session = BusSession {
+            connection,
+            router: router_handle,
+            mode,
+        };
+
+        let tasks = SessionTasks {
+            router,
+            router_rx,
+            mode: mode_tx,
+        };
+
+        Ok((session, tasks))
+    }
+
+    // Connect to the session bus, claim the watcher name and start watching the registered items.
+    // The returned task completes when the connection is lost.
+    pub(crate) async fn start(
+        registry: &RegistryHandle,
+        config: &Arc<WatcherConfig>,
+        refresh: &watch::Receiver<u64>,
+    ) -> Result<(BusSession, JoinHandle<()>)> {
+        let (session, tasks) = Self::connect(config).await?;
+        let closed = tasks.spawn(&session, registry, config, refresh).await?;
+        Ok((session, closed))
+    }
+}
+
+// The subsystems of a session which are not running yet, see `BusSession::connect`
+#[derive(Debug)]
+pub(crate) struct SessionTasks {
+    router: SignalRouter,
+    router_rx: mpsc::Receiver<RouterEvent>,
+    mode: watch::Sender<WatcherMode>,
+}
+
+impl SessionTasks {
+    // Claim the watcher name and start watching the registered items.
+    // The returned task completes when the connection is lost.
+    pub(crate) async fn spawn(
+        self,
+        session: &BusSession,
+        registry: &RegistryHandle,
+        config: &Arc<WatcherConfig>,
+        refresh: &watch::Receiver<u64>,
+    ) -> Result<JoinHandle<()>> {
+        let SessionTasks {
+            router,
+            router_rx,
+            mode,
+        } = self;
+
+        // The router reads every message of the connection, its stream only ends with the connection
+        let closed = config.tasks.spawn(async move {
+            if let Err(err) = router.run(router_rx).await {
+                tracing::error!("Signal router error: {err:?}");
+            }
+        });
+
+        tracing::info!("Starting notifier watcher");
+        start_notifier_watcher(
+            registry.clone(),
+            session.router.clone(),
+            config.clone(),
+            refresh.clone(),
+            mode,
+        )
+        .await?;
+
+        if let Some(interval) = config.health_check_interval {
+            let health_check = HealthCheck::new(
+                session.connection.clone(),
+                registry.clone(),
+                session.router.clone(),
+                config.call_timeout,
+                config.watcher_name.clone(),
+                session.mode.clone(),
+            );
+
+            config.tasks.spawn(async move {
+                if let Err(err) = health_check.run(interval).await {
+                    tracing::error!("Health check error: {err:?}");
+                }
+            });
+        }
+
+        Ok(closed)
+    }
+}
+
+// Start a new session whenever the connection to the session bus is lost, ex: when dbus-daemon
+// or dbus-broker restarts. The hosts receive a `NotifierItemMessage::Reset`, they are registered
+// again to the new watcher along with the items registered on the new bus.
+pub(crate) async fn supervise(
+    sessions: watch::Sender<BusSession>,
+    mut closed: JoinHandle<()>,
+    registry: RegistryHandle,
+    config: Arc<WatcherConfig>,
+    refresh: watch::Receiver<u64>,
+) {
+    loop {
+        let _ = (&mut closed).await;
+
+        // The watcher and its hosts are gone, or the watcher is shutting down
+        if sessions.is_closed() || config.tasks.is_shut_down() {
+            return;
+        }
+
+        tracing::warn!("Lost the session bus connection, reconnecting");
+        if registry.reset().await.is_err() {
+            return;
+        }
+
+        let mut interval = RECONNECT_INTERVAL;
+        loop {
+            match BusSession::start(&registry, &config, &refresh).await {
+                Ok((session, session_closed)) => {
+                    tracing::info!("Reconnected to the session bus");
+                    closed = session_closed;
+                    if sessions.send(session).is_err() {
+                        return;
+                    }
+
+                    config.hosts.reconnect(&config).await;
+                    break;
+                }
+                Err(err) => {
+                    tracing::debug!("Failed to reconnect, retrying in {interval:?}: {err}");
+                    tokio::time::sleep(interval).await;
+                    interval = (interval * 2).min(MAX_RECONNECT_INTERVAL);
+                }
+            }
+        }
+    }
+}
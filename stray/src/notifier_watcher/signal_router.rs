@@ -0,0 +1,215 @@
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::notifier_watcher::item_watcher::ITEM_INTERFACES;
+use crate::notifier_watcher::notifier_address::NotifierAddress;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::StreamExt;
+use zbus::fdo::DBusProxy;
+use zbus::names::BusName;
+use zbus::{Connection, MatchRule, Message, MessageStream, MessageType};
+
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+// Events sent to the signal router by the item watchers
+#[derive(Debug)]
+pub(crate) enum RouterEvent {
+    Subscribe {
+        // The unique name of the item owner, signals never carry well-known names
+        sender: String,
+        path: String,
+        // The address the item was registered with, used to unsubscribe
+        address: NotifierAddress,
+        signals: mpsc::Sender<Arc<Message>>,
+        overflow: Arc<Notify>,
+    },
+    // Stop routing the signals of the item registered at `address`, its watcher stops
+    // once its signal channel is closed
+    Unsubscribe {
+        address: NotifierAddress,
+    },
+}
+
+// The signals routed to an item watcher. When the watcher falls behind, signals are not queued
+// any further, `overflow` is notified instead so the watcher re-fetches every property.
+#[derive(Debug)]
+pub(crate) struct ItemSignals {
+    pub(crate) messages: mpsc::Receiver<Arc<Message>>,
+    pub(crate) overflow: Arc<Notify>,
+}
+
+// A cheap handle used by the item watchers to receive the signals of their item
+#[derive(Debug, Clone)]
+pub(crate) struct SignalRouterHandle {
+    events: mpsc::Sender<RouterEvent>,
+    connection: Connection,
+}
+
+impl SignalRouterHandle {
+    // Receive the StatusNotifierItem signals and the PropertiesChanged signals of the item at `address`
+    pub(crate) async fn subscribe(&self, address: &NotifierAddress) -> Result<ItemSignals> {
+        let sender = if address.destination.starts_with(':') {
+            address.destination.clone()
+        } else {
+            let name = BusName::try_from(address.destination.as_str())?;
+            DBusProxy::new(&self.connection)
+                .await?
+                .get_name_owner(name)
+                .await?
+                .to_string()
+        };
+
+        let (tx, rx) = mpsc::channel(32);
+        let overflow = Arc::new(Notify::new());
+        self.events
+            .send(RouterEvent::Subscribe {
+                sender,
+                path: address.path.clone(),
+                address: address.clone(),
+                signals: tx,
+                overflow: overflow.clone(),
+            })
+            .await
+            .map_err(|_| StatusNotifierWatcherError::RouterClosed)?;
+
+        Ok(ItemSignals {
+            messages: rx,
+            overflow,
+        })
+    }
+
+    // Close the signal channels of the item registered at `address`
+    pub(crate) async fn unsubscribe(&self, address: &NotifierAddress) -> Result<()> {
+        self.events
+            .send(RouterEvent::Unsubscribe {
+                address: address.clone(),
+            })
+            .await
+            .map_err(|_| StatusNotifierWatcherError::RouterClosed)
+    }
+}
+
+// The watcher subscribed to the signals of an item
+#[derive(Debug)]
+struct Route {
+    address: NotifierAddress,
+    signals: mpsc::Sender<Arc<Message>>,
+    overflow: Arc<Notify>,
+}
+
+// Receive the signals of every item on a single message stream and demultiplex them by sender
+// and object path, instead of creating proxies and signal streams for each item.
+#[derive(Debug)]
+pub(crate) struct SignalRouter {
+    connection: Connection,
+    routes: HashMap<(String, String), Route>,
+}
+
+impl SignalRouter {
+    pub(crate) fn new(
+        connection: Connection,
+    ) -> (Self, SignalRouterHandle, mpsc::Receiver<RouterEvent>) {
+        let (tx, rx) = mpsc::channel(32);
+        let handle = SignalRouterHandle {
+            events: tx,
+            connection: connection.clone(),
+        };
+
+        let router = SignalRouter {
+            connection,
+            routes: HashMap::new(),
+        };
+
+        (router, handle, rx)
+    }
+
+    pub(crate) async fn run(mut self, mut events: mpsc::Receiver<RouterEvent>) -> Result<()> {
+        // Subscribe before adding the match rules so no signal is missed
+        let mut messages = MessageStream::from(&self.connection);
+
+        // Items may export either the KDE or the freedesktop interface name
+        let dbus_proxy = DBusProxy::new(&self.connection).await?;
+        for interface in ITEM_INTERFACES {
+            let item_signals = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .interface(interface)?
+                .build();
+            let properties_changed = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .interface(PROPERTIES_INTERFACE)?
+                .member("PropertiesChanged")?
+                .arg(0, interface)?
+                .build();
+
+            dbus_proxy.add_match_rule(item_signals).await?;
+            dbus_proxy.add_match_rule(properties_changed).await?;
+        }
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(RouterEvent::Subscribe { sender, path, address, signals, overflow }) => {
+                        self.routes.insert((sender, path), Route { address, signals, overflow });
+                    }
+                    Some(RouterEvent::Unsubscribe { address }) => {
+                        self.routes.retain(|_, route| route.address != address);
+                    }
+                    None => return Ok(()),
+                },
+                message = messages.next() => match message {
+                    Some(Ok(message)) => self.route(message),
+                    Some(Err(err)) => tracing::warn!("Failed to read dbus message: {err}"),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    // Forward a signal to the watcher of the item which emitted it, if any
+    fn route(&mut self, message: Arc<Message>) {
+        if message.message_type() != MessageType::Signal {
+            return;
+        }
+
+        let interface = message.interface();
+        let is_item_signal = match interface.as_ref().map(|interface| interface.as_str()) {
+            Some(PROPERTIES_INTERFACE) => message
+                .member()
+                .is_some_and(|member| member.as_str() == "PropertiesChanged"),
+            Some(interface) => ITEM_INTERFACES.contains(&interface),
+            None => false,
+        };
+
+        if !is_item_signal {
+            return;
+        }
+
+        let Ok(header) = message.header() else {
+            return;
+        };
+
+        let (Ok(Some(sender)), Some(path)) = (header.sender(), message.path()) else {
+            return;
+        };
+
+        let key = (sender.to_string(), path.to_string());
+        let Some(route) = self.routes.get(&key) else {
+            return;
+        };
+
+        match route.signals.try_send(message) {
+            Ok(()) => {}
+            // Coalesce the pending changes, the notification is kept until the watcher
+            // awaits it, even when several signals overflow in the meantime
+            Err(TrySendError::Full(_)) => {
+                tracing::debug!("Item watcher is busy, coalescing signals from {}", key.0);
+                route.overflow.notify_one();
+            }
+            // The item watcher is gone
+            Err(TrySendError::Closed(_)) => {
+                self.routes.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::error::Result;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// Every task spawned by a watcher, they are stopped together when the watcher
+// is shut down, see `StatusNotifierWatcher::run`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WatcherTasks {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl WatcherTasks {
+    // Spawn a task which is dropped at its next await point once the watcher is shut down
+    pub(crate) fn spawn<F>(&self, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let token = self.token.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = task => {}
+            }
+        })
+    }
+
+    pub(crate) fn is_shut_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    // Stop every task and wait for them to complete
+    pub(crate) async fn shutdown(&self) {
+        self.token.cancel();
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+type Startup = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+// Spawns the tasks of a watcher, built along with the watcher and only polled by
+// `StatusNotifierWatcher::run` so constructing a watcher does not start anything
+pub(crate) struct PendingStartup(Mutex<Option<Startup>>);
+
+impl PendingStartup {
+    pub(crate) fn new<F>(startup: F) -> Self
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        PendingStartup(Mutex::new(Some(Box::pin(startup))))
+    }
+
+    // Returns the startup future, `None` once the watcher was started
+    pub(crate) fn take(&self) -> Option<Startup> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).take()
+    }
+
+    // Whether `StatusNotifierWatcher::run` was called, the startup may still be in progress
+    pub(crate) fn is_taken(&self) -> bool {
+        self.0
+            .lock()
+            .map(|startup| startup.is_none())
+            .unwrap_or(true)
+    }
+}
+
+impl fmt::Debug for PendingStartup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = self.is_taken();
+        f.debug_struct("PendingStartup")
+            .field("started", &started)
+            .finish()
+    }
+}
@@ -0,0 +1,274 @@
+//! Frontends can be developed without any live tray application by recording the messages
+//! received by a notifier host once, and replaying them later:
+//!
+//! ```rust, no_run
+//! # async fn example(tray: stray::StatusNotifierWatcher) -> stray::error::Result<()> {
+//! // Record a session
+//! let mut host = tray.create_notifier_host("recorder").await?.record("tray.jsonl").await?;
+//! while let Ok(message) = host.recv().await {
+//!     // ...
+//! }
+//!
+//! // Replay it, with the original timing
+//! let mut host = stray::replay::ReplayHost::open("tray.jsonl").await?;
+//! while let Ok(message) = host.recv().await {
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Recordings are stored as JSON lines. Replayed menus are only available through
+//! [`crate::message::menu::MenuHandle::cached`] and commands can't be sent back to the items.
+
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::message::menu::{MenuHandle, TrayMenu};
+use crate::message::tray::SpecViolation;
+use crate::message::{ItemAddress, ItemKey, MessageSequence, MessageStamp};
+use crate::notifier_host::NotifierHost;
+use crate::{NotifierItemMessage, StatusNotifierItem};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant};
+
+// A single line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedMessage {
+    // Time elapsed since the start of the recording
+    elapsed_ms: u64,
+    event: RecordedEvent,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RecordedEvent {
+    Update {
+        address: ItemAddress,
+        key: ItemKey,
+        item: Arc<StatusNotifierItem>,
+        menu: Option<TrayMenu>,
+    },
+    Rejected {
+        address: ItemAddress,
+        violations: Vec<SpecViolation>,
+    },
+    CommandFailed {
+        address: ItemAddress,
+        command: String,
+        error: String,
+    },
+    Error {
+        address: ItemAddress,
+        error: String,
+    },
+    Reset,
+    WatcherLost,
+    Remove {
+        address: ItemAddress,
+        key: ItemKey,
+    },
+}
+
+impl From<&NotifierItemMessage> for RecordedEvent {
+    fn from(message: &NotifierItemMessage) -> Self {
+        match message {
+            NotifierItemMessage::Update {
+                address,
+                key,
+                item,
+                menu,
+                ..
+            } => RecordedEvent::Update {
+                address: address.clone(),
+                key: key.clone(),
+                item: item.clone(),
+                menu: menu.cached().cloned(),
+            },
+            NotifierItemMessage::Rejected {
+                address,
+                violations,
+                ..
+            } => RecordedEvent::Rejected {
+                address: address.clone(),
+                violations: violations.clone(),
+            },
+            NotifierItemMessage::CommandFailed {
+                address,
+                command,
+                error,
+                ..
+            } => RecordedEvent::CommandFailed {
+                address: address.clone(),
+                command: command.clone(),
+                error: error.clone(),
+            },
+            NotifierItemMessage::Error { address, error, .. } => RecordedEvent::Error {
+                address: address.clone(),
+                error: error.clone(),
+            },
+            NotifierItemMessage::Reset { .. } => RecordedEvent::Reset,
+            NotifierItemMessage::WatcherLost { .. } => RecordedEvent::WatcherLost,
+            NotifierItemMessage::Remove { address, key, .. } => RecordedEvent::Remove {
+                address: address.clone(),
+                key: key.clone(),
+            },
+        }
+    }
+}
+
+impl From<RecordedEvent> for NotifierItemMessage {
+    fn from(event: RecordedEvent) -> Self {
+        match event {
+            RecordedEvent::Update {
+                address,
+                key,
+                item,
+                menu,
+            } => NotifierItemMessage::Update {
+                menu: MenuHandle::offline(address.clone(), item.menu.clone(), menu),
+                address,
+                key,
+                item,
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::Rejected {
+                address,
+                violations,
+            } => NotifierItemMessage::Rejected {
+                address,
+                violations,
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::CommandFailed {
+                address,
+                command,
+                error,
+            } => NotifierItemMessage::CommandFailed {
+                address,
+                command,
+                error,
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::Error { address, error } => NotifierItemMessage::Error {
+                address,
+                error,
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::Reset => NotifierItemMessage::Reset {
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::WatcherLost => NotifierItemMessage::WatcherLost {
+                stamp: MessageStamp::unstamped(),
+            },
+            RecordedEvent::Remove { address, key } => NotifierItemMessage::Remove {
+                address,
+                key,
+                stamp: MessageStamp::unstamped(),
+            },
+        }
+    }
+}
+
+impl NotifierHost {
+    /// Write every message received by this host to the file at `path`, see [`RecordingHost`].
+    pub async fn record(self, path: impl AsRef<Path>) -> Result<RecordingHost> {
+        let file = File::create(path).await?;
+        Ok(RecordingHost {
+            host: self,
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+}
+
+/// A [`NotifierHost`] writing the messages it receives to a recording file.
+pub struct RecordingHost {
+    host: NotifierHost,
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl RecordingHost {
+    /// Receive the next message and append it to the recording.
+    pub async fn recv(&mut self) -> Result<NotifierItemMessage> {
+        let message = self.host.recv().await?;
+        let recorded = RecordedMessage {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            event: RecordedEvent::from(&message),
+        };
+
+        let mut line = serde_json::to_vec(&recorded)?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+        Ok(message)
+    }
+
+    /// The underlying host, to send commands while recording.
+    pub fn host(&self) -> &NotifierHost {
+        &self.host
+    }
+
+    /// Stop recording and return the underlying host.
+    pub async fn finish(mut self) -> Result<NotifierHost> {
+        self.writer.flush().await?;
+        Ok(self.host)
+    }
+}
+
+/// Replay a recording made with [`NotifierHost::record`], exposing the same `recv` method as [`NotifierHost`].
+pub struct ReplayHost {
+    lines: Lines<BufReader<File>>,
+    start: Instant,
+    realtime: bool,
+    // Stamps are not recorded, the messages are stamped again in replay order
+    sequence: MessageSequence,
+}
+
+impl ReplayHost {
+    /// Open the recording at `path`, messages are replayed with their original timing.
+    pub async fn open(path: impl AsRef<Path>) -> Result<ReplayHost> {
+        let file = File::open(path).await?;
+        Ok(ReplayHost {
+            lines: BufReader::new(file).lines(),
+            start: Instant::now(),
+            realtime: true,
+            sequence: MessageSequence::default(),
+        })
+    }
+
+    /// Replay messages as fast as they are received, useful for reproducible visual tests.
+    pub fn without_delays(mut self) -> Self {
+        self.realtime = false;
+        self
+    }
+
+    /// Receive the next recorded message. Once the recording is exhausted this fails like a closed
+    /// [`NotifierHost`], with [`broadcast::error::RecvError::Closed`].
+    pub async fn recv(&mut self) -> Result<NotifierItemMessage> {
+        loop {
+            let Some(line) = self.lines.next_line().await? else {
+                return Err(StatusNotifierWatcherError::BroadCastRecvError(
+                    broadcast::error::RecvError::Closed,
+                ));
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let recorded: RecordedMessage = serde_json::from_str(&line)?;
+            if self.realtime {
+                tokio::time::sleep_until(self.start + Duration::from_millis(recorded.elapsed_ms))
+                    .await;
+            }
+
+            return Ok(self
+                .sequence
+                .stamp(NotifierItemMessage::from(recorded.event)));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Helpers to validate a stray build against the local dbus daemon and zbus version,
+//! see `examples/headless_smoke.rs`.
+
+use crate::dbus::notifier_watcher_proxy::StatusNotifierWatcherProxy;
+use crate::error::{Result, StatusNotifierWatcherError};
+use crate::notifier_host::NotifierHost;
+use crate::{CancellationToken, NotifierItemMessage, StatusNotifierWatcher};
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use zbus::names::BusName;
+use zbus::{dbus_interface, Connection, ConnectionBuilder};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MOCK_ITEM_IDS: [&str; 2] = ["stray-smoke-one", "stray-smoke-two"];
+
+/// Start a private dbus session, register two mock items against a [`StatusNotifierWatcher`]
+/// and check that a notifier host receives the expected updates and removal.
+///
+/// Note that this overrides `DBUS_SESSION_BUS_ADDRESS` for the current process and requires
+/// the `dbus-daemon` binary to be available.
+pub async fn smoke_test() -> Result<()> {
+    let _bus = PrivateBus::start()?;
+
+    let watcher = StatusNotifierWatcher::new(None).await?;
+    let token = CancellationToken::new();
+    let running = tokio::spawn(watcher.run(token.clone()));
+    wait_for_watcher().await?;
+    let mut host = watcher.create_notifier_host("smoke").await?;
+
+    let mut items = vec![];
+    for id in MOCK_ITEM_IDS {
+        items.push(register_mock_item(id).await?);
+    }
+
+    // Both items should be announced to the host
+    let mut pending = MOCK_ITEM_IDS.to_vec();
+    while !pending.is_empty() {
+        if let NotifierItemMessage::Update { item, .. } = recv(&mut host).await? {
+            pending.retain(|id| *id != item.id);
+        }
+    }
+
+    // Closing an item connection should remove it from the tray
+    let removed = items.remove(0);
+    let removed_address = removed
+        .unique_name()
+        .map(|name| name.to_string())
+        .ok_or_else(|| smoke_error("mock item has no unique name"))?;
+    drop(removed);
+
+    loop {
+        if let NotifierItemMessage::Remove { address, .. } = recv(&mut host).await? {
+            if address.destination() == removed_address {
+                break;
+            }
+        }
+    }
+
+    host.destroy().await?;
+    token.cancel();
+    running
+        .await
+        .map_err(|_| smoke_error("the watcher panicked"))?
+}
+
+fn smoke_error(message: impl Into<String>) -> StatusNotifierWatcherError {
+    StatusNotifierWatcherError::SmokeTestFailed(message.into())
+}
+
+async fn recv(host: &mut NotifierHost) -> Result<NotifierItemMessage> {
+    tokio::time::timeout(TIMEOUT, host.recv())
+        .await
+        .map_err(|_| smoke_error("timed out waiting for a message"))?
+}
+
+// The watcher claims its name in the background, wait for it before registering anything
+async fn wait_for_watcher() -> Result<()> {
+    let connection = Connection::session().await?;
+    let dbus_proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    let name = BusName::try_from("org.kde.StatusNotifierWatcher")?;
+
+    for _ in 0..100 {
+        if dbus_proxy.name_has_owner(name.clone()).await? {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Err(smoke_error("the watcher never acquired its name"))
+}
+
+async fn register_mock_item(id: &str) -> Result<Connection> {
+    let connection = ConnectionBuilder::session()?
+        .serve_at("/StatusNotifierItem", MockItem { id: id.to_string() })?
+        .build()
+        .await?;
+
+    StatusNotifierWatcherProxy::new(&connection)
+        .await?
+        .register_status_notifier_item("/StatusNotifierItem")
+        .await?;
+
+    Ok(connection)
+}
+
+// A dbus-daemon session killed on drop
+struct PrivateBus(Child);
+
+impl PrivateBus {
+    fn start() -> Result<Self> {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| smoke_error(format!("failed to start dbus-daemon: {err}")))?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| smoke_error("failed to read dbus-daemon output"))?;
+
+        let mut address = String::new();
+        BufReader::new(stdout)
+            .read_line(&mut address)
+            .map_err(|err| smoke_error(format!("failed to read dbus-daemon address: {err}")))?;
+
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        Ok(PrivateBus(child))
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// A minimal StatusNotifierItem
+struct MockItem {
+    id: String,
+}
+
+#[dbus_interface(name = "org.kde.StatusNotifierItem")]
+impl MockItem {
+    #[dbus_interface(property)]
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn category(&self) -> String {
+        "ApplicationStatus".to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> String {
+        "Active".to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn title(&self) -> String {
+        self.id.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn icon_name(&self) -> String {
+        "application-x-executable".to_string()
+    }
+}
@@ -0,0 +1,246 @@
+use crate::message::menu::TrayMenu;
+use crate::message::{ItemAddress, ItemKey, MessageStamp};
+use crate::{NotifierItemMessage, StatusNotifierItem};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// The last known state of an item, see [`TrayState`].
+#[derive(Debug, Clone)]
+pub struct TrayEntry {
+    /// The key identifying this item, see [`NotifierItemMessage::Update`]
+    pub key: ItemKey,
+    /// The item properties
+    pub item: Arc<StatusNotifierItem>,
+    /// The menu layout of the item, if it was fetched
+    pub menu: Option<Arc<TrayMenu>>,
+    /// The stamp of the message this entry was last updated from. Entries returned by
+    /// [`crate::StatusNotifierWatcher::items`] all carry the stamp of the latest message,
+    /// `None` if the watcher did not emit any message yet.
+    pub stamp: Option<MessageStamp>,
+}
+
+/// Keep track of the items of the tray by applying the [`NotifierItemMessage`]s received
+/// by a notifier host, so UIs only have to render the current state:
+///
+/// ```rust, no_run
+/// # use stray::message::ItemAddress;
+/// # use stray::tray_state::{TrayEntry, TrayState};
+/// # fn render<'a>(_: impl Iterator<Item = (&'a ItemAddress, &'a TrayEntry)>) {}
+/// # async fn example(mut host: stray::NotifierHost) {
+/// let mut state = TrayState::new();
+/// while let Ok(message) = host.recv().await {
+///     if state.apply(&message) {
+///         render(state.items());
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TrayState {
+    items: BTreeMap<ItemAddress, TrayEntry>,
+    revision: watch::Sender<u64>,
+}
+
+impl Default for TrayState {
+    fn default() -> Self {
+        TrayState {
+            items: BTreeMap::new(),
+            revision: watch::channel(0).0,
+        }
+    }
+}
+
+impl TrayState {
+    /// Creates an empty state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a message to the state, returns true if the state changed.
+    /// Updates older than the current entry of an item are ignored.
+    pub fn apply(&mut self, message: &NotifierItemMessage) -> bool {
+        let changed = match message {
+            NotifierItemMessage::Update {
+                address,
+                key,
+                item,
+                menu,
+                stamp,
+            } => {
+                let stale = self
+                    .items
+                    .get(address)
+                    .and_then(|entry| entry.stamp)
+                    .is_some_and(|current| current.seq > stamp.seq);
+
+                if !stale {
+                    self.items.insert(
+                        address.clone(),
+                        TrayEntry {
+                            key: key.clone(),
+                            item: item.clone(),
+                            menu: menu.shared(),
+                            stamp: Some(*stamp),
+                        },
+                    );
+                }
+
+                !stale
+            }
+            NotifierItemMessage::Remove { address, .. }
+            | NotifierItemMessage::Rejected { address, .. } => self.items.remove(address).is_some(),
+            NotifierItemMessage::Reset { .. } => {
+                let changed = !self.items.is_empty();
+                self.items.clear();
+                changed
+            }
+            NotifierItemMessage::CommandFailed { .. }
+            | NotifierItemMessage::Error { .. }
+            | NotifierItemMessage::WatcherLost { .. } => false,
+        };
+
+        if changed {
+            self.revision.send_modify(|revision| *revision += 1);
+        }
+
+        changed
+    }
+
+    /// Iterate over the known items, ordered by address.
+    pub fn items(&self) -> impl Iterator<Item = (&ItemAddress, &TrayEntry)> {
+        self.items.iter()
+    }
+
+    /// Returns the known items in display order, see [`StatusNotifierItem::sort_key`].
+    /// Items with the same key are ordered by address so the order is deterministic.
+    pub fn sorted_items(&self) -> Vec<(&ItemAddress, &TrayEntry)> {
+        let mut items: Vec<_> = self.items.iter().collect();
+        items.sort_by_cached_key(|(address, entry)| (entry.item.sort_key(), *address));
+        items
+    }
+
+    /// Returns the item at `address` if it is known.
+    pub fn get(&self, address: &ItemAddress) -> Option<&TrayEntry> {
+        self.items.get(address)
+    }
+
+    /// Returns the number of known items.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns true if the tray has no item.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns a copy of the current state, to hand over to a rendering thread.
+    pub fn snapshot(&self) -> BTreeMap<ItemAddress, TrayEntry> {
+        self.items.clone()
+    }
+
+    /// Returns a receiver notified with the state revision every time the state changes.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.revision.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::menu::MenuHandle;
+    use crate::notifier_watcher::registry::tests::item;
+    use std::time::SystemTime;
+
+    const FIRST: &str = ":1.52/StatusNotifierItem";
+    const SECOND: &str = ":1.53/StatusNotifierItem";
+
+    fn stamp(seq: u64) -> MessageStamp {
+        MessageStamp {
+            seq,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    fn updated(service: &str, id: &str, seq: u64) -> NotifierItemMessage {
+        let address = ItemAddress::new(service).unwrap();
+        NotifierItemMessage::Update {
+            key: ItemKey::Address(address.clone()),
+            menu: MenuHandle::offline(address.clone(), None, None),
+            address,
+            item: Arc::new(item(id)),
+            stamp: stamp(seq),
+        }
+    }
+
+    fn removed(service: &str, seq: u64) -> NotifierItemMessage {
+        let address = ItemAddress::new(service).unwrap();
+        NotifierItemMessage::Remove {
+            key: ItemKey::Address(address.clone()),
+            address,
+            stamp: stamp(seq),
+        }
+    }
+
+    fn ids(state: &TrayState) -> Vec<&str> {
+        state
+            .items()
+            .map(|(_, entry)| entry.item.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn should_apply_updates_and_removals() {
+        let mut state = TrayState::new();
+        let revision = state.subscribe();
+
+        assert!(state.apply(&updated(FIRST, "first", 0)));
+        assert!(state.apply(&updated(SECOND, "second", 1)));
+        assert!(state.apply(&updated(FIRST, "renamed", 2)));
+        assert_eq!(ids(&state), vec!["renamed", "second"]);
+        let first = state.get(&ItemAddress::new(FIRST).unwrap()).unwrap();
+        assert_eq!(first.stamp.map(|stamp| stamp.seq), Some(2));
+
+        assert!(state.apply(&removed(SECOND, 3)));
+        assert!(!state.apply(&removed(SECOND, 4)));
+        assert_eq!(ids(&state), vec!["renamed"]);
+        assert_eq!(*revision.borrow(), 4);
+    }
+
+    #[test]
+    fn should_ignore_stale_updates() {
+        let mut state = TrayState::new();
+
+        assert!(state.apply(&updated(FIRST, "latest", 5)));
+        assert!(!state.apply(&updated(FIRST, "stale", 4)));
+        assert_eq!(ids(&state), vec!["latest"]);
+    }
+
+    #[test]
+    fn should_clear_the_items_on_reset() {
+        let mut state = TrayState::new();
+        state.apply(&updated(FIRST, "first", 0));
+        state.apply(&updated(SECOND, "second", 1));
+
+        assert!(state.apply(&NotifierItemMessage::Reset { stamp: stamp(2) }));
+        assert!(state.is_empty());
+        assert!(!state.apply(&NotifierItemMessage::Reset { stamp: stamp(3) }));
+
+        // Items registered on the new bus are tracked again
+        assert!(state.apply(&updated(FIRST, "first", 4)));
+        assert_eq!(ids(&state), vec!["first"]);
+    }
+
+    #[test]
+    fn should_keep_the_items_when_the_watcher_is_lost() {
+        let mut state = TrayState::new();
+        state.apply(&updated(FIRST, "first", 0));
+
+        // The items are kept, stray now watches the items of the new watcher
+        assert!(!state.apply(&NotifierItemMessage::WatcherLost { stamp: stamp(1) }));
+        assert_eq!(ids(&state), vec!["first"]);
+        assert!(state.apply(&removed(FIRST, 2)));
+        assert!(state.is_empty());
+    }
+}